@@ -0,0 +1,327 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::collection_config::get_collection_config;
+use crate::elasticsearch::BulkDocument;
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// The marketplace fields side-indices are computed from
+#[derive(Debug, Clone)]
+pub struct ListingSnapshot {
+    pub token_address: Option<String>,
+    pub owner: Option<String>,
+    pub ron_price: Option<f64>,
+    pub listed: bool,
+}
+
+/// An order counts as a listing while it's open
+fn is_active_listing(order_status: Option<&str>, state: Option<&str>) -> bool {
+    order_status.is_some_and(|s| s.eq_ignore_ascii_case("open"))
+        || state.is_some_and(|s| s.eq_ignore_ascii_case("active"))
+}
+
+impl From<&ElasticsearchDocument> for ListingSnapshot {
+    fn from(doc: &ElasticsearchDocument) -> Self {
+        Self {
+            token_address: doc.token_address.clone(),
+            owner: doc.owner.clone(),
+            ron_price: doc.ron_price,
+            listed: is_active_listing(doc.order_status.as_deref(), doc.state.as_deref()),
+        }
+    }
+}
+
+impl From<&FlexibleElasticsearchDocument> for ListingSnapshot {
+    fn from(doc: &FlexibleElasticsearchDocument) -> Self {
+        Self {
+            token_address: doc.token_address.clone(),
+            owner: doc.owner.clone(),
+            ron_price: doc.ron_price,
+            listed: is_active_listing(doc.order_status.as_deref(), doc.state.as_deref()),
+        }
+    }
+}
+
+/// One document of the owners summary index
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OwnerSummary {
+    pub owner: String,
+    pub token_count: u64,
+    pub listed_count: u64,
+    pub total_listed_value: f64,
+    pub collections: Vec<OwnerCollectionSummary>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OwnerCollectionSummary {
+    pub token_address: String,
+    pub token_count: u64,
+    pub listed_count: u64,
+}
+
+impl BulkDocument for OwnerSummary {
+    fn document_id(&self) -> Option<&str> {
+        Some(&self.owner)
+    }
+}
+
+#[derive(Debug, Default)]
+struct OwnerTotals {
+    listed_value: f64,
+    // token_address -> (token_count, listed_count)
+    collections: BTreeMap<String, (u64, u64)>,
+}
+
+/// Accumulates per-owner token counts and listed value during the run
+#[derive(Debug, Default)]
+pub struct OwnerAggregator {
+    owners: HashMap<String, OwnerTotals>,
+}
+
+impl OwnerAggregator {
+    pub fn add(&mut self, snapshot: &ListingSnapshot) {
+        let Some(owner) = &snapshot.owner else {
+            return;
+        };
+        let totals = self.owners.entry(owner.to_lowercase()).or_default();
+        let collection = snapshot.token_address.as_deref().unwrap_or("unknown").to_lowercase();
+        let counts = totals.collections.entry(collection).or_default();
+        counts.0 += 1;
+        if snapshot.listed {
+            counts.1 += 1;
+            totals.listed_value += snapshot.ron_price.unwrap_or(0.0);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    pub fn into_summaries(self) -> Vec<OwnerSummary> {
+        let mut summaries: Vec<OwnerSummary> = self.owners
+            .into_iter()
+            .map(|(owner, totals)| OwnerSummary {
+                owner,
+                token_count: totals.collections.values().map(|c| c.0).sum(),
+                listed_count: totals.collections.values().map(|c| c.1).sum(),
+                total_listed_value: totals.listed_value,
+                collections: totals.collections
+                    .into_iter()
+                    .map(|(token_address, (token_count, listed_count))| OwnerCollectionSummary {
+                        token_address,
+                        token_count,
+                        listed_count,
+                    })
+                    .collect(),
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.owner.cmp(&b.owner));
+        summaries
+    }
+}
+
+/// One document of the collection stats index
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CollectionStats {
+    pub token_address: String,
+    pub name: Option<String>,
+    pub total_supply: u64,
+    pub listed_count: u64,
+    pub floor_price: Option<f64>,
+}
+
+impl BulkDocument for CollectionStats {
+    fn document_id(&self) -> Option<&str> {
+        Some(&self.token_address)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CollectionTotals {
+    total_supply: u64,
+    listed_count: u64,
+    floor_price: Option<f64>,
+}
+
+/// Accumulates per-collection supply, listings and floor price during the run
+#[derive(Debug, Default)]
+pub struct CollectionAggregator {
+    collections: HashMap<String, CollectionTotals>,
+}
+
+impl CollectionAggregator {
+    pub fn add(&mut self, snapshot: &ListingSnapshot) {
+        let Some(token_address) = &snapshot.token_address else {
+            return;
+        };
+        let totals = self.collections.entry(token_address.to_lowercase()).or_default();
+        totals.total_supply += 1;
+        if snapshot.listed {
+            totals.listed_count += 1;
+            if let Some(price) = snapshot.ron_price.filter(|p| *p > 0.0) {
+                totals.floor_price = Some(totals.floor_price.map_or(price, |floor| floor.min(price)));
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.collections.len()
+    }
+
+    pub fn into_stats(self) -> Vec<CollectionStats> {
+        let mut stats: Vec<CollectionStats> = self.collections
+            .into_iter()
+            .map(|(token_address, totals)| CollectionStats {
+                name: get_collection_config(&token_address).map(|c| c.name.clone()),
+                token_address,
+                total_supply: totals.total_supply,
+                listed_count: totals.listed_count,
+                floor_price: totals.floor_price,
+            })
+            .collect();
+        stats.sort_by(|a, b| a.token_address.cmp(&b.token_address));
+        stats
+    }
+}
+
+/// The side-index aggregations enabled for this run
+#[derive(Debug, Default)]
+pub struct Aggregators {
+    pub owners: Option<OwnerAggregator>,
+    pub collections: Option<CollectionAggregator>,
+}
+
+impl Aggregators {
+    pub fn is_enabled(&self) -> bool {
+        self.owners.is_some() || self.collections.is_some()
+    }
+
+    pub fn add(&mut self, snapshot: &ListingSnapshot) {
+        if let Some(owners) = &mut self.owners {
+            owners.add(snapshot);
+        }
+        if let Some(collections) = &mut self.collections {
+            collections.add(snapshot);
+        }
+    }
+}
+
+/// Index body for the owners summary index
+pub fn owners_summary_mapping() -> Value {
+    json!({
+        "settings": {
+            "number_of_shards": 1,
+            "number_of_replicas": 1
+        },
+        "mappings": {
+            "dynamic": false,
+            "properties": {
+                "owner": {"type": "keyword"},
+                "token_count": {"type": "long"},
+                "listed_count": {"type": "long"},
+                "total_listed_value": {"type": "double"},
+                "collections": {
+                    "type": "nested",
+                    "properties": {
+                        "token_address": {"type": "keyword"},
+                        "token_count": {"type": "long"},
+                        "listed_count": {"type": "long"}
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Index body for the collection stats index
+pub fn collections_stats_mapping() -> Value {
+    json!({
+        "settings": {
+            "number_of_shards": 1,
+            "number_of_replicas": 1
+        },
+        "mappings": {
+            "dynamic": false,
+            "properties": {
+                "token_address": {"type": "keyword"},
+                "name": {"type": "keyword"},
+                "total_supply": {"type": "long"},
+                "listed_count": {"type": "long"},
+                "floor_price": {"type": "double"}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(owner: &str, collection: &str, listed: bool, ron_price: Option<f64>) -> ListingSnapshot {
+        ListingSnapshot {
+            token_address: Some(collection.to_string()),
+            owner: Some(owner.to_string()),
+            ron_price,
+            listed,
+        }
+    }
+
+    #[test]
+    fn test_owner_summary_counts_per_collection() {
+        let mut aggregator = OwnerAggregator::default();
+        aggregator.add(&snapshot("0xAB", "0x1", true, Some(10.0)));
+        aggregator.add(&snapshot("0xab", "0x1", false, Some(99.0)));
+        aggregator.add(&snapshot("0xab", "0x2", true, Some(2.5)));
+        aggregator.add(&snapshot("0xcd", "0x1", false, None));
+
+        let summaries = aggregator.into_summaries();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0], OwnerSummary {
+            owner: "0xab".to_string(),
+            token_count: 3,
+            listed_count: 2,
+            total_listed_value: 12.5,
+            collections: vec![
+                OwnerCollectionSummary { token_address: "0x1".to_string(), token_count: 2, listed_count: 1 },
+                OwnerCollectionSummary { token_address: "0x2".to_string(), token_count: 1, listed_count: 1 },
+            ],
+        });
+    }
+
+    #[test]
+    fn test_collection_stats_floor_and_counts() {
+        let mut aggregator = CollectionAggregator::default();
+        aggregator.add(&snapshot("0xab", "0xA038C593115F6FCD673F6833E15462B475994879", true, Some(12.0)));
+        aggregator.add(&snapshot("0xab", "0xa038c593115f6fcd673f6833e15462b475994879", true, Some(4.5)));
+        aggregator.add(&snapshot("0xcd", "0xa038c593115f6fcd673f6833e15462b475994879", false, Some(1.0)));
+        aggregator.add(&snapshot("0xcd", "0x2", false, None));
+
+        let stats = aggregator.into_stats();
+        assert_eq!(stats, vec![
+            CollectionStats {
+                token_address: "0x2".to_string(),
+                name: None,
+                total_supply: 1,
+                listed_count: 0,
+                floor_price: None,
+            },
+            CollectionStats {
+                token_address: "0xa038c593115f6fcd673f6833e15462b475994879".to_string(),
+                name: Some("Wildforest Units".to_string()),
+                total_supply: 3,
+                listed_count: 2,
+                floor_price: Some(4.5),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_active_listing_detection() {
+        assert!(is_active_listing(Some("Open"), None));
+        assert!(is_active_listing(None, Some("active")));
+        assert!(!is_active_listing(Some("Matched"), Some("inactive")));
+        assert!(!is_active_listing(None, None));
+    }
+}
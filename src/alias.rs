@@ -0,0 +1,190 @@
+//! ELASTICSEARCH_INDEX naming an alias. Writes through an alias go to its write
+//! index, or to its only index; an alias over several indices without a write
+//! index rejects every bulk item, so the run checks that before writing anything.
+//! Reads (counts, `verify`) go through the same alias, so they see what was written.
+//!
+//! `alias next` and `alias swap` reindex without downtime: migrate into a new
+//! versioned index (`nfts_v3`), verify it, and move the alias over in one request.
+
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+use crate::deprecations::SendNotingWarnings;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliasTarget {
+    pub alias: String,
+    /// Indices the alias points at, sorted
+    pub indices: Vec<String>,
+    pub write_index: Option<String>,
+}
+
+impl AliasTarget {
+    /// The index writes through the alias go to, None if they'd be rejected
+    pub fn write_target(&self) -> Option<&str> {
+        match (&self.write_index, self.indices.as_slice()) {
+            (Some(index), _) => Some(index),
+            (None, [index]) => Some(index),
+            (None, _) => None,
+        }
+    }
+}
+
+/// The alias in a `GET /_alias/<name>` response
+fn parse_alias(alias: &str, body: &Value) -> Option<AliasTarget> {
+    let indices = body.as_object()?;
+    let mut target = AliasTarget { alias: alias.to_string(), indices: Vec::new(), write_index: None };
+    for (index, entry) in indices {
+        let Some(settings) = entry["aliases"].get(alias) else { continue };
+        target.indices.push(index.clone());
+        if settings["is_write_index"].as_bool() == Some(true) {
+            target.write_index = Some(index.clone());
+        }
+    }
+    target.indices.sort();
+    (!target.indices.is_empty()).then_some(target)
+}
+
+/// What `name` is an alias of; None if it's an index or doesn't exist
+pub async fn resolve_alias(client: &Client, elasticsearch_url: &str, name: &str) -> Result<Option<AliasTarget>> {
+    let url = format!("{}/_alias/{}", elasticsearch_url, name);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to look up aliases")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to look up alias {}: HTTP {}", name, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse alias response")?;
+    Ok(parse_alias(name, &body))
+}
+
+static WRITE_INDEX: OnceLock<String> = OnceLock::new();
+
+/// Resolve the target before writing: fail if it's an alias writes can't go
+/// through, and note the index they go to for reading its mapping and settings
+pub async fn check_write_alias(client: &Client, elasticsearch_url: &str, target: &str) -> Result<Option<AliasTarget>> {
+    let Some(alias) = resolve_alias(client, elasticsearch_url, target).await? else {
+        return Ok(None);
+    };
+    let Some(write_index) = alias.write_target() else {
+        anyhow::bail!("{} is an alias of {} indices ({}) without a write index, so every write would be rejected; \
+                       set is_write_index on one of them, or set ELASTICSEARCH_INDEX to an index",
+                      target, alias.indices.len(), alias.indices.join(", "));
+    };
+    info!("✓ {} is an alias; writes go to {}", target, write_index);
+    if alias.indices.len() > 1 {
+        warn!("⚠️  Alias {} points at {} indices ({}); counts and verify through it include all of them",
+              target, alias.indices.len(), alias.indices.join(", "));
+    }
+    WRITE_INDEX.set(write_index.to_string()).ok();
+    Ok(Some(alias))
+}
+
+/// The entry of the index writes go to in a per-index response (`_mapping`,
+/// `_settings`), which has an entry per index behind an alias
+pub fn write_index_entry(response: &Value) -> Option<&Value> {
+    let indices = response.as_object()?;
+    WRITE_INDEX.get()
+        .and_then(|index| indices.get(index))
+        .or_else(|| indices.values().next())
+}
+
+/// Version of `index` as a versioned index of `alias` (`nfts_v3` → 3)
+fn index_version(alias: &str, index: &str) -> Option<u64> {
+    index.strip_prefix(alias)?.strip_prefix("_v")?.parse().ok()
+}
+
+/// The versioned index after the highest of `existing`: `<alias>_v<n+1>`, `_v1` if none
+pub fn next_versioned_index(alias: &str, existing: &[String]) -> String {
+    let latest = existing.iter().filter_map(|index| index_version(alias, index)).max().unwrap_or(0);
+    format!("{}_v{}", alias, latest + 1)
+}
+
+/// The `<alias>_v<n>` indices in the cluster, whether the alias points at them or not
+pub async fn versioned_indices(client: &Client, elasticsearch_url: &str, alias: &str) -> Result<Vec<String>> {
+    let url = format!("{}/_cat/indices/{}_v*?format=json&h=index", elasticsearch_url, alias);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to list indices")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to list {}_v* indices: HTTP {}", alias, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse index list")?;
+    Ok(body.as_array().into_iter().flatten()
+        .filter_map(|entry| entry["index"].as_str())
+        .filter(|index| index_version(alias, index).is_some())
+        .map(str::to_string)
+        .collect())
+}
+
+/// `_aliases` actions moving `alias` from `old` to `new`, which becomes its write index
+fn swap_actions(alias: &str, old: &[String], new: &str) -> Value {
+    let mut actions: Vec<Value> = old.iter()
+        .filter(|index| *index != new)
+        .map(|index| json!({"remove": {"index": index, "alias": alias}}))
+        .collect();
+    actions.push(json!({"add": {"index": new, "alias": alias, "is_write_index": true}}));
+    json!({"actions": actions})
+}
+
+/// Point `alias` at `new` instead of `old`, atomically: readers see either all old or all new
+pub async fn swap_alias(client: &Client, elasticsearch_url: &str, alias: &str, old: &[String], new: &str) -> Result<()> {
+    let url = format!("{}/_aliases", elasticsearch_url);
+    let response = client.post(&url).json(&swap_actions(alias, old, new)).send_noting_warnings().await
+        .context("Failed to update aliases")?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("Failed to move alias {} to {}: HTTP {}: {}", alias, new, status, body);
+    }
+    Ok(())
+}
+
+pub async fn delete_index(client: &Client, elasticsearch_url: &str, index: &str) -> Result<()> {
+    let url = format!("{}/{}", elasticsearch_url, index);
+    let response = client.delete(&url).send_noting_warnings().await.context("Failed to delete index")?;
+    if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+        anyhow::bail!("Failed to delete {}: HTTP {}", index, response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_write_target_of_alias() {
+        let body = json!({
+            "nfts_v2": {"aliases": {"nfts": {"is_write_index": true}}},
+            "nfts_v1": {"aliases": {"nfts": {}, "nfts_old": {}}},
+        });
+        let alias = parse_alias("nfts", &body).unwrap();
+        assert_eq!(alias.indices, vec!["nfts_v1", "nfts_v2"]);
+        assert_eq!(alias.write_target(), Some("nfts_v2"));
+
+        let body = json!({"nfts_v1": {"aliases": {"nfts": {}}}, "nfts_v2": {"aliases": {"nfts": {}}}});
+        assert_eq!(parse_alias("nfts", &body).unwrap().write_target(), None);
+        let body = json!({"nfts_v1": {"aliases": {"nfts": {"is_write_index": false}}}});
+        assert_eq!(parse_alias("nfts", &body).unwrap().write_target(), Some("nfts_v1"));
+        assert_eq!(parse_alias("other", &body), None);
+
+        assert_eq!(write_index_entry(&json!({"nfts_v1": {"mappings": {}}})), Some(&json!({"mappings": {}})));
+    }
+
+    #[test]
+    fn test_versioned_swap() {
+        let existing = vec!["nfts_v2".to_string(), "nfts_v10".to_string(), "nfts_vx".to_string(), "nfts_old".to_string()];
+        assert_eq!(next_versioned_index("nfts", &existing), "nfts_v11");
+        assert_eq!(next_versioned_index("nfts", &[]), "nfts_v1");
+
+        let actions = swap_actions("nfts", &["nfts_v1".to_string(), "nfts_v2".to_string()], "nfts_v2");
+        assert_eq!(actions, json!({"actions": [
+            {"remove": {"index": "nfts_v1", "alias": "nfts"}},
+            {"add": {"index": "nfts_v2", "alias": "nfts", "is_write_index": true}},
+        ]}));
+    }
+}
@@ -0,0 +1,219 @@
+use futures::{stream, StreamExt};
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// How thoroughly asset URLs are checked before a document is indexed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetCheck {
+    /// Don't check, `assets_ok` stays unset
+    #[default]
+    Off,
+    /// Only check that URLs parse and use a known scheme
+    Syntax,
+    /// Also send a HEAD request to every distinct http(s) URL, or a GET where HEAD isn't allowed
+    Head,
+}
+
+/// Documents whose image / external_url can be validated
+pub trait AssetUrls {
+    fn asset_urls(&self) -> Vec<&str>;
+    fn set_assets_ok(&mut self, ok: bool);
+}
+
+impl AssetUrls for ElasticsearchDocument {
+    fn asset_urls(&self) -> Vec<&str> {
+        let external_url = self.raw_metadata.as_ref().and_then(|m| m["external_url"].as_str());
+        self.image.as_deref().into_iter().chain(external_url).collect()
+    }
+
+    fn set_assets_ok(&mut self, ok: bool) {
+        self.assets_ok = Some(ok);
+    }
+}
+
+impl AssetUrls for FlexibleElasticsearchDocument {
+    fn asset_urls(&self) -> Vec<&str> {
+        self.image.as_deref().into_iter().chain(self.external_url.as_deref()).collect()
+    }
+
+    fn set_assets_ok(&mut self, ok: bool) {
+        self.assets_ok = Some(ok);
+    }
+}
+
+/// Whether a URL is well-formed enough for the marketplace UI to load it
+pub fn is_valid_asset_url(url: &str) -> bool {
+    match Url::parse(url.trim()) {
+        Ok(url) => match url.scheme() {
+            "http" | "https" => url.host_str().is_some_and(|h| !h.is_empty()),
+            "ipfs" | "ar" => url.host_str().is_some() || url.path().len() > 1,
+            "data" => true,
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// HEAD requests a batch has in flight at once
+const HEAD_CONCURRENCY: usize = 16;
+
+/// Sets `assets_ok` on documents, remembering HEAD results per URL since many
+/// tokens of a collection share the same image
+pub struct AssetChecker {
+    client: Client,
+    mode: AssetCheck,
+    head_results: Mutex<HashMap<String, bool>>,
+    broken_documents: AtomicU64,
+}
+
+impl AssetChecker {
+    pub fn new(client: Client, mode: AssetCheck) -> Self {
+        Self {
+            client,
+            mode,
+            head_results: Mutex::new(HashMap::new()),
+            broken_documents: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn annotate<D: AssetUrls>(&self, documents: &mut [D]) {
+        if self.mode == AssetCheck::Off {
+            return;
+        }
+        let distinct: HashSet<String> = documents.iter().flat_map(AssetUrls::asset_urls).map(str::to_string).collect();
+        let results: HashMap<String, bool> = stream::iter(distinct)
+            .map(|url| async move {
+                let ok = self.url_ok(&url).await;
+                (url, ok)
+            })
+            .buffer_unordered(HEAD_CONCURRENCY)
+            .collect()
+            .await;
+        for doc in documents.iter_mut() {
+            let ok = doc.asset_urls().iter().all(|url| results[*url]);
+            if !ok {
+                self.broken_documents.fetch_add(1, Ordering::Relaxed);
+            }
+            doc.set_assets_ok(ok);
+        }
+    }
+
+    pub fn broken_documents(&self) -> u64 {
+        self.broken_documents.load(Ordering::Relaxed)
+    }
+
+    async fn url_ok(&self, url: &str) -> bool {
+        if !is_valid_asset_url(url) {
+            return false;
+        }
+        if self.mode != AssetCheck::Head || !url.starts_with("http") {
+            return true;
+        }
+
+        if let Some(ok) = self.head_results.lock().await.get(url) {
+            return *ok;
+        }
+        let status = match self.client.head(url).send().await {
+            // Some hosts only serve GET; ask for a single byte instead
+            Ok(response) if response.status() == StatusCode::METHOD_NOT_ALLOWED => {
+                self.client.get(url).header(RANGE, "bytes=0-0").send().await.map(|response| response.status())
+            }
+            sent => sent.map(|response| response.status()),
+        };
+        // Only an answer for the asset itself is remembered: timeouts, 429s and 5xx may pass on a later batch
+        let ok = match status {
+            Ok(status) if status.is_success() || status.is_redirection() => true,
+            Ok(StatusCode::NOT_FOUND | StatusCode::GONE) => false,
+            _ => return false,
+        };
+        self.head_results.lock().await.insert(url.to_string(), ok);
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+
+    #[test]
+    fn test_asset_url_syntax() {
+        assert!(is_valid_asset_url("https://cdn.skymavis.com/mm-cache/7/e/8f0d2bea.png"));
+        assert!(is_valid_asset_url("ipfs://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG"));
+        assert!(!is_valid_asset_url("not a url"));
+        assert!(!is_valid_asset_url("https://"));
+        assert!(!is_valid_asset_url("javascript:alert(1)"));
+        assert!(!is_valid_asset_url(""));
+    }
+
+    #[tokio::test]
+    async fn test_syntax_check_marks_documents() {
+        let checker = AssetChecker::new(Client::new(), AssetCheck::Syntax);
+        let mut docs = vec![
+            FlexibleElasticsearchDocument::from_record(crate::models_flexible::CsvRecord {
+                image: Some("https://example.com/1.png".to_string()),
+                ..Default::default()
+            }, None),
+            FlexibleElasticsearchDocument::from_record(crate::models_flexible::CsvRecord {
+                image: Some("example.com/2.png".to_string()),
+                ..Default::default()
+            }, None),
+        ];
+
+        checker.annotate(&mut docs).await;
+
+        assert_eq!(docs[0].assets_ok, Some(true));
+        assert_eq!(docs[1].assets_ok, Some(false));
+        assert_eq!(checker.broken_documents(), 1);
+    }
+
+    fn image_doc(url: String) -> FlexibleElasticsearchDocument {
+        FlexibleElasticsearchDocument::from_record(crate::models_flexible::CsvRecord {
+            image: Some(url),
+            ..Default::default()
+        }, None)
+    }
+
+    #[tokio::test]
+    async fn test_head_not_allowed_is_retried_as_get() {
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |_, request_line| {
+            Reply::Respond(if request_line.starts_with("HEAD") { 405 } else { 206 }, String::new())
+        }).await;
+        let checker = AssetChecker::new(Client::new(), AssetCheck::Head);
+        let mut docs = vec![image_doc(format!("{}/1.png", server.url()))];
+
+        checker.annotate(&mut docs).await;
+
+        assert_eq!(docs[0].assets_ok, Some(true));
+        assert_eq!(server.requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_only_missing_assets_are_remembered() {
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |_, request_line| {
+            Reply::Respond(if request_line.contains("/missing") { 404 } else { 503 }, String::new())
+        }).await;
+        let checker = AssetChecker::new(Client::new(), AssetCheck::Head);
+        let mut docs = vec![
+            image_doc(format!("{}/missing.png", server.url())),
+            image_doc(format!("{}/missing.png", server.url())),
+            image_doc(format!("{}/unavailable.png", server.url())),
+        ];
+
+        checker.annotate(&mut docs).await;
+        checker.annotate(&mut docs).await;
+
+        assert!(docs.iter().all(|doc| doc.assets_ok == Some(false)));
+        // The 404 is asked once for both documents and both batches, the 503 again in the second batch
+        assert_eq!(server.requests.load(Ordering::Relaxed), 3);
+    }
+}
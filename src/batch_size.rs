@@ -0,0 +1,130 @@
+//! How many documents go into one bulk request. BATCH_SIZE caps the count and
+//! MAX_BULK_BYTES the body, so collections with heavy metadata don't build requests
+//! the cluster rejects with 413. With ADAPTIVE_BATCH_SIZE the count follows the
+//! cluster: halved after a slow or rejected bulk request, grown by a tenth after a
+//! fast one, staying between MIN_BATCH_SIZE and BATCH_SIZE.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Rough size of the action line in front of each document
+const ACTION_LINE_BYTES: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct Adaptive {
+    pub min_documents: usize,
+    /// Bulk requests slower than this shrink the batches, those under half of it grow them
+    pub target_latency: Duration,
+}
+
+pub struct BatchSizer {
+    max_documents: usize,
+    max_bytes: Option<usize>,
+    adaptive: Option<Adaptive>,
+    documents: AtomicUsize,
+    /// Last change, so one slow spell seen by every worker at once halves only once
+    changed_at: Mutex<Option<Instant>>,
+}
+
+impl BatchSizer {
+    pub fn new(max_documents: usize, max_bytes: Option<usize>, adaptive: Option<Adaptive>) -> Self {
+        let max_documents = max_documents.max(1);
+        Self { max_documents, max_bytes, adaptive, documents: AtomicUsize::new(max_documents), changed_at: Mutex::new(None) }
+    }
+
+    /// Documents per batch right now
+    pub fn documents(&self) -> usize {
+        self.documents.load(Ordering::Relaxed)
+    }
+
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive.is_some()
+    }
+
+    /// Whether a batch holding `documents` documents in `bytes` bytes is complete
+    pub fn is_full(&self, documents: usize, bytes: usize) -> bool {
+        documents >= self.documents() || self.max_bytes.is_some_and(|max| bytes >= max)
+    }
+
+    /// Whether a document of `size` bytes must start a new batch rather than join
+    /// one of `bytes` bytes; a document over the limit on its own still gets sent
+    pub fn overflows(&self, bytes: usize, size: usize) -> bool {
+        bytes > 0 && self.max_bytes.is_some_and(|max| bytes + size > max)
+    }
+
+    /// Adjust to a bulk request that took `latency`, `rejected` when the cluster
+    /// pushed back on it (413, or items rejected with 429)
+    pub fn record(&self, latency: Duration, rejected: bool) {
+        let Some(adaptive) = &self.adaptive else { return };
+        let mut changed_at = self.changed_at.lock().unwrap();
+        // Give the new size a request's worth of time to show its effect
+        if changed_at.is_some_and(|at| at.elapsed() < adaptive.target_latency) {
+            return;
+        }
+        let current = self.documents();
+        let next = if rejected || latency > adaptive.target_latency {
+            current / 2
+        } else if latency < adaptive.target_latency / 2 {
+            current + (current / 10).max(1)
+        } else {
+            current
+        }.clamp(adaptive.min_documents.min(self.max_documents), self.max_documents);
+        if next == current {
+            return;
+        }
+        self.documents.store(next, Ordering::Relaxed);
+        *changed_at = Some(Instant::now());
+        if next < current {
+            info!(batch_size = next, "🎚️  Batch size lowered to {} documents ({})", next,
+                  if rejected { "rejected by the cluster".to_string() } else { format!("bulk took {:.1}s", latency.as_secs_f64()) });
+        } else {
+            debug!(batch_size = next, "Batch size raised to {} documents", next);
+        }
+    }
+}
+
+/// Bytes a document adds to a bulk body, estimated from its JSON
+pub fn bulk_size<T: Serialize>(document: &T) -> usize {
+    serde_json::to_vec(document).map_or(0, |json| json.len()) + ACTION_LINE_BYTES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_limit_and_latency_adjustment() {
+        let fixed = BatchSizer::new(100, Some(1000), None);
+        assert!(!fixed.is_full(99, 999));
+        assert!(fixed.is_full(100, 10) && fixed.is_full(1, 1000));
+        assert!(fixed.overflows(600, 500) && !fixed.overflows(0, 5000));
+        fixed.record(Duration::from_secs(60), true);
+        assert_eq!(fixed.documents(), 100);
+
+        let adaptive = Adaptive { min_documents: 30, target_latency: Duration::ZERO };
+        let sizer = BatchSizer::new(100, None, Some(adaptive));
+        sizer.record(Duration::from_millis(1), false);
+        assert_eq!(sizer.documents(), 50);
+        sizer.record(Duration::ZERO, true);
+        sizer.record(Duration::ZERO, true);
+        assert_eq!(sizer.documents(), 30);
+
+        let sizer = BatchSizer::new(100, None, Some(Adaptive { min_documents: 10, target_latency: Duration::from_millis(50) }));
+        sizer.record(Duration::from_secs(5), false);
+        assert_eq!(sizer.documents(), 50);
+        // Within the cool-down the next slow request changes nothing
+        sizer.record(Duration::from_secs(5), false);
+        assert_eq!(sizer.documents(), 50);
+        std::thread::sleep(Duration::from_millis(60));
+        sizer.record(Duration::ZERO, false);
+        assert_eq!(sizer.documents(), 55);
+        assert!(bulk_size(&serde_json::json!({"token_id": "1"})) > ACTION_LINE_BYTES);
+    }
+}
@@ -0,0 +1,112 @@
+//! Synthetic failures for resilience drills, only built with `--features chaos`:
+//! bulk requests randomly get a 429, a 500 or a client timeout instead of an
+//! answer from the cluster, so retries and checkpointing can be exercised
+//! without breaking the network.
+
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::deprecations::SendNotingWarnings;
+
+static CHAOS: OnceLock<Chaos> = OnceLock::new();
+
+/// Probability of each fault per request (CHAOS_*_RATE), drawn from a seeded sequence
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    pub throttle_rate: f64,
+    pub error_rate: f64,
+    pub timeout_rate: f64,
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// HTTP 429 without Retry-After
+    Throttled,
+    /// HTTP 500
+    ServerError,
+    /// The request is sent but the client gives up after 1ms
+    Timeout,
+}
+
+pub struct Chaos {
+    config: ChaosConfig,
+    draws: AtomicU64,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Self {
+        Self { config, draws: AtomicU64::new(0) }
+    }
+
+    /// The fault for the next request; the n-th draw is the same for a given seed
+    pub fn next_fault(&self) -> Option<Fault> {
+        let draw = self.draws.fetch_add(1, Ordering::Relaxed);
+        let roll = (splitmix64(self.config.seed ^ draw) >> 11) as f64 / (1u64 << 53) as f64;
+        let faults = [
+            (self.config.throttle_rate, Fault::Throttled),
+            (self.config.error_rate, Fault::ServerError),
+            (self.config.timeout_rate, Fault::Timeout),
+        ];
+        let mut threshold = 0.0;
+        for (rate, fault) in faults {
+            threshold += rate;
+            if roll < threshold {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Inject faults into all bulk requests; only the first call takes effect
+pub fn install(config: ChaosConfig) {
+    CHAOS.set(Chaos::new(config)).ok();
+}
+
+/// Send the request, unless the installed chaos draws a fault for it
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    match CHAOS.get().and_then(Chaos::next_fault) {
+        None => request.send_noting_warnings().await,
+        Some(Fault::Timeout) => request.timeout(Duration::from_millis(1)).send_noting_warnings().await,
+        Some(Fault::Throttled) => Ok(synthetic_response(StatusCode::TOO_MANY_REQUESTS)),
+        Some(Fault::ServerError) => Ok(synthetic_response(StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+fn synthetic_response(status: StatusCode) -> Response {
+    let mut response = http::Response::new(format!(r#"{{"error":"chaos: injected HTTP {}"}}"#, status.as_u16()));
+    *response.status_mut() = http::StatusCode::from_u16(status.as_u16()).unwrap();
+    Response::from(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults_follow_seed_and_rates() {
+        let config = ChaosConfig { throttle_rate: 0.2, error_rate: 0.1, timeout_rate: 0.1, seed: 42 };
+        let draw = |chaos: Chaos| (0..1000).map(|_| chaos.next_fault()).collect::<Vec<_>>();
+        let faults = draw(Chaos::new(config.clone()));
+        assert_eq!(faults, draw(Chaos::new(config.clone())));
+        assert_ne!(faults, draw(Chaos::new(ChaosConfig { seed: 43, ..config })));
+
+        let count = |fault| faults.iter().filter(|f| **f == Some(fault)).count();
+        assert!((150..250).contains(&count(Fault::Throttled)), "{}", count(Fault::Throttled));
+        assert!((60..140).contains(&count(Fault::ServerError)));
+        assert!((60..140).contains(&count(Fault::Timeout)));
+
+        assert_eq!(synthetic_response(StatusCode::TOO_MANY_REQUESTS).status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(Chaos::new(ChaosConfig::default()).next_fault().is_none());
+    }
+}
@@ -1,100 +1,419 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use roaring::RoaringTreemap;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 use tokio::fs;
+use tracing::{info, warn};
+
+use crate::checkpoint_store::CheckpointStore;
+use crate::fingerprint::{CsvFingerprint, HEADER_DIFFERENCE};
+use crate::input::RowOffset;
+use crate::key_set::KeySet;
+use crate::paths::ensure_parent_dir;
+
+/// How completed work is remembered between runs
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckpointMode {
+    /// Positional record indices (small state, requires identical row order)
+    #[default]
+    Index,
+    /// Hashes of document ids (larger state, survives re-ordered exports)
+    Key,
+}
+
+/// Document ids of checkpoints written before they came from a template
+const LEGACY_ID_TEMPLATE: &str = "{token_id}";
+
+/// Every this many rows the first pass notes the row's byte offset
+pub const ROW_OFFSET_INTERVAL: usize = 100_000;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MigrationCheckpoint {
     pub csv_file_path: String,
-    pub total_records: usize,
+    pub total_records: usize, // rows of the CSV (document ids to write in key mode)
     pub processed_records: usize,
     pub successful_batches: usize,
     pub failed_batches: usize,
-    pub completed_batch_ranges: Vec<(usize, usize)>, // (start_index, end_index) pairs
+    #[serde(default, with = "bitmap_base64")]
+    pub completed_indices: RoaringTreemap, // record indices of every indexed row (index mode only)
     pub start_time: u64, // Unix timestamp
+    #[serde(default)]
+    pub mode: CheckpointMode,
+    #[serde(default, rename = "completed_key_set")]
+    pub completed_keys: KeySet, // record_key() of every indexed document (key mode only)
+    #[serde(default)]
+    pub indexed_documents: u64, // documents the cluster accepted, per bulk response item
+    #[serde(default)]
+    pub rejected_documents: u64, // documents with an item error (see the bulk error log)
+    #[serde(default, with = "bitmap_base64")]
+    pub history_pending: RoaringTreemap, // indices (or keys) whose token document is written but orders history isn't
+    #[serde(default)]
+    pub row_offsets: Vec<RowOffset>, // start of every ROW_OFFSET_INTERVAL-th row, so a resumed run can seek past done rows
+    #[serde(default)]
+    pub csv_len: Option<u64>, // size of the CSV the row offsets were noted in
+    #[serde(default)]
+    pub boundary_offset: Option<RowOffset>, // start of a row whose predecessors are all confirmed, the furthest noted (index mode only)
+    #[serde(default)]
+    pub csv_fingerprint: Option<CsvFingerprint>, // the CSV the checkpoint was written for
+    #[serde(default)]
+    pub id_template: Option<String>, // DOCUMENT_ID_TEMPLATE the completed keys were hashed from (key mode only)
+    #[serde(skip)]
+    current_keys: Option<KeySet>, // record_key() of the ids in the CSV being migrated (key mode only)
+    // Checkpoints written before the bitmap existed; folded into completed_indices on load
+    #[serde(default, rename = "completed_batch_ranges", skip_serializing)]
+    legacy_batch_ranges: Vec<(usize, usize)>,
+    // Checkpoints written before the key set kept completed keys in a bitmap; folded into completed_keys on load
+    #[serde(default, rename = "completed_keys", with = "bitmap_base64", skip_serializing)]
+    legacy_completed_keys: RoaringTreemap,
+}
+
+/// Stable 64-bit key for a document id (FNV-1a, identical across runs and platforms)
+pub fn record_key(doc_id: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in doc_id.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 impl MigrationCheckpoint {
-    pub fn new(csv_file_path: String, total_records: usize) -> Self {
+    pub fn new(csv_file_path: String, total_records: usize, mode: CheckpointMode) -> Self {
         Self {
             csv_file_path,
             total_records,
             processed_records: 0,
             successful_batches: 0,
             failed_batches: 0,
-            completed_batch_ranges: Vec::new(),
+            completed_indices: RoaringTreemap::new(),
             start_time: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            mode,
+            completed_keys: KeySet::new(),
+            indexed_documents: 0,
+            rejected_documents: 0,
+            history_pending: RoaringTreemap::new(),
+            row_offsets: Vec::new(),
+            csv_len: None,
+            boundary_offset: None,
+            csv_fingerprint: None,
+            id_template: None,
+            current_keys: None,
+            legacy_batch_ranges: Vec::new(),
+            legacy_completed_keys: RoaringTreemap::new(),
         }
     }
 
-    pub fn get_safe_resume_point(&self) -> usize {
-        if self.completed_batch_ranges.is_empty() {
-            return 0;
+    /// Note where a row starts, for every ROW_OFFSET_INTERVAL-th row not noted yet
+    pub fn note_row_offset(&mut self, record_index: usize, position: Option<&csv::Position>) {
+        if record_index == 0 || !record_index.is_multiple_of(ROW_OFFSET_INTERVAL) {
+            return;
         }
+        if let Some(position) = position.filter(|_| self.row_offsets.last().is_none_or(|last| last.index < record_index)) {
+            self.row_offsets.push(RowOffset::new(record_index, position));
+        }
+    }
 
-        // Find the highest continuous range from the beginning
-        let mut safe_point = 0;
-        let mut sorted_ranges = self.completed_batch_ranges.clone();
-        sorted_ranges.sort_by_key(|r| r.0);
+    /// Note where a row starts if every row before it is confirmed and it's further
+    /// than the boundary noted so far, so a resumed run seeks straight past the
+    /// confirmed rows instead of to the noted row before them
+    pub fn note_boundary_offset(&mut self, offset: RowOffset) -> bool {
+        let further = offset.index > self.boundary_offset.map_or(0, |boundary| boundary.index);
+        if self.mode == CheckpointMode::Index && further && self.is_prefix_completed(offset.index) {
+            self.boundary_offset = Some(offset);
+            return true;
+        }
+        false
+    }
 
-        for (start, end) in sorted_ranges {
-            if start == safe_point {
-                safe_point = end;
-            } else if start > safe_point {
-                // Gap found, can't safely skip beyond this point
+    /// Move the boundary to the furthest of the streaming pass's row starts that
+    /// only has confirmed rows before it, dropping the starts it passed
+    pub fn advance_boundary(&mut self, starts: &RowStarts) {
+        let mut starts = starts.0.lock().unwrap();
+        let mut passed = Vec::new();
+        for (&index, &offset) in starts.iter() {
+            if !self.note_boundary_offset(offset) && index > self.boundary_offset.map_or(0, |boundary| boundary.index) {
                 break;
             }
+            passed.push(index);
+        }
+        for index in passed {
+            starts.remove(&index);
         }
+    }
 
-        safe_point
+    /// Whether every record before `record_index` is indexed
+    fn is_prefix_completed(&self, record_index: usize) -> bool {
+        record_index == 0 || self.completed_indices.rank(record_index as u64 - 1) == record_index as u64
+    }
+
+    /// Forget the row offsets if they were noted in a CSV of another size
+    pub fn check_csv_len(&mut self, csv_len: u64) {
+        if self.csv_len != Some(csv_len) {
+            if !self.row_offsets.is_empty() {
+                warn!("⚠️  CSV size changed since the checkpoint, reading it from the start");
+            }
+            self.row_offsets.clear();
+            self.boundary_offset = None;
+            self.csv_len = Some(csv_len);
+        }
     }
 
-    pub fn add_completed_batch(&mut self, start_index: usize, batch_size: usize) {
-        let end_index = start_index + batch_size;
-        self.completed_batch_ranges.push((start_index, end_index));
-        self.processed_records += batch_size;
+    /// Refuse to resume against a CSV other than the one this checkpoint was written
+    /// for, unless `force`. Checkpoints without a fingerprint take the current one.
+    /// Key mode matches rows by id, so a re-export with new rows only has to keep its columns.
+    pub fn check_fingerprint(&mut self, current: CsvFingerprint, force: bool) -> Result<()> {
+        let mut differences = self.csv_fingerprint.as_ref().map_or_else(Vec::new, |noted| noted.differences(&current));
+        if self.mode == CheckpointMode::Key && !differences.is_empty() {
+            // Noted offsets belong to the old file
+            self.row_offsets.clear();
+            self.boundary_offset = None;
+            differences.retain(|difference| difference == HEADER_DIFFERENCE);
+        }
+        if !differences.is_empty() {
+            if !force {
+                anyhow::bail!("{} changed since the checkpoint was written ({}); resuming would skip the wrong rows. \
+                               Remove the checkpoint to start over, or pass --force-resume to resume anyway",
+                              self.csv_file_path, differences.join(", "));
+            }
+            warn!("⚠️  {} changed since the checkpoint was written ({}), resuming anyway (--force-resume)",
+                  self.csv_file_path, differences.join(", "));
+            // Noted offsets belong to the old file
+            self.row_offsets.clear();
+            self.boundary_offset = None;
+        }
+        self.csv_fingerprint = Some(current);
+        Ok(())
+    }
+
+    /// Refuse to resume a key-mode checkpoint whose keys were hashed from other
+    /// document ids. Checkpoints written before DOCUMENT_ID_TEMPLATE hashed token ids.
+    pub fn check_id_template(&mut self, template: &str) -> Result<()> {
+        if self.mode != CheckpointMode::Key {
+            return Ok(());
+        }
+        let noted = self.id_template.as_deref()
+            .or_else(|| (!self.completed_keys.is_empty()).then_some(LEGACY_ID_TEMPLATE));
+        if let Some(noted) = noted.filter(|noted| *noted != template) {
+            anyhow::bail!("The checkpoint's completed ids were built with the document id template '{}', not '{}'; \
+                           resuming would index every row again under new ids. Set DOCUMENT_ID_TEMPLATE={} to resume, \
+                           or remove the checkpoint to start over", noted, template, noted);
+        }
+        self.id_template = Some(template.to_string());
+        Ok(())
+    }
+
+    /// Closest noted row at or before `record_index`, to seek to instead of reading from the start
+    pub fn row_offset_before(&self, record_index: usize) -> Option<RowOffset> {
+        let noted = self.row_offsets.iter().rev().find(|offset| offset.index <= record_index).copied();
+        let boundary = self.boundary_offset.filter(|boundary| boundary.index <= record_index);
+        match (noted, boundary) {
+            (Some(noted), Some(boundary)) if noted.index > boundary.index => Some(noted),
+            (noted, boundary) => boundary.or(noted),
+        }
+    }
+
+    /// First record index that hasn't been indexed yet; everything before it is done
+    pub fn get_safe_resume_point(&self) -> usize {
+        // Key mode has to look at every row, positions mean nothing there
+        if self.mode == CheckpointMode::Key {
+            return 0;
+        }
+
+        self.completed_indices
+            .iter()
+            .enumerate()
+            .find(|(expected, index)| *expected as u64 != *index)
+            .map_or(self.completed_indices.len() as usize, |(expected, _)| expected)
+    }
+
+    /// Record a successfully indexed batch built from the record indices `indices`.
+    /// `keys` are the record_key() values of the documents in the batch and are only
+    /// kept in key mode.
+    pub fn add_completed_batch(&mut self, indices: impl IntoIterator<Item = u64>, keys: &[u64]) {
+        match self.mode {
+            CheckpointMode::Index => {
+                self.completed_indices.extend(indices);
+                // Re-completed batches (e.g. after a resume) must not be counted twice
+                self.processed_records = self.completed_indices.len() as usize;
+                if !self.history_pending.is_empty() {
+                    self.history_pending -= &self.completed_indices;
+                }
+            }
+            CheckpointMode::Key => {
+                // Ids sent again (a resumed run, a superseded row) count once, ids of
+                // an earlier export that this one doesn't have not at all
+                for key in keys {
+                    let current = self.current_keys.as_ref().is_none_or(|current| current.contains(*key));
+                    if self.completed_keys.insert(*key) && current {
+                        self.processed_records += 1;
+                    }
+                    self.history_pending.remove(*key);
+                }
+            }
+        }
         self.successful_batches += 1;
     }
 
-    pub fn checkpoint_file_path(csv_file: &str) -> String {
-        format!("{}.checkpoint", csv_file)
+    /// Key mode: the record_key() of every document id the CSV being migrated has
+    /// rows to write for, done or not. A re-export may have dropped ids an earlier
+    /// run completed, so progress counts the completed ids among these.
+    pub fn set_current_keys(&mut self, keys: KeySet) {
+        self.total_records = keys.len() as usize;
+        self.processed_records = self.completed_keys.intersection_len(&keys) as usize;
+        self.current_keys = Some(keys);
+    }
+
+    /// Record a batch whose token documents were written but whose orders history
+    /// events were not. Its rows stay unprocessed, and on resume only the history
+    /// half is sent again.
+    pub fn add_history_pending(&mut self, indices: impl IntoIterator<Item = u64>, keys: &[u64]) {
+        match self.mode {
+            CheckpointMode::Index => self.history_pending.extend(indices),
+            CheckpointMode::Key => self.history_pending.extend(keys.iter().copied()),
+        }
+    }
+
+    /// Whether the token document of a record index (index mode) or document id
+    /// (key mode) is already written and only its history is missing
+    pub fn is_history_pending(&self, record_index: usize, doc_id: Option<&str>) -> bool {
+        match self.mode {
+            CheckpointMode::Index => self.history_pending.contains(record_index as u64),
+            CheckpointMode::Key => doc_id.is_some_and(|id| self.history_pending.contains(record_key(id))),
+        }
     }
 
-    pub async fn save(&self, csv_file: &str) -> Result<()> {
-        let checkpoint_path = Self::checkpoint_file_path(csv_file);
+    /// Count what the cluster did with a batch's documents. Rows are marked handled by
+    /// `add_completed_batch` either way; these counts say how many actually made it.
+    pub fn add_document_outcome(&mut self, indexed: usize, rejected: usize) {
+        self.indexed_documents += indexed as u64;
+        self.rejected_documents += rejected as u64;
+    }
+
+    /// Record a row dropped by the row filter or superseded by a duplicate, so it counts as handled.
+    /// Key mode counts document ids to write, which filtered rows aren't part of.
+    pub fn add_filtered(&mut self, record_index: usize) {
+        if self.mode == CheckpointMode::Index {
+            self.completed_indices.insert(record_index as u64);
+            self.processed_records = self.completed_indices.len() as usize;
+        }
+    }
+
+    /// Whether a record index was already indexed (always false in key mode)
+    pub fn is_index_completed(&self, record_index: usize) -> bool {
+        self.mode == CheckpointMode::Index && self.completed_indices.contains(record_index as u64)
+    }
+
+    /// Whether a document id was already indexed (always false in index mode)
+    pub fn is_key_completed(&self, doc_id: &str) -> bool {
+        self.mode == CheckpointMode::Key && self.completed_keys.contains(record_key(doc_id))
+    }
+
+    fn migrate_legacy_state(&mut self) {
+        for (start, end) in std::mem::take(&mut self.legacy_batch_ranges) {
+            self.completed_indices.insert_range(start as u64..end as u64);
+        }
+        self.completed_keys.extend(std::mem::take(&mut self.legacy_completed_keys));
+    }
+
+    pub async fn save(&self, store: &CheckpointStore, csv_file: &str) -> Result<()> {
+        if !store.is_enabled() {
+            return Ok(());
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        // Also under the CSV's content, for a run against a moved copy to find
+        if let Some(fingerprint) = &self.csv_fingerprint {
+            store.write(&fingerprint.content_key(), json.clone()).await?;
+        }
+        store.write(csv_file, json).await?;
+        info!("💾 Checkpoint saved: {} records processed", self.processed_records);
+        Ok(())
+    }
+
+    pub async fn write_to(&self, checkpoint_path: &Path) -> Result<()> {
         let json = serde_json::to_string_pretty(self)?;
-        fs::write(&checkpoint_path, json).await?;
-        println!("💾 Checkpoint saved: {} records processed", self.processed_records);
+        ensure_parent_dir(checkpoint_path).await?;
+        fs::write(checkpoint_path, json).await
+            .with_context(|| format!("Failed to write checkpoint {}", checkpoint_path.display()))?;
         Ok(())
     }
 
-    pub async fn load(csv_file: &str) -> Result<Option<Self>> {
-        let checkpoint_path = Self::checkpoint_file_path(csv_file);
-        
-        if !Path::new(&checkpoint_path).exists() {
+    /// Read a checkpoint file directly, without matching it to a CSV
+    pub async fn read_from(checkpoint_path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(checkpoint_path).await
+            .with_context(|| format!("Failed to read checkpoint {}", checkpoint_path.display()))?;
+        let mut checkpoint: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid checkpoint {}", checkpoint_path.display()))?;
+        checkpoint.migrate_legacy_state();
+        Ok(checkpoint)
+    }
+
+    /// Load the checkpoint of `csv_file`. A run moved to another machine passes
+    /// `checkpoint_for`, the CSV's path where the checkpoint was written: it's looked
+    /// up under that path when there's none under `csv_file` (saves go under `csv_file`),
+    /// then under `content_key` (`content_key_of` the CSV), found wherever the CSV moved.
+    pub async fn load(store: &CheckpointStore, csv_file: &str, checkpoint_for: Option<&str>, content_key: Option<&str>,
+                      mode: CheckpointMode) -> Result<Option<Self>> {
+        let mut found = None;
+        for key in [csv_file].into_iter().chain(checkpoint_for).chain(content_key) {
+            if let Some(content) = store.read(key).await? {
+                found = Some((key, content));
+                break;
+            }
+        }
+        let Some((key, content)) = found else {
+            return Ok(None);
+        };
+
+        let mut checkpoint: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid checkpoint {}", store.describe(key)))?;
+        checkpoint.migrate_legacy_state();
+        if !checkpoint.adopt(csv_file, checkpoint_for) {
+            warn!("⚠️  Checkpoint is for different CSV file, ignoring");
             return Ok(None);
         }
 
-        let content = fs::read_to_string(&checkpoint_path).await?;
-        let checkpoint: Self = serde_json::from_str(&content)?;
-        
-        // Verify the checkpoint is for the same CSV file
-        if checkpoint.csv_file_path != csv_file {
-            println!("⚠️  Checkpoint is for different CSV file, ignoring");
+        // Index ranges and id keys can't be translated into each other
+        if checkpoint.mode != mode {
+            warn!("⚠️  Checkpoint was written in {:?} mode but {:?} mode is configured, ignoring",
+                     checkpoint.mode, mode);
             return Ok(None);
         }
 
         Ok(Some(checkpoint))
     }
 
-    pub async fn cleanup(csv_file: &str) -> Result<()> {
-        let checkpoint_path = Self::checkpoint_file_path(csv_file);
-        if Path::new(&checkpoint_path).exists() {
-            fs::remove_file(&checkpoint_path).await?;
-            println!("🗑️  Checkpoint file removed");
+    /// Take this checkpoint over for `csv_file` if it was written for it: under the
+    /// same path, under `checkpoint_for`, or (when it has a fingerprint) under any
+    /// path, the CSV's content deciding in `check_fingerprint`
+    fn adopt(&mut self, csv_file: &str, checkpoint_for: Option<&str>) -> bool {
+        if self.csv_file_path == csv_file {
+            return true;
+        }
+        if checkpoint_for != Some(self.csv_file_path.as_str()) && self.csv_fingerprint.is_none() {
+            return false;
+        }
+        info!("📦 Checkpoint written for {} taken over for {}", self.csv_file_path, csv_file);
+        self.csv_file_path = csv_file.to_string();
+        // A copy has its own modification time; size and content are compared
+        if let Some(fingerprint) = &mut self.csv_fingerprint {
+            fingerprint.modified = None;
+        }
+        true
+    }
+
+    /// Remove the checkpoint from under each of `keys` (CSV paths and content keys)
+    pub async fn cleanup(store: &CheckpointStore, keys: &[String]) -> Result<()> {
+        let mut removed = false;
+        for key in keys {
+            removed |= store.delete(key).await?;
+        }
+        if removed {
+            info!("🗑️  Checkpoint removed");
         }
         Ok(())
     }
@@ -114,3 +433,487 @@ impl MigrationCheckpoint {
         (self.processed_records as f64 / self.total_records as f64) * 100.0
     }
 }
+
+/// Starts of rows the streaming pass read, the first of each transform chunk, for the
+/// boundary offset to move to once the rows before them are confirmed
+#[derive(Debug, Default)]
+pub struct RowStarts(std::sync::Mutex<BTreeMap<usize, RowOffset>>);
+
+impl RowStarts {
+    pub fn note(&self, offset: RowOffset) {
+        self.0.lock().unwrap().insert(offset.index, offset);
+    }
+}
+
+/// Combined coverage of several checkpoints for the same CSV (e.g. sharded runs)
+#[derive(Debug)]
+pub struct CoverageReport {
+    pub merged: MigrationCheckpoint,
+    pub covered_records: u64,
+    pub gaps: Vec<(u64, u64)>, // [start, end) record index ranges nobody indexed
+}
+
+impl MigrationCheckpoint {
+    /// Union the completed work of several checkpoints and find what's still missing
+    pub fn merge(checkpoints: Vec<MigrationCheckpoint>) -> Result<CoverageReport> {
+        let mut iter = checkpoints.into_iter();
+        let mut merged = iter.next().context("No checkpoints to merge")?;
+
+        for checkpoint in iter {
+            if checkpoint.mode != merged.mode {
+                anyhow::bail!("Cannot merge {:?} mode checkpoint with {:?} mode checkpoint",
+                              checkpoint.mode, merged.mode);
+            }
+            if checkpoint.id_template != merged.id_template {
+                anyhow::bail!("Cannot merge key checkpoints of different document id templates ({:?} and {:?})",
+                              merged.id_template, checkpoint.id_template);
+            }
+            if checkpoint.csv_file_path != merged.csv_file_path {
+                warn!("⚠️  Merging checkpoints for different CSV paths: {} and {}",
+                         merged.csv_file_path, checkpoint.csv_file_path);
+            }
+            merged.total_records = merged.total_records.max(checkpoint.total_records);
+            merged.successful_batches += checkpoint.successful_batches;
+            merged.failed_batches += checkpoint.failed_batches;
+            merged.start_time = merged.start_time.min(checkpoint.start_time);
+            merged.completed_indices |= checkpoint.completed_indices;
+            merged.completed_keys.union_with(checkpoint.completed_keys);
+            merged.history_pending |= checkpoint.history_pending;
+        }
+        // A shard may have finished what another left half-written
+        merged.history_pending -= &merged.completed_indices;
+        let finished: Vec<u64> = merged.history_pending.iter().filter(|key| merged.completed_keys.contains(*key)).collect();
+        for key in finished {
+            merged.history_pending.remove(key);
+        }
+
+        let (covered_records, gaps) = match merged.mode {
+            CheckpointMode::Index => {
+                let covered = merged.completed_indices.len();
+                (covered, missing_ranges(&merged.completed_indices, merged.total_records as u64))
+            }
+            // Without positions the best we can do is compare counts
+            CheckpointMode::Key => (merged.completed_keys.len(), Vec::new()),
+        };
+        merged.processed_records = covered_records as usize;
+
+        Ok(CoverageReport { merged, covered_records, gaps })
+    }
+}
+
+/// [start, end) ranges below `total` that are not in the bitmap
+fn missing_ranges(bitmap: &RoaringTreemap, total: u64) -> Vec<(u64, u64)> {
+    let mut gaps = Vec::new();
+    let mut expected = 0;
+    for index in bitmap.iter().take_while(|i| *i < total) {
+        if index > expected {
+            gaps.push((expected, index));
+        }
+        expected = index + 1;
+    }
+    if expected < total {
+        gaps.push((expected, total));
+    }
+    gaps
+}
+
+/// Serialize roaring bitmaps as base64 of their portable binary format,
+/// which stays compact in the JSON checkpoint even with millions of entries
+mod bitmap_base64 {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use roaring::RoaringTreemap;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bitmap: &RoaringTreemap, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+        bitmap.serialize_into(&mut bytes).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RoaringTreemap, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = STANDARD.decode(encoded).map_err(de::Error::custom)?;
+        RoaringTreemap::deserialize_from(&bytes[..]).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use base64::Engine;
+
+    #[test]
+    fn test_record_key_is_stable() {
+        assert_eq!(record_key("409192"), record_key("409192"));
+        assert_ne!(record_key("409192"), record_key("1647694"));
+    }
+
+    #[test]
+    fn test_filtered_rows_count_as_handled() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 6, CheckpointMode::Index);
+        // Batch 0..4 had row 2 filtered out, rows 4 and 5 were filtered after the last batch
+        checkpoint.add_filtered(2);
+        checkpoint.add_completed_batch(0..4, &[]);
+        checkpoint.add_filtered(4);
+        checkpoint.add_filtered(5);
+
+        assert_eq!(checkpoint.processed_records, 6);
+        assert!(checkpoint.is_completed());
+    }
+
+    #[test]
+    fn test_partial_batches_count_indexed_documents() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 4, CheckpointMode::Index);
+        checkpoint.add_completed_batch(0..4, &[]);
+        checkpoint.add_document_outcome(3, 1);
+
+        // All rows are handled, but only three documents made it into the index
+        assert!(checkpoint.is_completed());
+        assert_eq!(checkpoint.indexed_documents, 3);
+        assert_eq!(checkpoint.rejected_documents, 1);
+
+        let restored: MigrationCheckpoint = serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+        assert_eq!((restored.indexed_documents, restored.rejected_documents), (3, 1));
+    }
+
+    #[test]
+    fn test_half_written_batch_stays_pending_until_completed() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 4, CheckpointMode::Index);
+        checkpoint.add_history_pending(2..4, &[]);
+        assert!(!checkpoint.is_completed());
+        assert!(checkpoint.is_history_pending(2, None));
+        assert!(!checkpoint.is_history_pending(1, None));
+
+        let restored: MigrationCheckpoint = serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+        assert!(restored.is_history_pending(3, None));
+
+        checkpoint.add_completed_batch(0..4, &[]);
+        assert!(checkpoint.is_completed());
+        assert!(checkpoint.history_pending.is_empty());
+
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 1, CheckpointMode::Key);
+        checkpoint.add_history_pending(std::iter::empty(), &[record_key("7")]);
+        assert!(checkpoint.is_history_pending(0, Some("7")));
+        checkpoint.add_completed_batch(std::iter::empty(), &[record_key("7")]);
+        assert!(!checkpoint.is_history_pending(0, Some("7")));
+    }
+
+    #[test]
+    fn test_key_mode_skips_completed_ids() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 3, CheckpointMode::Key);
+        checkpoint.add_completed_batch(0..2, &[record_key("1"), record_key("2")]);
+
+        assert!(checkpoint.is_key_completed("1"));
+        assert!(checkpoint.is_key_completed("2"));
+        assert!(!checkpoint.is_key_completed("3"));
+        assert_eq!(checkpoint.get_safe_resume_point(), 0);
+    }
+
+    #[test]
+    fn test_row_offsets_for_seeking() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 0, CheckpointMode::Index);
+        checkpoint.check_csv_len(1 << 30);
+        let mut position = csv::Position::new();
+        for index in [0, 5, ROW_OFFSET_INTERVAL, ROW_OFFSET_INTERVAL, 2 * ROW_OFFSET_INTERVAL] {
+            position.set_byte(index as u64 * 100);
+            checkpoint.note_row_offset(index, Some(&position));
+        }
+        assert_eq!(checkpoint.row_offsets.len(), 2);
+        assert_eq!(checkpoint.row_offset_before(ROW_OFFSET_INTERVAL - 1), None);
+        assert_eq!(checkpoint.row_offset_before(2 * ROW_OFFSET_INTERVAL - 1).map(|offset| offset.byte), Some(ROW_OFFSET_INTERVAL as u64 * 100));
+
+        // Rows up to the boundary are confirmed; seeking there skips re-reading them
+        checkpoint.add_completed_batch(0..ROW_OFFSET_INTERVAL as u64 + 42, &[]);
+        position.set_byte(12_345_678);
+        assert!(!checkpoint.note_boundary_offset(RowOffset::new(ROW_OFFSET_INTERVAL + 43, &position)));
+        assert_eq!(checkpoint.boundary_offset, None);
+        assert!(checkpoint.note_boundary_offset(RowOffset::new(ROW_OFFSET_INTERVAL + 42, &position)));
+        assert_eq!(checkpoint.row_offset_before(ROW_OFFSET_INTERVAL + 50).map(|offset| offset.byte), Some(12_345_678));
+        assert_eq!(checkpoint.row_offset_before(ROW_OFFSET_INTERVAL + 41).map(|offset| offset.index), Some(ROW_OFFSET_INTERVAL));
+        assert_eq!(checkpoint.row_offset_before(2 * ROW_OFFSET_INTERVAL).map(|offset| offset.index), Some(2 * ROW_OFFSET_INTERVAL));
+
+        checkpoint.check_csv_len(1 << 30);
+        assert_eq!(checkpoint.row_offsets.len(), 2);
+        checkpoint.check_csv_len(1 << 31);
+        assert!(checkpoint.row_offsets.is_empty() && checkpoint.boundary_offset.is_none());
+    }
+
+    #[test]
+    fn test_boundary_follows_confirmed_prefix() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 1000, CheckpointMode::Index);
+        let starts = RowStarts::default();
+        let mut position = csv::Position::new();
+        for index in [0, 256, 512, 768] {
+            position.set_byte(index as u64 * 100);
+            starts.note(RowOffset::new(index, &position));
+        }
+
+        // Batches finish out of order; the boundary only moves past a gap-free prefix
+        checkpoint.add_completed_batch(256..512, &[]);
+        checkpoint.advance_boundary(&starts);
+        assert_eq!(checkpoint.boundary_offset, None);
+        checkpoint.add_completed_batch(0..256, &[]);
+        checkpoint.advance_boundary(&starts);
+        assert_eq!(checkpoint.boundary_offset.map(|offset| offset.index), Some(512));
+        assert_eq!(starts.0.lock().unwrap().keys().copied().collect::<Vec<_>>(), vec![768]);
+
+        // Resuming seeks straight to the boundary, even before any row offset was noted
+        let restored: MigrationCheckpoint = serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+        assert_eq!(restored.row_offset_before(restored.get_safe_resume_point()).map(|offset| offset.byte), Some(51_200));
+
+        // A key mode checkpoint has no positions to seek to
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 1000, CheckpointMode::Key);
+        checkpoint.add_completed_batch(0..256, &[]);
+        checkpoint.advance_boundary(&starts);
+        assert_eq!(checkpoint.boundary_offset, None);
+    }
+
+    #[test]
+    fn test_changed_csv_refused_unless_forced() {
+        let fingerprint = CsvFingerprint { len: 100, modified: Some(1), sample_sha256: "a".into(), schema_sha256: "b".into() };
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 10, CheckpointMode::Index);
+        checkpoint.check_fingerprint(fingerprint.clone(), false).unwrap();
+        checkpoint.check_fingerprint(fingerprint.clone(), false).unwrap();
+
+        let swapped = CsvFingerprint { sample_sha256: "c".into(), ..fingerprint.clone() };
+        let error = checkpoint.check_fingerprint(swapped.clone(), false).unwrap_err().to_string();
+        assert!(error.contains("content") && error.contains("--force-resume"), "{}", error);
+        checkpoint.check_fingerprint(swapped.clone(), true).unwrap();
+        assert_eq!(checkpoint.csv_fingerprint, Some(swapped.clone()));
+
+        // Key mode only needs the same columns, but drops the old file's offsets
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 10, CheckpointMode::Key);
+        checkpoint.check_fingerprint(fingerprint.clone(), false).unwrap();
+        let mut position = csv::Position::new();
+        position.set_byte(500);
+        checkpoint.note_row_offset(ROW_OFFSET_INTERVAL, Some(&position));
+        let grown = CsvFingerprint { len: 200, ..swapped };
+        checkpoint.check_fingerprint(grown.clone(), false).unwrap();
+        assert!(checkpoint.row_offset_before(ROW_OFFSET_INTERVAL).is_none());
+        let error = checkpoint.check_fingerprint(CsvFingerprint { schema_sha256: "d".into(), ..grown }, false).unwrap_err();
+        assert!(error.to_string().contains("header columns"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_moved_csv_takes_over_checkpoint() {
+        let mut checkpoint = MigrationCheckpoint::new("/mnt/a/export.csv".to_string(), 10, CheckpointMode::Index);
+        assert!(!checkpoint.adopt("/data/export.csv", None));
+        assert!(checkpoint.adopt("/data/export.csv", Some("/mnt/a/export.csv")));
+        assert_eq!(checkpoint.csv_file_path, "/data/export.csv");
+
+        let fingerprint = CsvFingerprint { len: 100, modified: Some(1), sample_sha256: "a".into(), schema_sha256: "b".into() };
+        checkpoint.csv_fingerprint = Some(fingerprint.clone());
+        assert!(checkpoint.adopt("export.csv", None));
+        // Copied with a new modification time, same content
+        checkpoint.check_fingerprint(CsvFingerprint { modified: Some(2), ..fingerprint }, false).unwrap();
+
+        // Moved without --checkpoint-for: found under the content key
+        let store = CheckpointStore::S3 { store: std::sync::Arc::new(object_store::memory::InMemory::new()), prefix: String::new() };
+        let fingerprint = CsvFingerprint { len: 100, modified: Some(1), sample_sha256: "ab".repeat(32), schema_sha256: "b".into() };
+        checkpoint.csv_fingerprint = Some(fingerprint.clone());
+        checkpoint.save(&store, "export.csv").await.unwrap();
+        let content_key = fingerprint.content_key();
+        let found = MigrationCheckpoint::load(&store, "/new/export.csv", None, Some(&content_key), CheckpointMode::Index).await.unwrap();
+        assert_eq!(found.map(|checkpoint| checkpoint.csv_file_path).as_deref(), Some("/new/export.csv"));
+        MigrationCheckpoint::cleanup(&store, &["export.csv".to_string(), content_key.clone()]).await.unwrap();
+        assert!(MigrationCheckpoint::load(&store, "/new/export.csv", None, Some(&content_key), CheckpointMode::Index).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resume_point_with_out_of_order_batches() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 40, CheckpointMode::Index);
+        assert_eq!(checkpoint.get_safe_resume_point(), 0);
+
+        checkpoint.add_completed_batch(10..20, &[]);
+        assert_eq!(checkpoint.get_safe_resume_point(), 0);
+
+        checkpoint.add_completed_batch(0..10, &[]);
+        checkpoint.add_completed_batch(30..40, &[]);
+        assert_eq!(checkpoint.get_safe_resume_point(), 20);
+
+        checkpoint.add_completed_batch(20..30, &[]);
+        assert_eq!(checkpoint.get_safe_resume_point(), 40);
+        assert!(checkpoint.is_completed());
+    }
+
+    #[test]
+    fn test_duplicate_batches_are_not_double_counted() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 20, CheckpointMode::Index);
+        checkpoint.add_completed_batch(0..10, &[]);
+        checkpoint.add_completed_batch(5..15, &[]);
+
+        assert_eq!(checkpoint.processed_records, 15);
+        assert!(!checkpoint.is_completed());
+    }
+
+    #[test]
+    fn test_legacy_ranges_are_migrated() {
+        let json = r#"{"csv_file_path":"test.csv","total_records":30,"processed_records":20,
+            "successful_batches":2,"failed_batches":0,"completed_batch_ranges":[[10,20],[0,10]],
+            "start_time":0}"#;
+        let mut checkpoint: MigrationCheckpoint = serde_json::from_str(json).unwrap();
+        checkpoint.migrate_legacy_state();
+
+        assert_eq!(checkpoint.get_safe_resume_point(), 20);
+
+        // Key mode kept its keys in a bitmap
+        let mut bitmap = Vec::new();
+        RoaringTreemap::from_iter([record_key("1")]).serialize_into(&mut bitmap).unwrap();
+        let json = serde_json::json!({"csv_file_path": "test.csv", "total_records": 2, "processed_records": 1,
+            "successful_batches": 1, "failed_batches": 0, "start_time": 0, "mode": "key",
+            "completed_keys": base64::engine::general_purpose::STANDARD.encode(bitmap)});
+        let mut checkpoint: MigrationCheckpoint = serde_json::from_value(json).unwrap();
+        checkpoint.migrate_legacy_state();
+        assert!(checkpoint.is_key_completed("1") && !checkpoint.is_key_completed("2"));
+        assert!(serde_json::to_value(&checkpoint).unwrap().get("completed_keys").is_none());
+    }
+
+    #[test]
+    fn test_merge_reports_gaps_between_shards() {
+        let mut first = MigrationCheckpoint::new("test.csv".to_string(), 100, CheckpointMode::Index);
+        first.add_completed_batch(0..30, &[]);
+        let mut second = MigrationCheckpoint::new("test.csv".to_string(), 100, CheckpointMode::Index);
+        second.add_completed_batch(40..90, &[]);
+
+        let report = MigrationCheckpoint::merge(vec![first, second]).unwrap();
+
+        assert_eq!(report.covered_records, 80);
+        assert_eq!(report.gaps, vec![(30, 40), (90, 100)]);
+    }
+
+    #[test]
+    fn test_merge_rejects_mixed_modes() {
+        let first = MigrationCheckpoint::new("test.csv".to_string(), 10, CheckpointMode::Index);
+        let second = MigrationCheckpoint::new("test.csv".to_string(), 10, CheckpointMode::Key);
+
+        assert!(MigrationCheckpoint::merge(vec![first, second]).is_err());
+    }
+
+    #[test]
+    fn test_key_mode_roundtrip() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 3, CheckpointMode::Key);
+        checkpoint.add_completed_batch(0..2, &[record_key("1"), record_key("2")]);
+        // A resumed run sending an id again, and filtered rows, don't move progress
+        checkpoint.add_completed_batch(2..3, &[record_key("2")]);
+        checkpoint.add_filtered(3);
+        assert_eq!(checkpoint.processed_records, 2);
+
+        let json = serde_json::to_string(&checkpoint).unwrap();
+        let restored: MigrationCheckpoint = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.mode, CheckpointMode::Key);
+        assert!(restored.is_key_completed("2"));
+        assert!(!restored.is_key_completed("3"));
+
+        // Written before document id templates: its keys are of bare token ids
+        let mut legacy = restored.clone();
+        assert!(legacy.check_id_template("{token_address}:{token_id}").is_err());
+        legacy.check_id_template("{token_id}").unwrap();
+        assert_eq!(legacy.id_template.as_deref(), Some("{token_id}"));
+        let mut fresh = MigrationCheckpoint::new("test.csv".to_string(), 3, CheckpointMode::Key);
+        fresh.check_id_template("{token_address}:{token_id}").unwrap();
+        assert!(fresh.check_id_template("{token_id}").is_err());
+    }
+
+    #[test]
+    fn test_reexport_with_replaced_id_is_not_complete() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 3, CheckpointMode::Key);
+        checkpoint.add_completed_batch(0..3, &[record_key("1"), record_key("2"), record_key("3")]);
+        assert!(checkpoint.is_completed());
+
+        // The re-export replaced id 3 with id 4, whose batch fails
+        checkpoint.set_current_keys(KeySet::from_iter(["1", "2", "4"].map(record_key)));
+        assert_eq!((checkpoint.processed_records, checkpoint.total_records), (2, 3));
+        checkpoint.add_failed_batch();
+        assert!(!checkpoint.is_completed());
+
+        checkpoint.add_completed_batch(2..3, &[record_key("4")]);
+        assert!(checkpoint.is_completed());
+    }
+
+    /// Order in which `batches` batches finish when `workers` run them like
+    /// `buffer_unordered`: batches start in order, any in-flight one may finish next
+    fn completion_order(batches: usize, workers: usize, picks: &[prop::sample::Index]) -> Vec<usize> {
+        let mut in_flight: Vec<usize> = (0..batches.min(workers)).collect();
+        let mut next = in_flight.len();
+        let mut picks = picks.iter().cycle();
+        let mut order = Vec::new();
+        while !in_flight.is_empty() {
+            let done = in_flight.swap_remove(picks.next().unwrap().index(in_flight.len()));
+            order.push(done);
+            if next < batches {
+                in_flight.push(next);
+                next += 1;
+            }
+        }
+        order
+    }
+
+    proptest! {
+        #[test]
+        fn prop_resume_never_skips_or_repeats_records(
+            records in 1usize..400,
+            batch_size in 1usize..40,
+            workers in 1usize..8,
+            filtered in prop::collection::btree_set(0usize..400, 0..40),
+            picks in prop::collection::vec(any::<prop::sample::Index>(), 1..32),
+            crashes in prop::collection::vec(any::<prop::sample::Index>(), 0..4),
+        ) {
+            let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), records, CheckpointMode::Index);
+            let mut runs = crashes.iter().map(Some).chain(std::iter::once(None));
+
+            while !checkpoint.is_completed() {
+                let crash = runs.next().expect("the run without a crash completes");
+                let resume_point = checkpoint.get_safe_resume_point();
+                prop_assert!((0..resume_point).all(|i| checkpoint.is_index_completed(i)));
+                prop_assert!(resume_point == records || !checkpoint.is_index_completed(resume_point));
+
+                // The same skip rule as the read loop in main
+                let mut to_process = Vec::new();
+                for index in 0..records {
+                    if index < resume_point || checkpoint.is_index_completed(index) {
+                        continue;
+                    }
+                    if filtered.contains(&index) {
+                        checkpoint.add_filtered(index);
+                    } else {
+                        to_process.push(index as u64);
+                    }
+                }
+                // Nothing already indexed is sent again
+                prop_assert!(to_process.iter().all(|&i| !checkpoint.is_index_completed(i as usize)));
+
+                let batches: Vec<&[u64]> = to_process.chunks(batch_size).collect();
+                let order = completion_order(batches.len(), workers, &picks);
+                let finished = crash.map_or(order.len(), |crash| crash.index(order.len() + 1));
+                for &batch in &order[..finished] {
+                    checkpoint.add_completed_batch(batches[batch].iter().copied(), &[]);
+                }
+
+                let json = serde_json::to_string(&checkpoint).unwrap();
+                checkpoint = serde_json::from_str(&json).unwrap();
+            }
+
+            prop_assert_eq!(checkpoint.processed_records, records);
+            prop_assert_eq!(checkpoint.get_safe_resume_point(), records);
+        }
+
+        #[test]
+        fn prop_overlapping_batches_counted_once(
+            batches in prop::collection::vec((0u64..200, 1u64..30), 1..40),
+        ) {
+            let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 300, CheckpointMode::Index);
+            let mut expected = std::collections::BTreeSet::new();
+            for (start, len) in &batches {
+                checkpoint.add_completed_batch(*start..start + len, &[]);
+                expected.extend(*start..start + len);
+            }
+
+            prop_assert_eq!(checkpoint.processed_records, expected.len());
+            let first_missing = (0..).find(|i| !expected.contains(i)).unwrap();
+            prop_assert_eq!(checkpoint.get_safe_resume_point() as u64, first_missing);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+
+use crate::config::AppConfig;
+use crate::deprecations::SendNotingWarnings;
+use crate::elasticsearch::{create_index_if_missing, wait_for_index_health, IndexHealthWait};
+use crate::paths::{ensure_parent_dir, state_file};
+
+pub const DEFAULT_CHECKPOINT_INDEX: &str = "migrator_checkpoints";
+
+/// Where checkpoints are persisted
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckpointStorage {
+    /// A file next to the CSV (or in STATE_DIR)
+    #[default]
+    File,
+    /// A document in CHECKPOINT_INDEX (default migrator_checkpoints) on the target cluster
+    Elasticsearch,
+    /// An object in CHECKPOINT_S3_BUCKET (credentials from the AWS environment)
+    S3,
+}
+
+/// Checkpoint persistence for read-only container filesystems and local disks alike
+pub enum CheckpointStore {
+    /// Nothing is read or written (one-off runs like `--ids-file` reprocessing)
+    Disabled,
+    File,
+    Elasticsearch {
+        client: Client,
+        elasticsearch_url: String,
+        index: String,
+    },
+    S3 {
+        store: Arc<dyn ObjectStore>,
+        prefix: String,
+    },
+}
+
+impl CheckpointStore {
+    /// `elasticsearch_url` is where `client` reaches the cluster (ELASTICSEARCH_URL, or
+    /// the ELASTICSEARCH_UNIX_SOCKET bridge)
+    pub fn from_config(config: &AppConfig, client: &Client, elasticsearch_url: &str) -> Result<Self> {
+        Ok(match config.checkpoint_storage {
+            CheckpointStorage::File => Self::File,
+            CheckpointStorage::Elasticsearch => Self::Elasticsearch {
+                client: client.clone(),
+                elasticsearch_url: elasticsearch_url.to_string(),
+                index: config.checkpoint_index(),
+            },
+            CheckpointStorage::S3 => {
+                let bucket = config.checkpoint_s3_bucket.as_deref()
+                    .context("CHECKPOINT_S3_BUCKET is required for s3 checkpoint storage")?;
+                let store = AmazonS3Builder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()
+                    .context("Failed to configure S3 checkpoint storage")?;
+                Self::S3 {
+                    store: Arc::new(store),
+                    prefix: config.checkpoint_s3_prefix.clone(),
+                }
+            }
+        })
+    }
+
+    /// Human-readable location of the checkpoint for a CSV
+    pub fn describe(&self, csv_file: &str) -> String {
+        match self {
+            Self::Disabled => "disabled".to_string(),
+            Self::File => state_file(csv_file, "checkpoint").display().to_string(),
+            Self::Elasticsearch { index, .. } => format!("{}/_doc/{}", index, checkpoint_key(csv_file)),
+            Self::S3 { .. } => self.object_path(csv_file).to_string(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Disabled)
+    }
+
+    pub async fn read(&self, csv_file: &str) -> Result<Option<String>> {
+        match self {
+            Self::Disabled => Ok(None),
+            Self::File => {
+                let path = state_file(csv_file, "checkpoint");
+                if !path.exists() {
+                    return Ok(None);
+                }
+                let content = fs::read_to_string(&path).await
+                    .with_context(|| format!("Failed to read checkpoint {}", path.display()))?;
+                Ok(Some(content))
+            }
+            Self::Elasticsearch { client, elasticsearch_url, index } => {
+                let url = format!("{}/{}/_doc/{}", elasticsearch_url, index, checkpoint_key(csv_file));
+                let response = client.get(&url).send_noting_warnings().await.context("Failed to fetch checkpoint")?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                if !response.status().is_success() {
+                    anyhow::bail!("Failed to fetch checkpoint: HTTP {}", response.status());
+                }
+                let doc: Value = response.json().await.context("Failed to parse checkpoint document")?;
+                Ok(doc["_source"]["checkpoint"].as_str().map(str::to_string))
+            }
+            Self::S3 { store, .. } => match store.get(&self.object_path(csv_file)).await {
+                Ok(result) => {
+                    let bytes = result.bytes().await.context("Failed to read checkpoint object")?;
+                    Ok(Some(String::from_utf8(bytes.to_vec()).context("Checkpoint object is not UTF-8")?))
+                }
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(e).context("Failed to fetch checkpoint object"),
+            },
+        }
+    }
+
+    pub async fn write(&self, csv_file: &str, json: String) -> Result<()> {
+        match self {
+            Self::Disabled => {}
+            Self::File => {
+                let path = state_file(csv_file, "checkpoint");
+                ensure_parent_dir(&path).await?;
+                fs::write(&path, json).await
+                    .with_context(|| format!("Failed to write checkpoint {}", path.display()))?;
+            }
+            Self::Elasticsearch { client, elasticsearch_url, index } => {
+                if create_index_if_missing(client, elasticsearch_url, index, &checkpoint_index_mapping()).await? {
+                    wait_for_index_health(client, elasticsearch_url, index, IndexHealthWait::Yellow, Duration::from_secs(30)).await?;
+                }
+                let url = format!("{}/{}/_doc/{}", elasticsearch_url, index, checkpoint_key(csv_file));
+                let body = json!({
+                    "csv_file_path": csv_file,
+                    "updated_at": std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    "checkpoint": json,
+                });
+                let response = client.put(&url).json(&body).send_noting_warnings().await.context("Failed to store checkpoint")?;
+                if !response.status().is_success() {
+                    anyhow::bail!("Failed to store checkpoint: HTTP {}", response.status());
+                }
+            }
+            Self::S3 { store, .. } => {
+                store.put(&self.object_path(csv_file), PutPayload::from(json.into_bytes())).await
+                    .context("Failed to store checkpoint object")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the checkpoint; returns whether one existed
+    pub async fn delete(&self, csv_file: &str) -> Result<bool> {
+        match self {
+            Self::Disabled => Ok(false),
+            Self::File => {
+                let path = state_file(csv_file, "checkpoint");
+                if !path.exists() {
+                    return Ok(false);
+                }
+                fs::remove_file(&path).await?;
+                Ok(true)
+            }
+            Self::Elasticsearch { client, elasticsearch_url, index } => {
+                let url = format!("{}/{}/_doc/{}", elasticsearch_url, index, checkpoint_key(csv_file));
+                let response = client.delete(&url).send_noting_warnings().await.context("Failed to delete checkpoint")?;
+                Ok(response.status().is_success())
+            }
+            Self::S3 { store, .. } => match store.delete(&self.object_path(csv_file)).await {
+                Ok(()) => Ok(true),
+                Err(object_store::Error::NotFound { .. }) => Ok(false),
+                Err(e) => Err(e).context("Failed to delete checkpoint object"),
+            },
+        }
+    }
+
+    fn object_path(&self, csv_file: &str) -> ObjectPath {
+        let key = checkpoint_key(csv_file);
+        match self {
+            Self::S3 { prefix, .. } if !prefix.is_empty() => {
+                ObjectPath::from(format!("{}/{}", prefix.trim_end_matches('/'), key))
+            }
+            _ => ObjectPath::from(key),
+        }
+    }
+}
+
+/// Name of a CSV's checkpoint in remote stores, independent of the local directory layout
+fn checkpoint_key(csv_file: &str) -> String {
+    state_file(csv_file, "checkpoint")
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "checkpoint".to_string())
+}
+
+/// Checkpoints are kept in _source only; nothing needs to be searchable
+fn checkpoint_index_mapping() -> Value {
+    json!({
+        "settings": {
+            "number_of_shards": 1
+        },
+        "mappings": {
+            "dynamic": false,
+            "properties": {
+                "csv_file_path": {"type": "keyword"},
+                "updated_at": {"type": "long"}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_object_store_round_trip() {
+        let store = CheckpointStore::S3 {
+            store: Arc::new(InMemory::new()),
+            prefix: "checkpoints/".to_string(),
+        };
+        let csv_file = "data/tokens.csv";
+
+        assert_eq!(store.read(csv_file).await.unwrap(), None);
+        store.write(csv_file, "{\"processed_records\":10}".to_string()).await.unwrap();
+        assert!(store.describe(csv_file).starts_with("checkpoints/"));
+        assert_eq!(store.read(csv_file).await.unwrap().as_deref(), Some("{\"processed_records\":10}"));
+        assert!(store.delete(csv_file).await.unwrap());
+        assert_eq!(store.read(csv_file).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,150 @@
+//! Checkpoint saves off the workers' path. On NFS or an object store a save can
+//! take seconds, and workers used to wait for it while holding the checkpoint.
+//! Workers now only ask for a save; a background task snapshots the checkpoint and
+//! writes it, and requests made meanwhile fold into its next write. Staleness is
+//! bounded: once the oldest unsaved batch is CHECKPOINT_MAX_STALENESS_SECS old, the
+//! next worker saves in line. The largest number of records that a crash would
+//! have sent again is reported at the end of the run.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::checkpoint::MigrationCheckpoint;
+use crate::checkpoint_store::CheckpointStore;
+
+pub struct CheckpointWriter {
+    checkpoint: Arc<Mutex<MigrationCheckpoint>>,
+    store: Arc<CheckpointStore>,
+    csv_file: String,
+    max_staleness: Duration,
+    requested: Notify,
+    /// Held for the length of a write, so saves land in the order they were snapshotted;
+    /// holds the processed records of the last save
+    saved_records: Mutex<usize>,
+    /// When the oldest change not yet saved was made
+    unsaved_since: std::sync::Mutex<Option<Instant>>,
+    max_window: AtomicUsize,
+    finished: AtomicBool,
+}
+
+impl CheckpointWriter {
+    pub fn new(checkpoint: Arc<Mutex<MigrationCheckpoint>>, store: Arc<CheckpointStore>, csv_file: &str, max_staleness: Duration, saved_records: usize) -> Arc<Self> {
+        Arc::new(Self {
+            checkpoint,
+            store,
+            csv_file: csv_file.to_string(),
+            max_staleness,
+            requested: Notify::new(),
+            saved_records: Mutex::new(saved_records),
+            unsaved_since: std::sync::Mutex::new(None),
+            max_window: AtomicUsize::new(0),
+            finished: AtomicBool::new(false),
+        })
+    }
+
+    /// The background task writing requested saves
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let writer = self.clone();
+        tokio::spawn(async move {
+            while !writer.finished.load(Ordering::Relaxed) {
+                writer.requested.notified().await;
+                if let Err(e) = writer.persist(false).await {
+                    warn!("Failed to save checkpoint: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Note a change to the checkpoint
+    pub fn changed(&self) {
+        self.unsaved_since.lock().unwrap().get_or_insert_with(Instant::now);
+    }
+
+    /// Ask the background task for a save, without waiting for it
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    fn is_stale(&self) -> bool {
+        self.unsaved_since.lock().unwrap().is_some_and(|since| since.elapsed() >= self.max_staleness)
+    }
+
+    /// Save in line when the background task has fallen more than the staleness bound behind
+    pub async fn save_if_stale(&self) {
+        if !self.is_stale() {
+            return;
+        }
+        warn!("⏳ Checkpoint unsaved for over {}s, saving before continuing", self.max_staleness.as_secs());
+        if let Err(e) = self.persist(true).await {
+            warn!("Failed to save checkpoint: {}", e);
+        }
+    }
+
+    async fn persist(&self, stale_only: bool) -> anyhow::Result<()> {
+        let mut saved_records = self.saved_records.lock().await;
+        // Another save may have landed while this one waited for its turn
+        if self.finished.load(Ordering::Relaxed) || (stale_only && !self.is_stale()) {
+            return Ok(());
+        }
+        let unsaved_since = self.unsaved_since.lock().unwrap().take();
+        let snapshot = self.checkpoint.lock().await.clone();
+        match snapshot.save(&self.store, &self.csv_file).await {
+            Ok(()) => {
+                let window = snapshot.processed_records.saturating_sub(*saved_records);
+                self.max_window.fetch_max(window, Ordering::Relaxed);
+                *saved_records = snapshot.processed_records;
+                Ok(())
+            }
+            Err(e) => {
+                let mut since = self.unsaved_since.lock().unwrap();
+                *since = since.min(unsaved_since).or(unsaved_since);
+                Err(e)
+            }
+        }
+    }
+
+    /// Stop saving in the background, once the write in progress (if any) has
+    /// landed; the caller makes the final save itself
+    pub async fn finish(&self) {
+        let _write = self.saved_records.lock().await;
+        self.finished.store(true, Ordering::Relaxed);
+        self.requested.notify_one();
+    }
+
+    /// The most records a crash during the run would have processed again
+    pub fn max_window(&self) -> usize {
+        self.max_window.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::CheckpointMode;
+
+    #[tokio::test]
+    async fn test_stale_checkpoint_saved_in_line_until_finished() {
+        let checkpoint = Arc::new(Mutex::new(MigrationCheckpoint::new("test.csv".to_string(), 1000, CheckpointMode::Index)));
+        let writer = CheckpointWriter::new(checkpoint.clone(), Arc::new(CheckpointStore::Disabled), "test.csv", Duration::ZERO, 0);
+        writer.save_if_stale().await;
+        assert_eq!(writer.max_window(), 0);
+
+        checkpoint.lock().await.processed_records = 400;
+        writer.changed();
+        writer.save_if_stale().await;
+        checkpoint.lock().await.processed_records = 600;
+        writer.changed();
+        writer.save_if_stale().await;
+        assert_eq!(writer.max_window(), 400);
+
+        writer.finish().await;
+        checkpoint.lock().await.processed_records = 1000;
+        writer.changed();
+        writer.save_if_stale().await;
+        assert_eq!(writer.max_window(), 400);
+    }
+}
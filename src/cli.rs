@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+/// Migrate ERC721 token exports from CSV into Elasticsearch.
+///
+/// Configuration comes from the environment and .env (see env.example); the
+/// flags below override it for one run. Without a subcommand, `migrate` runs.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigOverrides,
+    #[command(flatten)]
+    pub migrate: MigrateArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Parse the command line, exiting with clap's message on errors
+    pub fn parse_args() -> Self {
+        Self::try_parse_args_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Settings flags go anywhere; migrate's own flags before a subcommand would be
+    /// ignored by it, so they're refused
+    pub fn try_parse_args_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let cli = Self::try_parse_from(args)?;
+        if cli.command.is_some() && cli.migrate != MigrateArgs::default() {
+            return Err(Self::command().error(ErrorKind::ArgumentConflict,
+                "migrate's flags go after the subcommand they're for, e.g. `resume --yes`"));
+        }
+        Ok(cli)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Index the CSV, resuming from a checkpoint if there is one
+    Migrate(MigrateArgs),
+    /// Continue a previous run; fails if there is no checkpoint to resume from
+    Resume(MigrateArgs),
+    /// Compare the CSV with what the target index holds
+    Verify {
+        /// Fetch this many random CSV rows back by _id and compare their fields (0: counts only)
+        #[arg(long, default_value_t = 100)]
+        sample: usize,
+        /// Only check the rows and documents of this collection
+        #[arg(long)]
+        token_address: Option<String>,
+    },
+    /// Create the target index with the generated mapping, or check an existing one
+    CreateIndex,
+    /// Show the progress recorded in the checkpoint
+    Status,
+    /// Delete every document naming an address as owner, maker or matcher, from
+    /// every index the migrator writes (erasure requests)
+    Purge {
+        /// The address to remove
+        #[arg(long)]
+        owner: String,
+        /// Only count the matching documents
+        #[arg(long)]
+        dry_run: bool,
+        /// Skip typing the address to confirm (ASSUME_YES)
+        #[arg(long, short = 'y')]
+        yes: bool,
+    },
+    /// Work with checkpoint files
+    #[command(subcommand)]
+    Checkpoint(CheckpointCommand),
+    /// Inspect generated mappings
+    #[command(subcommand)]
+    Mapping(MappingCommand),
+    /// Zero-downtime reindexing through versioned indices behind an alias
+    #[command(subcommand)]
+    Alias(AliasCommand),
+    /// Count the distinct values of each trait per collection in the CSV files
+    AnalyzeTraits {
+        #[arg(long, value_enum, default_value = "json")]
+        format: TraitFormat,
+        /// Write the report here instead of stdout
+        #[arg(long)]
+        output: Option<String>,
+        /// Also write the frontend's facet definitions (terms and ranges of each
+        /// configured collection's extracted fields) to this file
+        #[arg(long)]
+        facets: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraitFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CheckpointCommand {
+    /// Combine checkpoints of sharded runs over the same CSV and fail if any
+    /// record range was not covered by at least one of them
+    Merge {
+        #[arg(required = true)]
+        checkpoints: Vec<String>,
+        /// Write the merged checkpoint here
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AliasCommand {
+    /// Print the next versioned index name for an alias (`nfts` → `nfts_v3`), to
+    /// migrate into with ELASTICSEARCH_INDEX
+    Next {
+        #[arg(long)]
+        alias: String,
+    },
+    /// Verify ELASTICSEARCH_INDEX, then atomically move the alias from the indices
+    /// it points at to it
+    Swap {
+        #[arg(long)]
+        alias: String,
+        /// Rows fetched back by _id before swapping (0: counts only)
+        #[arg(long, default_value_t = 100)]
+        sample: usize,
+        /// Delete the indices the alias pointed at after the swap
+        #[arg(long)]
+        delete_old: bool,
+        /// Skip typing the alias to confirm the deletion (ASSUME_YES)
+        #[arg(long, short = 'y')]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MappingCommand {
+    /// Print the index body (settings + mappings) used for a collection
+    Preview {
+        /// Collection address; the generic mapping without one
+        #[arg(long)]
+        collection: Option<String>,
+        /// Include the allocation settings INDEX_SETTINGS_FILE has for this index
+        #[arg(long)]
+        index: Option<String>,
+    },
+}
+
+#[derive(Args, Debug, Default, Clone, PartialEq)]
+pub struct MigrateArgs {
+    /// Reprocess only the token ids listed in this file (checkpoint not used)
+    #[arg(long)]
+    pub ids_file: Option<String>,
+    /// Index into a target that already holds documents (ALLOW_EXISTING)
+    #[arg(long)]
+    pub allow_existing: bool,
+    /// Create the target index with the generated mapping first (AUTO_CREATE_INDEX)
+    #[arg(long)]
+    pub create_index: bool,
+    /// Skip the confirmation prompt for protected clusters (ASSUME_YES)
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+    /// Save samples of rejected documents (CAPTURE_SAMPLE_ON_ERROR)
+    #[arg(long)]
+    pub capture_sample_on_error: bool,
+    /// Re-send the documents in the bulk error log instead of the CSV
+    #[arg(long)]
+    pub retry_dlq: bool,
+    /// No progress bar, progress logged every 10000 records and a short summary (QUIET)
+    #[arg(long, short = 'q')]
+    pub quiet: bool,
+    /// Fail the run, after it completes, if any value or row was dropped or altered (STRICT)
+    #[arg(long)]
+    pub strict: bool,
+    /// Resume even if the CSV no longer matches the checkpoint's fingerprint (FORCE_RESUME)
+    #[arg(long)]
+    pub force_resume: bool,
+    /// The CSV's path when its checkpoint was written, for a run moved to another
+    /// machine or mount (CHECKPOINT_FOR)
+    #[arg(long, value_name = "ORIGINAL_PATH")]
+    pub checkpoint_for: Option<String>,
+}
+
+/// Flags that take the place of configuration variables
+#[derive(Args, Debug, Default)]
+pub struct ConfigOverrides {
+    /// Settings profile from PROFILES_FILE (PROFILE)
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+    /// CSV_FILE
+    #[arg(long, global = true)]
+    pub csv_file: Option<String>,
+    /// ELASTICSEARCH_URL
+    #[arg(long, global = true)]
+    pub elasticsearch_url: Option<String>,
+    /// ELASTICSEARCH_INDEX
+    #[arg(long, global = true)]
+    pub index: Option<String>,
+    /// BATCH_SIZE
+    #[arg(long, global = true)]
+    pub batch_size: Option<usize>,
+    /// WORKERS
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+    /// OUTPUT: json for only JSON lines events on stdout (not --output, which
+    /// names the file some subcommands write)
+    #[arg(long, global = true, value_parser = ["text", "json"])]
+    pub output_format: Option<String>,
+    /// VERBOSE: debug logging for the migrator
+    #[arg(long, short = 'v', global = true)]
+    pub verbose: bool,
+    /// Any other variable, e.g. --set GROUP_ORDERS=true (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub set: Vec<String>,
+}
+
+impl ConfigOverrides {
+    /// Variables to set, in the names AppConfig reads
+    fn vars(&self) -> Result<Vec<(String, String)>> {
+        let mut vars = Vec::new();
+        let flags = [
+            ("PROFILE", self.profile.clone()),
+            ("CSV_FILE", self.csv_file.clone()),
+            ("ELASTICSEARCH_URL", self.elasticsearch_url.clone()),
+            ("ELASTICSEARCH_INDEX", self.index.clone()),
+            ("BATCH_SIZE", self.batch_size.map(|n| n.to_string())),
+            ("WORKERS", self.workers.map(|n| n.to_string())),
+            ("OUTPUT", self.output_format.clone()),
+            ("VERBOSE", self.verbose.then(|| "true".to_string())),
+        ];
+        for (name, value) in flags {
+            if let Some(value) = value {
+                vars.push((name.to_string(), value));
+            }
+        }
+        for assignment in &self.set {
+            let (name, value) = assignment.split_once('=')
+                .with_context(|| format!("--set expects KEY=VALUE, got '{}'", assignment))?;
+            vars.push((name.trim().to_uppercase(), value.to_string()));
+        }
+        Ok(vars)
+    }
+
+    /// Export the flags as environment variables, so they win over the real
+    /// environment, profiles and .env when the config is loaded. Must run before
+    /// anything reads APP_CONFIG and before other threads start.
+    pub fn apply(&self) -> Result<()> {
+        for (name, value) in self.vars()? {
+            std::env::set_var(name, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subcommands_and_overrides() {
+        let cli = Cli::try_parse_from(["migrator", "--ids-file", "ids.txt", "-y", "--workers", "8"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.migrate.ids_file.as_deref(), Some("ids.txt"));
+        assert!(cli.migrate.yes);
+        assert_eq!(cli.config.vars().unwrap(), vec![("WORKERS".to_string(), "8".to_string())]);
+
+        let cli = Cli::try_parse_from(["migrator", "resume", "--allow-existing", "--index", "nfts_v2", "--output-format", "json", "--set", "group_orders=true"]).unwrap();
+        assert!(matches!(&cli.command, Some(Command::Resume(args)) if args.allow_existing));
+        assert_eq!(cli.config.vars().unwrap(), vec![
+            ("ELASTICSEARCH_INDEX".to_string(), "nfts_v2".to_string()),
+            ("OUTPUT".to_string(), "json".to_string()),
+            ("GROUP_ORDERS".to_string(), "true".to_string()),
+        ]);
+
+        let cli = Cli::try_parse_from(["migrator", "checkpoint", "merge", "a.json", "b.json", "--output", "c.json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Checkpoint(CheckpointCommand::Merge { checkpoints, output }))
+            if checkpoints.len() == 2 && output.as_deref() == Some("c.json")));
+
+        assert!(Cli::try_parse_from(["migrator", "checkpoint", "merge"]).is_err());
+        let cli = Cli::try_parse_from(["migrator", "analyze-traits", "--format", "csv"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::AnalyzeTraits { format: TraitFormat::Csv, output: None, facets: None })));
+        assert!(Cli::try_parse_from(["migrator", "--set", "WORKERS"]).unwrap().config.vars().is_err());
+        let cli = Cli::try_parse_from(["migrator", "purge", "--owner", "0xabc", "--dry-run"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Purge { owner, dry_run: true, yes: false }) if owner == "0xabc"));
+    }
+
+    #[test]
+    fn test_settings_flags_before_the_subcommand() {
+        let cli = Cli::try_parse_args_from(["migrator", "--index", "foo", "status"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Status)));
+        assert_eq!(cli.config.vars().unwrap(), vec![("ELASTICSEARCH_INDEX".to_string(), "foo".to_string())]);
+        let cli = Cli::try_parse_args_from(["migrator", "--workers", "8", "checkpoint", "merge", "a.json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Checkpoint(CheckpointCommand::Merge { .. }))));
+        let cli = Cli::try_parse_args_from(["migrator", "--output-format", "json", "analyze-traits"]).unwrap();
+        assert_eq!(cli.config.output_format.as_deref(), Some("json"));
+
+        // Without a subcommand they're migrate's; before one they'd be lost
+        assert!(Cli::try_parse_args_from(["migrator", "--yes", "--workers", "8"]).unwrap().migrate.yes);
+        assert_eq!(Cli::try_parse_args_from(["migrator", "--yes", "resume"]).unwrap_err().kind(), ErrorKind::ArgumentConflict);
+        assert!(Cli::try_parse_args_from(["migrator", "resume", "--yes"]).is_ok());
+    }
+
+    #[test]
+    fn test_subcommand_output_paths_are_not_the_output_format() {
+        for args in [&["migrator", "checkpoint", "merge", "a.json", "--output", "c.json"][..], &["migrator", "analyze-traits", "--output", "report.json"]] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(cli.config.output_format, None);
+            cli.config.apply().unwrap();
+            crate::config::check_log_config().unwrap();
+        }
+        let cli = Cli::try_parse_from(["migrator", "analyze-traits", "--output", "report.json", "--output-format", "json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::AnalyzeTraits { output: Some(path), .. }) if path == "report.json"));
+        assert_eq!(cli.config.vars().unwrap(), vec![("OUTPUT".to_string(), "json".to_string())]);
+    }
+}
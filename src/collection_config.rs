@@ -1,111 +1,353 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
 use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::lru::Lru;
+use crate::text_analysis::installed_text_analysis;
 
 /// Configuration for a specific NFT collection
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct CollectionConfig {
+    #[serde(default)]
     pub address: String,
+    /// Regex for the addresses of a family of contracts, instead of one `address`
+    #[serde(default)]
+    pub address_pattern: Option<String>,
     pub name: String,
+    #[serde(default)]
     pub extracted_fields: Vec<ExtractedField>,
+    /// Ingest pipeline for this collection's documents, instead of ELASTICSEARCH_PIPELINE
+    #[serde(default)]
+    pub pipeline: Option<String>,
 }
 
 /// Field to extract from properties for fast queries
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ExtractedField {
     pub name: String,           // Field name in ES document
+    #[serde(rename = "type")]
     pub field_type: FieldType,  // Type for ES mapping
-    pub source_key: String,     // Key in raw_metadata.properties
+    #[serde(default)]
+    pub source_key: String,     // Key in raw_metadata.properties (defaults to name)
+    #[serde(default)]
+    pub required: bool,         // Documents without it are quarantined
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FieldType {
     Integer,
     Keyword,
     Text,
 }
 
-/// Get collection-specific configuration
+/// Addresses whose pattern lookup is remembered
+const RESOLVED_CACHE_SIZE: usize = 100_000;
+/// Collection mappings kept built
+const MAPPING_CACHE_SIZE: usize = 256;
+
+/// Collections loaded from COLLECTIONS_FILE, ahead of the built-in ones
+static LOADED_COLLECTIONS: OnceLock<CollectionRegistry> = OnceLock::new();
+
+/// Collection configs indexed for the per-record lookup: a map by lowercase
+/// address, pattern rules tried once per address, then the built-in set
+struct CollectionRegistry {
+    exact: HashMap<String, Arc<CollectionConfig>>,
+    /// Rules in file order; the first match wins
+    patterns: Vec<(Regex, Arc<CollectionConfig>)>,
+    builtin: HashMap<String, Arc<CollectionConfig>>,
+    /// Outcome of the pattern rules by address
+    resolved: Mutex<Lru<String, Option<Arc<CollectionConfig>>>>,
+    /// Mappings by config; the registry never drops a config, so its pointer is a stable key
+    mappings: Mutex<Lru<usize, Arc<Value>>>,
+}
+
+impl CollectionRegistry {
+    fn new(collections: Vec<CollectionConfig>) -> Self {
+        let mut exact = HashMap::new();
+        let mut patterns = Vec::new();
+        for collection in collections {
+            match collection.address_pattern.as_deref().map(address_regex) {
+                Some(Ok(regex)) => patterns.push((regex, Arc::new(collection))),
+                Some(Err(e)) => tracing::warn!("⚠️  Skipping collection '{}': {:#}", collection.name, e),
+                None => {
+                    exact.insert(collection.address.to_lowercase(), Arc::new(collection));
+                }
+            }
+        }
+        let builtin = builtin_collections().into_iter().map(|c| (c.address.clone(), Arc::new(c))).collect();
+        Self {
+            exact,
+            patterns,
+            builtin,
+            resolved: Mutex::new(Lru::new(RESOLVED_CACHE_SIZE)),
+            mappings: Mutex::new(Lru::new(MAPPING_CACHE_SIZE)),
+        }
+    }
+
+    fn lookup(&self, address: &str) -> Option<Arc<CollectionConfig>> {
+        // Addresses in exports are mostly lowercase already
+        let address = match address.bytes().any(|b| b.is_ascii_uppercase()) {
+            true => Cow::Owned(address.to_lowercase()),
+            false => Cow::Borrowed(address),
+        };
+        if let Some(config) = self.exact.get(address.as_ref()) {
+            return Some(config.clone());
+        }
+        if self.patterns.is_empty() {
+            return self.builtin.get(address.as_ref()).cloned();
+        }
+        let mut resolved = self.resolved.lock().unwrap();
+        let address = address.into_owned();
+        if let Some(config) = resolved.get(&address) {
+            return config.clone();
+        }
+        let config = self.patterns.iter()
+            .find(|(pattern, _)| pattern.is_match(&address))
+            .map(|(_, config)| config.clone())
+            .or_else(|| self.builtin.get(&address).cloned());
+        resolved.insert(address, config.clone());
+        config
+    }
+
+    fn mapping(&self, config: &Arc<CollectionConfig>) -> Arc<Value> {
+        let key = Arc::as_ptr(config) as usize;
+        let mut mappings = self.mappings.lock().unwrap();
+        if let Some(mapping) = mappings.get(&key) {
+            return mapping.clone();
+        }
+        let mapping = Arc::new(generate_collection_mapping(Some(config)));
+        mappings.insert(key, mapping.clone());
+        mapping
+    }
+}
+
+fn registry() -> &'static CollectionRegistry {
+    static BUILTIN_ONLY: OnceLock<CollectionRegistry> = OnceLock::new();
+    LOADED_COLLECTIONS.get().unwrap_or_else(|| BUILTIN_ONLY.get_or_init(|| CollectionRegistry::new(Vec::new())))
+}
+
+/// Case-insensitive, like addresses
+fn address_regex(pattern: &str) -> Result<Regex> {
+    regex::RegexBuilder::new(pattern).case_insensitive(true).build()
+        .with_context(|| format!("invalid address_pattern '{}'", pattern))
+}
+
+/// Contents of a collections.yaml / collections.json file
+#[derive(Debug, Deserialize)]
+struct CollectionsFile {
+    collections: Vec<CollectionConfig>,
+}
+
+/// Read and validate collection configs from a YAML or JSON file (by extension)
+pub fn load_collections(path: &str) -> Result<Vec<CollectionConfig>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read collections file {}", path))?;
+    let is_json = Path::new(path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    let file: CollectionsFile = if is_json {
+        serde_json::from_str(&content).with_context(|| format!("Invalid collections file {}", path))?
+    } else {
+        serde_yaml::from_str(&content).with_context(|| format!("Invalid collections file {}", path))?
+    };
+    validate_collections(file.collections).with_context(|| format!("Invalid collections file {}", path))
+}
+
+fn validate_collections(mut collections: Vec<CollectionConfig>) -> Result<Vec<CollectionConfig>> {
+    let base_fields = base_mapping()["mappings"]["properties"].as_object().cloned().unwrap_or_default();
+    let mut addresses = HashSet::new();
+    for collection in &mut collections {
+        if let Some(pattern) = &collection.address_pattern {
+            if !collection.address.is_empty() {
+                anyhow::bail!("collection '{}' has both an address and an address_pattern", collection.name);
+            }
+            address_regex(pattern).with_context(|| format!("collection '{}'", collection.name))?;
+        } else if !is_contract_address(&collection.address) {
+            anyhow::bail!("collection '{}': '{}' is not a contract address", collection.name, collection.address);
+        } else if !addresses.insert(collection.address.to_lowercase()) {
+            anyhow::bail!("collection {} is configured twice", collection.address);
+        }
+        let mut names = HashSet::new();
+        for field in &mut collection.extracted_fields {
+            if field.name.is_empty() {
+                anyhow::bail!("collection '{}' has an extracted field without a name", collection.name);
+            }
+            // Extracted fields are flattened into the document root
+            if base_fields.contains_key(&field.name) {
+                anyhow::bail!("collection '{}': extracted field '{}' clashes with a base document field", collection.name, field.name);
+            }
+            if !names.insert(field.name.clone()) {
+                anyhow::bail!("collection '{}': extracted field '{}' is listed twice", collection.name, field.name);
+            }
+            if field.source_key.is_empty() {
+                field.source_key = field.name.clone();
+            }
+        }
+    }
+    Ok(collections)
+}
+
+fn is_contract_address(address: &str) -> bool {
+    address.len() == 42 && address.starts_with("0x") && address[2..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Use these collections ahead of the built-in ones; only the first call takes effect
+pub fn install_collections(collections: Vec<CollectionConfig>) {
+    LOADED_COLLECTIONS.set(CollectionRegistry::new(collections)).ok();
+}
+
+/// Get collection-specific configuration: a collection from COLLECTIONS_FILE if
+/// one was loaded (by address, then by address_pattern), otherwise the built-in set.
 /// Returns None for unknown collections (will use generic mapping)
-pub fn get_collection_config(address: &str) -> Option<CollectionConfig> {
-    let address_lower = address.to_lowercase();
-    
-    match address_lower.as_str() {
+pub fn get_collection_config(address: &str) -> Option<Arc<CollectionConfig>> {
+    registry().lookup(address)
+}
+
+/// Mapping of the collection at `address`, built once per collection
+pub fn collection_mapping(address: &str) -> Option<Arc<Value>> {
+    let registry = registry();
+    registry.lookup(address).map(|config| registry.mapping(&config))
+}
+
+/// Finds the collection address in the name of a per-collection index (`nft_0x…`),
+/// for documents read back from such an index without their token_address
+#[derive(Debug, Clone)]
+pub struct CollectionIndexPattern(Regex);
+
+impl CollectionIndexPattern {
+    /// An address as a whole `_`-separated part of the name
+    pub const DEFAULT: &'static str = r"(?:^|_)(?P<address>0x[0-9a-fA-F]{40})(?:_|$)";
+
+    /// The pattern must capture the address in a group named `address`
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).with_context(|| format!("Invalid collection index pattern '{}'", pattern))?;
+        if !regex.capture_names().any(|name| name == Some("address")) {
+            anyhow::bail!("Collection index pattern '{}' has no (?P<address>...) group", pattern);
+        }
+        Ok(Self(regex))
+    }
+
+    /// Lowercase collection address in an index name
+    pub fn collection(&self, index: &str) -> Option<String> {
+        Some(self.0.captures(index)?.name("address")?.as_str().to_lowercase())
+    }
+
+    /// Set a document's missing token_address from the index it came from.
+    /// Returns whether the document was changed.
+    pub fn fill_token_address(&self, index: &str, document: &mut Value) -> bool {
+        let Some(document) = document.as_object_mut() else { return false };
+        if document.get("token_address").is_some_and(|address| !address.is_null()) {
+            return false;
+        }
+        let Some(address) = self.collection(index) else { return false };
+        document.insert("token_address".to_string(), Value::String(address));
+        true
+    }
+}
+
+impl Default for CollectionIndexPattern {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT).expect("default pattern is valid")
+    }
+}
+
+/// Collections compiled into the binary, the default set when no file overrides them
+fn builtin_collections() -> Vec<CollectionConfig> {
+    vec![
         // Wildforest Units Collection
-        "0xa038c593115f6fcd673f6833e15462b475994879" => Some(CollectionConfig {
-            address: address.to_string(),
+        CollectionConfig {
+            address: "0xa038c593115f6fcd673f6833e15462b475994879".to_string(),
+            address_pattern: None,
             name: "Wildforest Units".to_string(),
             extracted_fields: vec![
                 ExtractedField {
                     name: "tier".to_string(),
                     field_type: FieldType::Integer,
                     source_key: "tier".to_string(),
+                    required: true,
                 },
                 ExtractedField {
                     name: "level".to_string(),
                     field_type: FieldType::Integer,
                     source_key: "level".to_string(),
+                    required: false,
                 },
                 ExtractedField {
                     name: "rarity".to_string(),
                     field_type: FieldType::Keyword,
                     source_key: "rarity".to_string(),
+                    required: true,
                 },
                 ExtractedField {
                     name: "nft_type".to_string(),
                     field_type: FieldType::Keyword,
                     source_key: "type".to_string(),
+                    required: true,
                 },
             ],
-        }),
+            pipeline: None,
+        },
         
         // Example: Axie Infinity Collection
-        "0x32950db2a7164ae833121501c797d79e7b79d74c" => Some(CollectionConfig {
-            address: address.to_string(),
+        CollectionConfig {
+            address: "0x32950db2a7164ae833121501c797d79e7b79d74c".to_string(),
+            address_pattern: None,
             name: "Axie".to_string(),
             extracted_fields: vec![
                 ExtractedField {
                     name: "class".to_string(),
                     field_type: FieldType::Keyword,
                     source_key: "class".to_string(),
+                    required: true,
                 },
                 ExtractedField {
                     name: "body_part".to_string(),
                     field_type: FieldType::Keyword,
                     source_key: "body".to_string(),
+                    required: false,
                 },
                 ExtractedField {
                     name: "breed_count".to_string(),
                     field_type: FieldType::Integer,
                     source_key: "breedCount".to_string(),
+                    required: false,
                 },
             ],
-        }),
+            pipeline: None,
+        },
         
         // Example: Land Collection
-        "0x8c666c2fab1a27c49a01d608e23daa99dfa2b489" => Some(CollectionConfig {
-            address: address.to_string(),
+        CollectionConfig {
+            address: "0x8c666c2fab1a27c49a01d608e23daa99dfa2b489".to_string(),
+            address_pattern: None,
             name: "Land".to_string(),
             extracted_fields: vec![
                 ExtractedField {
                     name: "land_type".to_string(),
                     field_type: FieldType::Keyword,
                     source_key: "land_type".to_string(),
+                    required: true,
                 },
                 ExtractedField {
                     name: "x_coordinate".to_string(),
                     field_type: FieldType::Integer,
                     source_key: "col".to_string(),
+                    required: false,
                 },
                 ExtractedField {
                     name: "y_coordinate".to_string(),
                     field_type: FieldType::Integer,
                     source_key: "row".to_string(),
+                    required: false,
                 },
             ],
-        }),
-        
-        // Unknown collection - will use generic mapping
-        _ => None,
-    }
+            pipeline: None,
+        },
+    ]
 }
 
 /// Generate Elasticsearch mapping for a collection
@@ -127,8 +369,65 @@ pub fn generate_collection_mapping(config: Option<&CollectionConfig>) -> Value {
     mapping
 }
 
-/// Base mapping that all collections share
+/// Mapping for an index holding documents of several collections: the base mapping
+/// plus the extracted fields of every configured one. Fails when two collections
+/// map the same field differently.
+pub fn generate_index_mapping<'a>(addresses: impl IntoIterator<Item = &'a String>) -> Result<Value> {
+    let mut mapping = base_mapping();
+    let mut owners: HashMap<String, String> = HashMap::new();
+    for config in addresses.into_iter().filter_map(|address| get_collection_config(address)) {
+        let properties = mapping["mappings"]["properties"].as_object_mut().expect("properties should be an object");
+        for field in &config.extracted_fields {
+            let field_mapping = field_type_to_mapping(&field.field_type);
+            if let Some(existing) = properties.get(&field.name) {
+                if *existing != field_mapping {
+                    anyhow::bail!("Collections {} and {} map field '{}' differently ({} vs {})",
+                                  owners[&field.name], config.name, field.name, existing, field_mapping);
+                }
+            }
+            owners.insert(field.name.clone(), config.name.clone());
+            properties.insert(field.name.clone(), field_mapping);
+        }
+    }
+    Ok(mapping)
+}
+
+/// Type of a mapped field; object fields may leave it out
+fn mapped_type(field: &Value) -> &str {
+    field["type"].as_str().unwrap_or("object")
+}
+
+/// Fields whose type in an existing index differs from the generated `mappings`.
+/// Fields only one side has are not conflicts: Elasticsearch adds new ones.
+pub fn mapping_conflicts(expected: &Value, existing: &Value) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    collect_conflicts(&expected["properties"], &existing["properties"], "", &mut conflicts);
+    conflicts
+}
+
+fn collect_conflicts(expected: &Value, existing: &Value, prefix: &str, conflicts: &mut Vec<String>) {
+    let (Some(expected), Some(existing)) = (expected.as_object(), existing.as_object()) else { return };
+    for (name, field) in expected {
+        let Some(current) = existing.get(name) else { continue };
+        let path = format!("{}{}", prefix, name);
+        if mapped_type(field) != mapped_type(current) {
+            conflicts.push(format!("{}: expected {}, index has {}", path, mapped_type(field), mapped_type(current)));
+        } else {
+            collect_conflicts(&field["properties"], &current["properties"], &format!("{}.", path), conflicts);
+        }
+    }
+}
+
+/// Base mapping that all collections share, with the installed text analysis
 fn base_mapping() -> Value {
+    let mut mapping = base_mapping_template();
+    if let Some(analysis) = installed_text_analysis() {
+        analysis.apply(&mut mapping["mappings"]["properties"]);
+    }
+    mapping
+}
+
+fn base_mapping_template() -> Value {
     json!({
         "settings": {
             "number_of_shards": 1,
@@ -168,7 +467,30 @@ fn base_mapping() -> Value {
                 "maker": {"type": "keyword"},
                 "matcher": {"type": "keyword"},
                 "payment_token": {"type": "keyword"},
+                "payment_token_symbol": {"type": "keyword"},
+                "payment_token_known": {"type": "boolean"},
                 "order_id": {"type": "long"},
+                "orders": {
+                    "type": "nested",
+                    "properties": {
+                        "order_id": {"type": "long"},
+                        "kind": {"type": "long"},
+                        "maker": {"type": "keyword"},
+                        "matcher": {"type": "keyword"},
+                        "state": {"type": "keyword"},
+                        "order_status": {"type": "keyword"},
+                        "payment_token": {"type": "keyword"},
+                        "price": {"type": "double"},
+                        "base_price": {"type": "double"},
+                        "ended_price": {"type": "double"},
+                        "ron_price": {"type": "double"},
+                        "started_at": {"type": "long"},
+                        "ended_at": {"type": "long"},
+                        "expired_at": {"type": "long"}
+                    }
+                },
+                // Kept for reference only: an expired listing must not be searchable as one
+                "archived_order": {"type": "object", "enabled": false},
                 
                 // Timestamps
                 "started_at": {"type": "long"},
@@ -204,11 +526,18 @@ fn base_mapping() -> Value {
                 "video": {"type": "keyword", "index": false},
                 "animation_url": {"type": "keyword", "index": false},
                 "description": {"type": "text", "index": false},
+                "external_url": {"type": "keyword", "index": false},
+                "assets_ok": {"type": "boolean"},
                 
                 // Other
                 "is_shown": {"type": "boolean"},
                 "ownership_block_number": {"type": "long"},
-                "ownership_log_index": {"type": "integer"}
+                "ownership_log_index": {"type": "integer"},
+                
+                // Provenance (stored for tracing back to the CSV, not searchable)
+                "source_file": {"type": "keyword", "index": false},
+                "source_row": {"type": "long", "index": false},
+                "extraction_errors": {"type": "keyword"}
             }
         }
     })
@@ -247,27 +576,46 @@ pub fn extract_typed_value(value: &Value, field_type: &FieldType) -> Option<Valu
         }
         FieldType::Keyword => {
             // Normalize to lowercase
-            if let Some(s) = value.as_str() {
-                Some(json!(s.to_lowercase()))
-            } else if let Some(n) = value.as_i64() {
-                Some(json!(n.to_string().to_lowercase()))
-            } else {
-                None
+            match value.as_str() {
+                Some(s) => Some(json!(s.to_lowercase())),
+                None => value.as_i64().map(|n| json!(n.to_string())),
             }
         }
         FieldType::Text => {
             // Keep as-is
-            if let Some(s) = value.as_str() {
-                Some(json!(s))
-            } else if let Some(n) = value.as_i64() {
-                Some(json!(n.to_string()))
-            } else {
-                None
+            match value.as_str() {
+                Some(s) => Some(json!(s)),
+                None => value.as_i64().map(|n| json!(n.to_string())),
             }
         }
     }
 }
 
+/// Describe why required fields couldn't be extracted (empty when all are present)
+pub fn extraction_errors(
+    properties: Option<&Map<String, Value>>,
+    config: &CollectionConfig,
+) -> Vec<String> {
+    let mut required = config.extracted_fields.iter().filter(|f| f.required).peekable();
+
+    let Some(properties) = properties else {
+        return match required.peek() {
+            Some(_) => vec!["raw_metadata.properties missing".to_string()],
+            None => Vec::new(),
+        };
+    };
+
+    required
+        .filter_map(|field| match properties.get(&field.source_key) {
+            None | Some(Value::Null) => Some(format!("{}: missing", field.source_key)),
+            Some(value) => match extract_typed_value(value, &field.field_type) {
+                Some(_) => None,
+                None => Some(format!("{}: {} is not {:?}", field.source_key, value, field.field_type)),
+            },
+        })
+        .collect()
+}
+
 /// Extract collection-specific fields from properties
 pub fn extract_collection_fields(
     properties: &Map<String, Value>,
@@ -301,6 +649,82 @@ mod tests {
         assert_eq!(config.extracted_fields.len(), 4);
     }
 
+    #[test]
+    fn test_load_collections_yaml_and_json() {
+        let dir = std::env::temp_dir().join(format!("collections_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let yaml = dir.join("collections.yaml");
+        std::fs::write(&yaml, r#"
+collections:
+  - address: "0x00000000000000000000000000000000000000AB"
+    name: Pets
+    extracted_fields:
+      - { name: species, type: keyword, required: true }
+      - { name: pet_level, type: integer, source_key: level }
+"#).unwrap();
+        let loaded = load_collections(yaml.to_str().unwrap()).unwrap();
+        assert_eq!(loaded[0].name, "Pets");
+        assert_eq!(loaded[0].extracted_fields[0].source_key, "species");
+        assert!(loaded[0].extracted_fields[0].required);
+        assert_eq!(loaded[0].extracted_fields[1].source_key, "level");
+        assert!(matches!(loaded[0].extracted_fields[1].field_type, FieldType::Integer));
+
+        let json_path = dir.join("collections.json");
+        std::fs::write(&json_path, r#"{"collections":[{"address":"0x00000000000000000000000000000000000000ab","name":"Pets","extracted_fields":[{"name":"species","type":"float"}]}]}"#).unwrap();
+        let error = format!("{:#}", load_collections(json_path.to_str().unwrap()).unwrap_err());
+        assert!(error.contains("unknown variant `float`"), "{}", error);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_collections_validated() {
+        let collection = |address: &str, field: &str| CollectionConfig {
+            address: address.to_string(),
+            address_pattern: None,
+            name: "Pets".to_string(),
+            extracted_fields: vec![ExtractedField {
+                name: field.to_string(),
+                field_type: FieldType::Keyword,
+                source_key: String::new(),
+                required: false,
+            }],
+            pipeline: None,
+        };
+        let address = "0x00000000000000000000000000000000000000ab";
+        assert!(validate_collections(vec![collection(address, "species")]).is_ok());
+        assert!(validate_collections(vec![collection("0xab", "species")]).is_err());
+        assert!(validate_collections(vec![collection(address, "owner")]).is_err());
+        assert!(validate_collections(vec![collection(address, "a"), collection(&address.to_uppercase().replace("0X", "0x"), "b")]).is_err());
+    }
+
+    #[test]
+    fn test_registry_exact_pattern_and_builtin_lookup() {
+        let collection = |address: &str, pattern: Option<&str>, name: &str| CollectionConfig {
+            address: address.to_string(),
+            address_pattern: pattern.map(str::to_string),
+            name: name.to_string(),
+            extracted_fields: Vec::new(),
+            pipeline: None,
+        };
+        let registry = CollectionRegistry::new(vec![
+            collection("0xA038C593115F6FCD673F6833E15462B475994879", None, "Units v2"),
+            collection("", Some("^0xbeef"), "Beef family"),
+        ]);
+        let name = |address: &str| registry.lookup(address).map(|config| config.name.clone());
+        assert_eq!(name("0xa038c593115f6fcd673f6833e15462b475994879").as_deref(), Some("Units v2"));
+        assert_eq!(name("0xBEEF000000000000000000000000000000000001").as_deref(), Some("Beef family"));
+        assert_eq!(name("0x32950db2a7164ae833121501c797d79e7b79d74c").as_deref(), Some("Axie"));
+        assert_eq!(name("0x0000000000000000000000000000000000000001"), None);
+        // Pattern outcomes, misses included, are looked up once
+        assert_eq!(registry.resolved.lock().unwrap().len(), 3);
+
+        let beef = registry.lookup("0xbeef000000000000000000000000000000000002").unwrap();
+        assert!(Arc::ptr_eq(&registry.mapping(&beef), &registry.mapping(&beef)));
+        assert!(validate_collections(vec![collection("", Some("^0x(beef"), "Broken")]).is_err());
+        assert!(validate_collections(vec![collection("0xab", Some("^0xab"), "Both")]).is_err());
+    }
+
     #[test]
     fn test_get_unknown_collection() {
         let config = get_collection_config("0xunknown");
@@ -325,6 +749,24 @@ mod tests {
         assert_eq!(result, Some(json!("common")));
     }
 
+    #[test]
+    fn test_extraction_errors_for_required_fields() {
+        let config = get_collection_config("0xa038c593115f6fcd673f6833e15462b475994879").unwrap();
+
+        let errors = extraction_errors(None, &config);
+        assert_eq!(errors, vec!["raw_metadata.properties missing".to_string()]);
+
+        let properties = json!({"tier": "one", "rarity": "Common"});
+        let errors = extraction_errors(properties.as_object(), &config);
+        assert_eq!(errors, vec![
+            "tier: \"one\" is not Integer".to_string(),
+            "type: missing".to_string(),
+        ]);
+
+        let properties = json!({"tier": 1, "rarity": "Common", "type": "Archer"});
+        assert!(extraction_errors(properties.as_object(), &config).is_empty());
+    }
+
     #[test]
     fn test_generate_mapping_with_config() {
         let config = get_collection_config("0xa038c593115f6fcd673f6833e15462b475994879").unwrap();
@@ -348,5 +790,45 @@ mod tests {
         // Should NOT have collection-specific fields
         assert!(properties["tier"].is_null());
     }
+
+    #[test]
+    fn test_collection_from_index_name() {
+        let address = "0xA038C593115F6FCD673F6833E15462B475994879";
+        let pattern = CollectionIndexPattern::default();
+        assert_eq!(pattern.collection(&format!("nft_{}", address)), Some(address.to_lowercase()));
+        assert_eq!(pattern.collection(&format!("erc721_{}_v2", address)), Some(address.to_lowercase()));
+        assert_eq!(pattern.collection("nft_tokens"), None);
+
+        let mut document = json!({"token_id": "7", "token_address": null});
+        assert!(pattern.fill_token_address(&format!("nft_{}", address), &mut document));
+        assert_eq!(document["token_address"], address.to_lowercase());
+        let mut document = json!({"token_id": "7", "token_address": "0xkept"});
+        assert!(!pattern.fill_token_address(&format!("nft_{}", address), &mut document));
+        assert_eq!(document["token_address"], "0xkept");
+
+        let custom = CollectionIndexPattern::new(r"^tokens-(?P<address>0x[0-9a-f]+)$").unwrap();
+        assert_eq!(custom.collection("tokens-0xabc"), Some("0xabc".to_string()));
+        assert!(CollectionIndexPattern::new(r"^tokens-(0x[0-9a-f]+)$").is_err());
+    }
+
+    #[test]
+    fn test_index_mapping_conflicts_with_existing_index() {
+        let addresses = ["0xa038c593115f6fcd673f6833e15462b475994879".to_string(), "0xunknown".to_string()];
+        let mapping = generate_index_mapping(&addresses).unwrap();
+        let expected = &mapping["mappings"];
+        assert!(expected["properties"]["tier"].is_object());
+        assert!(mapping_conflicts(expected, expected).is_empty());
+
+        let existing = json!({"properties": {
+            "token_id": {"type": "keyword"},
+            "price": {"type": "keyword"},
+            "orders": {"type": "nested", "properties": {"order_id": {"type": "text"}}},
+            "extra": {"type": "long"}
+        }});
+        assert_eq!(mapping_conflicts(expected, &existing), vec![
+            "orders.order_id: expected long, index has text".to_string(),
+            "price: expected double, index has keyword".to_string(),
+        ]);
+    }
 }
 
@@ -1,21 +1,677 @@
+use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::assets::AssetCheck;
+use crate::batch_size::{Adaptive, BatchSizer};
+use crate::checkpoint::CheckpointMode;
+use crate::checkpoint_store::{CheckpointStorage, DEFAULT_CHECKPOINT_INDEX};
+use crate::collection_config::CollectionIndexPattern;
+use crate::elasticsearch::{quarantine_index_name, BodyDiagnostics, IdStrategy, IndexHealthWait, RetryPolicy, Routing, StallReissue, WriteMode};
+use crate::endpoint::Endpoint;
+use crate::expiry::ExpiredListings;
+use crate::logging::LogFormat;
+use crate::output::OutputFormat;
+use crate::ownership::DuplicateResolution;
+use crate::pseudonymize::FieldHasher;
+use crate::sorted::SortViolation;
+use crate::source::RecordFormat;
+use crate::rate_limit::RateLimits;
+use crate::spool::Spool;
+use crate::text_analysis::{parse_field_analyzers, parse_languages, MissingPlugin, TextAnalysis};
 
 lazy_static::lazy_static! {
     pub static ref APP_CONFIG: AppConfig = load_config_env::<AppConfig>();
+    pub static ref LOG_CONFIG: LogConfig = load_config_env::<LogConfig>();
+}
+
+/// Set once `load_env` has run, so the statics don't load the profile again
+static ENV_LOADED: std::sync::OnceLock<()> = std::sync::OnceLock::new();
+
+/// Logging settings, read apart from AppConfig so commands that don't need a
+/// cluster or CSV can log too
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub output: OutputFormat,
+    #[serde(default)]
+    pub verbose: bool,
+}
+
+impl LogConfig {
+    /// LOG_LEVEL, or debug for this crate under VERBOSE
+    pub fn level(&self) -> &str {
+        match (&self.log_level, self.verbose) {
+            (Some(level), _) => level,
+            (None, true) => "info,erc721_elasticsearch_migrator=debug",
+            (None, false) => "info",
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct AppConfig {
     pub csv_file: String,
+    #[serde(default)]
+    pub csv_file_concurrency: Option<usize>,
+    #[serde(default)]
+    pub input_format: RecordFormat,
+    /// Builder options for object store CSV_FILEs, comma-separated key=value
+    #[serde(default)]
+    pub object_store_options: Option<String>,
     pub elasticsearch_url: String,
     pub elasticsearch_index: String,
     pub batch_size: usize,
     pub workers: usize,
+    #[serde(default)]
+    pub transform_workers: Option<usize>,
     pub timeout_secs: u64,
+    #[serde(default)]
+    pub max_bulk_bytes: Option<usize>,
+    #[serde(default)]
+    pub adaptive_batch_size: bool,
+    #[serde(default)]
+    pub min_batch_size: Option<usize>,
+    #[serde(default)]
+    pub bulk_target_latency_ms: Option<u64>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub index_prefix: String,
+    #[serde(default)]
+    pub csv_download_dir: Option<String>,
+    #[serde(default)]
+    pub csv_sha256: Option<String>,
+    #[serde(default)]
+    pub spool_dir: Option<String>,
+    #[serde(default)]
+    pub spool_max_bytes: Option<u64>,
+    #[serde(default)]
+    pub spool_min_free_bytes: Option<u64>,
+    #[serde(default)]
+    pub require_confirmation: bool,
+    #[serde(default)]
+    pub assume_yes: bool,
+    #[serde(default)]
+    pub state_dir: Option<String>,
+    #[serde(default)]
+    pub checkpoint_mode: CheckpointMode,
+    #[serde(default)]
+    pub checkpoint_storage: CheckpointStorage,
+    #[serde(default)]
+    pub checkpoint_index: Option<String>,
+    #[serde(default)]
+    pub checkpoint_s3_bucket: Option<String>,
+    #[serde(default)]
+    pub checkpoint_s3_prefix: String,
+    #[serde(default)]
+    pub checkpoint_max_staleness_secs: Option<u64>,
+    #[serde(default)]
+    pub auto_create_index: bool,
+    #[serde(default)]
+    pub raise_total_fields_limit: bool,
+    #[serde(default)]
+    pub bulk_error_log: Option<String>,
+    #[serde(default)]
+    pub purge_audit_log: Option<String>,
+    #[serde(default)]
+    pub capture_sample_on_error: bool,
+    #[serde(default)]
+    pub document_id_template: Option<String>,
+    #[serde(default)]
+    pub bulk_body_checksums: bool,
+    #[serde(default)]
+    pub bulk_body_dump_dir: Option<String>,
+    #[serde(default)]
+    pub bulk_body_dump_min_bytes: Option<usize>,
+    #[serde(default)]
+    pub bulk_gzip: Option<bool>,
+    #[serde(default)]
+    pub bulk_gzip_min_bytes: Option<usize>,
+    #[serde(default)]
+    pub elasticsearch_pipeline: Option<String>,
+    #[serde(default)]
+    pub field_analyzers: Option<String>,
+    #[serde(default)]
+    pub description_languages: Option<String>,
+    #[serde(default)]
+    pub missing_plugin: MissingPlugin,
+    #[serde(default)]
+    pub routing: Option<String>,
+    #[serde(default)]
+    pub progress_file: Option<String>,
+    #[serde(default)]
+    pub progress_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    #[serde(default)]
+    pub target_records_per_sec: Option<f64>,
+    #[serde(default)]
+    pub max_requests_per_sec: Option<f64>,
+    #[serde(default)]
+    pub max_records_per_sec: Option<f64>,
+    #[serde(default)]
+    pub rate_limit_file: Option<String>,
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub priority_filter: Option<String>,
+    #[serde(default)]
+    pub group_orders: bool,
+    #[serde(default)]
+    pub sorted_by: Option<String>,
+    #[serde(default)]
+    pub sorted_violation: SortViolation,
+    #[serde(default)]
+    pub column_mapping: Option<String>,
+    #[serde(default)]
+    pub allow_missing_columns: bool,
+    #[serde(default)]
+    pub quarantine_failed_extraction: bool,
+    #[serde(default)]
+    pub write_mode: WriteMode,
+    #[cfg(feature = "chaos")]
+    #[serde(default)]
+    pub chaos_429_rate: f64,
+    #[cfg(feature = "chaos")]
+    #[serde(default)]
+    pub chaos_500_rate: f64,
+    #[cfg(feature = "chaos")]
+    #[serde(default)]
+    pub chaos_timeout_rate: f64,
+    #[cfg(feature = "chaos")]
+    #[serde(default)]
+    pub chaos_seed: Option<u64>,
+    #[serde(default)]
+    pub random_seed: Option<u64>,
+    #[serde(default)]
+    pub asset_check: AssetCheck,
+    #[serde(default)]
+    pub owners_summary_index: Option<String>,
+    #[serde(default)]
+    pub collections_stats_index: Option<String>,
+    #[serde(default)]
+    pub orders_history_index: Option<String>,
+    #[serde(default)]
+    pub payment_tokens: Option<String>,
+    #[serde(default)]
+    pub index_settings_file: Option<String>,
+    #[serde(default)]
+    pub index_wait_for_status: IndexHealthWait,
+    #[serde(default)]
+    pub index_wait_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub health_watchdog: bool,
+    #[serde(default)]
+    pub watchdog_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub watchdog_max_pending_tasks: Option<u64>,
+    #[serde(default)]
+    pub field_coverage_report: Option<String>,
+    #[serde(default)]
+    pub duplicate_resolution: DuplicateResolution,
+    #[serde(default)]
+    pub expired_listings: ExpiredListings,
+    #[serde(default)]
+    pub bulk_max_retries: Option<u32>,
+    #[serde(default)]
+    pub bulk_retry_initial_ms: Option<u64>,
+    #[serde(default)]
+    pub bulk_retry_max_backoff_secs: Option<u64>,
+    #[serde(default)]
+    pub bulk_retry_jitter: Option<bool>,
+    #[serde(default)]
+    pub allow_existing: bool,
+    #[serde(default)]
+    pub quiet: bool,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub force_resume: bool,
+    #[serde(default)]
+    pub checkpoint_for: Option<String>,
+    #[serde(default)]
+    pub heartbeat_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub stall_threshold_secs: Option<u64>,
+    #[serde(default)]
+    pub collections_file: Option<String>,
+    #[serde(default)]
+    pub collection_index_pattern: Option<String>,
+    #[serde(default)]
+    pub reissue_stalled_requests: bool,
+    #[serde(default)]
+    pub reissue_p99_multiplier: Option<f64>,
+    #[serde(default)]
+    pub reissue_min_secs: Option<u64>,
+    #[serde(default)]
+    pub reissue_max_attempts: Option<u32>,
+    #[serde(default)]
+    pub survive_restarts: bool,
+    #[serde(default)]
+    pub restart_max_wait_secs: Option<u64>,
+    #[serde(default)]
+    pub elasticsearch_username: Option<String>,
+    #[serde(default)]
+    pub elasticsearch_password: Option<String>,
+    #[serde(default)]
+    pub elasticsearch_api_key: Option<String>,
+    #[serde(default)]
+    pub elasticsearch_bearer_token: Option<String>,
+    #[serde(default)]
+    pub hash_fields: Option<String>,
+    #[serde(default)]
+    pub hash_key: Option<String>,
+    #[serde(default)]
+    pub hash_key_file: Option<String>,
+    #[serde(default)]
+    pub elasticsearch_connect_to: Option<SocketAddr>,
+    #[serde(default)]
+    pub elasticsearch_unix_socket: Option<String>,
+    #[serde(default)]
+    pub elasticsearch_host_header: Option<String>,
+    #[serde(default)]
+    pub proxy_url: Option<String>,
+    #[serde(default)]
+    pub proxy_username: Option<String>,
+    #[serde(default)]
+    pub proxy_password: Option<String>,
+    #[serde(default)]
+    pub no_proxy: Option<String>,
 }
 
-/// Read config environment variables from .env file, then override them with envy
-fn load_config_env<T: serde::de::DeserializeOwned>() -> T {
+impl AppConfig {
+    /// Index documents are written to, with the profile's index prefix applied
+    pub fn target_index(&self) -> String {
+        format!("{}{}", self.index_prefix, self.elasticsearch_index)
+    }
+
+    /// Indices a migration puts documents in: the target, its quarantine index and
+    /// the configured side indices
+    pub fn data_indices(&self) -> Vec<String> {
+        let target = self.target_index();
+        let quarantine = self.quarantine_failed_extraction.then(|| quarantine_index_name(&target));
+        [Some(target), quarantine, self.orders_history_index.clone(), self.owners_summary_index.clone(), self.collections_stats_index.clone()]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Indices a migration creates and writes: the data indices, and the checkpoint
+    /// index with CHECKPOINT_STORAGE=elasticsearch
+    pub fn written_indices(&self) -> Vec<String> {
+        let mut indices = self.data_indices();
+        if self.checkpoint_storage == CheckpointStorage::Elasticsearch {
+            indices.push(self.checkpoint_index());
+        }
+        indices
+    }
+
+    /// The index Elasticsearch checkpoints are kept in, migrator_checkpoints unless CHECKPOINT_INDEX is set
+    pub fn checkpoint_index(&self) -> String {
+        self.checkpoint_index.clone().unwrap_or_else(|| DEFAULT_CHECKPOINT_INDEX.to_string())
+    }
+
+    /// Where a CSV_FILE URL is downloaded to: CSV_DOWNLOAD_DIR, else SPOOL_DIR, else
+    /// STATE_DIR, else the working directory
+    pub fn csv_download_dir(&self) -> PathBuf {
+        PathBuf::from(self.csv_download_dir.as_deref()
+            .or(self.spool_dir.as_deref())
+            .or(self.state_dir.as_deref())
+            .unwrap_or("."))
+    }
+
+    /// The managed spool directory, if SPOOL_DIR is set; 1 GiB must stay free by default
+    pub fn spool(&self) -> Option<Spool> {
+        self.spool_dir.as_ref().map(|dir| Spool {
+            dir: PathBuf::from(dir),
+            max_bytes: self.spool_max_bytes,
+            min_free_bytes: self.spool_min_free_bytes.unwrap_or(1 << 30),
+        })
+    }
+
+    /// How long to wait for a newly created index to reach INDEX_WAIT_FOR_STATUS
+    pub fn index_wait_timeout(&self) -> Duration {
+        Duration::from_secs(self.index_wait_timeout_secs.unwrap_or(30))
+    }
+
+    /// Authorization header value for Elasticsearch requests, from whichever one of
+    /// basic auth, API key or bearer token is configured
+    pub fn elasticsearch_authorization(&self) -> Result<Option<String>> {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
+        let basic = match (&self.elasticsearch_username, &self.elasticsearch_password) {
+            (Some(username), Some(password)) => Some(format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password)))),
+            (None, None) => None,
+            _ => anyhow::bail!("ELASTICSEARCH_USERNAME and ELASTICSEARCH_PASSWORD must be set together"),
+        };
+        // An encoded API key is sent as is; an "id:api_key" pair is encoded first
+        let api_key = self.elasticsearch_api_key.as_ref().map(|key| match key.contains(':') {
+            true => format!("ApiKey {}", STANDARD.encode(key)),
+            false => format!("ApiKey {}", key),
+        });
+        let bearer = self.elasticsearch_bearer_token.as_ref().map(|token| format!("Bearer {}", token));
+
+        let mut configured = [basic, api_key, bearer].into_iter().flatten();
+        let authorization = configured.next();
+        if configured.next().is_some() {
+            anyhow::bail!("Set only one of ELASTICSEARCH_USERNAME/PASSWORD, ELASTICSEARCH_API_KEY and ELASTICSEARCH_BEARER_TOKEN");
+        }
+        Ok(authorization)
+    }
+
+    /// Failure injection rates; without CHAOS_SEED (or RANDOM_SEED) each run draws different faults
+    #[cfg(feature = "chaos")]
+    pub fn chaos(&self) -> crate::chaos::ChaosConfig {
+        crate::chaos::ChaosConfig {
+            throttle_rate: self.chaos_429_rate,
+            error_rate: self.chaos_500_rate,
+            timeout_rate: self.chaos_timeout_rate,
+            seed: self.chaos_seed.or(self.random_seed).unwrap_or_else(|| {
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+            }),
+        }
+    }
+
+    /// How to reach ELASTICSEARCH_URL
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            url: self.elasticsearch_url.clone(),
+            connect_to: self.elasticsearch_connect_to,
+            unix_socket: self.elasticsearch_unix_socket.as_ref().map(PathBuf::from),
+            host_header: self.elasticsearch_host_header.clone(),
+        }
+    }
+
+    /// Proxy for every request (cluster and asset URLs), with basic auth if
+    /// PROXY_USERNAME/PASSWORD are set and the hosts in NO_PROXY going direct
+    pub fn proxy(&self) -> Result<Option<reqwest::Proxy>> {
+        let Some(url) = &self.proxy_url else {
+            return Ok(None);
+        };
+        let mut proxy = reqwest::Proxy::all(url).with_context(|| format!("Invalid PROXY_URL '{}'", url))?;
+        match (&self.proxy_username, &self.proxy_password) {
+            (Some(username), Some(password)) => proxy = proxy.basic_auth(username, password),
+            (None, None) => {}
+            _ => anyhow::bail!("PROXY_USERNAME and PROXY_PASSWORD must be set together"),
+        }
+        Ok(Some(proxy.no_proxy(self.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string))))
+    }
+
+    /// How to read a collection address out of a per-collection index name
+    pub fn collection_index_pattern(&self) -> Result<CollectionIndexPattern> {
+        CollectionIndexPattern::new(self.collection_index_pattern.as_deref().unwrap_or(CollectionIndexPattern::DEFAULT))
+    }
+
+    /// Analyzers of FIELD_ANALYZERS and subfields of DESCRIPTION_LANGUAGES, before
+    /// the cluster's plugins are known
+    pub fn text_analysis(&self) -> Result<TextAnalysis> {
+        Ok(TextAnalysis {
+            field_analyzers: parse_field_analyzers(self.field_analyzers.as_deref().unwrap_or(""))?,
+            languages: parse_languages(self.description_languages.as_deref().unwrap_or(""))?,
+            plugins: None,
+            on_missing_plugin: self.missing_plugin,
+        })
+    }
+
+    /// Pseudonymization of HASH_FIELDS, keyed by HASH_KEY or the contents of HASH_KEY_FILE
+    pub fn field_hasher(&self) -> Result<Option<FieldHasher>> {
+        let Some(fields) = &self.hash_fields else {
+            return Ok(None);
+        };
+        let key = match (&self.hash_key, &self.hash_key_file) {
+            (Some(key), None) => key.clone().into_bytes(),
+            (None, Some(path)) => {
+                let key = std::fs::read(path).with_context(|| format!("Failed to read HASH_KEY_FILE {}", path))?;
+                // Secret files usually end in a newline that isn't part of the key
+                key.strip_suffix(b"\n").unwrap_or(&key).to_vec()
+            }
+            (Some(_), Some(_)) => anyhow::bail!("Set either HASH_KEY or HASH_KEY_FILE, not both"),
+            (None, None) => anyhow::bail!("HASH_FIELDS needs a key: set HASH_KEY or HASH_KEY_FILE"),
+        };
+        FieldHasher::new(&key, fields).map(Some)
+    }
+
+    /// Shard routing of token documents, by `_id` unless ROUTING is set
+    pub fn routing(&self) -> Result<Routing> {
+        Routing::parse(self.routing.as_deref().unwrap_or("none"))
+    }
+
+    /// How token document ids are built, `{token_address}:{token_id}` unless DOCUMENT_ID_TEMPLATE is set
+    pub fn id_strategy(&self) -> Result<IdStrategy> {
+        IdStrategy::new(self.document_id_template.as_deref().unwrap_or(IdStrategy::DEFAULT))
+    }
+
+    /// Bulk body logging/dumping; a dump directory alone turns checksums on too
+    pub fn body_diagnostics(&self) -> BodyDiagnostics {
+        BodyDiagnostics {
+            log_checksums: self.bulk_body_checksums || self.bulk_body_dump_dir.is_some(),
+            dump_dir: self.bulk_body_dump_dir.as_ref().map(PathBuf::from),
+            dump_min_bytes: self.bulk_body_dump_min_bytes.unwrap_or(1024 * 1024),
+        }
+    }
+
+    /// Bulk bodies at least this large are gzipped: BULK_GZIP_MIN_BYTES (default 64KiB),
+    /// unless BULK_GZIP=false
+    pub fn bulk_gzip_min_bytes(&self) -> Option<usize> {
+        self.bulk_gzip.unwrap_or(true).then(|| self.bulk_gzip_min_bytes.unwrap_or(64 * 1024))
+    }
+
+    /// Threads parsing rows into documents: TRANSFORM_WORKERS, else one per CPU up to 4
+    pub fn transform_workers(&self) -> usize {
+        self.transform_workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get().min(4)))
+            .max(1)
+    }
+
+    /// Batch limits from BATCH_SIZE and MAX_BULK_BYTES, adapting to the cluster with
+    /// ADAPTIVE_BATCH_SIZE
+    pub fn batch_sizer(&self) -> BatchSizer {
+        let adaptive = self.adaptive_batch_size.then(|| Adaptive {
+            min_documents: self.min_batch_size.unwrap_or(self.batch_size / 10).max(1),
+            target_latency: Duration::from_millis(self.bulk_target_latency_ms.unwrap_or(2000)),
+        });
+        BatchSizer::new(self.batch_size, self.max_bulk_bytes, adaptive)
+    }
+
+    /// Starting rate limits: RATE_LIMIT_FILE when it exists, otherwise MAX_REQUESTS_PER_SEC
+    /// and MAX_RECORDS_PER_SEC
+    pub fn rate_limits(&self) -> Result<RateLimits> {
+        match self.rate_limit_file.as_deref().map(Path::new).filter(|path| path.exists()) {
+            Some(path) => RateLimits::load(path),
+            None => RateLimits { requests_per_sec: self.max_requests_per_sec, records_per_sec: self.max_records_per_sec }
+                .with_query(""),
+        }
+    }
+
+    /// Retry policy for bulk requests, defaults filled in for unset BULK_RETRY_* variables
+    pub fn bulk_retry_policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            max_retries: self.bulk_max_retries.unwrap_or(default.max_retries),
+            initial_backoff: self.bulk_retry_initial_ms.map_or(default.initial_backoff, Duration::from_millis),
+            max_backoff: self.bulk_retry_max_backoff_secs.map_or(default.max_backoff, Duration::from_secs),
+            jitter: self.bulk_retry_jitter.unwrap_or(default.jitter),
+            stall_reissue: self.reissue_stalled_requests.then(|| {
+                let default = StallReissue::default();
+                StallReissue {
+                    p99_multiplier: self.reissue_p99_multiplier.unwrap_or(default.p99_multiplier),
+                    min_timeout: self.reissue_min_secs.map_or(default.min_timeout, Duration::from_secs),
+                    max_reissues: self.reissue_max_attempts.unwrap_or(default.max_reissues),
+                }
+            }),
+        }
+    }
+}
+
+/// Read config environment variables from .env file, then override them with envy.
+/// A selected profile sits between the two: real environment > profile > .env
+/// Fail with the reason, instead of panicking where APP_CONFIG is first used, when
+/// the settings are missing or don't parse. LOG_CONFIG must be loaded already.
+pub fn check_app_config() -> Result<()> {
+    envy::from_env::<AppConfig>().context("Invalid settings")?;
+    Ok(())
+}
+
+/// Fail with the reason when the logging settings don't parse, before LOG_CONFIG is used
+pub fn check_log_config() -> Result<()> {
+    envy::from_env::<LogConfig>().context("Invalid logging settings")?;
+    Ok(())
+}
+
+/// Put the selected profile and .env into the environment. Runs before anything
+/// reads LOG_CONFIG or APP_CONFIG, so a missing profile is an error and not a panic.
+pub fn load_env() -> Result<()> {
+    if let Some(profile) = selected_profile() {
+        let profiles_file = std::env::var("PROFILES_FILE").unwrap_or_else(|_| "profiles.toml".to_string());
+        apply_profile(&profiles_file, &profile).with_context(|| format!("Failed to load profile '{}'", profile))?;
+    }
     dotenvy::dotenv().ok();
-    envy::from_env().unwrap()
+    ENV_LOADED.set(()).ok();
+    Ok(())
+}
+
+/// The statics' settings; `start()` loads the environment and checks them first,
+/// so failing here is a bug
+fn load_config_env<T: serde::de::DeserializeOwned>() -> T {
+    if ENV_LOADED.get().is_none() {
+        load_env().expect("settings environment");
+    }
+    envy::from_env().expect("settings checked before use")
+}
+
+/// Profile chosen with PROFILE (which `--profile <name>` sets)
+fn selected_profile() -> Option<String> {
+    std::env::var("PROFILE").ok()
+}
+
+/// Export the profile's settings as environment variables that aren't already set
+fn apply_profile(profiles_file: &str, profile: &str) -> Result<()> {
+    let content = std::fs::read_to_string(Path::new(profiles_file))
+        .with_context(|| format!("Failed to read {}", profiles_file))?;
+    for (key, value) in profile_settings(&content, profile)? {
+        if std::env::var_os(&key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    std::env::set_var("PROFILE", profile);
+    Ok(())
+}
+
+/// Settings of one `[profile]` table as (ENV_NAME, value) pairs
+fn profile_settings(content: &str, profile: &str) -> Result<Vec<(String, String)>> {
+    let profiles: toml::Table = content.parse().context("Invalid profiles TOML")?;
+    let table = profiles.get(profile)
+        .and_then(|p| p.as_table())
+        .with_context(|| format!("No [{}] table in profiles file", profile))?;
+
+    table.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => value.to_string(),
+                _ => anyhow::bail!("Profile setting '{}' must be a string, number or boolean", key),
+            };
+            Ok((key.to_uppercase(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = r#"
+[staging]
+elasticsearch_url = "http://es-staging:9200"
+workers = 4
+
+[prod]
+elasticsearch_url = "https://es-prod:9200"
+index_prefix = "prod_"
+batch_size = 5000
+target_records_per_sec = 2500.5
+allow_missing_columns = false
+"#;
+
+    #[test]
+    fn test_profile_settings() {
+        let mut settings = profile_settings(PROFILES, "prod").unwrap();
+        settings.sort();
+        assert_eq!(settings, vec![
+            ("ALLOW_MISSING_COLUMNS".to_string(), "false".to_string()),
+            ("BATCH_SIZE".to_string(), "5000".to_string()),
+            ("ELASTICSEARCH_URL".to_string(), "https://es-prod:9200".to_string()),
+            ("INDEX_PREFIX".to_string(), "prod_".to_string()),
+            ("TARGET_RECORDS_PER_SEC".to_string(), "2500.5".to_string()),
+        ]);
+    }
+
+    fn config(vars: &[(&str, &str)]) -> AppConfig {
+        let required = [("CSV_FILE", "nfts.csv"), ("ELASTICSEARCH_URL", "http://localhost:9200"),
+                        ("ELASTICSEARCH_INDEX", "nfts"), ("BATCH_SIZE", "100"), ("WORKERS", "1"), ("TIMEOUT_SECS", "5")];
+        envy::from_iter(required.iter().chain(vars).map(|(k, v)| (k.to_string(), v.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn test_elasticsearch_authorization() {
+        assert_eq!(config(&[]).elasticsearch_authorization().unwrap(), None);
+        assert_eq!(
+            config(&[("ELASTICSEARCH_USERNAME", "elastic"), ("ELASTICSEARCH_PASSWORD", "secret")]).elasticsearch_authorization().unwrap(),
+            Some("Basic ZWxhc3RpYzpzZWNyZXQ=".to_string())
+        );
+        assert_eq!(
+            config(&[("ELASTICSEARCH_API_KEY", "id:key")]).elasticsearch_authorization().unwrap(),
+            Some("ApiKey aWQ6a2V5".to_string())
+        );
+        assert_eq!(
+            config(&[("ELASTICSEARCH_API_KEY", "aWQ6a2V5")]).elasticsearch_authorization().unwrap(),
+            Some("ApiKey aWQ6a2V5".to_string())
+        );
+        assert_eq!(
+            config(&[("ELASTICSEARCH_BEARER_TOKEN", "t0ken")]).elasticsearch_authorization().unwrap(),
+            Some("Bearer t0ken".to_string())
+        );
+        assert!(config(&[("ELASTICSEARCH_USERNAME", "elastic")]).elasticsearch_authorization().is_err());
+        assert!(config(&[("ELASTICSEARCH_API_KEY", "k"), ("ELASTICSEARCH_BEARER_TOKEN", "t")]).elasticsearch_authorization().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_proxy() {
+        use crate::test_server::{Reply, TestServer};
+        use std::sync::{Arc, Mutex};
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let proxy = TestServer::start("127.0.0.1:0".parse().unwrap(), {
+            let seen = seen.clone();
+            move |_, request_line| {
+                seen.lock().unwrap().push(request_line.to_string());
+                Reply::Respond(200, "{}".to_string())
+            }
+        }).await;
+        let proxied = config(&[("PROXY_URL", &proxy.url()), ("PROXY_USERNAME", "u"), ("PROXY_PASSWORD", "p"), ("NO_PROXY", "127.0.0.2")]);
+        let client = reqwest::Client::builder().proxy(proxied.proxy().unwrap().unwrap()).build().unwrap();
+
+        client.get("http://es.internal:9200/_cluster/health").send().await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec!["GET http://es.internal:9200/_cluster/health HTTP/1.1"]);
+        // Bypassed hosts are connected to directly, which fails here
+        assert!(client.get("http://127.0.0.2:1/").send().await.is_err());
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        assert!(config(&[]).proxy().unwrap().is_none());
+        assert!(config(&[("PROXY_URL", "http://proxy:3128"), ("PROXY_USERNAME", "u")]).proxy().is_err());
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        assert!(profile_settings(PROFILES, "dev").is_err());
+        assert!(profile_settings("[dev]\nworkers = [1, 2]", "dev").is_err());
+    }
 }
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use std::io::{BufRead, IsTerminal, Write};
+
+use crate::config::AppConfig;
+use crate::output::{human, is_json};
+
+/// Profiles that always ask before writing, even without REQUIRE_CONFIRMATION
+const PROTECTED_PROFILES: &[&str] = &["prod", "production"];
+
+/// What a run is about to do, shown before anything is written
+#[derive(Debug)]
+pub struct ImpactSummary {
+    pub cluster_url: String,
+    pub cluster_name: Option<String>,
+    pub index: String,
+    pub profile: Option<String>,
+    pub mode: String,
+    pub scope: ImpactScope,
+}
+
+/// How much a run writes
+#[derive(Debug)]
+pub enum ImpactScope {
+    Records { to_process: usize, total: usize },
+    /// CSV_FILE naming several files, confirmed once for all of them
+    Files { to_migrate: usize, total: usize },
+}
+
+impl ImpactSummary {
+    pub fn print(&self) {
+        human("\n📋 Impact summary:");
+        human(format_args!("   Cluster: {}{}", self.cluster_url,
+                           self.cluster_name.as_deref().map(|n| format!(" ({})", n)).unwrap_or_default()));
+        if let Some(profile) = &self.profile {
+            human(format_args!("   Profile: {}", profile));
+        }
+        human(format_args!("   Target index: {}", self.index));
+        human(format_args!("   Mode: {}", self.mode));
+        match self.scope {
+            ImpactScope::Records { to_process, total } => human(format_args!("   Records: {} to write ({} in CSV)", to_process, total)),
+            ImpactScope::Files { to_migrate, total } => human(format_args!("   CSV files: {} to migrate ({} matching)", to_migrate, total)),
+        }
+    }
+}
+
+/// Whether this run has to be confirmed before writing
+pub fn requires_confirmation(config: &AppConfig) -> bool {
+    let protected_profile = config.profile.as_deref()
+        .is_some_and(|p| PROTECTED_PROFILES.contains(&p.to_lowercase().as_str()));
+    config.require_confirmation || protected_profile
+}
+
+/// Print the impact summary and ask the operator to type the target index name.
+/// `assume_yes` (--yes / ASSUME_YES) skips the prompt but still prints the summary.
+pub fn confirm(summary: &ImpactSummary, assume_yes: bool) -> Result<()> {
+    summary.print();
+    confirm_by_typing("the target index name", &summary.index, assume_yes)
+}
+
+/// Ask the operator to type `expected` (described as `what`) before going on
+pub fn confirm_by_typing(what: &str, expected: &str, assume_yes: bool) -> Result<()> {
+    if assume_yes {
+        human("✓ Confirmed via --yes");
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("Confirmation required but stdin is not a terminal (pass --yes to proceed)");
+    }
+
+    let prompt = format!("Type {} ('{}') to continue: ", what, expected);
+    match is_json() {
+        true => std::io::stderr().write_all(prompt.as_bytes())?,
+        false => {
+            print!("{}", prompt);
+            std::io::stdout().flush()?;
+        }
+    }
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    check_answer(&answer, expected)
+}
+
+fn check_answer(answer: &str, expected: &str) -> Result<()> {
+    if answer.trim() == expected {
+        Ok(())
+    } else {
+        anyhow::bail!("Confirmation did not match '{}', aborting", expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answer_must_match_index() {
+        assert!(check_answer("nft_tokens\n", "nft_tokens").is_ok());
+        assert!(check_answer("yes\n", "nft_tokens").is_err());
+        assert!(check_answer("", "nft_tokens").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! End-of-input audit: rows read, rows parsed, documents built and documents
+//! acknowledged, with what explains the difference between each stage (rows done
+//! earlier, id-less documents, rejections, ...). A difference nothing explains is
+//! printed as unaccounted instead of hiding behind "100% complete".
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{info, warn};
+
+use crate::elasticsearch::BulkOutcome;
+
+/// A reason rows or documents leave between two stages, with how many did
+type StageDrop<'a> = (&'a str, usize);
+
+/// What became of the documents handed to the workers, added to by each batch
+#[derive(Debug, Default)]
+pub struct DocumentTally {
+    acknowledged: AtomicUsize,
+    rejected: AtomicUsize,
+    not_found: AtomicUsize,
+    without_id: AtomicUsize,
+    history_only: AtomicUsize,
+    in_failed_batches: AtomicUsize,
+}
+
+impl DocumentTally {
+    /// A batch of `documents` the cluster accepted; history-only batches don't send their token documents
+    pub fn add_outcome(&self, documents: usize, history_only: bool, outcome: &BulkOutcome) {
+        if history_only {
+            self.history_only.fetch_add(documents, Ordering::Relaxed);
+            return;
+        }
+        self.acknowledged.fetch_add(outcome.indexed + outcome.existing + outcome.deleted, Ordering::Relaxed);
+        self.rejected.fetch_add(outcome.failed, Ordering::Relaxed);
+        self.not_found.fetch_add(outcome.not_found, Ordering::Relaxed);
+        self.without_id.fetch_add(outcome.skipped, Ordering::Relaxed);
+    }
+
+    /// A batch of `documents` that failed as a whole
+    pub fn add_failed_batch(&self, documents: usize) {
+        self.in_failed_batches.fetch_add(documents, Ordering::Relaxed);
+    }
+}
+
+/// Rows and documents of a run, stage by stage
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Conservation {
+    /// Rows in the input, including the ones a resumed run seeked past
+    pub rows_read: usize,
+    pub rows_done_earlier: usize,
+    pub rows_filtered: usize,
+    /// Rows not listed in --ids-file
+    pub rows_unselected: usize,
+    /// Duplicate token rows superseded by a later ownership
+    pub rows_superseded: usize,
+    /// Rows the streaming pass parsed into documents
+    pub rows_parsed: usize,
+    /// Order rows merged into the document of another row of their token
+    pub rows_merged: usize,
+    pub documents_built: usize,
+    pub documents_without_id: usize,
+    /// Documents whose token half an earlier run wrote; only their orders history was sent
+    pub documents_history_only: usize,
+    pub documents_in_failed_batches: usize,
+    pub documents_rejected: usize,
+    /// Tombstones whose document wasn't found
+    pub documents_not_found: usize,
+    /// Documents indexed, already present (create mode) or deleted (tombstones)
+    pub documents_acknowledged: usize,
+}
+
+impl Conservation {
+    pub fn add_tally(&mut self, tally: &DocumentTally) {
+        self.documents_acknowledged = tally.acknowledged.load(Ordering::Relaxed);
+        self.documents_rejected = tally.rejected.load(Ordering::Relaxed);
+        self.documents_not_found = tally.not_found.load(Ordering::Relaxed);
+        self.documents_without_id = tally.without_id.load(Ordering::Relaxed);
+        self.documents_history_only = tally.history_only.load(Ordering::Relaxed);
+        self.documents_in_failed_batches = tally.in_failed_batches.load(Ordering::Relaxed);
+    }
+
+    /// Rows read but neither skipped nor parsed (e.g. a stream that stopped early)
+    pub fn unaccounted_rows(&self) -> i64 {
+        self.rows_read as i64
+            - (self.rows_done_earlier + self.rows_filtered + self.rows_unselected + self.rows_superseded + self.rows_parsed) as i64
+    }
+
+    /// Documents built but neither acknowledged nor explained by a later stage
+    pub fn unaccounted_documents(&self) -> i64 {
+        self.documents_built as i64
+            - (self.documents_without_id + self.documents_history_only + self.documents_in_failed_batches
+                + self.documents_rejected + self.documents_not_found + self.documents_acknowledged) as i64
+    }
+
+    /// The stages as a table: each total, then what left between it and the next
+    pub fn print(&self) {
+        let stages: [(&str, usize, &[StageDrop]); 4] = [
+            ("Rows read", self.rows_read, &[
+                ("done in earlier runs", self.rows_done_earlier),
+                ("filtered out", self.rows_filtered),
+                ("not in --ids-file", self.rows_unselected),
+                ("superseded duplicates", self.rows_superseded),
+            ]),
+            ("Rows parsed", self.rows_parsed, &[("merged into their token's document", self.rows_merged)]),
+            ("Documents built", self.documents_built, &[
+                ("without a document id", self.documents_without_id),
+                ("orders history only", self.documents_history_only),
+                ("in failed batches", self.documents_in_failed_batches),
+                ("rejected by Elasticsearch", self.documents_rejected),
+                ("deletes that found no document", self.documents_not_found),
+            ]),
+            ("Documents acknowledged", self.documents_acknowledged, &[]),
+        ];
+        info!("   Conservation:");
+        for (stage, total, drops) in stages {
+            info!("     {:<38} {:>10}", stage, total);
+            for (reason, count) in drops.iter().filter(|(_, count)| *count > 0) {
+                info!("       {:<36} {:>10}", reason, -(*count as i64));
+            }
+        }
+        if self.unaccounted_rows() != 0 {
+            warn!("⚠️  {} rows read are unaccounted for before parsing", self.unaccounted_rows());
+        }
+        if self.unaccounted_documents() != 0 {
+            warn!("⚠️  {} documents built are unaccounted for after indexing", self.unaccounted_documents());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_row_is_accounted_for() {
+        let tally = DocumentTally::default();
+        tally.add_outcome(5, false, &BulkOutcome { indexed: 2, deleted: 1, not_found: 1, skipped: 1, ..BulkOutcome::default() });
+        tally.add_outcome(2, true, &BulkOutcome::default());
+        tally.add_outcome(1, false, &BulkOutcome { failed: 1, ..BulkOutcome::default() });
+        tally.add_failed_batch(3);
+
+        let mut conservation = Conservation {
+            rows_read: 21,
+            rows_done_earlier: 5,
+            rows_filtered: 2,
+            rows_superseded: 1,
+            rows_parsed: 13,
+            rows_merged: 2,
+            documents_built: 11,
+            ..Conservation::default()
+        };
+        conservation.add_tally(&tally);
+        assert_eq!(conservation.documents_acknowledged, 3);
+        assert_eq!((conservation.unaccounted_rows(), conservation.unaccounted_documents()), (0, 0));
+
+        // A final batch that never reached the workers shows up as a gap
+        conservation.documents_built += 5;
+        conservation.rows_read += 1;
+        assert_eq!((conservation.unaccounted_rows(), conservation.unaccounted_documents()), (1, 5));
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use tracing::info;
+
+use crate::collection_config::get_collection_config;
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+use crate::paths::ensure_parent_dir;
+
+/// Documents carrying collection-specific fields extracted from their metadata
+pub trait ExtractedFields {
+    fn collection_address(&self) -> Option<&str>;
+    /// None when the document model doesn't extract fields at all
+    fn extracted_fields(&self) -> Option<&Map<String, Value>>;
+}
+
+impl ExtractedFields for ElasticsearchDocument {
+    fn collection_address(&self) -> Option<&str> {
+        self.token_address.as_deref()
+    }
+
+    fn extracted_fields(&self) -> Option<&Map<String, Value>> {
+        None
+    }
+}
+
+impl ExtractedFields for FlexibleElasticsearchDocument {
+    fn collection_address(&self) -> Option<&str> {
+        self.token_address.as_deref()
+    }
+
+    fn extracted_fields(&self) -> Option<&Map<String, Value>> {
+        Some(&self.extracted_fields)
+    }
+}
+
+/// How many of a collection's documents had one extracted field populated
+#[derive(Debug, Serialize, PartialEq)]
+pub struct FieldCoverage {
+    pub field: String,
+    pub populated: u64,
+    pub percentage: f64,
+}
+
+/// Extracted field coverage of one configured collection
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CollectionCoverage {
+    pub token_address: String,
+    pub name: String,
+    pub documents: u64,
+    pub fields: Vec<FieldCoverage>,
+}
+
+#[derive(Debug)]
+struct CollectionCounts {
+    name: String,
+    fields: Vec<String>,
+    documents: u64,
+    populated: HashMap<String, u64>,
+}
+
+/// Counts, per configured collection, how often each extracted field was populated,
+/// so an exporter change that silently empties a trait shows up in the summary
+#[derive(Debug, Default)]
+pub struct CoverageTracker {
+    // lowercase token_address -> counts, None for collections without a config
+    collections: HashMap<String, Option<CollectionCounts>>,
+}
+
+impl CoverageTracker {
+    pub fn add<D: ExtractedFields>(&mut self, doc: &D) {
+        let (Some(address), Some(extracted)) = (doc.collection_address(), doc.extracted_fields()) else {
+            return;
+        };
+        let counts = self.collections.entry(address.to_lowercase()).or_insert_with(|| {
+            get_collection_config(address).map(|config| CollectionCounts {
+                name: config.name.clone(),
+                fields: config.extracted_fields.iter().map(|f| f.name.clone()).collect(),
+                documents: 0,
+                populated: HashMap::new(),
+            })
+        });
+        let Some(counts) = counts else {
+            return;
+        };
+
+        counts.documents += 1;
+        for field in &counts.fields {
+            let populated = match extracted.get(field) {
+                None | Some(Value::Null) => false,
+                Some(Value::String(s)) => !s.is_empty(),
+                Some(_) => true,
+            };
+            if populated {
+                *counts.populated.entry(field.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    /// Coverage of every configured collection seen, ordered by address
+    pub fn report(&self) -> Vec<CollectionCoverage> {
+        let sorted: BTreeMap<&String, &CollectionCounts> = self.collections.iter()
+            .filter_map(|(address, counts)| counts.as_ref().map(|c| (address, c)))
+            .collect();
+
+        sorted.into_iter()
+            .map(|(address, counts)| CollectionCoverage {
+                token_address: address.clone(),
+                name: counts.name.clone(),
+                documents: counts.documents,
+                fields: counts.fields.iter()
+                    .map(|field| {
+                        let populated = counts.populated.get(field).copied().unwrap_or(0);
+                        FieldCoverage {
+                            field: field.clone(),
+                            populated,
+                            percentage: populated as f64 * 100.0 / counts.documents.max(1) as f64,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+pub fn print_coverage(report: &[CollectionCoverage]) {
+    for collection in report {
+        let fields: Vec<String> = collection.fields.iter()
+            .map(|f| format!("{}: {:.1}%", f.field, f.percentage))
+            .collect();
+        info!("     {} ({}, {} documents): {}", collection.name, collection.token_address, collection.documents, fields.join(", "));
+    }
+}
+
+pub async fn write_coverage(path: &Path, report: &[CollectionCoverage]) -> Result<()> {
+    ensure_parent_dir(path).await?;
+    tokio::fs::write(path, serde_json::to_string_pretty(report)?).await
+        .with_context(|| format!("Failed to write field coverage report {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models_flexible::CsvRecord;
+
+    const WILDFOREST: &str = "0xa038c593115f6fcd673f6833e15462b475994879";
+
+    fn doc(address: &str, raw_metadata: &str) -> FlexibleElasticsearchDocument {
+        let config = get_collection_config(address);
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_address: Some(address.to_string()),
+            token_id: Some("1".to_string()),
+            raw_metadata: Some(raw_metadata.to_string()),
+            ..Default::default()
+        }, config.as_deref())
+    }
+
+    #[test]
+    fn test_coverage_per_field() {
+        let mut tracker = CoverageTracker::default();
+        tracker.add(&doc(WILDFOREST, r#"{"properties":{"tier":1,"rarity":"Common","type":"Archer"}}"#));
+        tracker.add(&doc(WILDFOREST, r#"{"properties":{"tier":2,"rarity":"","type":"Archer"}}"#));
+        tracker.add(&doc(WILDFOREST, r#"{"properties":{"tier":"x","level":3}}"#));
+        tracker.add(&doc("0xunknown", r#"{"properties":{"tier":1}}"#));
+
+        let report = tracker.report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].name, "Wildforest Units");
+        assert_eq!(report[0].documents, 3);
+
+        let coverage: HashMap<&str, u64> = report[0].fields.iter().map(|f| (f.field.as_str(), f.populated)).collect();
+        assert_eq!(coverage["tier"], 2);
+        assert_eq!(coverage["level"], 1);
+        assert_eq!(coverage["rarity"], 1);
+        assert_eq!(coverage["nft_type"], 2);
+        assert!((report[0].fields[0].percentage - 66.666).abs() < 0.01);
+    }
+}
@@ -0,0 +1,247 @@
+//! CSV_FILE naming many files: a directory (every `*.csv` in it, `*.jsonl` with
+//! INPUT_FORMAT=jsonl) or a pattern in the file name (`exports/export-*.csv`, with
+//! `*` and `?`). The files are migrated one after another in natural order, or
+//! CSV_FILE_CONCURRENCY at a time (files sharing document ids still one after
+//! another), each with its own checkpoint. A manifest next to them records which files are finished, so a
+//! rerun after a crash skips those and resumes the rest from their checkpoints.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use roaring::RoaringTreemap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::checkpoint::record_key;
+use crate::config::APP_CONFIG;
+use crate::download::is_url;
+use crate::paths::{ensure_parent_dir, state_file};
+use crate::remote::{is_remote, list_objects};
+
+fn has_wildcards(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Whether CSV_FILE names a set of files rather than one
+pub fn is_multi_file(csv_file: &str) -> bool {
+    if is_remote(csv_file) {
+        return csv_file.rsplit('/').next().is_some_and(|name| name.is_empty() || has_wildcards(name));
+    }
+    let path = Path::new(csv_file);
+    !is_url(csv_file) && (path.is_dir() || path.file_name().is_some_and(|name| has_wildcards(&name.to_string_lossy())))
+}
+
+/// Regex for a file name pattern: `*` is any run of characters, `?` any one
+fn name_regex(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).with_context(|| format!("Invalid CSV_FILE pattern '{}'", pattern))
+}
+
+/// Order of file names with their digit runs compared as numbers, so
+/// `export-2.csv` comes before `export-10.csv`
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else { return a.len().cmp(&b.len()) };
+        let ordering = match x.is_ascii_digit() && y.is_ascii_digit() {
+            true => {
+                let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let (x_end, y_end) = (digits(a), digits(b));
+                let (x_digits, y_digits) = (a[..x_end].trim_start_matches('0'), b[..y_end].trim_start_matches('0'));
+                let ordering = x_digits.len().cmp(&y_digits.len()).then_with(|| x_digits.cmp(y_digits));
+                (a, b) = (&a[x_end..], &b[y_end..]);
+                ordering
+            }
+            false => {
+                (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+                x.cmp(&y)
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// The files CSV_FILE names, sorted by path with numbers in natural order
+pub fn expand(csv_file: &str) -> Result<Vec<String>> {
+    let mut files = match is_remote(csv_file) {
+        true => expand_remote(csv_file)?,
+        false => expand_local(csv_file)?,
+    };
+    if files.is_empty() {
+        anyhow::bail!("No CSV files match {}", csv_file);
+    }
+    files.sort_by(|a, b| natural_cmp(a, b).then_with(|| a.cmp(b)));
+    Ok(files)
+}
+
+/// Objects under an object store "directory" (a URL ending in `/`) or matching a pattern
+fn expand_remote(csv_file: &str) -> Result<Vec<String>> {
+    let (dir, name) = csv_file.rsplit_once('/').context("CSV_FILE URL has no path")?;
+    if has_wildcards(dir) {
+        anyhow::bail!("CSV_FILE '{}': only the file name may contain * or ?", csv_file);
+    }
+    let pattern = match name.is_empty() {
+        true => format!("*.{}", APP_CONFIG.input_format.extension()),
+        false => name.to_string(),
+    };
+    let regex = name_regex(&pattern)?;
+    Ok(list_objects(&format!("{}/", dir))?.into_iter()
+        .filter(|url| url.rsplit('/').next().is_some_and(|name| regex.is_match(name)))
+        .collect())
+}
+
+fn expand_local(csv_file: &str) -> Result<Vec<String>> {
+    let path = Path::new(csv_file);
+    let (dir, pattern) = match path.is_dir() {
+        true => (path, format!("*.{}", APP_CONFIG.input_format.extension())),
+        false => (
+            path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")),
+            path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        ),
+    };
+    if path.parent().is_some_and(|parent| has_wildcards(&parent.to_string_lossy())) {
+        anyhow::bail!("CSV_FILE '{}': only the file name may contain * or ?", csv_file);
+    }
+    let regex = name_regex(&pattern)?;
+    // A bare pattern lists bare names, like the CSV_FILE of a single file would be
+    let in_dir = path.is_dir() || path.parent().is_some_and(|parent| !parent.as_os_str().is_empty());
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file() && regex.is_match(&name) {
+            files.push(match in_dir {
+                true => dir.join(&name).to_string_lossy().into_owned(),
+                false => name,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// Files sharing document ids, by position in `ids` (one id set per file), each
+/// group in file order: a group's files run one after another so a later file's
+/// rows of an id win, as in a serial run, while groups can run side by side
+pub fn id_sharing_groups(ids: &[RoaringTreemap]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<(RoaringTreemap, Vec<usize>)> = Vec::new();
+    for (file, file_ids) in ids.iter().enumerate() {
+        let (mut group_ids, mut files) = (file_ids.clone(), vec![file]);
+        let mut i = 0;
+        while i < groups.len() {
+            if groups[i].0.is_disjoint(&group_ids) {
+                i += 1;
+                continue;
+            }
+            let (other_ids, other_files) = groups.swap_remove(i);
+            group_ids |= other_ids;
+            files.extend(other_files);
+        }
+        files.sort_unstable();
+        groups.push((group_ids, files));
+    }
+    groups.sort_by_key(|(_, files)| files[0]);
+    groups.into_iter().map(|(_, files)| files).collect()
+}
+
+/// Where the manifest of a file set is kept (STATE_DIR, else next to the files)
+pub fn manifest_path(csv_file: &str) -> PathBuf {
+    let dir = match Path::new(csv_file).is_dir() {
+        true => Path::new(csv_file),
+        false => Path::new(csv_file).parent().unwrap_or(Path::new("")),
+    };
+    let name = format!("csv-files-{:08x}", record_key(csv_file) as u32);
+    state_file(&dir.join(name).to_string_lossy(), "manifest")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    Running,
+    /// Stopped early or failed; its checkpoint resumes it
+    Incomplete,
+    Completed,
+}
+
+/// The aggregate checkpoint of a file set: how far each file got
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub files: BTreeMap<String, FileState>,
+}
+
+impl FileManifest {
+    pub async fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path).await {
+            Ok(content) => serde_json::from_str(&content).with_context(|| format!("Invalid manifest {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read manifest {}", path.display())),
+        }
+    }
+
+    /// Replace the manifest atomically (write a temp file, then rename over it)
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut temp = path.as_os_str().to_os_string();
+        temp.push(".tmp");
+        ensure_parent_dir(path).await?;
+        fs::write(&temp, serde_json::to_string_pretty(self)?).await
+            .with_context(|| format!("Failed to write manifest {}", path.display()))?;
+        fs::rename(&temp, path).await
+            .with_context(|| format!("Failed to replace manifest {}", path.display()))
+    }
+
+    pub fn is_completed(&self, file: &str) -> bool {
+        self.files.get(file) == Some(&FileState::Completed)
+    }
+
+    /// Files of `files` that still have to be migrated
+    pub fn pending<'a>(&self, files: &'a [String]) -> Vec<&'a String> {
+        files.iter().filter(|file| !self.is_completed(file)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pattern_expansion_and_manifest() {
+        let dir = std::env::temp_dir().join(format!("csv-files-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["export-2.csv", "export-10.csv", "export-1.csv", "export-1.csv.checkpoint", "other.csv"] {
+            std::fs::write(dir.join(name), "token_address,token_id\n").unwrap();
+        }
+        let pattern = dir.join("export-*.csv").to_string_lossy().into_owned();
+        assert!(is_multi_file(&pattern) && is_multi_file(&dir.to_string_lossy()));
+        assert!(!is_multi_file("https://example.com/export-*.csv"));
+        let files = expand(&pattern).unwrap();
+        let names: Vec<_> = files.iter().map(|file| Path::new(file).file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["export-1.csv", "export-2.csv", "export-10.csv"]);
+        assert_eq!(expand(&dir.to_string_lossy()).unwrap().len(), 4);
+        assert!(expand(&dir.join("none-?.csv").to_string_lossy()).is_err());
+
+        let path = manifest_path(&pattern);
+        let mut manifest = FileManifest::load(&path).await.unwrap();
+        manifest.files.insert(files[0].clone(), FileState::Completed);
+        manifest.files.insert(files[1].clone(), FileState::Incomplete);
+        manifest.save(&path).await.unwrap();
+        let manifest = FileManifest::load(&path).await.unwrap();
+        assert_eq!(manifest.pending(&files), vec![&files[1], &files[2]]);
+        std::fs::remove_dir_all(&dir).ok();
+
+        let ids: Vec<RoaringTreemap> = [&[1, 2][..], &[3], &[2, 4], &[4, 5], &[6]].iter()
+            .map(|ids| ids.iter().copied().collect())
+            .collect();
+        assert_eq!(id_sharing_groups(&ids), vec![vec![0, 2, 3], vec![1], vec![4]]);
+    }
+}
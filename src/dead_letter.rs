@@ -0,0 +1,310 @@
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::warn;
+
+use crate::collection_config::CollectionIndexPattern;
+use crate::elasticsearch::{bulk_index_documents, bulk_pipeline, collection_pipeline, BulkDocument, BulkOperation, RetryPolicy, WriteMode};
+
+/// One line of the bulk error log (`BulkErrorLog`): a document the cluster
+/// rejected, with the reason
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeadLetter {
+    #[serde(rename = "_id")]
+    pub id: Option<String>,
+    pub index: Option<String>,
+    pub status: u16,
+    pub error_type: String,
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logged_at: Option<u64>,
+    /// Missing for failures whose document wasn't found in the batch; those can't be re-sent
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub document: Value,
+    /// How the document was sent; missing in logs written before it was recorded,
+    /// whose documents were all sent as token documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<BulkOperation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_document: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>,
+}
+
+impl DeadLetter {
+    fn resendable(&self) -> bool {
+        self.id.is_some() && !self.document.is_null()
+    }
+}
+
+/// A dead letter re-sent as the document it carries
+struct DeadLetterDocument<'a>(&'a DeadLetter);
+
+impl Serialize for DeadLetterDocument<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.0.document.serialize(serializer)
+    }
+}
+
+/// Sent as the kind of document it was logged as, with the routing and pipeline
+/// it was first sent with
+impl BulkDocument for DeadLetterDocument<'_> {
+    fn document_id(&self) -> Option<&str> {
+        self.0.id.as_deref()
+    }
+
+    fn pipeline(&self) -> Option<String> {
+        collection_pipeline(self.0.document["token_address"].as_str()?).or_else(bulk_pipeline)
+    }
+
+    fn is_deletion(&self) -> bool {
+        self.0.action == Some(BulkOperation::Delete)
+    }
+
+    fn is_token_document(&self) -> bool {
+        self.0.token_document.unwrap_or(true)
+    }
+
+    fn first_sent_with(&self) -> Option<(Option<&str>, Option<&str>)> {
+        self.0.token_document.map(|_| (self.0.routing.as_deref(), self.0.pipeline.as_deref()))
+    }
+}
+
+/// Read every dead letter in the bulk error log
+pub async fn read_dead_letters(path: &Path) -> Result<Vec<DeadLetter>> {
+    let content = tokio::fs::read_to_string(path).await
+        .with_context(|| format!("Failed to read bulk error log {}", path.display()))?;
+    content.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| serde_json::from_str(line)
+            .with_context(|| format!("{}:{}: invalid dead letter", path.display(), number + 1)))
+        .collect()
+}
+
+/// Outcome of `--retry-dlq`
+#[derive(Debug, Default)]
+pub struct DeadLetterRetry {
+    pub retried: usize,
+    /// Written, or deleted for tombstones
+    pub indexed: usize,
+    pub still_failing: usize,
+}
+
+/// How `--retry-dlq` sends documents
+pub struct ResendSettings<'a> {
+    pub batch_size: usize,
+    /// The run's WRITE_MODE, so a retry doesn't overwrite what an upsert run would keep
+    pub mode: WriteMode,
+    pub retry: &'a RetryPolicy,
+}
+
+/// Re-send the documents of the bulk error log, each to the index it was rejected
+/// by (or `default_index`). Documents without a token_address get the collection
+/// `collections` finds in that index's name. The file is rewritten with only the
+/// documents that failed again (and lines without a document), and removed when
+/// none are left.
+pub async fn retry_dead_letters(
+    client: &Client,
+    elasticsearch_url: &str,
+    path: &Path,
+    default_index: &str,
+    settings: &ResendSettings<'_>,
+    collections: &CollectionIndexPattern,
+) -> Result<DeadLetterRetry> {
+    let letters = read_dead_letters(path).await?;
+    let mut by_index: BTreeMap<String, Vec<DeadLetter>> = BTreeMap::new();
+    let (letters, mut remaining): (Vec<DeadLetter>, Vec<DeadLetter>) = letters.into_iter().partition(DeadLetter::resendable);
+    for mut letter in letters {
+        let index = letter.index.clone().unwrap_or_else(|| default_index.to_string());
+        collections.fill_token_address(&index, &mut letter.document);
+        by_index.entry(index).or_default().push(letter);
+    }
+
+    let mut report = DeadLetterRetry::default();
+    for (index, letters) in by_index {
+        for chunk in letters.chunks(settings.batch_size.max(1)) {
+            report.retried += chunk.len();
+            let documents: Vec<DeadLetterDocument> = chunk.iter().map(DeadLetterDocument).collect();
+            let outcome = match bulk_index_documents(client, elasticsearch_url, &index, &documents, false, settings.mode, settings.retry).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!("Retrying dead letters in {} failed: {:#}", index, e);
+                    remaining.extend_from_slice(chunk);
+                    continue;
+                }
+            };
+            report.indexed += outcome.indexed + outcome.existing + outcome.deleted + outcome.not_found;
+            // Keep the failed letters with the error of this attempt
+            for failure in &outcome.failures {
+                if let Some(letter) = chunk.iter().find(|letter| failure.id == letter.id) {
+                    remaining.push(DeadLetter {
+                        status: failure.status,
+                        error_type: failure.error.error_type.clone(),
+                        reason: failure.error.reason.clone(),
+                        ..letter.clone()
+                    });
+                }
+            }
+        }
+    }
+    report.still_failing = remaining.len();
+
+    if remaining.is_empty() {
+        tokio::fs::remove_file(path).await
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+    } else {
+        // Write next to the file and rename, so an interrupted retry keeps the old letters
+        let mut content = String::new();
+        for letter in &remaining {
+            content.push_str(&serde_json::to_string(letter)?);
+            content.push('\n');
+        }
+        let temp = path.with_extension("ndjson.tmp");
+        tokio::fs::write(&temp, content).await
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        tokio::fs::rename(&temp, path).await
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elasticsearch::{BulkItemError, BulkItemFailure};
+    use crate::error_log::BulkErrorLog;
+    use crate::test_server::{Reply, TestServer};
+    use serde_json::json;
+
+    #[derive(Serialize)]
+    struct Doc {
+        token_id: String,
+        price: String,
+    }
+
+    impl BulkDocument for Doc {
+        fn document_id(&self) -> Option<&str> {
+            Some(&self.token_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_documents_round_trip() {
+        let documents = vec![
+            Doc { token_id: "1".to_string(), price: "12".to_string() },
+            Doc { token_id: "2".to_string(), price: "n/a".to_string() },
+        ];
+        let failures = vec![BulkItemFailure {
+            id: Some("2".to_string()),
+            index: Some("nfts".to_string()),
+            status: 400,
+            error: BulkItemError {
+                error_type: "mapper_parsing_exception".to_string(),
+                reason: Some("failed to parse field [price]".to_string()),
+            },
+        }];
+        let path = std::env::temp_dir().join(format!("dead-letter-{}.ndjson", std::process::id()));
+        let log = BulkErrorLog::new(&path);
+        log.append(1, &documents, WriteMode::Index, &failures).await.unwrap();
+        log.append(2, &documents, WriteMode::Index, &failures).await.unwrap();
+
+        // The bulk error log is the dead-letter file
+        let read = read_dead_letters(&path).await.unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!((read[0].id.as_deref(), read[1].batch), (Some("2"), Some(2)));
+        assert_eq!(read[0].reason.as_deref(), Some("failed to parse field [price]"));
+        assert!(read[0].resendable());
+        // The retried body is the original document, not the envelope
+        assert_eq!(serde_json::to_value(DeadLetterDocument(&read[0])).unwrap(), json!({"token_id": "2", "price": "n/a"}));
+        // Side-index documents aren't routed or piped like token documents on retry
+        assert_eq!(read[0].token_document, Some(false));
+        assert!(!DeadLetterDocument(&read[0]).is_token_document());
+        assert_eq!(DeadLetterDocument(&read[0]).first_sent_with(), Some((None, None)));
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_delete_is_retried_as_delete() {
+        #[derive(Serialize)]
+        struct Tombstone {
+            token_id: &'static str,
+        }
+        impl BulkDocument for Tombstone {
+            const TOKEN_DOCUMENT: bool = true;
+            fn document_id(&self) -> Option<&str> {
+                Some("0xabc:3")
+            }
+            fn is_deletion(&self) -> bool {
+                true
+            }
+        }
+        let failures = vec![BulkItemFailure {
+            id: Some("0xabc:3".to_string()),
+            index: Some("nfts".to_string()),
+            status: 503,
+            error: BulkItemError { error_type: "unavailable_shards_exception".to_string(), reason: None },
+        }];
+        let path = std::env::temp_dir().join(format!("dead-letter-delete-{}.ndjson", std::process::id()));
+        BulkErrorLog::new(&path).append(1, &[Tombstone { token_id: "3" }], WriteMode::Upsert, &failures).await.unwrap();
+
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |_, _| Reply::Respond(200, json!({"errors": false, "items": [
+            {"delete": {"_id": "0xabc:3", "_index": "nfts", "status": 200}},
+        ]}).to_string())).await;
+        let retry = RetryPolicy { max_retries: 0, ..RetryPolicy::default() };
+        let settings = ResendSettings { batch_size: 10, mode: WriteMode::Upsert, retry: &retry };
+        let report = retry_dead_letters(&Client::new(), &server.url(), &path, "nfts", &settings, &CollectionIndexPattern::default()).await.unwrap();
+
+        // Deleted again instead of written back
+        let body = server.bodies.lock().unwrap().concat();
+        assert_eq!(body.lines().collect::<Vec<_>>(), vec![r#"{"delete":{"_id":"0xabc:3"}}"#]);
+        assert_eq!((report.retried, report.indexed, report.still_failing), (1, 1, 0));
+        assert!(!path.exists());
+    }
+
+    #[derive(Serialize)]
+    struct Token {
+        id: &'static str,
+    }
+
+    impl BulkDocument for Token {
+        const TOKEN_DOCUMENT: bool = true;
+
+        fn document_id(&self) -> Option<&str> {
+            Some(self.id)
+        }
+
+        fn pipeline(&self) -> Option<String> {
+            Some("land-geo".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_documents_keep_their_pipeline() {
+        let failures = vec![BulkItemFailure {
+            id: Some("land".to_string()),
+            index: Some("nfts".to_string()),
+            status: 400,
+            error: BulkItemError { error_type: "mapper_parsing_exception".to_string(), reason: None },
+        }];
+        let path = std::env::temp_dir().join(format!("dead-letter-token-{}.ndjson", std::process::id()));
+        BulkErrorLog::new(&path).append(1, &[Token { id: "land" }], WriteMode::Index, &failures).await.unwrap();
+
+        let read = read_dead_letters(&path).await.unwrap();
+        assert_eq!((read[0].token_document, read[0].pipeline.as_deref()), (Some(true), Some("land-geo")));
+        assert!(DeadLetterDocument(&read[0]).is_token_document());
+        assert_eq!(DeadLetterDocument(&read[0]).first_sent_with(), Some((None, Some("land-geo"))));
+
+        // Letters logged before the routing was recorded are re-sent as token documents
+        let legacy: DeadLetter = serde_json::from_value(json!({"_id": "1", "index": "nfts", "status": 400, "error_type": "x", "document": {}})).unwrap();
+        assert!(DeadLetterDocument(&legacy).is_token_document() && DeadLetterDocument(&legacy).first_sent_with().is_none());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Deprecation warnings Elasticsearch sends back in `Warning` headers (deprecated
+//! mapping parameters, settings, query syntax). Each distinct warning is logged
+//! once when first seen, and all of them are listed in the summary with how often
+//! they came, so they're fixed before the next major upgrade turns them into errors.
+//! Requests to the cluster go out through `send_noting_warnings`, and every command
+//! prints the list when it ends.
+
+use reqwest::header::{HeaderMap, WARNING};
+use reqwest::{RequestBuilder, Response};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tracing::warn;
+
+static WARNINGS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// The text of a `Warning` header: `299 Elasticsearch-<version> "<text>" ["<date>"]`
+fn warning_text(header: &str) -> Option<String> {
+    let quoted = &header[header.find('"')? + 1..];
+    let mut text = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            '"' => return Some(text),
+            c => text.push(c),
+        }
+    }
+    None
+}
+
+/// Note the warnings of a response's headers
+fn note_warnings(headers: &HeaderMap) {
+    let texts: Vec<String> = headers.get_all(WARNING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(warning_text)
+        .collect();
+    if texts.is_empty() {
+        return;
+    }
+    let mut warnings = WARNINGS.lock().unwrap();
+    for text in texts {
+        let count = warnings.entry(text.clone()).or_default();
+        if *count == 0 {
+            warn!("⚠️  Elasticsearch deprecation warning: {}", text);
+        }
+        *count += 1;
+    }
+}
+
+/// Note the warnings of a response, passing it on
+pub fn noting_warnings(response: Response) -> Response {
+    note_warnings(response.headers());
+    response
+}
+
+/// Sending a request to Elasticsearch, noting the warnings of its response
+pub trait SendNotingWarnings {
+    fn send_noting_warnings(self) -> impl Future<Output = reqwest::Result<Response>> + Send;
+}
+
+impl SendNotingWarnings for RequestBuilder {
+    fn send_noting_warnings(self) -> impl Future<Output = reqwest::Result<Response>> + Send {
+        let sent = self.send();
+        async move { sent.await.map(noting_warnings) }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DeprecationWarning {
+    pub warning: String,
+    /// Responses that carried it
+    pub count: u64,
+}
+
+/// Every distinct warning seen so far
+pub fn deprecation_warnings() -> Vec<DeprecationWarning> {
+    WARNINGS.lock().unwrap().iter()
+        .map(|(text, count)| DeprecationWarning { warning: text.clone(), count: *count })
+        .collect()
+}
+
+/// List every warning seen, at the end of a command
+pub fn print_deprecation_warnings() {
+    let deprecations = deprecation_warnings();
+    if !deprecations.is_empty() {
+        warn!("⚠️  Elasticsearch sent {} deprecation warnings; fix them before the next major upgrade:", deprecations.len());
+        for deprecation in &deprecations {
+            warn!("     {} ({} responses)", deprecation.warning, deprecation.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_warnings_parsed_and_deduplicated() {
+        assert_eq!(warning_text(r#"299 Elasticsearch-8.11.0-abc "[types removal] \"_doc\" is deprecated" "Mon, 01 Jan 2024 00:00:00 GMT""#).as_deref(),
+                   Some(r#"[types removal] "_doc" is deprecated"#));
+        assert_eq!(warning_text("299 Elasticsearch-8.11.0 unquoted"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.append(WARNING, HeaderValue::from_static(r#"299 Elasticsearch-8.11.0 "test: [index.soft_deletes] is deprecated""#));
+        headers.append(WARNING, HeaderValue::from_static(r#"299 Elasticsearch-8.11.0 "test: [flush] is deprecated""#));
+        note_warnings(&headers);
+        note_warnings(&headers);
+        let warnings: Vec<_> = deprecation_warnings().into_iter().filter(|seen| seen.warning.starts_with("test: ")).collect();
+        assert_eq!(warnings, vec![
+            DeprecationWarning { warning: "test: [flush] is deprecated".to_string(), count: 2 },
+            DeprecationWarning { warning: "test: [index.soft_deletes] is deprecated".to_string(), count: 2 },
+        ]);
+    }
+}
@@ -0,0 +1,302 @@
+//! CSV_FILE given as an http(s) URL: the export is downloaded to a local copy
+//! first, resuming an interrupted download with a Range request instead of starting
+//! over, and checked against CSV_SHA256 when set. A complete copy is revalidated
+//! with a HEAD request and downloaded again if the export changed. Checkpoints and
+//! the other state files then belong to the local copy.
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::spool::Spool;
+
+/// Tries per download; each one continues where the previous stopped
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+pub fn is_url(csv_file: &str) -> bool {
+    csv_file.starts_with("http://") || csv_file.starts_with("https://")
+}
+
+/// Where the CSV at `url` is downloaded to: its file name, in `dir`
+pub fn local_path(url: &str, dir: &Path) -> Result<PathBuf> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid CSV_FILE URL '{}'", url))?;
+    let name = parsed.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .with_context(|| format!("CSV_FILE URL '{}' has no file name", url))?;
+    Ok(dir.join(name))
+}
+
+/// Sidecar of a local copy, so a later run knows what it holds and how far it got
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct DownloadState {
+    url: String,
+    /// ETag or Last-Modified of the response, sent as If-Range when resuming so a
+    /// changed export is downloaded again rather than spliced onto the old one
+    validator: Option<String>,
+    complete: bool,
+}
+
+fn state_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".download");
+    PathBuf::from(name)
+}
+
+async fn read_state(path: &Path) -> Option<DownloadState> {
+    let content = tokio::fs::read(state_path(path)).await.ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+async fn write_state(path: &Path, state: &DownloadState) -> Result<()> {
+    tokio::fs::write(state_path(path), serde_json::to_vec(state)?).await
+        .with_context(|| format!("Failed to write {}", state_path(path).display()))
+}
+
+/// Remove a downloaded copy and its sidecar
+pub async fn remove_download(path: &Path) -> Result<()> {
+    tokio::fs::remove_file(path).await.with_context(|| format!("Failed to remove {}", path.display()))?;
+    tokio::fs::remove_file(state_path(path)).await.ok();
+    Ok(())
+}
+
+/// ETag, or else Last-Modified, of a response
+fn validator_of(headers: &HeaderMap) -> Option<String> {
+    headers.get(ETAG).or_else(|| headers.get(LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// First byte of a `Content-Range: bytes <start>-<end>/<size>` header
+fn range_start(content_range: &str) -> Option<u64> {
+    content_range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+/// Whether the export at `url` is still the one a complete copy was downloaded
+/// from; a server that can't tell (no validator, failed HEAD) counts as changed
+async fn unchanged(client: &Client, url: &str, validator: Option<&str>) -> bool {
+    let current = match client.head(url).send().await {
+        Ok(response) if response.status().is_success() => validator_of(response.headers()),
+        Ok(response) => {
+            warn!("⚠️  Can't revalidate the downloaded CSV (HTTP {})", response.status());
+            None
+        }
+        Err(e) => {
+            warn!("⚠️  Can't revalidate the downloaded CSV: {}", e);
+            None
+        }
+    };
+    current.is_some() && current.as_deref() == validator
+}
+
+/// Download `url` to `path`, continuing a partial copy from an earlier run. An
+/// already complete copy is kept while the server reports the same ETag or
+/// Last-Modified. With `sha256`, the finished file must match it.
+/// Inside a spool, the rest of the file must fit in it before any of it is written.
+pub async fn download(client: &Client, url: &str, path: &Path, sha256: Option<&str>, spool: Option<&Spool>) -> Result<()> {
+    let state = read_state(path).await;
+    if let Some(state) = state.as_ref().filter(|state| state.url != url) {
+        anyhow::bail!("{} already holds a download of {}; use another CSV_DOWNLOAD_DIR", path.display(), state.url);
+    }
+    let reuse = match &state {
+        Some(DownloadState { complete: true, validator, .. }) if path.exists() => {
+            let unchanged = unchanged(client, url, validator.as_deref()).await;
+            if !unchanged {
+                info!("🔄 {} may have changed since it was downloaded, downloading it again", url);
+            }
+            unchanged
+        }
+        _ => false,
+    };
+    match reuse {
+        true => info!("✓ Using downloaded CSV {}", path.display()),
+        false => {
+            if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // Only a partial copy is continued; a stale complete one starts over
+            let mut validator = state.filter(|state| !state.complete).and_then(|state| state.validator);
+            let mut attempt = 0;
+            loop {
+                match fetch(client, url, path, &mut validator, spool).await {
+                    Ok(()) => break,
+                    Err(e) if attempt + 1 < DOWNLOAD_ATTEMPTS => {
+                        attempt += 1;
+                        let delay = Duration::from_millis(500 << attempt);
+                        warn!("Download of {} interrupted ({:#}), resuming in {:.1}s", url, e, delay.as_secs_f64());
+                        tokio::time::sleep(delay).await;
+                    }
+                    Err(e) => return Err(e.context(format!("Failed to download {}", url))),
+                }
+            }
+            write_state(path, &DownloadState { url: url.to_string(), validator, complete: true }).await?;
+        }
+    }
+
+    if let Some(expected) = sha256 {
+        let actual = file_sha256(path).await?;
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            // A corrupt copy must not be reused by the next run
+            tokio::fs::remove_file(state_path(path)).await.ok();
+            anyhow::bail!("{} has sha256 {}, expected {} (CSV_SHA256)", path.display(), actual, expected);
+        }
+        info!("✓ CSV checksum verified");
+    }
+    Ok(())
+}
+
+/// One request for the rest of the file
+async fn fetch(client: &Client, url: &str, path: &Path, validator: &mut Option<String>, spool: Option<&Spool>) -> Result<()> {
+    let have = match validator {
+        Some(_) => tokio::fs::metadata(path).await.map(|metadata| metadata.len()).unwrap_or(0),
+        // Without a validator a partial copy can't be told apart from another version
+        None => 0,
+    };
+    let mut request = client.get(url);
+    if let (true, Some(validator)) = (have > 0, validator.as_ref()) {
+        request = request.header(RANGE, format!("bytes={}-", have)).header(IF_RANGE, validator);
+    }
+    let mut response = request.send().await?;
+
+    let append = match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let start = response.headers().get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(range_start);
+            if start != Some(have) {
+                // Not the continuation of the partial copy; the next try starts over
+                *validator = None;
+                anyhow::bail!("Asked for bytes {}- but got Content-Range starting at {:?}", have, start);
+            }
+            true
+        }
+        // The partial copy was already the whole file
+        StatusCode::RANGE_NOT_SATISFIABLE if have > 0 => return Ok(()),
+        status if status.is_success() => false,
+        status => anyhow::bail!("HTTP {}", status),
+    };
+    if append {
+        info!("🔄 Resuming download of {} at {} bytes", url, have);
+    } else {
+        *validator = validator_of(response.headers());
+        info!("⬇️  Downloading {} to {}", url, path.display());
+    }
+    if let Some(spool) = spool.filter(|spool| spool.contains(path)) {
+        // A full copy replaces what is there, so only the growth counts
+        let existing = match append {
+            true => 0,
+            false => tokio::fs::metadata(path).await.map(|metadata| metadata.len()).unwrap_or(0),
+        };
+        spool.preflight(response.content_length().unwrap_or(0).saturating_sub(existing))?;
+    }
+    write_state(path, &DownloadState { url: url.to_string(), validator: validator.clone(), complete: false }).await?;
+
+    let mut file = tokio::fs::OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(path).await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut received = 0u64;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
+    }
+    file.flush().await?;
+    info!("✓ Downloaded {} bytes of {}", received, url);
+    Ok(())
+}
+
+async fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await.with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const CSV: &str = "token_address,token_id\n0xabc,1\n0xabc,2\n0xabc,3\n";
+
+    const CHANGED_CSV: &str = "token_address,token_id\n0xabc,4\n";
+
+    /// Serves CSV, cutting the first response off halfway; Range requests get the
+    /// rest. From the fourth request on the export is CHANGED_CSV.
+    async fn flaky_server() -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/exports/nfts.csv", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for number in 0..6 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 4096];
+                let read = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..read]).to_lowercase();
+                let etag = if number < 3 { "v1" } else { "v2" };
+                let response = match number {
+                    0 => format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\netag: \"v1\"\r\n\r\n{}", CSV.len(), &CSV[..20]),
+                    1 => format!("HTTP/1.1 206 Partial Content\r\ncontent-length: {}\r\ncontent-range: bytes 20-{}/{}\r\n\r\n{}",
+                                 CSV.len() - 20, CSV.len() - 1, CSV.len(), &CSV[20..]),
+                    _ if request.starts_with("head ") => format!("HTTP/1.1 200 OK\r\ncontent-length: 0\r\netag: \"{}\"\r\n\r\n", etag),
+                    _ => format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\netag: \"v2\"\r\n\r\n{}", CHANGED_CSV.len(), CHANGED_CSV),
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.ok();
+                requests.push(request);
+            }
+            requests
+        });
+        (url, task)
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_download_resumes_with_range() {
+        let dir = std::env::temp_dir().join(format!("download-test-{}", std::process::id()));
+        let (url, server) = flaky_server().await;
+        let path = local_path(&url, &dir).unwrap();
+        assert_eq!(path, dir.join("nfts.csv"));
+
+        let checksum = hex_sha256(CSV);
+        download(&Client::new(), &url, &path, Some(&checksum), None).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CSV);
+
+        // A complete copy is reused while its ETag holds, but still has to match the checksum
+        download(&Client::new(), &url, &path, None, None).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CSV);
+        assert!(download(&Client::new(), "http://elsewhere/nfts.csv", &path, None, None).await.is_err());
+        // ... and downloaded again once the export changed
+        download(&Client::new(), &url, &path, None, None).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CHANGED_CSV);
+        assert!(download(&Client::new(), &url, &path, Some(&hex_sha256("other")), None).await.is_err());
+        assert!(read_state(&path).await.is_none());
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("\r\nrange: bytes=20-\r\n"));
+        assert!(requests[1].contains("\r\nif-range: \"v1\"\r\n"));
+        assert!(requests[2].starts_with("head ") && requests[3].starts_with("head ") && requests[4].starts_with("get "));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start("bytes 20-45/46"), Some(20));
+        assert_eq!(range_start("bytes 0-45/*"), Some(0));
+        assert_eq!(range_start("bytes */46"), None);
+    }
+
+    fn hex_sha256(content: &str) -> String {
+        Sha256::digest(content.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
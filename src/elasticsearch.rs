@@ -1,73 +1,1513 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
 
-use crate::models::{BulkIndexAction, BulkIndexMetadata, ElasticsearchDocument};
+use crate::alias::write_index_entry;
+use crate::collection_config::get_collection_config;
+use crate::deprecations::SendNotingWarnings;
+use crate::metrics;
+use crate::models::{BulkAction, BulkIndexMetadata, ElasticsearchDocument};
+use crate::models_flexible::FlexibleElasticsearchDocument;
+use crate::resources::record_bytes_sent;
 
-pub async fn bulk_index_documents(
+/// A document that can be written with a bulk index action
+pub trait BulkDocument: Serialize {
+    /// `_id` of the document; documents without one are skipped
+    fn document_id(&self) -> Option<&str>;
+
+    /// Whether the document belongs in the quarantine index instead of the target
+    fn needs_quarantine(&self) -> bool {
+        false
+    }
+
+    /// Whether these are token documents: they go through ELASTICSEARCH_PIPELINE
+    /// and are routed by ROUTING, the side indices' documents aren't
+    const TOKEN_DOCUMENT: bool = false;
+
+    /// Pipeline for this document instead of ELASTICSEARCH_PIPELINE (its collection's)
+    fn pipeline(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the bulk request deletes the document instead of writing it
+    fn is_deletion(&self) -> bool {
+        false
+    }
+
+    /// Whether this document is a token document, for types that carry both kinds
+    /// (documents re-sent from the bulk error log)
+    fn is_token_document(&self) -> bool {
+        Self::TOKEN_DOCUMENT
+    }
+
+    /// Routing value and pipeline the document was first sent with, which replace
+    /// ROUTING and the pipelines when it's re-sent from the bulk error log
+    fn first_sent_with(&self) -> Option<(Option<&str>, Option<&str>)> {
+        None
+    }
+}
+
+/// So a subset of a batch can be re-sent without cloning its documents
+impl<D: BulkDocument> BulkDocument for &D {
+    const TOKEN_DOCUMENT: bool = D::TOKEN_DOCUMENT;
+
+    fn document_id(&self) -> Option<&str> {
+        (*self).document_id()
+    }
+
+    fn needs_quarantine(&self) -> bool {
+        (*self).needs_quarantine()
+    }
+
+    fn pipeline(&self) -> Option<String> {
+        (*self).pipeline()
+    }
+
+    fn is_deletion(&self) -> bool {
+        (*self).is_deletion()
+    }
+
+    fn is_token_document(&self) -> bool {
+        (*self).is_token_document()
+    }
+
+    fn first_sent_with(&self) -> Option<(Option<&str>, Option<&str>)> {
+        (*self).first_sent_with()
+    }
+}
+
+impl BulkDocument for ElasticsearchDocument {
+    const TOKEN_DOCUMENT: bool = true;
+
+    fn document_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
+impl BulkDocument for FlexibleElasticsearchDocument {
+    const TOKEN_DOCUMENT: bool = true;
+
+    fn document_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn needs_quarantine(&self) -> bool {
+        !self.extraction_errors.is_empty()
+    }
+
+    fn pipeline(&self) -> Option<String> {
+        collection_pipeline(self.token_address.as_deref()?)
+    }
+
+    fn is_deletion(&self) -> bool {
+        self.deleted
+    }
+}
+
+/// The ingest pipeline the collection at `token_address` configures, if any
+pub fn collection_pipeline(token_address: &str) -> Option<String> {
+    get_collection_config(token_address)?.pipeline.clone()
+}
+
+static ID_STRATEGY: OnceLock<IdStrategy> = OnceLock::new();
+
+/// How the `_id` of a token document is built: a template with `{token_address}`
+/// (lowercase) and `{token_id}` placeholders (DOCUMENT_ID_TEMPLATE)
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdStrategy {
+    template: String,
+}
+
+impl IdStrategy {
+    /// Token ids are only unique within a collection
+    pub const DEFAULT: &'static str = "{token_address}:{token_id}";
+
+    pub fn new(template: &str) -> Result<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').map(|end| start + end)
+                .with_context(|| format!("Unclosed placeholder in document id template '{}'", template))?;
+            let placeholder = &rest[start + 1..end];
+            if placeholder != "token_address" && placeholder != "token_id" {
+                anyhow::bail!("Unknown placeholder {{{}}} in document id template '{}'", placeholder, template);
+            }
+            rest = &rest[end + 1..];
+        }
+        if !template.contains("{token_id}") {
+            anyhow::bail!("Document id template '{}' must contain {{token_id}}", template);
+        }
+        Ok(Self { template: template.to_string() })
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// None when the token id, or a token address the template needs, is missing
+    pub fn document_id(&self, token_address: Option<&str>, token_id: Option<&str>) -> Option<String> {
+        let token_id = token_id.map(str::trim).filter(|id| !id.is_empty())?;
+        let mut id = self.template.replace("{token_id}", token_id);
+        if self.template.contains("{token_address}") {
+            let token_address = token_address.map(str::trim).filter(|address| !address.is_empty())?;
+            id = id.replace("{token_address}", &token_address.to_lowercase());
+        }
+        Some(id)
+    }
+}
+
+impl Default for IdStrategy {
+    fn default() -> Self {
+        Self { template: Self::DEFAULT.to_string() }
+    }
+}
+
+/// Shard routing of token documents (ROUTING)
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Routing {
+    /// By `_id`, Elasticsearch's default
+    #[default]
+    None,
+    /// By collection (lowercase), so each collection's documents share a shard
+    TokenAddress,
+    /// By another top-level document field (`field:<name>`)
+    Field(String),
+}
+
+/// Fields that change over a token's life: routing by one would send a later
+/// version of a document to another shard, leaving the old copy behind
+const MUTABLE_FIELDS: &[&str] = &[
+    "owner", "maker", "matcher", "order_id", "order_status", "state", "kind", "price", "base_price",
+    "ended_price", "ron_price", "payment_token", "started_at", "ended_at", "expired_at", "is_shown",
+    "ownership_block_number", "ownership_log_index", "metadata_last_updated",
+];
+
+/// A token's current listing: a row without one means it isn't listed, so upserts
+/// send these as null instead of leaving the stored listing in place
+const LISTING_FIELDS: &[&str] = &[
+    "base_price", "ended_at", "ended_price", "expired_at", "kind", "maker", "matcher", "order_id",
+    "payment_token", "price", "ron_price", "started_at", "state", "order_status",
+];
+
+/// The listing's payment token as PAYMENT_TOKENS annotates it; without the registry
+/// the run doesn't write them, so upserts leave them alone
+const PAYMENT_TOKEN_FIELDS: &[&str] = &["payment_token_symbol", "payment_token_known"];
+
+impl Routing {
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.trim() {
+            "" | "none" => Ok(Self::None),
+            "token_address" => Ok(Self::TokenAddress),
+            spec => match spec.strip_prefix("field:").map(str::trim) {
+                Some(field) if MUTABLE_FIELDS.contains(&field) => anyhow::bail!(
+                    "ROUTING can't use {}: it changes over a token's life, and a changed value routes the same _id \
+                     to another shard, leaving a duplicate behind", field),
+                Some(field) if !field.is_empty() => Ok(Self::Field(field.to_string())),
+                _ => anyhow::bail!("ROUTING must be none, token_address or field:<name>, not '{}'", spec),
+            },
+        }
+    }
+
+    /// Routing value of a document; None routes it by `_id`
+    fn value(&self, document: &Value) -> Option<String> {
+        let value = match self {
+            Self::None => return None,
+            Self::TokenAddress => document["token_address"].as_str().map(str::to_lowercase),
+            Self::Field(field) => match &document[field.as_str()] {
+                Value::String(value) => Some(value.clone()),
+                Value::Number(value) => Some(value.to_string()),
+                _ => None,
+            },
+        };
+        value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+    }
+}
+
+static ROUTING: OnceLock<Routing> = OnceLock::new();
+
+/// Route all token documents by `routing`; only the first call takes effect
+pub fn install_routing(routing: Routing) {
+    ROUTING.set(routing).ok();
+}
+
+/// Use this strategy for all token documents; only the first call takes effect
+pub fn install_id_strategy(strategy: IdStrategy) {
+    ID_STRATEGY.set(strategy).ok();
+}
+
+/// `_id` of a token document under the installed strategy
+pub fn token_document_id(token_address: Option<&str>, token_id: Option<&str>) -> Option<String> {
+    ID_STRATEGY.get_or_init(IdStrategy::default).document_id(token_address, token_id)
+}
+
+/// Create an index with the given settings/mappings body unless it already exists.
+/// Returns whether the index was created.
+pub async fn create_index_if_missing(
     client: &Client,
     elasticsearch_url: &str,
     index_name: &str,
-    documents: Vec<ElasticsearchDocument>,
-) -> Result<usize> {
-    if documents.is_empty() {
-        return Ok(0);
+    body: &Value,
+) -> Result<bool> {
+    let url = format!("{}/{}", elasticsearch_url, index_name);
+    let exists = client.head(&url).send_noting_warnings().await.context("Failed to check index")?;
+    if exists.status().is_success() {
+        return Ok(false);
+    }
+
+    let response = client.put(&url).json(body).send_noting_warnings().await.context("Failed to create index")?;
+    if response.status().is_success() {
+        Ok(true)
+    } else {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_default();
+        Err(anyhow::anyhow!("Failed to create index {}: HTTP {} - {}", index_name, status, error_text))
+    }
+}
+
+/// The `mappings` of an index, None when it doesn't exist
+pub async fn fetch_index_mapping(client: &Client, elasticsearch_url: &str, index_name: &str) -> Result<Option<Value>> {
+    let url = format!("{}/{}/_mapping", elasticsearch_url, index_name);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to fetch index mapping")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to fetch mapping of {}: HTTP {}", index_name, response.status());
+    }
+    let mapping: Value = response.json().await.context("Failed to parse index mapping")?;
+    // Keyed by concrete index; an alias may have several
+    Ok(write_index_entry(&mapping)
+        .map(|index| index["mappings"].clone()))
+}
+
+/// Health an index must reach after creation before documents are sent to it
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexHealthWait {
+    /// Don't wait
+    Off,
+    /// Primary shards allocated (enough for single-node clusters)
+    #[default]
+    Yellow,
+    /// Replicas allocated too
+    Green,
+}
+
+impl IndexHealthWait {
+    fn as_status(self) -> Option<&'static str> {
+        match self {
+            Self::Off => None,
+            Self::Yellow => Some("yellow"),
+            Self::Green => Some("green"),
+        }
+    }
+}
+
+/// Block until a freshly created index reaches the wanted health, so the
+/// first bulk request doesn't race shard allocation
+pub async fn wait_for_index_health(
+    client: &Client,
+    elasticsearch_url: &str,
+    index_name: &str,
+    wait: IndexHealthWait,
+    timeout: Duration,
+) -> Result<()> {
+    let Some(status) = wait.as_status() else {
+        return Ok(());
+    };
+    let url = format!("{}/_cluster/health/{}", elasticsearch_url, index_name);
+    let response = client.get(&url)
+        .query(&[("wait_for_status", status), ("timeout", &format!("{}s", timeout.as_secs()))])
+        // The request itself must outlive the server-side wait
+        .timeout(timeout + Duration::from_secs(10))
+        .send_noting_warnings()
+        .await
+        .with_context(|| format!("Failed to wait for {} health of {}", status, index_name))?;
+
+    // A timed out wait comes back as 408 with the current health in the body
+    let http_status = response.status();
+    let health: Value = response.json().await.unwrap_or_default();
+    if health["timed_out"].as_bool() == Some(false) {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "Index {} did not reach {} health within {}s (HTTP {}, status {})",
+        index_name, status, timeout.as_secs(), http_status, health["status"].as_str().unwrap_or("unknown")
+    )
+}
+
+/// Index that receives documents whose required collection fields failed extraction
+pub fn quarantine_index_name(index_name: &str) -> String {
+    format!("{}_quarantine", index_name)
+}
+
+/// Upper bound for a single Retry-After wait, so a misbehaving proxy can't stall a worker
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+static THROTTLED_MILLIS: AtomicU64 = AtomicU64::new(0);
+static REISSUED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static DELETED_DOCUMENTS: AtomicU64 = AtomicU64::new(0);
+static DELETES_NOT_FOUND: AtomicU64 = AtomicU64::new(0);
+static BULK_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static BULK_LATENCIES: Mutex<LatencyWindow> = Mutex::new(LatencyWindow::new());
+static BODY_DIAGNOSTICS: OnceLock<BodyDiagnostics> = OnceLock::new();
+
+/// Evidence for bodies corrupted on the way to the cluster (e.g. by a proxy): the
+/// length and SHA-256 of every bulk body, also sent as `X-Body-SHA256`, and copies
+/// of large bodies to compare against what arrived
+#[derive(Debug, Clone, Default)]
+pub struct BodyDiagnostics {
+    pub log_checksums: bool,
+    /// Write bodies of at least `dump_min_bytes` here
+    pub dump_dir: Option<PathBuf>,
+    pub dump_min_bytes: usize,
+}
+
+/// Turn on body diagnostics for all bulk requests; only the first call takes effect
+pub fn install_body_diagnostics(diagnostics: BodyDiagnostics) {
+    BODY_DIAGNOSTICS.set(diagnostics).ok();
+}
+
+/// Hex SHA-256 of a request body, as `sha256sum` prints it
+fn body_checksum(body: &str) -> String {
+    Sha256::digest(body.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Log a bulk body's checksum and dump it if it's large; returns the checksum
+async fn inspect_body(diagnostics: &BodyDiagnostics, index_name: &str, body: &str) -> String {
+    let checksum = body_checksum(body);
+    info!("🔎 Bulk body for {}: {} bytes, sha256 {}", index_name, body.len(), checksum);
+    if let Some(dir) = diagnostics.dump_dir.as_ref().filter(|_| body.len() >= diagnostics.dump_min_bytes) {
+        let path = dir.join(format!("bulk-{}.ndjson", checksum));
+        let written = async {
+            tokio::fs::create_dir_all(dir).await?;
+            tokio::fs::write(&path, body).await
+        };
+        match written.await {
+            Ok(()) => info!("🔎 Dumped bulk body to {}", path.display()),
+            Err(e) => warn!("Failed to dump bulk body to {}: {}", path.display(), e),
+        }
+    }
+    checksum
+}
+
+/// Bulk bodies of at least this many bytes are sent gzip-compressed; None sends all as is
+static BULK_GZIP_MIN_BYTES: OnceLock<Option<usize>> = OnceLock::new();
+
+/// Compress large bulk bodies for all bulk requests; only the first call takes effect
+pub fn install_bulk_compression(min_bytes: Option<usize>) {
+    BULK_GZIP_MIN_BYTES.set(min_bytes).ok();
+}
+
+/// Whether token documents carry the PAYMENT_TOKENS annotations
+static PAYMENT_TOKEN_ANNOTATIONS: OnceLock<bool> = OnceLock::new();
+
+/// Upsert the payment token annotations along with the listing, as null when a
+/// document has none; only the first call takes effect
+pub fn install_payment_token_annotations(annotated: bool) {
+    PAYMENT_TOKEN_ANNOTATIONS.set(annotated).ok();
+}
+
+/// Ingest pipeline of token document bulk requests (ELASTICSEARCH_PIPELINE)
+static BULK_PIPELINE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Send token documents through `pipeline`; only the first call takes effect
+pub fn install_bulk_pipeline(pipeline: Option<String>) {
+    BULK_PIPELINE.set(pipeline).ok();
+}
+
+/// Routing value and pipeline (other than the request's) of `doc`'s bulk action:
+/// ROUTING's and its collection's for a token document, or those it was first sent
+/// with. `source` is the serialized document, only needed under `routing`.
+fn routing_and_pipeline<D: BulkDocument>(doc: &D, routing: &Routing, source: Option<&Value>) -> (Option<String>, Option<String>) {
+    if let Some((routing, pipeline)) = doc.first_sent_with() {
+        return (routing.map(str::to_string), pipeline.map(str::to_string));
+    }
+    if !doc.is_token_document() {
+        return (None, None);
+    }
+    (source.and_then(|source| routing.value(source)), doc.pipeline())
+}
+
+/// Routing value and ingest pipeline `doc` is sent with, for the bulk error log:
+/// a token document without a pipeline of its own goes through ELASTICSEARCH_PIPELINE
+pub fn sent_with<D: BulkDocument>(doc: &D, source: &Value) -> (Option<String>, Option<String>) {
+    let (routing, pipeline) = routing_and_pipeline(doc, ROUTING.get().unwrap_or(&Routing::None), Some(source));
+    let request_pipeline = (doc.first_sent_with().is_none() && doc.is_token_document()).then(bulk_pipeline).flatten();
+    (routing, pipeline.or(request_pipeline))
+}
+
+/// ELASTICSEARCH_PIPELINE, as installed
+pub fn bulk_pipeline() -> Option<String> {
+    BULK_PIPELINE.get().cloned().flatten()
+}
+
+/// `_bulk` URL of `index_name`, with `?pipeline=` for documents that go through it.
+/// Updates (WRITE_MODE=upsert) don't run ingest pipelines, so they get none.
+fn bulk_url<D: BulkDocument>(elasticsearch_url: &str, index_name: &str, mode: WriteMode, pipeline: Option<&str>) -> String {
+    match pipeline.filter(|_| D::TOKEN_DOCUMENT && mode != WriteMode::Upsert) {
+        Some(pipeline) => {
+            let query = url::form_urlencoded::Serializer::new(String::new()).append_pair("pipeline", pipeline).finish();
+            format!("{}/{}/_bulk?{}", elasticsearch_url, index_name, query)
+        }
+        None => format!("{}/{}/_bulk", elasticsearch_url, index_name),
+    }
+}
+
+/// The body as sent, gzipped when it's large enough, and whether it was
+fn encode_body(body: String, gzip_min_bytes: Option<usize>) -> (Bytes, bool) {
+    if gzip_min_bytes.is_some_and(|min_bytes| body.len() >= min_bytes) {
+        let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+        // Writing to a Vec can't fail
+        if encoder.write_all(body.as_bytes()).is_ok() {
+            if let Ok(compressed) = encoder.finish() {
+                return (Bytes::from(compressed), true);
+            }
+        }
+    }
+    (Bytes::from(body), false)
+}
+
+/// The cluster couldn't be reached, or had no node to serve the request, for all
+/// retries of a bulk request, as during a rolling restart. The batch can be sent
+/// again once the cluster is back.
+#[derive(Debug)]
+pub struct ClusterUnavailable(pub String);
+
+impl std::fmt::Display for ClusterUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Elasticsearch unavailable: {}", self.0)
+    }
+}
+
+impl std::error::Error for ClusterUnavailable {}
+
+/// The cluster refused a bulk body of this many bytes as too large (HTTP 413)
+#[derive(Debug)]
+pub struct PayloadTooLarge(pub usize);
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bulk body of {} bytes too large for the cluster (HTTP 413); lower MAX_BULK_BYTES", self.0)
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+/// The process-wide bulk counters at one moment. A file's own counts are the
+/// difference between a snapshot taken as it starts and one taken as it ends.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BulkCounters {
+    /// Documents removed by tombstone rows (bulk delete actions)
+    pub deleted_documents: u64,
+    /// Tombstones whose document wasn't found (HTTP 404 on the delete)
+    pub deletes_not_found: u64,
+    /// Bulk requests cancelled and sent again because they stalled
+    pub reissued_requests: u64,
+    /// Total time workers spent waiting on 429 responses
+    pub throttled_time: Duration,
+}
+
+impl BulkCounters {
+    pub fn now() -> Self {
+        BulkCounters {
+            deleted_documents: DELETED_DOCUMENTS.load(Ordering::Relaxed),
+            deletes_not_found: DELETES_NOT_FOUND.load(Ordering::Relaxed),
+            reissued_requests: REISSUED_REQUESTS.load(Ordering::Relaxed),
+            throttled_time: Duration::from_millis(THROTTLED_MILLIS.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// What was counted between `start` and this snapshot
+    pub fn since(&self, start: &BulkCounters) -> BulkCounters {
+        BulkCounters {
+            deleted_documents: self.deleted_documents.saturating_sub(start.deleted_documents),
+            deletes_not_found: self.deletes_not_found.saturating_sub(start.deletes_not_found),
+            reissued_requests: self.reissued_requests.saturating_sub(start.reissued_requests),
+            throttled_time: self.throttled_time.saturating_sub(start.throttled_time),
+        }
+    }
+}
+
+/// Latencies of the most recent successful bulk requests
+#[derive(Debug)]
+struct LatencyWindow {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyWindow {
+    /// Requests remembered for the percentile
+    const SIZE: usize = 200;
+    /// Requests needed before the percentile is trusted
+    const MIN_SAMPLES: usize = 20;
+
+    const fn new() -> Self {
+        Self { samples: VecDeque::new() }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() == Self::SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// Latency below which `percentile` (0-100) of the remembered requests finished
+    fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.samples.len() < Self::MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+}
+
+/// Cancel a bulk request that runs far past the usual p99 latency and send it
+/// again; document ids make the resend idempotent
+#[derive(Debug, Clone, PartialEq)]
+pub struct StallReissue {
+    /// A request stalls after this multiple of the recent p99 latency
+    pub p99_multiplier: f64,
+    /// Never cancel requests faster than this
+    pub min_timeout: Duration,
+    /// Reissues per batch before waiting on the request like any other
+    pub max_reissues: u32,
+}
+
+impl Default for StallReissue {
+    fn default() -> Self {
+        Self { p99_multiplier: 3.0, min_timeout: Duration::from_secs(10), max_reissues: 2 }
+    }
+}
+
+impl StallReissue {
+    /// How long a request may run before it is reissued, None until enough requests were seen
+    fn deadline(&self, p99: Option<Duration>) -> Option<Duration> {
+        p99.map(|p99| p99.mul_f64(self.p99_multiplier).max(self.min_timeout))
+    }
+}
+
+/// Wait requested by a `Retry-After` header, given as seconds or an HTTP date
+fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => httpdate::parse_http_date(value).ok()?
+            .duration_since(now)
+            .unwrap_or(Duration::ZERO),
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// How failed bulk requests are retried: connection errors, 429 and 5xx responses
+/// are retried with exponential backoff; other responses fail the batch right away
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Randomize each wait between half and all of the backoff, so workers
+    /// that failed together don't retry together
+    pub jitter: bool,
+    pub stall_reissue: Option<StallReissue>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+            stall_reissue: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff before retry number `attempt` (0-based), without jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+
+    /// Wait before retry number `attempt`; `random` is uniform in [0, 1)
+    fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let backoff = self.backoff(attempt);
+        if self.jitter {
+            backoff.mul_f64(0.5 + random / 2.0)
+        } else {
+            backoff
+        }
+    }
+}
+
+/// Seed of the backoff jitter and the number of draws made from it
+static JITTER_SEED: OnceLock<(u64, AtomicU64)> = OnceLock::new();
+
+/// Draw backoff jitter from `seed` for the rest of the process, so retry timings
+/// repeat from run to run; only the first call takes effect
+pub fn install_jitter_seed(seed: u64) {
+    JITTER_SEED.set((seed, AtomicU64::new(0))).ok();
+}
+
+/// The `draw`-th value of the sequence of `seed` (splitmix64)
+fn seeded_bits(seed: u64, draw: u64) -> u64 {
+    let mut z = seed.wrapping_add(draw.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Uniform in [0, 1): from the installed jitter seed, else seeded per call by the
+/// std hasher's random keys
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = match JITTER_SEED.get() {
+        Some((seed, draws)) => seeded_bits(*seed, draws.fetch_add(1, Ordering::Relaxed)),
+        None => std::collections::hash_map::RandomState::new().build_hasher().finish(),
+    };
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Whether a response status is worth retrying
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Response body of a `_bulk` request
+#[derive(Debug, Deserialize)]
+pub struct BulkResponse {
+    #[serde(default)]
+    pub errors: bool,
+    #[serde(default)]
+    pub items: Vec<BulkResponseItem>,
+}
+
+/// One item of a bulk response, keyed by the action that produced it
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkResponseItem {
+    Index(BulkItemResult),
+    Create(BulkItemResult),
+    Update(BulkItemResult),
+    Delete(BulkItemResult),
+}
+
+impl BulkResponseItem {
+    pub fn result(&self) -> &BulkItemResult {
+        match self {
+            Self::Index(result) | Self::Create(result) | Self::Update(result) | Self::Delete(result) => result,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkItemResult {
+    #[serde(rename = "_id")]
+    pub id: Option<String>,
+    #[serde(rename = "_index")]
+    pub index: Option<String>,
+    pub status: u16,
+    pub error: Option<BulkItemError>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct BulkItemError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub reason: Option<String>,
+}
+
+/// A document the cluster rejected within an otherwise successful bulk request
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkItemFailure {
+    pub id: Option<String>,
+    pub index: Option<String>,
+    pub status: u16,
+    pub error: BulkItemError,
+}
+
+impl BulkResponse {
+    /// Items the cluster wrote: a 2xx status and no error
+    pub fn indexed(&self) -> usize {
+        self.items.iter()
+            .filter(|item| !matches!(item, BulkResponseItem::Delete(_)))
+            .map(BulkResponseItem::result)
+            .filter(|item| item.error.is_none() && (200..300).contains(&item.status))
+            .count()
+    }
+
+    /// Delete items that removed their document
+    pub fn deleted(&self) -> usize {
+        self.delete_results().filter(|item| item.error.is_none() && (200..300).contains(&item.status)).count()
+    }
+
+    /// Delete items that found no document (404), e.g. already gone, or sent to the
+    /// wrong shard because the routing differs from the one it was indexed with
+    pub fn not_found(&self) -> usize {
+        self.delete_results().filter(|item| item.error.is_none() && item.status == 404).count()
+    }
+
+    fn delete_results(&self) -> impl Iterator<Item = &BulkItemResult> {
+        self.items.iter().filter_map(|item| match item {
+            BulkResponseItem::Delete(result) => Some(result),
+            _ => None,
+        })
+    }
+
+    pub fn failures(&self) -> Vec<BulkItemFailure> {
+        if !self.errors {
+            return Vec::new();
+        }
+        self.items
+            .iter()
+            .map(BulkResponseItem::result)
+            .filter_map(|item| item.error.as_ref().map(|error| BulkItemFailure {
+                id: item.id.clone(),
+                index: item.index.clone(),
+                status: item.status,
+                error: error.clone(),
+            }))
+            .collect()
+    }
+}
+
+/// How token documents are written (WRITE_MODE)
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    /// Replace the whole document
+    #[default]
+    Index,
+    /// Only add documents that don't exist yet, leaving existing ones alone
+    Create,
+    /// Merge the document's non-null fields into the existing one (creating it if
+    /// missing), so fields written by other pipelines are kept
+    Upsert,
+}
+
+/// Bulk action of a document, as the bulk error log records it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkOperation {
+    Index,
+    Create,
+    Update,
+    Delete,
+}
+
+impl BulkOperation {
+    /// What a bulk request in `mode` does with `doc`; tombstones delete it
+    pub fn of<D: BulkDocument>(doc: &D, mode: WriteMode) -> Self {
+        match mode {
+            _ if doc.is_deletion() => Self::Delete,
+            WriteMode::Index => Self::Index,
+            WriteMode::Create => Self::Create,
+            WriteMode::Upsert => Self::Update,
+        }
     }
+}
 
+/// Result of a bulk request that the cluster accepted
+#[derive(Debug, Default)]
+pub struct BulkOutcome {
+    /// Documents indexed without an item error, as reported per item
+    pub indexed: usize,
+    /// Documents that already existed in create mode, left as they were
+    pub existing: usize,
+    /// Tombstone rows whose document was deleted
+    pub deleted: usize,
+    /// Tombstone rows whose document wasn't found
+    pub not_found: usize,
+    /// Documents the cluster rejected; `failures` has the error of each
+    pub failed: usize,
+    /// Documents without an id, which were never sent
+    pub skipped: usize,
+    /// Item-level 429s that were re-sent, whether or not a retry went through
+    pub throttled: usize,
+    pub failures: Vec<BulkItemFailure>,
+}
+
+/// Build the NDJSON bulk body, returning it with the number of documents it contains.
+/// With `quarantine` set, documents that need it are routed to the quarantine index.
+/// Upserts clear the payment token annotations only with `payment_token_annotations`.
+fn build_bulk_body<D: BulkDocument>(
+    index_name: &str,
+    documents: &[D],
+    quarantine: bool,
+    mode: WriteMode,
+    routing: &Routing,
+    payment_token_annotations: bool,
+) -> Result<(String, usize)> {
     let mut bulk_body = String::new();
     let mut valid_docs = 0;
 
     for doc in documents {
-        if let Some(token_id) = &doc.token_id {
-            let doc_id = token_id.to_string();
+        if let Some(doc_id) = doc.document_id() {
+            let token_document = doc.is_token_document();
+            // Routed documents are serialized once, for both the routing value and the body
+            let routed = token_document && *routing != Routing::None && doc.first_sent_with().is_none();
+            let source = routed.then(|| serde_json::to_value(doc)).transpose()?;
+            let (routing, pipeline) = routing_and_pipeline(doc, routing, source.as_ref());
+            if doc.is_deletion() {
+                // Tombstones delete from the target; the routing must match the one it was indexed with
+                let metadata = BulkIndexMetadata { id: doc_id.to_string(), index: None, pipeline: None, routing };
+                bulk_body.push_str(&serde_json::to_string(&BulkAction::Delete(metadata))?);
+                bulk_body.push('\n');
+                valid_docs += 1;
+                continue;
+            }
+
+            let index = (quarantine && doc.needs_quarantine()).then(|| quarantine_index_name(index_name));
             
-            // Add index action
-            let index_action = BulkIndexAction {
-                index: BulkIndexMetadata { id: doc_id },
+            // A collection's own pipeline overrides the request's for its documents
+            let pipeline = pipeline.filter(|_| mode != WriteMode::Upsert);
+            let metadata = BulkIndexMetadata { id: doc_id.to_string(), index, pipeline, routing };
+            let action = match mode {
+                WriteMode::Index => BulkAction::Index(metadata),
+                WriteMode::Create => BulkAction::Create(metadata),
+                WriteMode::Upsert => BulkAction::Update(metadata),
             };
-            bulk_body.push_str(&serde_json::to_string(&index_action)?);
+            bulk_body.push_str(&serde_json::to_string(&action)?);
             bulk_body.push('\n');
-            
-            // Add document
-            bulk_body.push_str(&serde_json::to_string(&doc)?);
+
+            if mode == WriteMode::Upsert {
+                // Unset fields would otherwise null out what other pipelines wrote; an
+                // unset listing is a cleared one and is sent as null
+                let mut fields = match source {
+                    Some(source) => source,
+                    None => serde_json::to_value(doc)?,
+                };
+                if let Value::Object(fields) = &mut fields {
+                    fields.retain(|_, value| !value.is_null());
+                    if token_document {
+                        let payment_tokens = PAYMENT_TOKEN_FIELDS.iter().filter(|_| payment_token_annotations);
+                        for field in LISTING_FIELDS.iter().chain(payment_tokens) {
+                            fields.entry(*field).or_insert(Value::Null);
+                        }
+                    }
+                }
+                bulk_body.push_str(&serde_json::json!({"doc": fields, "doc_as_upsert": true}).to_string());
+            } else if let Some(source) = source {
+                bulk_body.push_str(&source.to_string());
+            } else {
+                bulk_body.push_str(&serde_json::to_string(doc)?);
+            }
             bulk_body.push('\n');
             
             valid_docs += 1;
         }
     }
 
+    Ok((bulk_body, valid_docs))
+}
+
+fn is_already_existing(failure: &BulkItemFailure) -> bool {
+    failure.status == StatusCode::CONFLICT.as_u16() && failure.error.error_type == "version_conflict_engine_exception"
+}
+
+/// A document the cluster had no room for (a full write queue), worth sending again
+fn is_throttled(failure: &BulkItemFailure) -> bool {
+    failure.status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+}
+
+/// Send `documents` in one bulk request; documents rejected with an item-level 429
+/// are re-sent on the bulk retry schedule, the rest of the batch isn't
+pub async fn bulk_index_documents<D: BulkDocument>(
+    client: &Client,
+    elasticsearch_url: &str,
+    index_name: &str,
+    documents: &[D],
+    quarantine: bool,
+    mode: WriteMode,
+    retry: &RetryPolicy,
+) -> Result<BulkOutcome> {
+    let mut outcome = send_bulk(client, elasticsearch_url, index_name, documents, quarantine, mode, retry).await?;
+    let mut attempt = 0;
+    while attempt < retry.max_retries && outcome.failures.iter().any(is_throttled) {
+        let (throttled, failures): (Vec<BulkItemFailure>, Vec<BulkItemFailure>) =
+            std::mem::take(&mut outcome.failures).into_iter().partition(is_throttled);
+        let resend: Vec<&D> = documents.iter()
+            .filter(|document| throttled.iter().any(|failure| failure.id.as_deref() == document.document_id()))
+            .collect();
+        let delay = retry.delay(attempt, random_fraction());
+        warn!("{} documents rejected with HTTP 429 ({}), retry {}/{} in {:.1}s",
+              throttled.len(), throttled[0].error.error_type, attempt + 1, retry.max_retries, delay.as_secs_f64());
+        THROTTLED_MILLIS.fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+        tokio::time::sleep(delay).await;
+
+        let retried = send_bulk(client, elasticsearch_url, index_name, &resend, quarantine, mode, retry).await?;
+        outcome.indexed += retried.indexed;
+        outcome.existing += retried.existing;
+        outcome.deleted += retried.deleted;
+        outcome.not_found += retried.not_found;
+        outcome.throttled += throttled.len();
+        outcome.failures = failures.into_iter().chain(retried.failures).collect();
+        outcome.failed = outcome.failures.len();
+        attempt += 1;
+    }
+    Ok(outcome)
+}
+
+async fn send_bulk<D: BulkDocument>(
+    client: &Client,
+    elasticsearch_url: &str,
+    index_name: &str,
+    documents: &[D],
+    quarantine: bool,
+    mode: WriteMode,
+    retry: &RetryPolicy,
+) -> Result<BulkOutcome> {
+    if documents.is_empty() {
+        return Ok(BulkOutcome::default());
+    }
+
+    let (bulk_body, valid_docs) = build_bulk_body(index_name, documents, quarantine, mode, ROUTING.get().unwrap_or(&Routing::None),
+                                                 PAYMENT_TOKEN_ANNOTATIONS.get().copied().unwrap_or(false))?;
+
+    let skipped = documents.len() - valid_docs;
     if valid_docs == 0 {
-        return Ok(0);
+        return Ok(BulkOutcome { skipped, ..BulkOutcome::default() });
     }
 
-    let url = format!("{}/{}/_bulk", elasticsearch_url, index_name);
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/x-ndjson")
-        .body(bulk_body)
-        .send()
-        .await
-        .context("Failed to send bulk request")?;
+    let checksum = match BODY_DIAGNOSTICS.get().filter(|diagnostics| diagnostics.log_checksums) {
+        Some(diagnostics) => Some(inspect_body(diagnostics, index_name, &bulk_body).await),
+        None => None,
+    };
 
-    if response.status().is_success() {
-        let result: Value = response.json().await.context("Failed to parse response")?;
-        
-        if let Some(items) = result["items"].as_array() {
-            let errors: Vec<_> = items
-                .iter()
-                .filter_map(|item| item["index"]["error"].as_object())
-                .collect();
-            
-            if !errors.is_empty() {
-                eprintln!("Bulk indexing had {} errors out of {} documents", errors.len(), valid_docs);
+    // Sent as X-Opaque-Id, which Elasticsearch puts in its slowlogs and tasks API
+    let opaque_id = format!("migrator-{}-{}", std::process::id(), BULK_REQUESTS.fetch_add(1, Ordering::Relaxed));
+    debug!(opaque_id = %opaque_id, documents = valid_docs, bytes = bulk_body.len(), "Sending bulk request to {}", index_name);
+
+    let body_len = bulk_body.len();
+    let (payload, gzipped) = encode_body(bulk_body, BULK_GZIP_MIN_BYTES.get().copied().flatten());
+    if gzipped {
+        debug!(opaque_id = %opaque_id, "Bulk body gzipped from {} to {} bytes", body_len, payload.len());
+    }
+
+    let url = bulk_url::<D>(elasticsearch_url, index_name, mode, BULK_PIPELINE.get().and_then(Option::as_deref));
+    let mut attempt = 0;
+    let mut reissues = 0;
+    let response = loop {
+        record_bytes_sent(payload.len());
+        let mut request = client
+            .post(&url)
+            .header("Content-Type", "application/x-ndjson")
+            .header("X-Opaque-Id", &opaque_id);
+        if gzipped {
+            request = request.header("Content-Encoding", "gzip");
+        }
+        if let Some(checksum) = &checksum {
+            request = request.header("X-Body-SHA256", checksum);
+        }
+        let request = request.body(payload.clone());
+        #[cfg(feature = "chaos")]
+        let request = crate::chaos::send(request);
+        #[cfg(not(feature = "chaos"))]
+        let request = request.send_noting_warnings();
+        let deadline = retry.stall_reissue.as_ref()
+            .filter(|stall| reissues < stall.max_reissues)
+            .and_then(|stall| stall.deadline(BULK_LATENCIES.lock().unwrap().percentile(99.0)));
+        let started = Instant::now();
+        let sent = match deadline {
+            Some(deadline) => match tokio::time::timeout(deadline, request).await {
+                Ok(sent) => sent,
+                Err(_) => {
+                    reissues += 1;
+                    REISSUED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+                    warn!(opaque_id = %opaque_id, "Bulk request stalled for {:.1}s, reissuing", deadline.as_secs_f64());
+                    continue;
+                }
+            },
+            None => request.await,
+        };
+        metrics::record_bulk_response(sent.as_ref().ok().map(|response| response.status().as_u16()), started.elapsed());
+        if sent.as_ref().is_ok_and(|response| response.status().is_success()) {
+            BULK_LATENCIES.lock().unwrap().record(started.elapsed());
+        }
+
+        let delay = match &sent {
+            Ok(response) if !is_retryable(response.status()) => break sent.context("Failed to send bulk request")?,
+            _ if attempt >= retry.max_retries => break sent.map_err(|e| ClusterUnavailable(e.to_string()))?,
+            // Honour the server's Retry-After on 429 instead of our own schedule
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                let delay = retry_after(response.headers(), SystemTime::now())
+                    .unwrap_or_else(|| retry.delay(attempt, random_fraction()));
+                warn!(opaque_id = %opaque_id, "Bulk request throttled (HTTP 429), retrying in {:.1}s", delay.as_secs_f64());
+                THROTTLED_MILLIS.fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+                delay
+            }
+            Ok(response) => {
+                let delay = retry.delay(attempt, random_fraction());
+                warn!(opaque_id = %opaque_id, "Bulk request failed (HTTP {}), retry {}/{} in {:.1}s",
+                          response.status(), attempt + 1, retry.max_retries, delay.as_secs_f64());
+                delay
+            }
+            Err(e) => {
+                let delay = retry.delay(attempt, random_fraction());
+                warn!(opaque_id = %opaque_id, "Bulk request failed ({}), retry {}/{} in {:.1}s", e, attempt + 1, retry.max_retries, delay.as_secs_f64());
+                delay
             }
+        };
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+
+    if response.status().is_success() {
+        let result: BulkResponse = response.json().await.context("Failed to parse response")?;
+        let mut failures = result.failures();
+        // A conflict on create means the document is already there, which is what create mode wants
+        let mut existing = 0;
+        if mode == WriteMode::Create {
+            failures.retain(|failure| !is_already_existing(failure));
+            existing = result.failures().len() - failures.len();
+        }
+        metrics::record_item_errors(failures.iter().map(|failure| failure.error.error_type.as_str()));
+        if !failures.is_empty() {
+            warn!("Bulk indexing had {} errors out of {} documents", failures.len(), valid_docs);
         }
         
-        Ok(valid_docs)
+        let indexed = result.indexed();
+        let deleted = result.deleted();
+        let not_found = result.not_found();
+        DELETED_DOCUMENTS.fetch_add(deleted as u64, Ordering::Relaxed);
+        DELETES_NOT_FOUND.fetch_add(not_found as u64, Ordering::Relaxed);
+        if not_found > 0 {
+            warn!("⚠️  {} deletes found no document; if they were indexed with different ROUTING, they are still there", not_found);
+        }
+        let accounted = indexed + existing + deleted + not_found + failures.len();
+        if accounted != valid_docs {
+            warn!("Bulk response accounted for {} of {} documents", accounted, valid_docs);
+        }
+        Ok(BulkOutcome { indexed, existing, deleted, not_found, failed: failures.len(), skipped, throttled: 0, failures })
     } else {
         let status = response.status();
         let error_text = response.text().await.unwrap_or_default();
-        eprintln!("Bulk indexing failed: HTTP {} - {}", status, error_text);
+        warn!(opaque_id = %opaque_id, "Bulk indexing failed: HTTP {} - {}", status, error_text);
+        if let Some(checksum) = &checksum {
+            warn!("Failed bulk body was {} bytes, sha256 {}", body_len, checksum);
+        }
+        if matches!(status, StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT) {
+            return Err(ClusterUnavailable(format!("HTTP {}", status)).into());
+        }
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            return Err(PayloadTooLarge(body_len).into());
+        }
         Err(anyhow::anyhow!("Bulk indexing failed: HTTP {}", status))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+    use crate::collection_config::get_collection_config;
+    use crate::models_flexible::CsvRecord;
+
+    fn wildforest_doc(token_id: &str, raw_metadata: &str) -> FlexibleElasticsearchDocument {
+        let config = get_collection_config("0xa038c593115f6fcd673f6833e15462b475994879").unwrap();
+        let record = CsvRecord {
+            token_address: Some("0xa038c593115f6fcd673f6833e15462b475994879".to_string()),
+            token_id: Some(token_id.to_string()),
+            raw_metadata: Some(raw_metadata.to_string()),
+            ..Default::default()
+        };
+        FlexibleElasticsearchDocument::from_record(record, Some(&config))
+    }
+
+    #[test]
+    fn test_failed_extraction_routed_to_quarantine() {
+        let docs = vec![
+            wildforest_doc("1", r#"{"properties":{"tier":1,"rarity":"Common","type":"Archer"}}"#),
+            wildforest_doc("2", r#"{"name":"No properties"}"#),
+        ];
+
+        let (body, count) = build_bulk_body("nfts", &docs, true, WriteMode::Index, &Routing::None, false).unwrap();
+        let lines: Vec<Value> = body.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+
+        assert_eq!(count, 2);
+        assert_eq!(lines[0], serde_json::json!({"index": {"_id": "0xa038c593115f6fcd673f6833e15462b475994879:1"}}));
+        assert_eq!(lines[2], serde_json::json!({"index": {"_id": "0xa038c593115f6fcd673f6833e15462b475994879:2", "_index": "nfts_quarantine"}}));
+        assert_eq!(lines[3]["extraction_errors"], serde_json::json!(["raw_metadata.properties missing"]));
+    }
+
+    #[test]
+    fn test_seeded_jitter_repeats() {
+        let draws = |seed| (0..5).map(|draw| seeded_bits(seed, draw)).collect::<Vec<_>>();
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
+        assert_ne!(seeded_bits(42, 0), seeded_bits(42, 1));
+    }
+
+    #[test]
+    fn test_large_bodies_gzipped() {
+        let body = "{\"index\":{}}\n{\"raw_metadata\":\"aaaaaaaa\"}\n".repeat(100);
+        let (payload, gzipped) = encode_body(body.clone(), Some(1024));
+        assert!(gzipped && payload.len() < body.len() / 4);
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&payload[..]), &mut decoded).unwrap();
+        assert_eq!(decoded, body);
+
+        assert_eq!(encode_body(body.clone(), Some(body.len() + 1)), (Bytes::from(body.clone()), false));
+        assert!(!encode_body(body, None).1);
+    }
+
+    #[test]
+    fn test_quarantine_disabled_keeps_target_index() {
+        let docs = vec![wildforest_doc("2", r#"{"name":"No properties"}"#)];
+
+        let (body, _) = build_bulk_body("nfts", &docs, false, WriteMode::Index, &Routing::None, false).unwrap();
+        let action: Value = serde_json::from_str(body.lines().next().unwrap()).unwrap();
+
+        assert_eq!(action, serde_json::json!({"index": {"_id": "0xa038c593115f6fcd673f6833e15462b475994879:2"}}));
+    }
+
+    #[test]
+    fn test_routing_of_token_documents() {
+        let docs = vec![wildforest_doc("2", r#"{"name":"Knight"}"#)];
+        let routing = Routing::parse("token_address").unwrap();
+        let (body, _) = build_bulk_body("nfts", &docs, false, WriteMode::Index, &routing, false).unwrap();
+        let lines: Vec<Value> = body.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines[0], serde_json::json!({"index": {
+            "_id": "0xa038c593115f6fcd673f6833e15462b475994879:2",
+            "routing": "0xa038c593115f6fcd673f6833e15462b475994879",
+        }}));
+        assert_eq!(lines[1]["name"], "Knight");
+
+        let (body, _) = build_bulk_body("nfts", &docs, false, WriteMode::Upsert, &Routing::parse("field:token_id").unwrap(), false).unwrap();
+        assert!(body.starts_with(r#"{"update":{"_id":"0xa038c593115f6fcd673f6833e15462b475994879:2","routing":"2"}}"#), "{}", body);
+        let (body, _) = build_bulk_body("nfts", &docs, false, WriteMode::Index, &Routing::parse("field:external_url").unwrap(), false).unwrap();
+        assert!(!body.contains("routing"));
+
+        assert_eq!(Routing::parse("none").unwrap(), Routing::None);
+        assert!(Routing::parse("tokenaddress").is_err());
+        assert!(Routing::parse("field:").is_err());
+        assert!(Routing::parse("field:owner").is_err());
+    }
+
+    #[test]
+    fn test_tombstones_are_deleted() {
+        let mut tombstone = wildforest_doc("3", "");
+        tombstone.deleted = true;
+        let docs = vec![wildforest_doc("2", r#"{"name":"Knight"}"#), tombstone];
+        let (body, valid_docs) = build_bulk_body("nfts", &docs, true, WriteMode::Upsert, &Routing::parse("token_address").unwrap(), false).unwrap();
+        assert_eq!(valid_docs, 2);
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], r#"{"delete":{"_id":"0xa038c593115f6fcd673f6833e15462b475994879:3","routing":"0xa038c593115f6fcd673f6833e15462b475994879"}}"#);
+
+        let response: BulkResponse = serde_json::from_value(serde_json::json!({"errors": false, "items": [
+            {"index": {"_id": "1", "status": 201}},
+            {"delete": {"_id": "2", "status": 200}},
+            {"delete": {"_id": "3", "status": 404}},
+        ]})).unwrap();
+        assert_eq!((response.indexed(), response.deleted(), response.not_found()), (1, 1, 1));
+        assert!(response.failures().is_empty());
+    }
+
+    #[test]
+    fn test_counters_of_a_later_file_leave_out_earlier_ones() {
+        let start = BulkCounters { deleted_documents: 5, deletes_not_found: 2, reissued_requests: 1, throttled_time: Duration::from_secs(2) };
+        let end = BulkCounters { deleted_documents: 8, deletes_not_found: 2, reissued_requests: 4, throttled_time: Duration::from_secs(7) };
+        assert_eq!(end.since(&start), BulkCounters {
+            deleted_documents: 3,
+            deletes_not_found: 0,
+            reissued_requests: 3,
+            throttled_time: Duration::from_secs(5),
+        });
+    }
+
+    #[test]
+    fn test_pipeline_for_token_documents() {
+        #[derive(Serialize)]
+        struct Token(&'static str);
+        impl BulkDocument for Token {
+            const TOKEN_DOCUMENT: bool = true;
+            fn document_id(&self) -> Option<&str> {
+                Some(self.0)
+            }
+            fn pipeline(&self) -> Option<String> {
+                (self.0 == "land").then(|| "land-geo".to_string())
+            }
+        }
+
+        let (body, _) = build_bulk_body("nfts", &[Token("land"), Token("axie")], false, WriteMode::Index, &Routing::None, false).unwrap();
+        let actions: Vec<Value> = body.lines().step_by(2).map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(actions, vec![
+            serde_json::json!({"index": {"_id": "land", "pipeline": "land-geo"}}),
+            serde_json::json!({"index": {"_id": "axie"}}),
+        ]);
+        let (body, _) = build_bulk_body("nfts", &[Token("land")], false, WriteMode::Upsert, &Routing::None, false).unwrap();
+        assert!(!body.contains("pipeline"));
+
+        assert_eq!(bulk_url::<Token>("http://es", "nfts", WriteMode::Index, Some("enrich")), "http://es/nfts/_bulk?pipeline=enrich");
+        assert_eq!(bulk_url::<Token>("http://es", "nfts", WriteMode::Upsert, Some("enrich")), "http://es/nfts/_bulk");
+        assert_eq!(bulk_url::<Token>("http://es", "nfts", WriteMode::Index, Some("a&b=c #1")), "http://es/nfts/_bulk?pipeline=a%26b%3Dc+%231");
+        assert_eq!(bulk_url::<crate::orders_history::OrderEvent>("http://es", "nft_orders", WriteMode::Index, Some("enrich")), "http://es/nft_orders/_bulk");
+    }
+
+    #[test]
+    fn test_resent_documents_keep_how_they_were_sent() {
+        #[derive(Serialize)]
+        struct Resent(&'static str, bool);
+        impl BulkDocument for Resent {
+            fn document_id(&self) -> Option<&str> {
+                Some(self.0)
+            }
+            fn is_token_document(&self) -> bool {
+                self.1
+            }
+            fn first_sent_with(&self) -> Option<(Option<&str>, Option<&str>)> {
+                Some(if self.1 { (Some("0xabc"), Some("land-geo")) } else { (None, None) })
+            }
+        }
+
+        let routing = Routing::parse("token_address").unwrap();
+        let (body, _) = build_bulk_body("nfts", &[Resent("land", true), Resent("event", false)], false, WriteMode::Index, &routing, false).unwrap();
+        let actions: Vec<Value> = body.lines().step_by(2).map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(actions, vec![
+            serde_json::json!({"index": {"_id": "land", "pipeline": "land-geo", "routing": "0xabc"}}),
+            serde_json::json!({"index": {"_id": "event"}}),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_and_create_modes() {
+        let docs = vec![wildforest_doc("2", r#"{"name":"Knight"}"#)];
+        let (body, _) = build_bulk_body("nfts", &docs, false, WriteMode::Upsert, &Routing::None, false).unwrap();
+        let lines: Vec<Value> = body.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines[0], serde_json::json!({"update": {"_id": "0xa038c593115f6fcd673f6833e15462b475994879:2"}}));
+        assert_eq!(lines[1]["doc_as_upsert"], true);
+        assert_eq!(lines[1]["doc"]["name"], "Knight");
+        // Unset fields are left out rather than nulling the stored ones
+        assert!(lines[1]["doc"].get("owner").is_none());
+        // ...but a row without a listing clears the stored one
+        assert_eq!(lines[1]["doc"].get("order_id"), Some(&Value::Null));
+        // Payment token annotations only this run's PAYMENT_TOKENS registry writes
+        assert!(lines[1]["doc"].get("payment_token_symbol").is_none());
+        let (body, _) = build_bulk_body("nfts", &docs, false, WriteMode::Upsert, &Routing::None, true).unwrap();
+        let lines: Vec<Value> = body.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines[1]["doc"].get("payment_token_symbol"), Some(&Value::Null));
+        assert_eq!(lines[1]["doc"].get("payment_token_known"), Some(&Value::Null));
+
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |_, _| Reply::Respond(200, r#"{"errors":true,"items":[
+            {"create":{"_id":"a","status":201}},
+            {"create":{"_id":"b","status":409,"error":{"type":"version_conflict_engine_exception","reason":"document already exists"}}},
+            {"create":{"_id":"c","status":400,"error":{"type":"mapper_parsing_exception"}}}]}"#.to_string())).await;
+        let docs = vec![wildforest_doc("1", "{}"), wildforest_doc("2", "{}"), wildforest_doc("3", "{}")];
+        let outcome = bulk_index_documents(&Client::new(), &server.url(), "nfts", &docs, false, WriteMode::Create, &fast_retries(0)).await.unwrap();
+        assert_eq!((outcome.indexed, outcome.existing, outcome.failed), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_item_level_429s_are_resent() {
+        // A full write queue rejects one document, then has room for it
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |number, _| Reply::Respond(200, match number {
+            0 => r#"{"errors":true,"items":[
+                {"index":{"_id":"0xa038c593115f6fcd673f6833e15462b475994879:1","status":201}},
+                {"index":{"_id":"0xa038c593115f6fcd673f6833e15462b475994879:2","status":429,"error":{"type":"es_rejected_execution_exception"}}},
+                {"index":{"_id":"0xa038c593115f6fcd673f6833e15462b475994879:3","status":400,"error":{"type":"mapper_parsing_exception"}}}]}"#,
+            _ => r#"{"errors":false,"items":[{"index":{"_id":"0xa038c593115f6fcd673f6833e15462b475994879:2","status":201}}]}"#,
+        }.to_string())).await;
+        let docs = vec![wildforest_doc("1", "{}"), wildforest_doc("2", "{}"), wildforest_doc("3", "{}")];
+        let outcome = bulk_index_documents(&Client::new(), &server.url(), "nfts", &docs, false, WriteMode::Index, &fast_retries(3)).await.unwrap();
+        assert_eq!((outcome.indexed, outcome.failed, outcome.throttled), (2, 1, 1));
+        assert_eq!(outcome.failures[0].error.error_type, "mapper_parsing_exception");
+        assert_eq!(server.requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_id_strategy_templates() {
+        let default = IdStrategy::default();
+        assert_eq!(default.document_id(Some("0xABC"), Some(" 7 ")), Some("0xabc:7".to_string()));
+        assert_eq!(default.document_id(None, Some("7")), None);
+        assert_eq!(default.document_id(Some("0xabc"), None), None);
+
+        let legacy = IdStrategy::new("{token_id}").unwrap();
+        assert_eq!(legacy.document_id(None, Some("7")), Some("7".to_string()));
+
+        let custom = IdStrategy::new("nft-{token_address}-{token_id}").unwrap();
+        assert_eq!(custom.document_id(Some("0xabc"), Some("7")), Some("nft-0xabc-7".to_string()));
+
+        assert!(IdStrategy::new("{token_address}").is_err());
+        assert!(IdStrategy::new("{owner}:{token_id}").is_err());
+        assert!(IdStrategy::new("{token_id").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_body_checksum_and_dump_threshold() {
+        // sha256sum of "abc"
+        assert_eq!(body_checksum("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let dir = std::env::temp_dir().join(format!("bulk-dump-test-{}", std::process::id()));
+        let diagnostics = BodyDiagnostics { log_checksums: true, dump_dir: Some(dir.clone()), dump_min_bytes: 4 };
+        let small = inspect_body(&diagnostics, "nfts", "abc").await;
+        let large = inspect_body(&diagnostics, "nfts", "abcd").await;
+
+        assert!(!dir.join(format!("bulk-{}.ndjson", small)).exists());
+        assert_eq!(std::fs::read_to_string(dir.join(format!("bulk-{}.ndjson", large))).unwrap(), "abcd");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_retry_after_seconds_and_date() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now), None);
+
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(7)));
+
+        let date = httpdate::fmt_http_date(now + Duration::from_secs(12));
+        headers.insert(RETRY_AFTER, date.parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(12)));
+
+        headers.insert(RETRY_AFTER, "86400".parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn test_retry_backoff_is_capped() {
+        let retry = RetryPolicy { jitter: false, ..RetryPolicy::default() };
+        assert_eq!(retry.delay(0, 0.9), Duration::from_millis(500));
+        assert_eq!(retry.delay(2, 0.9), Duration::from_secs(2));
+        assert_eq!(retry.delay(10, 0.9), Duration::from_secs(30));
+        assert_eq!(retry.delay(u32::MAX, 0.9), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_retry_jitter_stays_within_half_backoff() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.delay(2, 0.0), Duration::from_secs(1));
+        assert_eq!(retry.delay(2, 0.5), Duration::from_millis(1500));
+        for _ in 0..100 {
+            let delay = retry.delay(3, random_fraction());
+            assert!(delay >= Duration::from_secs(2) && delay < Duration::from_secs(4), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_stall_deadline_follows_p99() {
+        let mut window = LatencyWindow::new();
+        for ms in 1..LatencyWindow::MIN_SAMPLES as u64 {
+            window.record(Duration::from_millis(ms * 100));
+        }
+        assert_eq!(window.percentile(99.0), None);
+        window.record(Duration::from_secs(30));
+        assert_eq!(window.percentile(99.0), Some(Duration::from_secs(30)));
+        assert_eq!(window.percentile(50.0), Some(Duration::from_secs(1)));
+
+        let stall = StallReissue::default();
+        assert_eq!(stall.deadline(None), None);
+        assert_eq!(stall.deadline(Some(Duration::from_secs(1))), Some(Duration::from_secs(10)));
+        assert_eq!(stall.deadline(Some(Duration::from_secs(30))), Some(Duration::from_secs(90)));
+
+        for _ in 0..LatencyWindow::SIZE {
+            window.record(Duration::from_millis(200));
+        }
+        assert_eq!(window.samples.len(), LatencyWindow::SIZE);
+        assert_eq!(window.percentile(99.0), Some(Duration::from_millis(200)));
+    }
+
+    fn fast_retries(max_retries: u32) -> RetryPolicy {
+        RetryPolicy { max_retries, initial_backoff: Duration::from_millis(5), jitter: false, ..RetryPolicy::default() }
+    }
+
+    #[tokio::test]
+    async fn test_bulk_request_survives_node_restart() {
+        // The node drops the first three requests while it restarts, then answers
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |number, _| match number {
+            0..=2 => Reply::Drop,
+            _ => Reply::Respond(200, r#"{"errors":false,"items":[{"index":{"_id":"1","status":201}}]}"#.to_string()),
+        }).await;
+        let client = Client::new();
+        let docs = vec![wildforest_doc("1", "{}")];
+
+        let outcome = bulk_index_documents(&client, &server.url(), "nfts", &docs, false, WriteMode::Index, &fast_retries(5)).await.unwrap();
+        assert_eq!(outcome.indexed, 1);
+        assert_eq!(server.requests.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_outage_longer_than_retries_is_cluster_unavailable() {
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |_, _| {
+            Reply::Respond(503, r#"{"error":"no master"}"#.to_string())
+        }).await;
+        let docs = vec![wildforest_doc("1", "{}")];
+
+        let error = bulk_index_documents(&Client::new(), &server.url(), "nfts", &docs, false, WriteMode::Index, &fast_retries(2)).await.unwrap_err();
+        assert!(error.downcast_ref::<ClusterUnavailable>().is_some(), "{:#}", error);
+        assert_eq!(server.requests.load(Ordering::Relaxed), 3);
+
+        let url = format!("http://{}", server.stop());
+        let error = bulk_index_documents(&Client::new(), &url, "nfts", &docs, false, WriteMode::Index, &fast_retries(1)).await.unwrap_err();
+        assert!(error.downcast_ref::<ClusterUnavailable>().is_some(), "{:#}", error);
+    }
+
+    #[test]
+    fn test_only_transient_statuses_retried() {
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable(StatusCode::BAD_REQUEST));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn test_partial_batch_counts_items() {
+        let response: BulkResponse = serde_json::from_value(serde_json::json!({
+            "errors": true,
+            "items": [
+                {"index": {"_id": "1", "_index": "nfts", "status": 201}},
+                {"index": {"_id": "2", "_index": "nfts", "status": 200}},
+                {"index": {"_id": "3", "_index": "nfts", "status": 400,
+                           "error": {"type": "mapper_parsing_exception", "reason": "bad price"}}},
+                {"index": {"_id": "4", "_index": "nfts", "status": 429,
+                           "error": {"type": "es_rejected_execution_exception"}}}
+            ]
+        })).unwrap();
+        assert_eq!(response.indexed(), 2);
+        assert_eq!(response.failures().len(), 2);
+    }
+
+    #[test]
+    fn test_bulk_response_failures_are_typed() {
+        let response: BulkResponse = serde_json::from_value(serde_json::json!({
+            "took": 3,
+            "errors": true,
+            "items": [
+                {"index": {"_index": "nfts", "_id": "1", "status": 201, "result": "created"}},
+                {"index": {"_index": "nfts", "_id": "2", "status": 400, "error": {
+                    "type": "mapper_parsing_exception",
+                    "reason": "failed to parse field [price] of type [double]",
+                    "caused_by": {"type": "number_format_exception"}
+                }}},
+                {"create": {"_index": "nfts", "_id": "3", "status": 409, "error": {"type": "version_conflict_engine_exception"}}}
+            ]
+        })).unwrap();
+
+        let failures = response.failures();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].id.as_deref(), Some("2"));
+        assert_eq!(failures[0].status, 400);
+        assert_eq!(failures[0].error.error_type, "mapper_parsing_exception");
+        assert_eq!(failures[1].error.reason, None);
+    }
+}
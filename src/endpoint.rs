@@ -0,0 +1,142 @@
+//! Reaching the cluster when ELASTICSEARCH_URL isn't directly connectable: through
+//! a Unix domain socket, at another address (a port-forward), or with a Host header
+//! other than the URL's (an ingress behind the port-forward).
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue, HOST};
+use reqwest::ClientBuilder;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UnixStream};
+use url::{Host, Url};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Default)]
+pub struct Endpoint {
+    /// ELASTICSEARCH_URL as configured
+    pub url: String,
+    /// Connect here instead of resolving the URL's host; the URL's port must match,
+    /// so TLS and the Host header still see the configured name
+    pub connect_to: Option<SocketAddr>,
+    /// Send all requests over this socket (plain HTTP)
+    pub unix_socket: Option<PathBuf>,
+    /// Host header to send instead of the URL's host
+    pub host_header: Option<String>,
+}
+
+impl Endpoint {
+    /// Apply the endpoint to the cluster client. Returns the builder and the base URL
+    /// requests must use, which is a loopback bridge when going through a Unix socket.
+    /// Must be called inside the runtime.
+    pub fn configure(&self, mut builder: ClientBuilder) -> Result<(ClientBuilder, String)> {
+        let url = Url::parse(&self.url).with_context(|| format!("Invalid ELASTICSEARCH_URL '{}'", self.url))?;
+        let mut host_header = self.host_header.clone();
+        let mut base_url = self.url.trim_end_matches('/').to_string();
+
+        match (&self.connect_to, &self.unix_socket) {
+            (Some(_), Some(_)) => anyhow::bail!("Set only one of ELASTICSEARCH_CONNECT_TO and ELASTICSEARCH_UNIX_SOCKET"),
+            (Some(addr), None) => {
+                let Some(Host::Domain(domain)) = url.host() else {
+                    anyhow::bail!("ELASTICSEARCH_CONNECT_TO needs a host name in ELASTICSEARCH_URL, not {}", self.url);
+                };
+                // The resolver override only replaces the address, connections keep the URL's port
+                if url.port_or_known_default() != Some(addr.port()) {
+                    anyhow::bail!("ELASTICSEARCH_CONNECT_TO {} must use the port of ELASTICSEARCH_URL {}", addr, self.url);
+                }
+                builder = builder.resolve(domain, *addr);
+                info!("✓ Connecting to {} for {}", addr, domain);
+            }
+            (None, Some(path)) => {
+                if url.scheme() != "http" {
+                    anyhow::bail!("ELASTICSEARCH_UNIX_SOCKET only supports http:// URLs");
+                }
+                // Virtual hosts behind the socket still see the configured name
+                host_header = host_header.or_else(|| url.host_str().map(|host| match url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_string(),
+                }));
+                let bridge = bridge_unix_socket(path)?;
+                base_url = format!("http://{}{}", bridge, url.path().trim_end_matches('/'));
+                info!("✓ Connecting through Unix socket {}", path.display());
+            }
+            (None, None) => {}
+        }
+
+        if let Some(host) = host_header {
+            let mut headers = HeaderMap::new();
+            headers.insert(HOST, HeaderValue::from_str(&host).context("Invalid ELASTICSEARCH_HOST_HEADER")?);
+            builder = builder.default_headers(headers);
+        }
+        Ok((builder, base_url))
+    }
+}
+
+/// Listen on a loopback port and copy every connection to the Unix socket, since
+/// the HTTP client can only open TCP connections
+fn bridge_unix_socket(path: &Path) -> Result<SocketAddr> {
+    if !path.exists() {
+        anyhow::bail!("Unix socket {} does not exist", path.display());
+    }
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").context("Failed to bind Unix socket bridge")?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+    let path = path.to_path_buf();
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            let path = path.clone();
+            tokio::spawn(async move {
+                match UnixStream::connect(&path).await {
+                    Ok(mut outbound) => {
+                        tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await.ok();
+                    }
+                    Err(e) => warn!("Failed to connect to Unix socket {}: {}", path.display(), e),
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_requests_over_unix_socket_keep_configured_host() {
+        let path = std::env::temp_dir().join(format!("es-endpoint-test-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let body = r#"{"status":"green"}"#;
+            let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_lowercase()
+        });
+
+        let endpoint = Endpoint { url: "http://es.internal:9200".to_string(), unix_socket: Some(path.clone()), ..Default::default() };
+        let (builder, base_url) = endpoint.configure(reqwest::Client::builder()).unwrap();
+        assert!(base_url.starts_with("http://127.0.0.1:"));
+        let response = builder.build().unwrap().get(format!("{}/_cluster/health", base_url)).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), r#"{"status":"green"}"#);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("get /_cluster/health "));
+        assert!(request.contains("\r\nhost: es.internal:9200\r\n"));
+        std::fs::remove_file(&path).ok();
+
+        let port_forward = Endpoint { url: "https://es.internal:9200".to_string(), connect_to: Some("127.0.0.1:9201".parse().unwrap()), ..Default::default() };
+        assert!(port_forward.configure(reqwest::Client::builder()).is_err());
+        let by_ip = Endpoint { url: "http://10.0.0.1:9200".to_string(), connect_to: Some("127.0.0.1:9200".parse().unwrap()), ..Default::default() };
+        assert!(by_ip.configure(reqwest::Client::builder()).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! Indices a run picks per batch rather than up front, like the quarantine index:
+//! each is created (or found to exist) by the first batch that needs it, while
+//! batches arriving at the same time wait for that one check instead of repeating it.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::metrics;
+
+#[derive(Default)]
+pub struct EnsuredIndices {
+    indices: Mutex<HashMap<String, Arc<OnceCell<()>>>>,
+    created: AtomicU64,
+}
+
+impl EnsuredIndices {
+    /// Run `create` for `index` unless an earlier call already succeeded; it returns
+    /// whether it created the index. A failed attempt is tried again by the next call.
+    pub async fn ensure<F, Fut>(&self, index: &str, create: F) -> Result<()>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<bool>>,
+    {
+        let cell = self.indices.lock().unwrap().entry(index.to_string()).or_default().clone();
+        cell.get_or_try_init(|| async {
+            if create().await? {
+                self.created.fetch_add(1, Ordering::Relaxed);
+                metrics::record_index_created();
+                info!("✓ Created index {}", index);
+            }
+            anyhow::Ok(())
+        }).await?;
+        Ok(())
+    }
+
+    /// Indices this run created
+    pub fn created(&self) -> u64 {
+        self.created.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn test_each_index_checked_once() {
+        let ensured = EnsuredIndices::default();
+        let checks = AtomicUsize::new(0);
+        let check = || async {
+            checks.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            Ok(true)
+        };
+        let (first, second) = tokio::join!(ensured.ensure("nfts_quarantine", check), ensured.ensure("nfts_quarantine", check));
+        first.unwrap();
+        second.unwrap();
+        ensured.ensure("nfts_quarantine", check).await.unwrap();
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+        assert_eq!(ensured.created(), 1);
+
+        assert!(ensured.ensure("other", || async { anyhow::bail!("HTTP 503") }).await.is_err());
+        ensured.ensure("other", || async { Ok(false) }).await.unwrap();
+        assert_eq!(ensured.created(), 1);
+    }
+}
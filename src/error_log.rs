@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::elasticsearch::{sent_with, BulkDocument, BulkItemFailure, BulkOperation, WriteMode};
+use crate::paths::ensure_parent_dir;
+
+/// One line of the bulk error log
+#[derive(Debug, Serialize)]
+struct ErrorLogEntry<'a> {
+    #[serde(rename = "_id")]
+    id: Option<&'a str>,
+    index: Option<&'a str>,
+    status: u16,
+    error_type: &'a str,
+    reason: Option<&'a str>,
+    batch: usize,
+    logged_at: u64,
+    /// The rejected document, which `--retry-dlq` re-sends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<Value>,
+    /// How the document was sent, so `--retry-dlq` sends it the same way: orders
+    /// history events aren't token documents, which are routed and piped, and a
+    /// failed tombstone is deleted again rather than indexed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<BulkOperation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_document: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routing: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pipeline: Option<String>,
+}
+
+/// NDJSON log of documents the cluster rejected, one line per failed item, with
+/// the document itself so the log doubles as the `--retry-dlq` dead-letter file
+pub struct BulkErrorLog {
+    path: PathBuf,
+    file: Mutex<Option<tokio::fs::File>>,
+    entries: AtomicUsize,
+}
+
+impl BulkErrorLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), file: Mutex::new(None), entries: AtomicUsize::new(0) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of failures logged during this run
+    pub fn len(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// Append the failures of one batch of `documents`, sent in `mode`; the file is
+    /// only created once something fails
+    pub async fn append<D: BulkDocument>(&self, batch: usize, documents: &[D], mode: WriteMode, failures: &[BulkItemFailure]) -> Result<()> {
+        if failures.is_empty() {
+            return Ok(());
+        }
+        let logged_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut lines = String::new();
+        for failure in failures {
+            let doc = failure.id.as_deref().and_then(|id| documents.iter().find(|doc| doc.document_id() == Some(id)));
+            let document = doc.map(serde_json::to_value).transpose()?;
+            let (routing, pipeline) = doc.zip(document.as_ref()).map_or((None, None), |(doc, source)| sent_with(doc, source));
+            let entry = ErrorLogEntry {
+                id: failure.id.as_deref(),
+                index: failure.index.as_deref(),
+                status: failure.status,
+                error_type: &failure.error.error_type,
+                reason: failure.error.reason.as_deref(),
+                batch,
+                logged_at,
+                document,
+                action: doc.map(|doc| BulkOperation::of(doc, mode)),
+                token_document: doc.map(BulkDocument::is_token_document),
+                routing,
+                pipeline,
+            };
+            lines.push_str(&serde_json::to_string(&entry)?);
+            lines.push('\n');
+        }
+
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            ensure_parent_dir(&self.path).await?;
+            *file = Some(tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await
+                .with_context(|| format!("Failed to open bulk error log {}", self.path.display()))?);
+        }
+        let writer = file.as_mut().unwrap();
+        writer.write_all(lines.as_bytes()).await?;
+        writer.flush().await?;
+        self.entries.fetch_add(failures.len(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elasticsearch::BulkItemError;
+
+    #[derive(Serialize)]
+    struct Doc {
+        token_id: &'static str,
+    }
+
+    impl BulkDocument for Doc {
+        fn document_id(&self) -> Option<&str> {
+            Some(self.token_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failures_appended_as_ndjson() {
+        let path = std::env::temp_dir().join(format!("bulk-errors-{}.ndjson", std::process::id()));
+        let log = BulkErrorLog::new(&path);
+        let failure = BulkItemFailure {
+            id: Some("409192".to_string()),
+            index: Some("nfts".to_string()),
+            status: 400,
+            error: BulkItemError {
+                error_type: "mapper_parsing_exception".to_string(),
+                reason: Some("failed to parse field [price]".to_string()),
+            },
+        };
+
+        let documents = [Doc { token_id: "409192" }];
+        log.append(3, &documents, WriteMode::Index, &[]).await.unwrap();
+        assert!(!path.exists());
+        log.append(3, &documents, WriteMode::Index, &[failure.clone(), failure]).await.unwrap();
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let first: serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert_eq!(first["_id"], "409192");
+        assert_eq!(first["error_type"], "mapper_parsing_exception");
+        assert_eq!(first["batch"], 3);
+        assert_eq!(first["document"]["token_id"], "409192");
+        assert_eq!(first["action"], "index");
+        assert_eq!(log.len(), 2);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
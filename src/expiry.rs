@@ -0,0 +1,136 @@
+use serde::Deserialize;
+
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+use crate::orders::{OrderEntry, OrderFields};
+
+/// What to do with listings whose `expired_at` has passed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiredListings {
+    /// Index order fields as exported
+    #[default]
+    Keep,
+    /// Null out the order fields
+    Clear,
+    /// Move the order fields into `archived_order` (stored, not searchable)
+    Archive,
+}
+
+/// Documents whose top-level order fields describe the token's current listing
+pub trait ListingExpiry: OrderFields {
+    fn expired_at(&self) -> Option<i64>;
+    fn clear_order_fields(&mut self);
+    fn set_archived_order(&mut self, order: OrderEntry);
+}
+
+macro_rules! clear_order_fields {
+    ($doc:expr) => {{
+        $doc.base_price = None;
+        $doc.ended_at = None;
+        $doc.ended_price = None;
+        $doc.expired_at = None;
+        $doc.kind = None;
+        $doc.maker = None;
+        $doc.matcher = None;
+        $doc.order_id = None;
+        $doc.payment_token = None;
+        $doc.payment_token_symbol = None;
+        $doc.payment_token_known = None;
+        $doc.price = None;
+        $doc.ron_price = None;
+        $doc.started_at = None;
+        $doc.state = None;
+        $doc.order_status = None;
+    }};
+}
+
+impl ListingExpiry for ElasticsearchDocument {
+    fn expired_at(&self) -> Option<i64> {
+        self.expired_at
+    }
+
+    fn clear_order_fields(&mut self) {
+        clear_order_fields!(self)
+    }
+
+    fn set_archived_order(&mut self, order: OrderEntry) {
+        self.archived_order = Some(order);
+    }
+}
+
+impl ListingExpiry for FlexibleElasticsearchDocument {
+    fn expired_at(&self) -> Option<i64> {
+        self.expired_at
+    }
+
+    fn clear_order_fields(&mut self) {
+        clear_order_fields!(self)
+    }
+
+    fn set_archived_order(&mut self, order: OrderEntry) {
+        self.archived_order = Some(order);
+    }
+}
+
+/// Clear or archive the listing if it expired before `now` (unix seconds).
+/// Returns whether the document was changed.
+pub fn expire_listing<D: ListingExpiry>(doc: &mut D, mode: ExpiredListings, now: i64) -> bool {
+    if mode == ExpiredListings::Keep || doc.expired_at().is_none_or(|expired_at| expired_at >= now) {
+        return false;
+    }
+    if mode == ExpiredListings::Archive {
+        if let Some(order) = doc.order_entry() {
+            doc.set_archived_order(order);
+        }
+    }
+    doc.clear_order_fields();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models_flexible::CsvRecord;
+
+    fn listing(expired_at: &str) -> FlexibleElasticsearchDocument {
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_id: Some("1".to_string()),
+            order_id: Some("42".to_string()),
+            maker: Some("0xabc".to_string()),
+            price: Some("12.5".to_string()),
+            state: Some("active".to_string()),
+            expired_at: Some(expired_at.to_string()),
+            ..Default::default()
+        }, None)
+    }
+
+    #[test]
+    fn test_expired_listing_archived() {
+        let mut doc = listing("1000");
+        assert!(expire_listing(&mut doc, ExpiredListings::Archive, 2000));
+
+        assert_eq!(doc.price, None);
+        assert_eq!(doc.maker, None);
+        assert_eq!(doc.state, None);
+        let archived = doc.archived_order.unwrap();
+        assert_eq!(archived.order_id, Some(42));
+        assert_eq!(archived.price, Some(12.5));
+        assert_eq!(archived.expired_at, Some(1000));
+    }
+
+    #[test]
+    fn test_only_past_expiry_cleared() {
+        let mut live = listing("3000");
+        assert!(!expire_listing(&mut live, ExpiredListings::Clear, 2000));
+        assert_eq!(live.price, Some(12.5));
+
+        let mut kept = listing("1000");
+        assert!(!expire_listing(&mut kept, ExpiredListings::Keep, 2000));
+
+        let mut cleared = listing("1000");
+        assert!(expire_listing(&mut cleared, ExpiredListings::Clear, 2000));
+        assert_eq!(cleared.order_id, None);
+        assert_eq!(cleared.archived_order, None);
+    }
+}
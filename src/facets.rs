@@ -0,0 +1,105 @@
+//! Facet definitions for the frontend's filter UI. Field types come from the same
+//! generated mapping the index is created with, values from the trait analysis:
+//! keyword fields become term facets with their sorted values, integer fields
+//! range facets. Text fields aren't facetable and are left out.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::collection_config::{collection_mapping, get_collection_config};
+use crate::traits::{TraitAnalyzer, TraitValues};
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Facet {
+    Terms {
+        field: String,
+        values: Vec<String>,
+        /// More values exist than the analysis keeps
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        truncated: bool,
+    },
+    Range {
+        field: String,
+        min: i64,
+        max: i64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct CollectionFacets {
+    pub name: String,
+    pub facets: Vec<Facet>,
+}
+
+/// The facet file: per collection address, its facets in extracted-field order
+#[derive(Debug, Serialize)]
+pub struct FacetConfig {
+    pub index: String,
+    pub collections: BTreeMap<String, CollectionFacets>,
+}
+
+impl FacetConfig {
+    /// Facets of the configured collections the analyzer saw documents of
+    pub fn generate(index: &str, analyzer: &TraitAnalyzer) -> Self {
+        let mut collections = BTreeMap::new();
+        for (address, analyzed) in analyzer.report() {
+            let Some(config) = get_collection_config(address).filter(|config| !config.extracted_fields.is_empty()) else {
+                continue;
+            };
+            let Some(mapping) = collection_mapping(address) else { continue };
+            let facets = config.extracted_fields.iter()
+                .filter_map(|field| {
+                    let values = analyzed.traits.get(&field.name)?;
+                    facet(&field.name, mapping["mappings"]["properties"][&field.name]["type"].as_str()?, values)
+                })
+                .collect();
+            collections.insert(address.clone(), CollectionFacets { name: config.name.clone(), facets });
+        }
+        Self { index: index.to_string(), collections }
+    }
+}
+
+fn facet(field: &str, mapped_type: &str, values: &TraitValues) -> Option<Facet> {
+    match mapped_type {
+        "keyword" if !values.values.is_empty() => Some(Facet::Terms {
+            field: field.to_string(),
+            values: values.values.keys().cloned().collect(),
+            truncated: values.other > 0,
+        }),
+        "integer" => values.range.map(|(min, max)| Facet::Range { field: field.to_string(), min, max }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models_flexible::{CsvRecord, FlexibleElasticsearchDocument};
+
+    #[test]
+    fn test_facets_follow_mapping_types() {
+        let wildforest = "0xa038c593115f6fcd673f6833e15462b475994879";
+        let config = get_collection_config(wildforest);
+        let mut analyzer = TraitAnalyzer::default();
+        for (id, properties) in [("1", r#"{"rarity":"Rare","type":"Archer","tier":3,"level":5}"#), ("2", r#"{"rarity":"common","tier":1,"level":12}"#)] {
+            analyzer.add(&FlexibleElasticsearchDocument::from_record(CsvRecord {
+                token_address: Some(wildforest.to_string()),
+                token_id: Some(id.to_string()),
+                raw_metadata: Some(format!(r#"{{"properties":{}}}"#, properties)),
+                ..Default::default()
+            }, config.as_deref()));
+        }
+
+        let facets = FacetConfig::generate("nft_tokens", &analyzer);
+        let collection = &facets.collections[wildforest];
+        assert_eq!(collection.name, "Wildforest Units");
+        assert!(collection.facets.contains(&Facet::Terms { field: "rarity".to_string(), values: vec!["common".to_string(), "rare".to_string()], truncated: false }));
+        assert!(collection.facets.contains(&Facet::Range { field: "level".to_string(), min: 5, max: 12 }));
+
+        let json = serde_json::to_value(&facets).unwrap();
+        let json = &json["collections"][wildforest]["facets"];
+        assert_eq!(json[0], serde_json::json!({"type": "range", "field": "tier", "min": 1, "max": 3}));
+        assert!(json[2].get("truncated").is_none());
+    }
+}
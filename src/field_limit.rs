@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+
+use crate::alias::write_index_entry;
+use crate::deprecations::SendNotingWarnings;
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// Elasticsearch's default `index.mapping.total_fields.limit`
+pub const DEFAULT_TOTAL_FIELDS_LIMIT: u64 = 1000;
+
+/// Documents whose dynamically mapped keys add fields to the index mapping
+pub trait DynamicFields {
+    /// Add the dotted paths of dynamically mapped fields to `paths`
+    fn dynamic_field_paths(&self, paths: &mut BTreeSet<String>);
+}
+
+impl DynamicFields for ElasticsearchDocument {
+    // attributes is `flattened` and raw_metadata is disabled: nothing is mapped dynamically
+    fn dynamic_field_paths(&self, _paths: &mut BTreeSet<String>) {}
+}
+
+impl DynamicFields for FlexibleElasticsearchDocument {
+    fn dynamic_field_paths(&self, paths: &mut BTreeSet<String>) {
+        if let Some(properties) = &self.properties {
+            object_paths(properties, "properties", paths);
+        }
+    }
+}
+
+fn object_paths(object: &Map<String, Value>, prefix: &str, paths: &mut BTreeSet<String>) {
+    for (key, value) in object {
+        let path = format!("{}.{}", prefix, key);
+        value_paths(value, &path, paths);
+        paths.insert(path);
+    }
+}
+
+fn value_paths(value: &Value, path: &str, paths: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(inner) => object_paths(inner, path, paths),
+        Value::Array(items) => items.iter().for_each(|item| value_paths(item, path, paths)),
+        _ => {}
+    }
+}
+
+/// Dotted paths of every field in a mapping's `properties`, counted the way
+/// total_fields.limit counts them (objects and multi-fields included)
+pub fn mapping_field_paths(properties: &Value, prefix: &str, paths: &mut BTreeSet<String>) {
+    let Some(properties) = properties.as_object() else {
+        return;
+    };
+    for (name, field) in properties {
+        let path = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+        if let Some(inner) = field.get("properties") {
+            mapping_field_paths(inner, &path, paths);
+        }
+        if let Some(multi_fields) = field.get("fields") {
+            mapping_field_paths(multi_fields, &path, paths);
+        }
+        paths.insert(path);
+    }
+}
+
+/// Estimated number of mapped fields once the run is done
+#[derive(Debug, PartialEq)]
+pub struct FieldEstimate {
+    pub mapped: usize,
+    pub dynamic_new: usize,
+    pub total: usize,
+}
+
+pub fn estimate_fields(mapped: &BTreeSet<String>, dynamic: &BTreeSet<String>) -> FieldEstimate {
+    let dynamic_new = dynamic.difference(mapped).count();
+    FieldEstimate { mapped: mapped.len(), dynamic_new, total: mapped.len() + dynamic_new }
+}
+
+/// Limit to raise to: 25% headroom over the estimate, rounded up to a multiple of 500
+pub fn suggested_limit(estimated_fields: usize) -> u64 {
+    let with_headroom = (estimated_fields as u64 * 5).div_ceil(4);
+    (with_headroom.div_ceil(500) * 500).max(DEFAULT_TOTAL_FIELDS_LIMIT)
+}
+
+/// The existing index's field limit and mapped field paths, or None if it doesn't exist yet
+pub async fn fetch_field_usage(client: &Client, elasticsearch_url: &str, index: &str) -> Result<Option<(u64, BTreeSet<String>)>> {
+    let url = format!("{}/{}/_settings?include_defaults=true&flat_settings=true", elasticsearch_url, index);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to fetch index settings")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to fetch settings of {}: HTTP {}", index, response.status());
+    }
+    let settings: Value = response.json().await?;
+    // Keyed by concrete index; an alias may have several
+    let limit = write_index_entry(&settings)
+        .and_then(|index| {
+            let key = "index.mapping.total_fields.limit";
+            index["settings"][key].as_str().or_else(|| index["defaults"][key].as_str())
+        })
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_TOTAL_FIELDS_LIMIT);
+
+    let url = format!("{}/{}/_mapping", elasticsearch_url, index);
+    let mapping: Value = client.get(&url).send_noting_warnings().await.context("Failed to fetch index mapping")?
+        .json().await.context("Failed to parse index mapping")?;
+    let mut paths = BTreeSet::new();
+    if let Some(index_mapping) = write_index_entry(&mapping) {
+        mapping_field_paths(&index_mapping["mappings"]["properties"], "", &mut paths);
+    }
+
+    Ok(Some((limit, paths)))
+}
+
+/// Raise the (dynamic) total_fields.limit setting of an existing index
+pub async fn raise_field_limit(client: &Client, elasticsearch_url: &str, index: &str, limit: u64) -> Result<()> {
+    let url = format!("{}/{}/_settings", elasticsearch_url, index);
+    let response = client.put(&url)
+        .json(&json!({"index.mapping.total_fields.limit": limit}))
+        .send_noting_warnings()
+        .await
+        .context("Failed to update index settings")?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_default();
+        anyhow::bail!("Failed to raise total_fields.limit of {}: HTTP {} - {}", index, status, error_text);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection_config::{generate_collection_mapping, get_collection_config};
+    use crate::models_flexible::CsvRecord;
+
+    #[test]
+    fn test_mapping_paths_include_objects_and_multi_fields() {
+        let mut paths = BTreeSet::new();
+        mapping_field_paths(&json!({
+            "name": {"type": "text", "fields": {"keyword": {"type": "keyword"}}},
+            "orders": {"type": "nested", "properties": {"price": {"type": "double"}}},
+            "owner": {"type": "keyword"}
+        }), "", &mut paths);
+
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        assert_eq!(paths, vec!["name", "name.keyword", "orders", "orders.price", "owner"]);
+    }
+
+    #[test]
+    fn test_dynamic_properties_counted_once() {
+        let doc = FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_id: Some("1".to_string()),
+            raw_metadata: Some(r#"{"properties":{"tier":1,"stats":{"hp":10,"atk":3}}}"#.to_string()),
+            ..Default::default()
+        }, None);
+        let mut dynamic = BTreeSet::new();
+        doc.dynamic_field_paths(&mut dynamic);
+        assert_eq!(dynamic.len(), 4);
+
+        let config = get_collection_config("0xa038c593115f6fcd673f6833e15462b475994879");
+        let mut mapped = BTreeSet::new();
+        mapping_field_paths(&generate_collection_mapping(config.as_deref())["mappings"]["properties"], "", &mut mapped);
+        let estimate = estimate_fields(&mapped, &dynamic);
+        assert_eq!(estimate.dynamic_new, 4);
+        assert_eq!(estimate.total, mapped.len() + 4);
+    }
+
+    #[test]
+    fn test_suggested_limit_has_headroom() {
+        assert_eq!(suggested_limit(100), 1000);
+        assert_eq!(suggested_limit(950), 1500);
+        assert_eq!(suggested_limit(1600), 2000);
+    }
+}
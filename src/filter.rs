@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use csv::StringRecord;
+use std::cmp::Ordering;
+
+/// A row predicate evaluated on raw CSV values before transformation, e.g.
+/// `state == 'active' && ron_price > 0`.
+///
+/// Supports `==`, `!=`, `<`, `<=`, `>`, `>=` against quoted strings, numbers and
+/// `null` (empty or missing value), combined with `&&`, `||`, `!` and parentheses.
+/// A bare column name is true when the value is non-empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowFilter {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Present(usize),
+    Compare(usize, Op, Literal),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Str(String),
+    Num(f64),
+    Null,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl RowFilter {
+    /// Parse the expression and resolve its column names against the CSV header
+    pub fn compile(expression: &str, headers: &StringRecord) -> Result<Self> {
+        let tokens = tokenize(expression)
+            .with_context(|| format!("Invalid filter expression '{}'", expression))?;
+        let mut parser = Parser { tokens, pos: 0, headers };
+        let expr = parser.parse_or()
+            .with_context(|| format!("Invalid filter expression '{}'", expression))?;
+        if parser.pos < parser.tokens.len() {
+            anyhow::bail!("Invalid filter expression '{}': unexpected {:?}", expression, parser.tokens[parser.pos]);
+        }
+        Ok(Self { expr })
+    }
+
+    pub fn matches(&self, row: &StringRecord) -> bool {
+        eval(&self.expr, row)
+    }
+}
+
+fn eval(expr: &Expr, row: &StringRecord) -> bool {
+    match expr {
+        Expr::Or(a, b) => eval(a, row) || eval(b, row),
+        Expr::And(a, b) => eval(a, row) && eval(b, row),
+        Expr::Not(inner) => !eval(inner, row),
+        Expr::Present(column) => value(row, *column).is_some(),
+        Expr::Compare(column, op, literal) => {
+            let ordering = match (value(row, *column), literal) {
+                (None, Literal::Null) => Some(Ordering::Equal),
+                (None, _) | (Some(_), Literal::Null) => None,
+                (Some(v), Literal::Str(s)) => Some(v.cmp(s.as_str())),
+                (Some(v), Literal::Num(n)) => v.parse::<f64>().ok().and_then(|v| v.partial_cmp(n)),
+            };
+            match op {
+                Op::Eq => ordering == Some(Ordering::Equal),
+                Op::Ne => ordering != Some(Ordering::Equal),
+                Op::Lt => ordering == Some(Ordering::Less),
+                Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                Op::Gt => ordering == Some(Ordering::Greater),
+                Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            }
+        }
+    }
+}
+
+/// Trimmed value of a column; empty and missing values are null
+fn value(row: &StringRecord, column: usize) -> Option<&str> {
+    row.get(column).map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => { tokens.push(Token::Open); i += 1; }
+            ')' => { tokens.push(Token::Close); i += 1; }
+            '&' if next == Some('&') => { tokens.push(Token::And); i += 2; }
+            '|' if next == Some('|') => { tokens.push(Token::Or); i += 2; }
+            '=' if next == Some('=') => { tokens.push(Token::Op(Op::Eq)); i += 2; }
+            '!' if next == Some('=') => { tokens.push(Token::Op(Op::Ne)); i += 2; }
+            '<' if next == Some('=') => { tokens.push(Token::Op(Op::Le)); i += 2; }
+            '>' if next == Some('=') => { tokens.push(Token::Op(Op::Ge)); i += 2; }
+            '!' => { tokens.push(Token::Not); i += 1; }
+            '<' => { tokens.push(Token::Op(Op::Lt)); i += 1; }
+            '>' => { tokens.push(Token::Op(Op::Gt)); i += 1; }
+            '\'' | '"' => {
+                let end = chars[i + 1..].iter().position(|&ch| ch == c)
+                    .with_context(|| format!("unterminated string at position {}", i))?;
+                tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let len = chars[i..].iter()
+                    .take_while(|ch| ch.is_ascii_digit() || matches!(ch, '.' | '-' | 'e' | 'E'))
+                    .count();
+                let text: String = chars[i..i + len].iter().collect();
+                let number = text.parse().with_context(|| format!("invalid number '{}'", text))?;
+                tokens.push(Token::Num(number));
+                i += len;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..].iter().take_while(|ch| ch.is_alphanumeric() || **ch == '_').count();
+                tokens.push(Token::Ident(chars[i..i + len].iter().collect()));
+                i += len;
+            }
+            other => anyhow::bail!("unexpected '{}' at position {}", other, i),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    headers: &'a StringRecord,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            left = Expr::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            left = Expr::And(Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let inner = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => anyhow::bail!("missing ')'"),
+                }
+            }
+            Some(Token::Ident(name)) => {
+                let column = self.headers.iter().position(|h| h == name)
+                    .with_context(|| format!("unknown column '{}'", name))?;
+                let Some(Token::Op(op)) = self.peek().cloned() else {
+                    return Ok(Expr::Present(column));
+                };
+                self.pos += 1;
+                let literal = match self.next() {
+                    Some(Token::Str(s)) => Literal::Str(s),
+                    Some(Token::Num(n)) => Literal::Num(n),
+                    Some(Token::Ident(word)) if word == "null" => Literal::Null,
+                    other => anyhow::bail!("expected a value after {:?}, found {:?}", op, other),
+                };
+                Ok(Expr::Compare(column, op, literal))
+            }
+            other => anyhow::bail!("expected a column, '!' or '(', found {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> StringRecord {
+        StringRecord::from(vec!["token_id", "state", "ron_price", "owner"])
+    }
+
+    fn row(values: &[&str]) -> StringRecord {
+        StringRecord::from(values.to_vec())
+    }
+
+    #[test]
+    fn test_comparisons_and_logic() {
+        let filter = RowFilter::compile("state == 'active' && ron_price > 0", &headers()).unwrap();
+        assert!(filter.matches(&row(&["1", "active", "2.5", "0xab"])));
+        assert!(!filter.matches(&row(&["2", "active", "0", "0xab"])));
+        assert!(!filter.matches(&row(&["3", "inactive", "5", "0xab"])));
+        assert!(!filter.matches(&row(&["4", "active", "", "0xab"])));
+
+        let filter = RowFilter::compile("!(owner == null) || (state != \"active\")", &headers()).unwrap();
+        assert!(filter.matches(&row(&["1", "active", "1", "0xab"])));
+        assert!(filter.matches(&row(&["2", "inactive", "1", ""])));
+        assert!(!filter.matches(&row(&["3", "active", "1", " "])));
+    }
+
+    #[test]
+    fn test_bare_column_checks_presence() {
+        let filter = RowFilter::compile("owner && ron_price <= 10.5", &headers()).unwrap();
+        assert!(filter.matches(&row(&["1", "", "10.5", "0xab"])));
+        assert!(!filter.matches(&row(&["1", "", "11", "0xab"])));
+        assert!(!filter.matches(&row(&["1", "", "1", ""])));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        assert!(RowFilter::compile("price > 0", &headers()).is_err());
+        assert!(RowFilter::compile("state == ", &headers()).is_err());
+        assert!(RowFilter::compile("(state == 'a'", &headers()).is_err());
+        assert!(RowFilter::compile("state == 'a' owner", &headers()).is_err());
+        assert!(RowFilter::compile("state == 'unterminated", &headers()).is_err());
+    }
+}
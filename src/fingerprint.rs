@@ -0,0 +1,121 @@
+//! What a checkpoint remembers about the CSV it was written for, beyond its path:
+//! size, modification time, a hash of its first and last bytes and a hash of the
+//! header row. Resuming against a different export under the same name would skip
+//! the wrong rows, so a mismatch stops the run unless `--force-resume` is given.
+
+use anyhow::Result;
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::source::{input_metadata, open_input};
+
+/// Bytes hashed from each end of the file
+const SAMPLE_BYTES: u64 = 1 << 20;
+
+/// How `differences` words changed header columns
+pub const HEADER_DIFFERENCE: &str = "header columns";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CsvFingerprint {
+    pub len: u64,
+    /// Seconds since the epoch, when the file system reports it and the CSV wasn't moved
+    pub modified: Option<u64>,
+    /// sha256 of the first and last SAMPLE_BYTES of the file
+    pub sample_sha256: String,
+    /// sha256 of the header row, after repairs and COLUMN_MAPPING
+    pub schema_sha256: String,
+}
+
+impl CsvFingerprint {
+    pub fn of(path: &Path, headers: &StringRecord) -> Result<Self> {
+        let (len, modified, sample_sha256) = sample(path)?;
+        let mut schema = Sha256::new();
+        for header in headers {
+            schema.update(header.as_bytes());
+            schema.update([0]);
+        }
+        Ok(Self { len, modified, sample_sha256, schema_sha256: hex(&schema.finalize()) })
+    }
+
+    /// Name the checkpoint is also stored under, so it's found by content wherever
+    /// the CSV is moved
+    pub fn content_key(&self) -> String {
+        content_key(self.len, &self.sample_sha256)
+    }
+
+    /// What differs in `current` from this fingerprint, in words
+    pub fn differences(&self, current: &Self) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.len != current.len {
+            differences.push(format!("size {} -> {} bytes", self.len, current.len));
+        }
+        // Not noted for checkpoints carried over from another path
+        if self.modified.is_some() && self.modified != current.modified {
+            differences.push("modification time".to_string());
+        }
+        if self.sample_sha256 != current.sample_sha256 {
+            differences.push("content".to_string());
+        }
+        if self.schema_sha256 != current.schema_sha256 {
+            differences.push(HEADER_DIFFERENCE.to_string());
+        }
+        differences
+    }
+}
+
+/// `content_key` of the CSV at `path`, without reading its header row
+pub fn content_key_of(path: &Path) -> Result<String> {
+    let (len, _, sample_sha256) = sample(path)?;
+    Ok(content_key(len, &sample_sha256))
+}
+
+fn content_key(len: u64, sample_sha256: &str) -> String {
+    format!("csv-{}-{}", len, &sample_sha256[..16])
+}
+
+/// Size, modification time and sha256 of the first and last SAMPLE_BYTES
+fn sample(path: &Path) -> Result<(u64, Option<u64>, String)> {
+    let path = path.to_string_lossy();
+    let (len, modified) = input_metadata(&path)?;
+    let mut file = open_input(&path)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = Vec::new();
+    (&mut file).take(SAMPLE_BYTES).read_to_end(&mut buffer)?;
+    if len > SAMPLE_BYTES {
+        file.seek(SeekFrom::Start(len.saturating_sub(SAMPLE_BYTES).max(SAMPLE_BYTES)))?;
+        file.read_to_end(&mut buffer)?;
+    }
+    hasher.update(&buffer);
+    Ok((len, modified, hex(&hasher.finalize())))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_csv_detected() {
+        let path = std::env::temp_dir().join(format!("fingerprint-test-{}.csv", std::process::id()));
+        let headers = StringRecord::from(vec!["token_address", "token_id"]);
+        std::fs::write(&path, "token_address,token_id\n0xabc,1\n").unwrap();
+        let original = CsvFingerprint::of(&path, &headers).unwrap();
+        assert!(original.differences(&CsvFingerprint::of(&path, &headers).unwrap()).is_empty());
+
+        let renamed = StringRecord::from(vec!["token_address", "id"]);
+        assert_eq!(original.differences(&CsvFingerprint::of(&path, &renamed).unwrap()), vec!["header columns"]);
+
+        // Same size, other rows
+        std::fs::write(&path, "token_address,token_id\n0xabc,2\n").unwrap();
+        let changed = CsvFingerprint::of(&path, &headers).unwrap();
+        assert!(original.differences(&changed).contains(&"content".to_string()));
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,129 @@
+//! Property tests for the metadata parsers.
+//!
+//! raw_metadata and attributes come from arbitrary third-party token URIs, so the
+//! parsers are fed generated JSON, arbitrary strings and pathological shapes
+//! (deep nesting, huge objects) and must neither panic nor blow up.
+
+use proptest::prelude::*;
+use serde_json::{json, Map, Value};
+use std::time::{Duration, Instant};
+
+use crate::collection_config::{extract_typed_value, get_collection_config, FieldType};
+use crate::models::{parse_attributes, parse_raw_metadata};
+use crate::models_flexible::{parse_raw_metadata_struct, parse_raw_metadata_value, CsvRecord, FlexibleElasticsearchDocument};
+
+const WILDFOREST: &str = "0xa038c593115f6fcd673f6833e15462b475994879";
+
+/// Arbitrary JSON, including integers at the i64/u64 edges and non-ASCII keys
+fn arb_json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        // Dyadic fractions print and parse back exactly
+        any::<i32>().prop_map(|n| Value::from(n as f64 / 8.0)),
+        "\\PC{0,20}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(6, 64, 8, |inner| prop_oneof![
+        prop::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+        prop::collection::btree_map("\\PC{0,10}", inner, 0..8).prop_map(|m| Value::Object(m.into_iter().collect())),
+    ])
+}
+
+fn arb_field_type() -> impl Strategy<Value = FieldType> {
+    prop_oneof![Just(FieldType::Integer), Just(FieldType::Keyword), Just(FieldType::Text)]
+}
+
+proptest! {
+    #[test]
+    fn prop_arbitrary_strings_never_panic(input in "\\PC*") {
+        let input = Some(input);
+        parse_raw_metadata_struct(&input);
+        parse_raw_metadata_value(&input);
+        parse_raw_metadata(&input);
+        parse_attributes(&input);
+    }
+
+    #[test]
+    fn prop_valid_json_round_trips(value in arb_json()) {
+        let input = Some(value.to_string());
+        prop_assert_eq!(parse_raw_metadata_value(&input), Some(value.clone()));
+        prop_assert_eq!(parse_raw_metadata(&input), Some(value.clone()));
+
+        let parsed = parse_raw_metadata_struct(&input);
+        if let Some(Value::Object(properties)) = value.get("properties") {
+            if let Some(parsed) = parsed {
+                prop_assert_eq!(parsed.properties.as_ref(), Some(properties));
+            }
+        }
+    }
+
+    #[test]
+    fn prop_attributes_flatten_first_array_element(attributes in prop::collection::btree_map("\\PC{0,10}", arb_json(), 0..16)) {
+        let input = Some(Value::Object(attributes.clone().into_iter().collect()).to_string());
+        let flattened = parse_attributes(&input).unwrap();
+
+        prop_assert_eq!(flattened.len(), attributes.len());
+        for (key, value) in &attributes {
+            let expected = match value {
+                Value::Array(items) if !items.is_empty() => &items[0],
+                other => other,
+            };
+            prop_assert_eq!(&flattened[key], expected);
+        }
+    }
+
+    #[test]
+    fn prop_typed_values_have_their_type(value in arb_json(), field_type in arb_field_type()) {
+        match (extract_typed_value(&value, &field_type), field_type) {
+            (None, _) => {}
+            (Some(typed), FieldType::Integer) => prop_assert!(typed.is_i64()),
+            (Some(typed), FieldType::Keyword) => {
+                let keyword = typed.as_str().unwrap();
+                prop_assert_eq!(keyword, keyword.to_lowercase());
+            }
+            (Some(typed), FieldType::Text) => prop_assert!(typed.is_string()),
+        }
+    }
+
+    #[test]
+    fn prop_documents_build_from_any_metadata(properties in arb_json(), name in arb_json()) {
+        let config = get_collection_config(WILDFOREST);
+        let raw_metadata = json!({"name": name, "properties": properties});
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_address: Some(WILDFOREST.to_string()),
+            token_id: Some("1".to_string()),
+            raw_metadata: Some(raw_metadata.to_string()),
+            attributes: Some(properties.to_string()),
+            ..Default::default()
+        }, config.as_deref());
+    }
+}
+
+#[test]
+fn test_deep_nesting_rejected_without_overflow() {
+    for depth in [100, 1_000, 100_000] {
+        let nested = format!("{{\"properties\":{}{}}}", "[".repeat(depth), "]".repeat(depth));
+        let input = Some(nested);
+        parse_raw_metadata_struct(&input);
+        parse_raw_metadata_value(&input);
+        parse_attributes(&input);
+    }
+}
+
+#[test]
+fn test_large_metadata_parses_quickly() {
+    let properties: Map<String, Value> = (0..200_000)
+        .map(|i| (format!("trait_{}", i), json!([format!("value {}", i)])))
+        .collect();
+    let input = Some(json!({"name": "Big", "properties": properties}).to_string());
+
+    let started = Instant::now();
+    let parsed = parse_raw_metadata_struct(&input).unwrap();
+    parse_raw_metadata_value(&input).unwrap();
+    parse_attributes(&Some(Value::Object(properties).to_string())).unwrap();
+
+    assert_eq!(parsed.properties.unwrap().len(), 200_000);
+    assert!(started.elapsed() < Duration::from_secs(20), "took {:?}", started.elapsed());
+}
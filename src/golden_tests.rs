@@ -0,0 +1,82 @@
+//! Golden-file tests for the CSV → document transformation.
+//!
+//! Every `tests/golden/<name>.csv` is transformed the way a run would (collection
+//! config lookup, extraction, payment token annotation) and compared with
+//! `tests/golden/<name>.ndjson`, one document per line. After an intended change
+//! to the transformation, regenerate the expected files with
+//! `UPDATE_GOLDEN=1 cargo test golden` and review the diff like any other code.
+//! Documents are written through `serde_json::Value`, whose objects keep their keys
+//! sorted, so those diffs only show changes.
+
+use csv::ReaderBuilder;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::collection_config::get_collection_config;
+use crate::models_flexible::{CsvRecord, FlexibleElasticsearchDocument};
+use crate::payment_tokens::PaymentTokenRegistry;
+
+const PAYMENT_TOKENS: &str = "0xc99a6a985ed2cac1ef41640596c5a5f9f4e19ef5=WETH,0xe514d9deb7966c8be0ca922de8a064264ea6bcd4=WRON";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn transform(csv_path: &Path) -> Vec<String> {
+    let mut reader = ReaderBuilder::new().has_headers(true).from_path(csv_path).unwrap();
+    let source_file = csv_path.file_name().map(|name| name.to_string_lossy().into_owned());
+    let headers = reader.headers().unwrap().clone();
+    let mut documents = Vec::new();
+    for result in reader.records() {
+        let row = result.unwrap();
+        let record: CsvRecord = row.deserialize(Some(&headers)).unwrap();
+        let config = record.token_address.as_deref().and_then(get_collection_config);
+        let mut doc = FlexibleElasticsearchDocument::from_record(record, config.as_deref());
+        doc.source_file = source_file.clone();
+        doc.source_row = row.position().map(|p| p.line());
+        documents.push(doc);
+    }
+    PaymentTokenRegistry::parse(PAYMENT_TOKENS).unwrap().annotate(&mut documents);
+    documents.iter().map(|doc| serde_json::to_value(doc).unwrap().to_string()).collect()
+}
+
+#[test]
+fn test_golden_transforms() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut fixtures: Vec<PathBuf> = std::fs::read_dir(golden_dir()).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "csv"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "no fixtures in {}", golden_dir().display());
+
+    let mut mismatches = Vec::new();
+    for csv_path in &fixtures {
+        let actual = transform(csv_path);
+        let golden_path = csv_path.with_extension("ndjson");
+        if update {
+            std::fs::write(&golden_path, actual.join("\n") + "\n").unwrap();
+            continue;
+        }
+
+        let expected = std::fs::read_to_string(&golden_path)
+            .unwrap_or_else(|_| panic!("missing {}, run with UPDATE_GOLDEN=1", golden_path.display()));
+        let expected: Vec<&str> = expected.lines().collect();
+        if expected.len() != actual.len() {
+            mismatches.push(format!("{}: {} documents, expected {}", csv_path.display(), actual.len(), expected.len()));
+            continue;
+        }
+        for (line, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+            let expected: Value = serde_json::from_str(expected).unwrap();
+            let actual: Value = serde_json::from_str(actual).unwrap();
+            if expected != actual {
+                mismatches.push(format!(
+                    "{} document {}:\n  expected: {}\n  actual:   {}",
+                    golden_path.display(), line + 1, expected, actual
+                ));
+            }
+        }
+    }
+
+    assert!(mismatches.is_empty(), "golden files differ (UPDATE_GOLDEN=1 regenerates them):\n{}", mismatches.join("\n"));
+}
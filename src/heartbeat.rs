@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// A bulk request a worker is waiting on
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    batch_num: usize,
+    started: Instant,
+}
+
+/// One worker's line in a heartbeat
+#[derive(Debug, PartialEq)]
+pub struct WorkerStatus {
+    pub worker: usize,
+    pub batch_num: usize,
+    pub elapsed: Duration,
+    pub stalled: bool,
+}
+
+/// Tracks which bulk request each worker is in, so slow clusters can be told
+/// apart from hung workers. Workers take the lowest free slot number while
+/// their request runs.
+#[derive(Default)]
+pub struct Heartbeats {
+    in_flight: Mutex<BTreeMap<usize, InFlight>>,
+}
+
+/// Frees the worker slot when the bulk request finishes
+pub struct HeartbeatGuard {
+    heartbeats: Arc<Heartbeats>,
+    worker: usize,
+}
+
+impl Drop for HeartbeatGuard {
+    fn drop(&mut self) {
+        self.heartbeats.in_flight.lock().unwrap().remove(&self.worker);
+    }
+}
+
+impl Heartbeats {
+    /// Mark the start of a bulk request for a batch
+    pub fn start(self: &Arc<Self>, batch_num: usize) -> HeartbeatGuard {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let worker = (0..).find(|slot| !in_flight.contains_key(slot)).unwrap();
+        in_flight.insert(worker, InFlight { batch_num, started: Instant::now() });
+        HeartbeatGuard { heartbeats: self.clone(), worker }
+    }
+
+    /// Every in-flight request, flagged when it has run longer than `stall_threshold`
+    pub fn status(&self, now: Instant, stall_threshold: Duration) -> Vec<WorkerStatus> {
+        self.in_flight.lock().unwrap().iter()
+            .map(|(&worker, request)| {
+                let elapsed = now.saturating_duration_since(request.started);
+                WorkerStatus { worker, batch_num: request.batch_num, elapsed, stalled: elapsed > stall_threshold }
+            })
+            .collect()
+    }
+
+    /// Log a heartbeat line per worker every `interval`
+    pub fn spawn(self: &Arc<Self>, interval: Duration, stall_threshold: Duration) -> JoinHandle<()> {
+        let heartbeats = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let status = heartbeats.status(Instant::now(), stall_threshold);
+                if status.is_empty() {
+                    info!("💓 No bulk requests in flight");
+                }
+                for worker in status {
+                    if worker.stalled {
+                        warn!("⚠️  Worker {} stalled: batch {} has been in its bulk request for {:.1}s (threshold {}s)",
+                                 worker.worker, worker.batch_num, worker.elapsed.as_secs_f64(), stall_threshold.as_secs());
+                    } else {
+                        info!("💓 Worker {}: batch {}, {:.1}s in bulk request", worker.worker, worker.batch_num, worker.elapsed.as_secs_f64());
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_reused_and_stalls_flagged() {
+        let heartbeats = Arc::new(Heartbeats::default());
+        let first = heartbeats.start(0);
+        let second = heartbeats.start(1);
+        drop(first);
+        let _third = heartbeats.start(2);
+
+        let later = Instant::now() + Duration::from_secs(5);
+        let status = heartbeats.status(later, Duration::from_secs(60));
+        assert_eq!(status.iter().map(|w| (w.worker, w.batch_num)).collect::<Vec<_>>(), vec![(0, 2), (1, 1)]);
+        assert!(status.iter().all(|w| !w.stalled));
+
+        let status = heartbeats.status(later, Duration::from_secs(2));
+        assert!(status.iter().all(|w| w.stalled));
+
+        drop(second);
+        assert_eq!(heartbeats.status(later, Duration::from_secs(60)).len(), 1);
+    }
+}
@@ -0,0 +1,149 @@
+//! Shape of the documents written: histograms of their bulk size, name length and
+//! number of properties keys, for the `summary` event. They show whether an index
+//! is bloated by a few huge documents or by all of them, and where a truncation
+//! or offload threshold would bite.
+
+use serde::Serialize;
+
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// Powers of two up to 2^40 cover any size a bulk request accepts
+const BUCKETS: usize = 41;
+
+/// Counts of values in power-of-two buckets: bucket i holds values up to 2^i
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { counts: [0; BUCKETS], count: 0, sum: 0, max: 0 }
+    }
+}
+
+/// One non-empty bucket
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Bucket {
+    /// Upper bound, inclusive
+    pub le: u64,
+    pub count: u64,
+}
+
+/// A histogram as reported; percentiles are the upper bound of their bucket
+#[derive(Debug, Serialize, PartialEq)]
+pub struct HistogramReport {
+    pub count: u64,
+    pub mean: f64,
+    pub max: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub buckets: Vec<Bucket>,
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: u64) {
+        let bucket = (value.max(1).next_power_of_two().trailing_zeros() as usize).min(BUCKETS - 1);
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    fn percentile(&self, fraction: f64) -> u64 {
+        let rank = (self.count as f64 * fraction).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return (1u64 << bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn report(&self) -> HistogramReport {
+        HistogramReport {
+            count: self.count,
+            mean: if self.count == 0 { 0.0 } else { self.sum as f64 / self.count as f64 },
+            max: self.max,
+            p50: self.percentile(0.5),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+            buckets: self.counts.iter().enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(bucket, count)| Bucket { le: 1 << bucket, count: *count })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DocumentShapes {
+    bulk_bytes: Histogram,
+    name_chars: Histogram,
+    properties_keys: Histogram,
+}
+
+/// `document_shapes` of the summary
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DocumentShapesReport {
+    /// Bytes each document adds to a bulk request, action line included
+    pub bulk_bytes: HistogramReport,
+    /// Of documents with a name
+    pub name_chars: HistogramReport,
+    /// Of documents with properties
+    pub properties_keys: HistogramReport,
+}
+
+impl DocumentShapes {
+    pub fn add(&mut self, doc: &FlexibleElasticsearchDocument, bulk_bytes: usize) {
+        self.bulk_bytes.add(bulk_bytes as u64);
+        if let Some(name) = &doc.name {
+            self.name_chars.add(name.chars().count() as u64);
+        }
+        if let Some(properties) = &doc.properties {
+            self.properties_keys.add(properties.len() as u64);
+        }
+    }
+
+    pub fn report(&self) -> DocumentShapesReport {
+        DocumentShapesReport {
+            bulk_bytes: self.bulk_bytes.report(),
+            name_chars: self.name_chars.report(),
+            properties_keys: self.properties_keys.report(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_and_percentiles() {
+        let mut histogram = Histogram::default();
+        for value in [0, 1, 2, 3, 900, 1000, 1024, 1025] {
+            histogram.add(value);
+        }
+        (0..92).for_each(|_| histogram.add(5));
+        let report = histogram.report();
+        assert_eq!(report.count, 100);
+        assert_eq!(report.max, 1025);
+        assert_eq!(report.buckets, vec![
+            Bucket { le: 1, count: 2 },
+            Bucket { le: 2, count: 1 },
+            Bucket { le: 4, count: 1 },
+            Bucket { le: 8, count: 92 },
+            Bucket { le: 1024, count: 3 },
+            Bucket { le: 2048, count: 1 },
+        ]);
+        assert_eq!(report.p50, 8);
+        assert_eq!(report.p99, 1024);
+        assert_eq!(Histogram::default().report().p50, 0);
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+
+/// Token ids selected for reprocessing with `--ids-file`
+#[derive(Debug, Default)]
+pub struct IdSelection {
+    ids: HashSet<String>,
+    found: HashSet<String>,
+}
+
+impl IdSelection {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read ids file {}", path))?;
+        Ok(Self::parse(&content))
+    }
+
+    /// One id per line; blank lines and `#` comments are ignored
+    pub fn parse(content: &str) -> Self {
+        let ids = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        Self { ids, found: HashSet::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Whether a row with this token id should be processed; remembers ids seen
+    pub fn select(&mut self, token_id: &str) -> bool {
+        let token_id = token_id.trim();
+        if !self.ids.contains(token_id) {
+            return false;
+        }
+        if !self.found.contains(token_id) {
+            self.found.insert(token_id.to_string());
+        }
+        true
+    }
+
+    /// Selected ids that no CSV row had, sorted
+    pub fn missing(&self) -> Vec<&str> {
+        let mut missing: Vec<&str> = self.ids.difference(&self.found).map(String::as_str).collect();
+        missing.sort_unstable();
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection_and_missing_ids() {
+        let mut selection = IdSelection::parse("# broken after verify\n409192\n\n 1647694 \n77\n");
+        assert_eq!(selection.len(), 3);
+
+        assert!(selection.select("409192"));
+        assert!(selection.select(" 1647694"));
+        assert!(!selection.select("5"));
+        assert_eq!(selection.missing(), vec!["77"]);
+    }
+}
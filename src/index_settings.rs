@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+const ALLOCATION_PREFIX: &str = "index.routing.allocation.";
+
+/// Per-index allocation settings (data tiers, node attributes) merged into the
+/// settings block of indices the migrator creates, read from INDEX_SETTINGS_FILE
+#[derive(Debug, Default)]
+pub struct IndexSettings {
+    /// (index pattern, flat settings) in file order; later tables win
+    entries: Vec<(String, Map<String, Value>)>,
+}
+
+impl IndexSettings {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(Path::new(path))
+            .with_context(|| format!("Failed to read index settings file {}", path))?;
+        Self::parse(&content).with_context(|| format!("Invalid index settings file {}", path))
+    }
+
+    /// Tables are index names (`*` matches any run of characters), keys are
+    /// `index.routing.allocation.*` settings (the `index.` prefix is optional)
+    pub fn parse(content: &str) -> Result<Self> {
+        let tables: toml::Table = content.parse().context("Invalid TOML")?;
+        let mut entries = Vec::new();
+        for (pattern, table) in tables {
+            let table = table.as_table()
+                .with_context(|| format!("'{}' must be a table of settings", pattern))?;
+            let mut settings = Map::new();
+            for (key, value) in table {
+                let key = if key.starts_with("index.") { key.clone() } else { format!("index.{}", key) };
+                if !key.starts_with(ALLOCATION_PREFIX) {
+                    anyhow::bail!("[{}] {}: only index.routing.allocation.* settings are supported", pattern, key);
+                }
+                let value = match value {
+                    toml::Value::String(s) => Value::String(s.clone()),
+                    toml::Value::Integer(_) | toml::Value::Boolean(_) => Value::String(value.to_string()),
+                    _ => anyhow::bail!("[{}] {} must be a string, integer or boolean", pattern, key),
+                };
+                settings.insert(key, value);
+            }
+            entries.push((pattern, settings));
+        }
+        Ok(Self { entries })
+    }
+
+    /// Settings of every table whose pattern matches the index
+    pub fn settings_for(&self, index: &str) -> Map<String, Value> {
+        let mut settings = Map::new();
+        for (pattern, entry) in &self.entries {
+            if matches_pattern(pattern, index) {
+                settings.extend(entry.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+        }
+        settings
+    }
+
+    /// Index body with the index's allocation settings added to its settings block
+    pub fn apply(&self, index: &str, body: &Value) -> Value {
+        let mut body = body.clone();
+        let settings = self.settings_for(index);
+        if !settings.is_empty() {
+            if !body["settings"].is_object() {
+                body["settings"] = Value::Object(Map::new());
+            }
+            body["settings"].as_object_mut().unwrap().extend(settings);
+        }
+        body
+    }
+}
+
+/// Glob match where `*` stands for any (possibly empty) run of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SETTINGS: &str = r#"
+["nft_orders_20*"]
+"index.routing.allocation.include._tier_preference" = "data_warm,data_hot"
+"routing.allocation.total_shards_per_node" = 2
+
+["nft_orders_2024*"]
+"index.routing.allocation.include._tier_preference" = "data_hot"
+"#;
+
+    #[test]
+    fn test_patterns_matched_in_order() {
+        let settings = IndexSettings::parse(SETTINGS).unwrap();
+
+        let old = settings.settings_for("nft_orders_2021");
+        assert_eq!(old["index.routing.allocation.include._tier_preference"], "data_warm,data_hot");
+        assert_eq!(old["index.routing.allocation.total_shards_per_node"], "2");
+
+        let recent = settings.settings_for("nft_orders_2024_q1");
+        assert_eq!(recent["index.routing.allocation.include._tier_preference"], "data_hot");
+        assert!(settings.settings_for("owners_summary").is_empty());
+
+        // File order, not name order: a catch-all written last overrides the tables above it
+        let settings = IndexSettings::parse(&format!("{}\n[\"nft_*\"]\n\"routing.allocation.total_shards_per_node\" = 1", SETTINGS)).unwrap();
+        let recent = settings.settings_for("nft_orders_2024_q1");
+        assert_eq!(recent["index.routing.allocation.include._tier_preference"], "data_hot");
+        assert_eq!(recent["index.routing.allocation.total_shards_per_node"], "1");
+    }
+
+    #[test]
+    fn test_settings_added_to_body() {
+        let settings = IndexSettings::parse(SETTINGS).unwrap();
+        let body = json!({"settings": {"number_of_shards": 1}, "mappings": {}});
+
+        let applied = settings.apply("nft_orders_2022", &body);
+        assert_eq!(applied["settings"]["number_of_shards"], 1);
+        assert_eq!(applied["settings"]["index.routing.allocation.include._tier_preference"], "data_warm,data_hot");
+        assert_eq!(settings.apply("nfts", &body), body);
+    }
+
+    #[test]
+    fn test_non_allocation_settings_rejected() {
+        assert!(IndexSettings::parse("[nfts]\n\"index.number_of_replicas\" = 0").is_err());
+        assert!(IndexSettings::parse("nfts = \"warm\"").is_err());
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("nfts", "nfts"));
+        assert!(!matches_pattern("nfts", "nfts_2021"));
+        assert!(matches_pattern("*_orders_*", "nft_orders_2021"));
+        assert!(matches_pattern("nft*2021", "nft_orders_2021"));
+        assert!(!matches_pattern("nft*2021", "nft_orders_2022"));
+        assert!(matches_pattern("*", "anything"));
+    }
+}
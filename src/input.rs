@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use tracing::{info, warn};
+
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// What was found (and fixed) at the start of the CSV input
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputFormat {
+    /// Number of UTF-8 byte order marks stripped (Windows exports, re-saved files)
+    pub boms_stripped: usize,
+    /// Lines end in CRLF; the CSV reader treats them like LF
+    pub crlf: bool,
+}
+
+impl InputFormat {
+    pub fn print(&self) {
+        if self.boms_stripped > 0 {
+            warn!("⚠️  Stripped UTF-8 byte order mark from the start of the CSV");
+        }
+        if self.crlf {
+            info!("ℹ️  CSV uses CRLF line endings");
+        }
+    }
+}
+
+/// Where a CSV row starts, noted while reading so a later pass can seek to it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RowOffset {
+    /// Record index of the row (0 is the first row after the header)
+    pub index: usize,
+    /// Byte offset as the CSV reader counts it, after stripped byte order marks
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+}
+
+impl RowOffset {
+    pub fn new(index: usize, position: &csv::Position) -> Self {
+        Self { index, byte: position.byte(), line: position.line(), record: position.record() }
+    }
+}
+
+/// Continue `reader` at the row `offset` points to, skipping the rows before it
+/// without reading them
+pub fn seek_to_row<R: Read + Seek>(reader: &mut csv::Reader<R>, format: &InputFormat, offset: &RowOffset) -> Result<()> {
+    let mut position = csv::Position::new();
+    position.set_byte(offset.byte).set_line(offset.line).set_record(offset.record);
+    let boms = (format.boms_stripped * UTF8_BOM.len()) as u64;
+    reader.seek_raw(SeekFrom::Start(boms + offset.byte), position).context("Failed to seek in the CSV")
+}
+
+/// Strip UTF-8 byte order marks and detect line endings, rejecting UTF-16 input
+pub fn prepare_input<R: Read>(input: R) -> Result<(BufReader<R>, InputFormat)> {
+    let mut reader = BufReader::new(input);
+    let mut format = InputFormat::default();
+
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.starts_with(UTF8_BOM) {
+            reader.consume(UTF8_BOM.len());
+            format.boms_stripped += 1;
+        } else if buffer.starts_with(b"\xFF\xFE") || buffer.starts_with(b"\xFE\xFF") {
+            anyhow::bail!("CSV is UTF-16 encoded, re-export it as UTF-8");
+        } else {
+            break;
+        }
+    }
+
+    let buffer = reader.fill_buf()?;
+    if let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+        format.crlf = newline > 0 && buffer[newline - 1] == b'\r';
+    }
+
+    Ok((reader, format))
+}
+
+/// A gzip-compressed input (`*.csv.gz`), read and sought in decompressed bytes, so
+/// row offsets work as they do for plain files. Seeking decompresses up to the
+/// target, from the start when it lies behind.
+pub struct GzipInput<R: Read + Seek> {
+    /// Only None while being replaced
+    decoder: Option<MultiGzDecoder<BufReader<R>>>,
+    position: u64,
+}
+
+impl<R: Read + Seek> GzipInput<R> {
+    pub fn new(input: R) -> Self {
+        Self { decoder: Some(MultiGzDecoder::new(BufReader::new(input))), position: 0 }
+    }
+
+    fn decoder(&mut self) -> &mut MultiGzDecoder<BufReader<R>> {
+        self.decoder.as_mut().expect("gzip decoder is only taken while rewinding")
+    }
+
+    fn rewind_input(&mut self) -> std::io::Result<()> {
+        let mut input = self.decoder.take().expect("gzip decoder is only taken while rewinding").into_inner();
+        let rewound = input.rewind();
+        self.decoder = Some(MultiGzDecoder::new(input));
+        self.position = 0;
+        rewound
+    }
+}
+
+impl<R: Read + Seek> Read for GzipInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.decoder().read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for GzipInput<R> {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Can't seek from the end of gzip input")),
+        };
+        let target = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the input"))?;
+        if target < self.position {
+            self.rewind_input()?;
+        }
+        let skip = target - self.position;
+        let skipped = std::io::copy(&mut self.by_ref().take(skip), &mut std::io::sink())?;
+        if skipped < skip {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Seek past the end of gzip input"));
+        }
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(input: &[u8]) -> (String, InputFormat) {
+        let (mut reader, format) = prepare_input(input).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        (content, format)
+    }
+
+    #[test]
+    fn test_bom_and_crlf_detected() {
+        let (content, format) = read_all(b"\xEF\xBB\xBF\xEF\xBB\xBFtoken_address,token_id\r\n0xab,1\r\n");
+        assert_eq!(content, "token_address,token_id\r\n0xab,1\r\n");
+        assert_eq!(format, InputFormat { boms_stripped: 2, crlf: true });
+    }
+
+    #[test]
+    fn test_plain_input_untouched() {
+        let (content, format) = read_all(b"token_address,token_id\n0xab,1\n");
+        assert_eq!(content, "token_address,token_id\n0xab,1\n");
+        assert_eq!(format, InputFormat::default());
+    }
+
+    #[test]
+    fn test_seek_to_noted_row() {
+        let input = b"\xEF\xBB\xBFtoken_address,token_id\n0xab,1\n0xab,2\n0xab,3\n".to_vec();
+        let (buffered, format) = prepare_input(std::io::Cursor::new(input.clone())).unwrap();
+        let mut reader = csv::Reader::from_reader(buffered);
+        let offsets: Vec<RowOffset> = reader.records().enumerate()
+            .map(|(index, row)| RowOffset::new(index, row.unwrap().position().unwrap()))
+            .collect();
+
+        let (buffered, _) = prepare_input(std::io::Cursor::new(input)).unwrap();
+        let mut reader = csv::Reader::from_reader(buffered);
+        seek_to_row(&mut reader, &format, &offsets[1]).unwrap();
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][1], "2");
+        assert_eq!(rows[0].position().unwrap().line(), 3);
+        assert_eq!(reader.headers().unwrap().get(1), Some("token_id"));
+    }
+
+    #[test]
+    fn test_gzip_input_seeks_in_decompressed_bytes() {
+        let content = b"token_address,token_id\n0xab,1\n0xab,2\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, content).unwrap();
+        let mut input = GzipInput::new(std::io::Cursor::new(encoder.finish().unwrap()));
+
+        let mut read = String::new();
+        input.read_to_string(&mut read).unwrap();
+        assert_eq!(read.as_bytes(), content);
+        assert_eq!(input.seek(SeekFrom::Start(30)).unwrap(), 30);
+        read.clear();
+        input.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "0xab,2\n");
+        assert!(input.seek(SeekFrom::Start(100)).is_err());
+    }
+
+    #[test]
+    fn test_utf16_rejected() {
+        assert!(prepare_input(&b"\xFF\xFEt\x00o\x00"[..]).is_err());
+    }
+}
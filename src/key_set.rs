@@ -0,0 +1,150 @@
+//! Set of 64-bit checkpoint keys (`record_key()` of document ids). The hashes are
+//! spread over the whole range, which leaves a roaring bitmap one container per
+//! key; sorted and delta-encoded they take about 7 bytes each in the checkpoint.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeySet(BTreeSet<u64>);
+
+impl KeySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.0.contains(&key)
+    }
+
+    /// Returns whether the key was new
+    pub fn insert(&mut self, key: u64) -> bool {
+        self.0.insert(key)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Number of keys in both sets
+    pub fn intersection_len(&self, other: &KeySet) -> u64 {
+        let (small, large) = if self.0.len() <= other.0.len() { (self, other) } else { (other, self) };
+        small.iter().filter(|key| large.contains(*key)).count() as u64
+    }
+
+    pub fn union_with(&mut self, other: KeySet) {
+        self.0.extend(other.0);
+    }
+
+    /// Sorted keys as LEB128 varints of the difference to the previous key
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.0.len() * 7);
+        let mut previous = 0;
+        for &key in &self.0 {
+            let mut delta = key - previous;
+            previous = key;
+            while delta >= 0x80 {
+                bytes.push(delta as u8 | 0x80);
+                delta >>= 7;
+            }
+            bytes.push(delta as u8);
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        let mut previous: u64 = 0;
+        let mut delta: u64 = 0;
+        let mut shift = 0;
+        for &byte in bytes {
+            // The tenth byte may only carry the top bit
+            if shift > 63 || (shift == 63 && byte & 0x7e != 0) {
+                return Err("key delta overflows 64 bits".to_string());
+            }
+            delta |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 != 0 {
+                shift += 7;
+                continue;
+            }
+            previous = previous.checked_add(delta).ok_or("key overflows 64 bits")?;
+            keys.push(previous);
+            delta = 0;
+            shift = 0;
+        }
+        if shift != 0 {
+            return Err("truncated key delta".to_string());
+        }
+        // Ascending already, which BTreeSet builds from in linear time
+        Ok(Self(keys.into_iter().collect()))
+    }
+}
+
+impl FromIterator<u64> for KeySet {
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Extend<u64> for KeySet {
+    fn extend<I: IntoIterator<Item = u64>>(&mut self, iter: I) {
+        self.0.extend(iter);
+    }
+}
+
+/// Base64 of the delta encoding, a single string in the JSON checkpoint
+impl Serialize for KeySet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(self.encode()))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = STANDARD.decode(encoded).map_err(de::Error::custom)?;
+        Self::decode(&bytes).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::record_key;
+    use roaring::RoaringTreemap;
+
+    #[test]
+    fn test_round_trip() {
+        let keys = KeySet::from_iter([0, 1, 127, 128, 1 << 40, u64::MAX]);
+        let json = serde_json::to_string(&keys).unwrap();
+        assert_eq!(serde_json::from_str::<KeySet>(&json).unwrap(), keys);
+        assert!(KeySet::decode(&[0x80]).is_err());
+        let mut max = vec![0xff; 9];
+        max.push(0x01);
+        assert_eq!(KeySet::decode(&max).unwrap(), KeySet::from_iter([u64::MAX]));
+        *max.last_mut().unwrap() = 0x02;
+        assert!(KeySet::decode(&max).is_err());
+        assert_eq!(keys.intersection_len(&KeySet::from_iter([1, 2, u64::MAX])), 2);
+    }
+
+    #[test]
+    fn test_million_keys_stay_compact() {
+        let keys: KeySet = (0..1_000_000).map(|id| record_key(&format!("0xa038c593115f6fcd673f6833e15462b475994879:{}", id))).collect();
+        let json = serde_json::to_string(&keys).unwrap();
+        // About 7 bytes a key before base64, a third of the roaring bitmap's
+        assert!(json.len() < 10_000_000, "{} bytes", json.len());
+        let bitmap = RoaringTreemap::from_iter(keys.iter());
+        assert!(bitmap.serialized_size() > 2 * json.len());
+        assert_eq!(serde_json::from_str::<KeySet>(&json).unwrap().len(), 1_000_000);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Which pass over the CSV a document is sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lane {
+    /// Rows matching PRIORITY_FILTER, sent first
+    Priority,
+    /// Everything else (all rows without a priority filter)
+    #[default]
+    Rest,
+}
+
+impl Lane {
+    fn slot(self) -> usize {
+        match self {
+            Lane::Priority => 0,
+            Lane::Rest => 1,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lane::Priority => "priority",
+            Lane::Rest => "remaining",
+        }
+    }
+}
+
+/// Records sent and indexed per lane, so the priority lane's completion can be
+/// reported while the rest is still running
+pub struct LaneProgress {
+    sent: [AtomicU64; 2],
+    done: [AtomicU64; 2],
+    closed: [AtomicBool; 2],
+    started: Instant,
+    finished: Mutex<[Option<Duration>; 2]>,
+}
+
+impl Default for LaneProgress {
+    fn default() -> Self {
+        Self {
+            sent: Default::default(),
+            done: Default::default(),
+            closed: Default::default(),
+            started: Instant::now(),
+            finished: Mutex::new([None; 2]),
+        }
+    }
+}
+
+/// One lane's line in the summary
+#[derive(Debug, PartialEq)]
+pub struct LaneStatus {
+    pub lane: Lane,
+    pub done: u64,
+    pub sent: u64,
+    /// Time from the start of the run until the lane was fully indexed
+    pub finished: Option<Duration>,
+}
+
+impl LaneProgress {
+    /// A batch of `records` rows was handed to the workers
+    pub fn add_sent(&self, lane: Lane, records: u64) {
+        self.sent[lane.slot()].fetch_add(records, Ordering::Relaxed);
+    }
+
+    /// Every batch of the lane has been handed to the workers. Returns the lane's
+    /// elapsed time if its batches were all indexed already.
+    pub fn close(&self, lane: Lane) -> Option<Duration> {
+        self.closed[lane.slot()].store(true, Ordering::Release);
+        self.check_finished(lane)
+    }
+
+    /// A batch of `records` rows was indexed. Returns the lane's elapsed time when
+    /// this batch completed it.
+    pub fn add_done(&self, lane: Lane, records: u64) -> Option<Duration> {
+        self.done[lane.slot()].fetch_add(records, Ordering::Relaxed);
+        self.check_finished(lane)
+    }
+
+    /// Mark the lane finished the first time it is closed with everything indexed
+    fn check_finished(&self, lane: Lane) -> Option<Duration> {
+        let slot = lane.slot();
+        let mut finished = self.finished.lock().unwrap();
+        if finished[slot].is_some() || !self.closed[slot].load(Ordering::Acquire)
+            || self.done[slot].load(Ordering::Relaxed) < self.sent[slot].load(Ordering::Relaxed) {
+            return None;
+        }
+        finished[slot] = Some(self.started.elapsed());
+        finished[slot]
+    }
+
+    pub fn status(&self) -> Vec<LaneStatus> {
+        let finished = self.finished.lock().unwrap();
+        [Lane::Priority, Lane::Rest].into_iter()
+            .map(|lane| LaneStatus {
+                lane,
+                done: self.done[lane.slot()].load(Ordering::Relaxed),
+                sent: self.sent[lane.slot()].load(Ordering::Relaxed),
+                finished: finished[lane.slot()],
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lane_completes_once_closed_and_indexed() {
+        let progress = LaneProgress::default();
+        progress.add_sent(Lane::Priority, 10);
+        progress.add_sent(Lane::Priority, 5);
+        // Indexed everything sent so far, but more batches may still come
+        assert_eq!(progress.add_done(Lane::Priority, 15), None);
+        progress.add_sent(Lane::Priority, 5);
+        assert_eq!(progress.close(Lane::Priority), None);
+        progress.add_sent(Lane::Rest, 100);
+        assert!(progress.add_done(Lane::Priority, 5).is_some());
+        assert_eq!(progress.add_done(Lane::Priority, 0), None);
+
+        let status = progress.status();
+        assert_eq!((status[0].done, status[0].sent), (20, 20));
+        assert!(status[0].finished.is_some());
+        assert_eq!((status[1].done, status[1].sent, status[1].finished), (0, 100, None));
+
+        // The last batch indexed before the producer got to close the lane
+        progress.add_done(Lane::Rest, 100);
+        assert!(progress.close(Lane::Rest).is_some());
+    }
+}
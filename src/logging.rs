@@ -0,0 +1,99 @@
+//! Log output through `tracing`: readable lines by default, or one JSON object per
+//! event (LOG_FORMAT=json) for shipping into ELK. Each batch runs in a `batch` span
+//! with its number, worker slot and index, so its events can be found together.
+//! Logs go to stderr; stdout is left to the reports of `status`, `verify` and co.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::EnvFilter;
+
+use crate::progress_bar;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// LOG_LEVEL as a filter: a level, or directives like `info,erc721_elasticsearch_migrator=debug`
+fn filter(level: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(level).with_context(|| format!("Invalid LOG_LEVEL '{}'", level))
+}
+
+/// Install the global subscriber; call once, before anything logs
+pub fn init(level: &str, format: LogFormat) -> Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(level)?)
+        .with_writer(|| LogWriter)
+        .with_target(false);
+    let installed = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_span_list(true).try_init(),
+    };
+    installed.map_err(|e| anyhow::anyhow!("Failed to set up logging: {}", e))
+}
+
+/// Writes log lines to stderr, above the progress bar when one is shown
+struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        progress_bar::suspend(|| std::io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Numbers the batches in flight by the lowest free slot, for the `worker` field
+/// of their spans
+#[derive(Default)]
+pub struct WorkerSlots {
+    taken: Mutex<BTreeSet<usize>>,
+}
+
+/// A taken slot; freed when the batch is done
+pub struct WorkerSlot {
+    slots: Arc<WorkerSlots>,
+    pub id: usize,
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.slots.taken.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl WorkerSlots {
+    pub fn acquire(self: &Arc<Self>) -> WorkerSlot {
+        let mut taken = self.taken.lock().unwrap();
+        let id = (0..).find(|slot| !taken.contains(slot)).unwrap();
+        taken.insert(id);
+        WorkerSlot { slots: self.clone(), id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_slots_and_level_filter() {
+        let slots = Arc::new(WorkerSlots::default());
+        let first = slots.acquire();
+        let second = slots.acquire();
+        assert_eq!((first.id, second.id), (0, 1));
+        drop(first);
+        assert_eq!(slots.acquire().id, 0);
+
+        assert!(filter("debug").is_ok());
+        assert!(filter("info,erc721_elasticsearch_migrator=trace").is_ok());
+        assert!(filter("reqwest=loud").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Small least-recently-used cache for values worth computing once per key but too
+//! many to keep forever, like collection lookups by address.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1), entries: HashMap::new(), order: BTreeMap::new(), tick: 0 }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let (_, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Add or replace `key`, dropping the least recently used entry when full
+    pub fn insert(&mut self, key: K, value: V) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&used);
+        } else if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.insert(self.tick, key);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_evicted() {
+        let mut cache = Lru::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), None);
+        cache.insert("a", 10);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"a"), Some(&10));
+        assert_eq!(cache.get(&"c"), Some(&3));
+    }
+}
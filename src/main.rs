@@ -1,231 +1,2305 @@
+#![recursion_limit = "256"]
+
+mod aggregates;
+mod alias;
+mod assets;
+mod batch_size;
+#[cfg(feature = "chaos")]
+mod chaos;
 mod checkpoint;
+mod checkpoint_store;
+mod checkpoint_writer;
+mod cli;
 mod config;
+mod confirm;
+mod conservation;
+mod coverage;
+mod csv_files;
+mod dead_letter;
+mod deprecations;
+mod download;
 mod elasticsearch;
+mod endpoint;
+mod ensured_indices;
+mod error_log;
+mod expiry;
+mod facets;
+mod field_limit;
+mod filter;
+mod fingerprint;
+mod heartbeat;
+mod histogram;
+#[cfg(test)]
+mod fuzz_tests;
+#[cfg(test)]
+mod golden_tests;
+#[cfg(test)]
+mod test_server;
+mod ids;
+mod index_settings;
+mod input;
+mod key_set;
+mod lanes;
+mod logging;
+mod lru;
+mod metrics;
 mod models;
 mod models_flexible;
+mod orders;
+mod orders_history;
+mod outcome;
+mod output;
+mod ownership;
+mod paths;
+mod payment_tokens;
+mod privileges;
+mod progress;
+mod progress_bar;
+mod pseudonymize;
+mod purge;
+mod rate_limit;
+mod record;
+mod remote;
+mod resources;
+mod run_history;
 mod collection_config;
+mod samples;
+mod schema;
+mod selection;
+mod sorted;
+mod shutdown;
+mod source;
+mod spool;
+mod strict;
+mod text_analysis;
+mod throughput;
+mod verify;
+mod traits;
+mod watchdog;
 
 use anyhow::{Context, Result};
-use csv::ReaderBuilder;
+use csv::StringRecord;
 use futures::stream::{self, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use regex::Regex;
 use reqwest::Client;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use roaring::RoaringTreemap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{mpsc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::signal;
+use tracing::{error, info, info_span, warn, Instrument};
 
-use crate::checkpoint::MigrationCheckpoint;
-use crate::config::APP_CONFIG;
-use crate::elasticsearch::bulk_index_documents;
+use crate::aggregates::{collections_stats_mapping, owners_summary_mapping, Aggregators, CollectionAggregator, ListingSnapshot, OwnerAggregator};
+use crate::alias::{check_write_alias, delete_index, next_versioned_index, resolve_alias, swap_alias, versioned_indices};
+use crate::assets::{AssetCheck, AssetChecker};
+use crate::batch_size::{bulk_size, BatchSizer};
+use crate::checkpoint::{record_key, CheckpointMode, MigrationCheckpoint, RowStarts};
+use crate::checkpoint_store::CheckpointStore;
+use crate::checkpoint_writer::CheckpointWriter;
+use crate::cli::{AliasCommand, CheckpointCommand, Cli, Command, MappingCommand, MigrateArgs, TraitFormat};
+use crate::config::{check_app_config, check_log_config, load_env, APP_CONFIG, LOG_CONFIG};
+use crate::confirm::{confirm, confirm_by_typing, requires_confirmation, ImpactScope, ImpactSummary};
+use crate::conservation::{Conservation, DocumentTally};
+use crate::dead_letter::{read_dead_letters, retry_dead_letters, ResendSettings};
+use crate::deprecations::{deprecation_warnings, print_deprecation_warnings, SendNotingWarnings};
+use crate::download::{download, is_url, local_path, remove_download};
+use crate::coverage::{print_coverage, write_coverage, CoverageTracker};
+use crate::csv_files::{expand as expand_csv_files, id_sharing_groups, is_multi_file, manifest_path as csv_files_manifest_path, FileManifest, FileState};
+use crate::elasticsearch::{bulk_index_documents, create_index_if_missing, fetch_index_mapping, install_body_diagnostics, install_bulk_compression, install_bulk_pipeline, install_id_strategy, install_jitter_seed, install_payment_token_annotations, install_routing, quarantine_index_name, token_document_id, wait_for_index_health, BulkCounters, BulkDocument, BulkOutcome, ClusterUnavailable, PayloadTooLarge, WriteMode};
+use crate::ensured_indices::EnsuredIndices;
+use crate::error_log::BulkErrorLog;
+use crate::expiry::{expire_listing, ExpiredListings};
+use crate::facets::FacetConfig;
+use crate::field_limit::{estimate_fields, fetch_field_usage, mapping_field_paths, raise_field_limit, suggested_limit, DEFAULT_TOTAL_FIELDS_LIMIT};
+use crate::filter::RowFilter;
+use crate::fingerprint::{content_key_of, CsvFingerprint};
+use crate::heartbeat::Heartbeats;
+use crate::histogram::DocumentShapes;
+use crate::ids::IdSelection;
+use crate::index_settings::IndexSettings;
+use crate::input::RowOffset;
+use crate::lanes::{Lane, LaneProgress};
+use crate::logging::WorkerSlots;
 use crate::models_flexible::{CsvRecord, FlexibleElasticsearchDocument};
-use crate::collection_config::get_collection_config;
+use crate::collection_config::{collection_mapping, generate_collection_mapping, generate_index_mapping, get_collection_config, install_collections, load_collections, mapping_conflicts};
+use crate::orders::{merge_order_rows, token_rows_adjacent};
+use crate::orders_history::{orders_history_mapping, OrderEvent, OrderEvents};
+use crate::outcome::{error_code, ConfigError, Outcome, Resumable, VerificationFailed};
+use crate::output::{emit, emit_error, install_output_format, is_json};
+use crate::ownership::{DuplicateIndex, DuplicateResolution};
+use crate::paths::{ensure_parent_dir, spool_file, state_file};
+use crate::payment_tokens::PaymentTokenRegistry;
+use crate::privileges::{check_privileges, for_writing, missing_indices, IndexPrivileges};
+use crate::progress::{ProgressFile, RunStarted, RunState, RunSummary};
+use crate::progress_bar::MigrationProgress;
+use crate::pseudonymize::{install_field_hasher, pseudonymize};
+use crate::purge::{count_matches, purge_index, purge_query, PurgeAudit};
+use crate::rate_limit::{install_rate_limiter, reload_on_sighup, RateLimits};
+use crate::resources::ResourceUsage;
+use crate::run_history::{document_count, existing_data_error, last_run, record_run, CompletedRun};
+use crate::samples::SampleCapture;
+use crate::schema::{apply_column_mapping, parse_column_mapping, repair_headers, validate_headers};
+use crate::shutdown::ShutdownHandler;
+use crate::selection::{select_rows, Selection, SelectionRules};
+use crate::source::{input_metadata, open_source, RecordSource};
+use crate::sorted::SortedInputCheck;
+use crate::strict::DataLoss;
+use crate::text_analysis::{install_text_analysis, installed_plugins, parse_field_analyzers, parse_languages, MissingPlugin, TextAnalysis};
+use crate::throughput::ThroughputGovernor;
+use crate::traits::TraitAnalyzer;
+use crate::verify::{count_documents, differing_fields, fetch_documents, refresh_index, Sampler};
+use crate::watchdog::HealthWatchdog;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse_args();
+    let result = start(cli);
+    match &result {
+        Ok(outcome) => (*outcome).into(),
+        Err(e) => {
+            emit_error(e);
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error_code(e))
+        }
+    }
+}
+
+fn start(cli: Cli) -> Result<Outcome> {
+    // Flags become environment variables, before the runtime starts threads and anything reads APP_CONFIG
+    cli.config.apply().context(ConfigError)?;
+    load_env().context(ConfigError)?;
+    check_log_config().context(ConfigError)?;
+    logging::init(LOG_CONFIG.level(), LOG_CONFIG.log_format).context(ConfigError)?;
+    install_output_format(LOG_CONFIG.output);
+    tokio::runtime::Runtime::new()?.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<Outcome> {
+    // Commands that only read files given as arguments work without the settings of a run
+    if !matches!(cli.command, Some(Command::Checkpoint(_) | Command::Mapping(_))) {
+        check_app_config().context(ConfigError)?;
+        if let Some(hasher) = APP_CONFIG.field_hasher().context(ConfigError)? {
+            info!("🔒 Pseudonymizing {}", hasher.fields().join(", "));
+            install_field_hasher(hasher);
+        }
+    }
+    let outcome = run_command(cli.command, cli.migrate).await;
+    // Whatever the command, create-index's mapping deprecations included
+    print_deprecation_warnings();
+    outcome
+}
+
+async fn run_command(command: Option<Command>, migrate_args: MigrateArgs) -> Result<Outcome> {
+    match command {
+        Some(Command::Checkpoint(CheckpointCommand::Merge { checkpoints, output })) => run_checkpoint_merge(&checkpoints, output.as_deref()).await,
+        Some(Command::Mapping(MappingCommand::Preview { collection, index })) => run_mapping_preview(collection.as_deref(), index.as_deref()),
+        Some(Command::AnalyzeTraits { format, output, facets }) => run_analyze_traits(format, output.as_deref(), facets.as_deref()).await,
+        Some(Command::Alias(AliasCommand::Next { alias })) => run_alias_next(&elasticsearch_client()?, &alias).await,
+        Some(Command::Alias(AliasCommand::Swap { alias, sample, delete_old, yes })) => run_alias_swap(&elasticsearch_client()?, &alias, sample, delete_old, yes).await,
+        Some(Command::Status) => run_status(&elasticsearch_client()?).await,
+        Some(Command::Purge { owner, dry_run, yes }) => run_purge(&elasticsearch_client()?, &owner, dry_run, yes).await,
+        Some(Command::Verify { sample, token_address }) => run_verify(&elasticsearch_client()?, sample, token_address.as_deref()).await,
+        Some(Command::CreateIndex) => run_create_index(&elasticsearch_client()?).await,
+        Some(Command::Resume(args)) => return migrate(args, true).await,
+        Some(Command::Migrate(args)) => return migrate(args, false).await,
+        None => return migrate(migrate_args, false).await,
+    }.map(|()| Outcome::Success)
+}
+
+/// How often a run checks that SPOOL_DIR still has room
+const SPOOL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+static ELASTICSEARCH_BASE_URL: OnceLock<String> = OnceLock::new();
+
+/// Base URL of cluster requests: ELASTICSEARCH_URL, or the bridge to
+/// ELASTICSEARCH_UNIX_SOCKET once `elasticsearch_client` has set it up
+fn elasticsearch_url() -> &'static str {
+    ELASTICSEARCH_BASE_URL.get().unwrap_or(&APP_CONFIG.elasticsearch_url)
+}
+
+static CSV_PATH: OnceLock<String> = OnceLock::new();
+
+/// The CSV to read: CSV_FILE, or its local copy once `fetch_csv` has resolved a URL
+fn csv_file() -> &'static str {
+    CSV_PATH.get().unwrap_or(&APP_CONFIG.csv_file)
+}
+
+/// When CSV_FILE is a URL, point `csv_file()` at the local copy, downloading (or
+/// finishing) it first if `fetch` is set
+async fn fetch_csv(fetch: bool) -> Result<()> {
+    let url = &APP_CONFIG.csv_file;
+    if !is_url(url) {
+        return Ok(());
+    }
+    let path = local_path(url, &APP_CONFIG.csv_download_dir())?;
+    if fetch {
+        let mut builder = Client::builder().connect_timeout(Duration::from_secs(APP_CONFIG.timeout_secs));
+        if let Some(proxy) = APP_CONFIG.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        download(&builder.build()?, url, &path, APP_CONFIG.csv_sha256.as_deref(), APP_CONFIG.spool().as_ref()).await?;
+    }
+    CSV_PATH.set(path.to_string_lossy().into_owned()).ok();
+    Ok(())
+}
+
+/// HTTP client for the cluster. Credentials go on every request of this client,
+/// so it only talks to Elasticsearch.
+fn elasticsearch_client() -> Result<Client> {
+    install_body_diagnostics(APP_CONFIG.body_diagnostics());
+    install_bulk_compression(APP_CONFIG.bulk_gzip_min_bytes());
+    install_bulk_pipeline(APP_CONFIG.elasticsearch_pipeline.clone());
+    install_payment_token_annotations(APP_CONFIG.payment_tokens.is_some());
+    if let Some(seed) = APP_CONFIG.random_seed {
+        info!("🎲 Retry jitter seeded with RANDOM_SEED={}", seed);
+        install_jitter_seed(seed);
+    }
+    install_id_strategy(APP_CONFIG.id_strategy()?);
+    install_routing(APP_CONFIG.routing().context(ConfigError)?);
+    #[cfg(feature = "chaos")]
+    {
+        let chaos = APP_CONFIG.chaos();
+        info!("💥 Chaos mode: injecting 429 {:.0}%, 500 {:.0}%, timeout {:.0}% of bulk requests (seed {})",
+                 chaos.throttle_rate * 100.0, chaos.error_rate * 100.0, chaos.timeout_rate * 100.0, chaos.seed);
+        chaos::install(chaos);
+    }
+    let mut default_headers = HeaderMap::new();
+    if let Some(authorization) = APP_CONFIG.elasticsearch_authorization()? {
+        let mut value = HeaderValue::from_str(&authorization).context("Invalid Elasticsearch credentials")?;
+        value.set_sensitive(true);
+        default_headers.insert(AUTHORIZATION, value);
+    }
+    let mut builder = Client::builder();
+    if let Some(proxy) = APP_CONFIG.proxy()? {
+        builder = builder.proxy(proxy);
+    }
+    let (builder, base_url) = APP_CONFIG.endpoint().configure(builder)?;
+    ELASTICSEARCH_BASE_URL.set(base_url).ok();
+    builder
+        .timeout(Duration::from_secs(APP_CONFIG.timeout_secs))
+        .default_headers(default_headers)
+        // Drop idle connections soon, so ones to a restarted node aren't reused
+        .pool_idle_timeout(Duration::from_secs(30))
+        .tcp_keepalive(Duration::from_secs(30))
+        .build()
+        .context("Failed to create HTTP client")
+}
+
+/// `migrate` (and `resume`, which insists on a checkpoint)
+async fn migrate(args: MigrateArgs, resume_only: bool) -> Result<Outcome> {
+    let client = elasticsearch_client()?;
+    if let Some(spool) = &APP_CONFIG.spool() {
+        spool.preflight(0)?;
+        info!("✓ Spool dir {} has room", spool.dir.display());
+    }
+    if let Some(addr) = APP_CONFIG.metrics_addr {
+        let (addr, _) = metrics::serve(addr).await?;
+        info!("✓ Prometheus metrics on http://{}/metrics", addr);
+    }
+    check_write_alias(&client, elasticsearch_url(), &APP_CONFIG.target_index()).await.context(ConfigError)?;
+    check_privileges(&client, elasticsearch_url(), &migrate_privileges(&client, &args).await?).await.context(ConfigError)?;
+    configure_text_analysis(&client).await?;
+    if is_multi_file(&APP_CONFIG.csv_file) {
+        return migrate_files(&client, args, resume_only).await;
+    }
+    fetch_csv(!args.retry_dlq).await?;
+    if args.retry_dlq {
+        return run_retry_dlq(&client, csv_file()).await;
+    }
+    migrate_file(&client, args, resume_only, csv_file()).await
+}
+
+/// CSV_FILE naming a directory or pattern: migrate each file not finished yet,
+/// CSV_FILE_CONCURRENCY at a time, noting in the manifest how far each got
+async fn migrate_files(client: &Client, args: MigrateArgs, resume_only: bool) -> Result<Outcome> {
+    let pattern = &APP_CONFIG.csv_file;
+    if args.checkpoint_for.is_some() || APP_CONFIG.checkpoint_for.is_some() {
+        return Err(anyhow::anyhow!("--checkpoint-for names one CSV; CSV_FILE {} names several", pattern).context(ConfigError));
+    }
+    let files = expand_csv_files(pattern).context(ConfigError)?;
+    if args.retry_dlq {
+        let mut outcome = Outcome::Success;
+        for file in &files {
+            outcome = outcome.max(run_retry_dlq(client, file).await?);
+        }
+        return Ok(outcome);
+    }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let csv_file = &APP_CONFIG.csv_file;
+    let manifest_path = csv_files_manifest_path(pattern);
+    let manifest = FileManifest::load(&manifest_path).await?;
+    if resume_only && manifest.files.is_empty() {
+        anyhow::bail!("No run to resume for {} ({} does not exist)", pattern, manifest_path.display());
+    }
+    let pending: Vec<String> = manifest.pending(&files).into_iter().cloned().collect();
+    info!("📂 {} CSV files match {}: {} finished, {} to migrate", files.len(), pattern, files.len() - pending.len(), pending.len());
+    // The files share the target, so the check for earlier loads is made once, for the set
+    if manifest.files.is_empty() && args.ids_file.is_none() && !existing_data_allowed(&args) {
+        refuse_existing_data(client, pattern).await?;
+    }
+    let concurrency = APP_CONFIG.csv_file_concurrency.unwrap_or(1).max(1);
+    if concurrency > 1 {
+        info!("✓ Migrating {} files at a time", concurrency);
+    }
+    // Asked once for the set: concurrent files would each prompt on the same stdin
+    if requires_confirmation(&APP_CONFIG) {
+        let health: serde_json::Value = match client.get(format!("{}/_cluster/health", elasticsearch_url())).send_noting_warnings().await {
+            Ok(response) => response.json().await.unwrap_or_default(),
+            Err(_) => serde_json::Value::Null,
+        };
+        let impact = ImpactSummary {
+            cluster_url: APP_CONFIG.elasticsearch_url.clone(),
+            cluster_name: health["cluster_name"].as_str().map(str::to_string),
+            index: APP_CONFIG.target_index(),
+            profile: APP_CONFIG.profile.clone(),
+            mode: if args.ids_file.is_some() {
+                "reprocess selected ids (--ids-file)".to_string()
+            } else if !manifest.files.is_empty() {
+                format!("resume ({} files finished)", files.len() - pending.len())
+            } else {
+                "new run".to_string()
+            },
+            scope: ImpactScope::Files { to_migrate: pending.len(), total: files.len() },
+        };
+        confirm(&impact, APP_CONFIG.assume_yes || args.yes)?;
+    }
+    // One progress bar can't show several files
+    let args = MigrateArgs { allow_existing: true, yes: true, quiet: args.quiet || concurrency > 1, ..args };
+
+    let manifest = tokio::sync::Mutex::new(manifest);
+    let set_state = |file: String, state: FileState| {
+        let (manifest, manifest_path) = (&manifest, &manifest_path);
+        async move {
+            let mut manifest = manifest.lock().await;
+            manifest.files.insert(file, state);
+            manifest.save(manifest_path).await
+        }
+    };
+    // Files sharing ids run in file order, so the later file's rows win like in a serial run
+    let groups: Vec<Vec<String>> = match concurrency > 1 {
+        true => {
+            let ids = pending.iter().map(|file| file_document_ids(file)).collect::<Result<Vec<_>>>()?;
+            let groups: Vec<Vec<String>> = id_sharing_groups(&ids).into_iter()
+                .map(|group| group.into_iter().map(|file| pending[file].clone()).collect())
+                .collect();
+            if groups.len() < pending.len() {
+                info!("✓ {} files share document ids with another; those run one after another",
+                      groups.iter().filter(|group| group.len() > 1).map(Vec::len).sum::<usize>());
+            }
+            groups
+        }
+        false => pending.into_iter().map(|file| vec![file]).collect(),
+    };
+    let migrate_tracked = |file: String, args: MigrateArgs| {
+        let set_state = &set_state;
+        async move {
+            info!("📄 Migrating {}", file);
+            let result = match set_state(file.clone(), FileState::Running).await {
+                Ok(()) => migrate_file(client, args, false, &file).await,
+                Err(e) => Err(e),
+            };
+            let state = match &result {
+                Ok(Outcome::Success | Outcome::CompletedWithDeadLetters) => FileState::Completed,
+                Ok(Outcome::AbortedResumable) | Err(_) => FileState::Incomplete,
+            };
+            let result = match set_state(file.clone(), state).await {
+                Ok(()) => result,
+                Err(e) => result.and(Err(e)),
+            };
+            (file, result)
+        }
+    };
+    let results: Vec<(String, Result<Outcome>)> = stream::iter(groups)
+        .map(|group| {
+            let (args, migrate_tracked) = (args.clone(), &migrate_tracked);
+            async move {
+                let mut results = Vec::new();
+                for file in group {
+                    let (file, result) = migrate_tracked(file, args.clone()).await;
+                    let finished = matches!(result, Ok(Outcome::Success | Outcome::CompletedWithDeadLetters));
+                    results.push((file, result));
+                    // Resuming it later would overwrite what the group's later files wrote
+                    if !finished {
+                        break;
+                    }
+                }
+                results
+            }
+        })
+        .buffer_unordered(concurrency)
+        .flat_map(stream::iter)
+        .collect()
+        .await;
+
+    let mut outcome = Outcome::Success;
+    let mut failures = Vec::new();
+    for (file, result) in results {
+        match result {
+            Ok(file_outcome) => outcome = outcome.max(file_outcome),
+            Err(e) => {
+                error!("❌ {}: {:#}", file, e);
+                failures.push(e.context(file));
+            }
+        }
+    }
+    let manifest = manifest.into_inner();
+    let finished = files.iter().filter(|file| manifest.is_completed(file)).count();
+    info!("📂 CSV files finished: {}/{}", finished, files.len());
+    let failed = failures.len();
+    if let Some(error) = failures.into_iter().next() {
+        return Err(error.context(format!("{} of {} files failed", failed, files.len())));
+    }
+    if finished == files.len() {
+        tokio::fs::remove_file(&manifest_path).await
+            .with_context(|| format!("Failed to remove {}", manifest_path.display()))?;
+    }
+    Ok(outcome)
+}
+
+/// The inputs CSV_FILE names: every file of a directory or pattern, else the one
+/// file (downloaded first if it's a URL)
+async fn input_files() -> Result<Vec<String>> {
+    if is_multi_file(&APP_CONFIG.csv_file) {
+        return expand_csv_files(&APP_CONFIG.csv_file).context(ConfigError);
+    }
+    fetch_csv(true).await?;
+    Ok(vec![csv_file().to_string()])
+}
+
+/// Open an input with its header row as a migration reads it: repaired, then
+/// renamed by COLUMN_MAPPING
+fn open_with_headers(csv_file: &str) -> Result<(Box<dyn RecordSource>, StringRecord)> {
+    let (source, _) = open_source(csv_file)?;
+    let (mut headers, _) = repair_headers(source.headers());
+    if let Some(spec) = &APP_CONFIG.column_mapping {
+        headers = apply_column_mapping(&headers, &parse_column_mapping(spec)?);
+    }
+    Ok((source, headers))
+}
+
+/// Document ids of an input's rows, as checkpoint keys
+fn file_document_ids(csv_file: &str) -> Result<RoaringTreemap> {
+    let (mut source, headers) = open_with_headers(csv_file)?;
+    let column = headers.iter().position(|h| h == "token_id").with_context(|| format!("{} has no token_id column", csv_file))?;
+    let address_column = headers.iter().position(|h| h == "token_address");
+    let mut ids = RoaringTreemap::new();
+    for row in source.rows() {
+        let row = row?;
+        if let Some(doc_id) = token_document_id(address_column.and_then(|column| row.get(column)), row.get(column)) {
+            ids.insert(record_key(&doc_id));
+        }
+    }
+    Ok(ids)
+}
+
+/// Migrate one CSV file, resuming from its checkpoint
+async fn migrate_file(client: &Client, args: MigrateArgs, resume_only: bool, csv_file: &str) -> Result<Outcome> {
+    let spool = APP_CONFIG.spool();
+
+    // Reprocessing a list of ids is a one-off fix: it neither resumes nor touches the checkpoint
+    let mut id_selection = match &args.ids_file {
+        Some(path) => {
+            let selection = IdSelection::load(path).context(ConfigError)?;
+            info!("✓ Reprocessing {} ids from {} (checkpoint not used)", selection.len(), path);
+            Some(selection)
+        }
+        None => None,
+    };
+    install_configured_collections()?;
+    let index_settings = configured_index_settings()?;
+    let checkpoint_store = Arc::new(match id_selection {
+        Some(_) => CheckpointStore::Disabled,
+        None => CheckpointStore::from_config(&APP_CONFIG, client, elasticsearch_url())?,
+    });
     
     // Check for existing checkpoint
-    let mut checkpoint = match MigrationCheckpoint::load(csv_file).await? {
+    let checkpoint_for = args.checkpoint_for.clone().or_else(|| APP_CONFIG.checkpoint_for.clone());
+    let content_key = match checkpoint_store.is_enabled() {
+        true => Some(content_key_of(Path::new(csv_file))?),
+        false => None,
+    };
+    // Every key the checkpoint may be stored under, for removing it once done
+    let checkpoint_keys: Vec<String> = [Some(csv_file.to_string()), checkpoint_for.clone(), content_key.clone()].into_iter().flatten().collect();
+    let mut checkpoint = match MigrationCheckpoint::load(&checkpoint_store, csv_file, checkpoint_for.as_deref(), content_key.as_deref(),
+                                                         APP_CONFIG.checkpoint_mode).await? {
         Some(cp) => {
             let resume_point = cp.get_safe_resume_point();
-            println!("📁 Found checkpoint: {:.1}% complete ({}/{} records)", 
+            info!("📁 Found checkpoint: {:.1}% complete ({}/{} records)", 
                      cp.progress_percentage(), cp.processed_records, cp.total_records);
-            println!("🔄 Resuming from record {} (safe continuous point)", resume_point);
+            match cp.mode {
+                CheckpointMode::Index => info!("🔄 Resuming from record {} (safe continuous point)", resume_point),
+                CheckpointMode::Key => info!("🔄 Resuming by document id ({} ids already indexed)", cp.completed_keys.len()),
+            }
             cp
         }
+        None if resume_only => anyhow::bail!("No checkpoint to resume for {} ({})", csv_file, checkpoint_store.describe(csv_file)),
         None => {
-            println!("🆕 Starting new migration: {}", csv_file);
+            info!("🆕 Starting new migration: {}", csv_file);
             // We'll create the checkpoint after reading the CSV
-            MigrationCheckpoint::new(csv_file.to_string(), 0)
+            MigrationCheckpoint::new(csv_file.to_string(), 0, APP_CONFIG.checkpoint_mode)
         }
     };
+
+    if checkpoint.total_records == 0 && id_selection.is_none() && !existing_data_allowed(&args) {
+        refuse_existing_data(client, csv_file).await?;
+    }
     
-    println!("Config: Elasticsearch={}, Index={}, Batch={}, Workers={}", 
-             APP_CONFIG.elasticsearch_url, APP_CONFIG.elasticsearch_index, 
+    if let Some(profile) = &APP_CONFIG.profile {
+        info!("Profile: {}", profile);
+    }
+    info!("Config: Elasticsearch={}, Index={}, Batch={}, Workers={}", 
+             APP_CONFIG.elasticsearch_url, APP_CONFIG.target_index(), 
              APP_CONFIG.batch_size, APP_CONFIG.workers);
     let start_time = Instant::now();
-
-    let client = Client::builder()
-        .timeout(Duration::from_secs(APP_CONFIG.timeout_secs))
-        .build()
-        .context("Failed to create HTTP client")?;
+    // The bulk counters are process-wide; earlier files of the set are already in them
+    let counters_at_start = BulkCounters::now();
 
     // Test connection
-    let health_response = client.get(&format!("{}/_cluster/health", APP_CONFIG.elasticsearch_url)).send().await?;
+    let health_response = client.get(format!("{}/_cluster/health", elasticsearch_url())).send_noting_warnings().await?;
     if !health_response.status().is_success() {
         return Err(anyhow::anyhow!("Elasticsearch not available"));
     }
-    println!("✓ Elasticsearch connected");
+    let health: serde_json::Value = health_response.json().await.unwrap_or_default();
+    info!("✓ Elasticsearch connected");
 
     // Read CSV
-    let file = std::fs::File::open(csv_file)?;
-    let mut reader = ReaderBuilder::new().has_headers(true).from_reader(file);
+    let (mut source, input_format) = open_source(csv_file)?;
+    input_format.print();
+
+    // Check the header row before deserializing, so renamed columns don't silently become None
+    let (mut headers, repairs) = repair_headers(source.headers());
+    if !repairs.is_empty() {
+        for (original, repaired) in &repairs {
+            warn!("⚠️  Repaired header {:?} -> '{}'", original, repaired);
+        }
+        source.set_headers(headers.clone());
+    }
+    if let Some(spec) = &APP_CONFIG.column_mapping {
+        headers = apply_column_mapping(&headers, &parse_column_mapping(spec).context(ConfigError)?);
+        source.set_headers(headers.clone());
+    }
+    let header_report = validate_headers(&headers);
+    header_report.print();
+    if !header_report.is_clean() && !APP_CONFIG.allow_missing_columns {
+        return Err(anyhow::anyhow!(
+            "CSV header doesn't match the expected columns (set COLUMN_MAPPING, or ALLOW_MISSING_COLUMNS=true to continue)")
+            .context(ConfigError));
+    }
+    checkpoint.check_fingerprint(CsvFingerprint::of(Path::new(csv_file), &headers)?, APP_CONFIG.force_resume || args.force_resume)?;
+    checkpoint.check_id_template(APP_CONFIG.id_strategy()?.template())?;
     
-    let mut records = Vec::new();
-    let mut record_index = 0;
-    let resume_point = checkpoint.get_safe_resume_point();
+    let row_filter = match &APP_CONFIG.filter {
+        Some(expression) => {
+            let filter = RowFilter::compile(expression, &headers).context(ConfigError)?;
+            info!("✓ Row filter: {}", expression);
+            Some(filter)
+        }
+        None => None,
+    };
+    
+    let priority_filter = match &APP_CONFIG.priority_filter {
+        Some(expression) => {
+            let filter = RowFilter::compile(expression, &headers).context(ConfigError)?;
+            info!("✓ Priority lane: rows matching {} are indexed first", expression);
+            Some(filter)
+        }
+        None => None,
+    };
     
-    for result in reader.deserialize() {
-        let record: CsvRecord = result?;
-        
-        // Skip records that were already safely processed
-        if record_index < resume_point {
-            record_index += 1;
-            continue;
-        }
-        
-        records.push((record_index, record)); // Store with original index
-        record_index += 1;
+    let sorted_check = match &APP_CONFIG.sorted_by {
+        Some(column) => Some(SortedInputCheck::new(column, &headers).context(ConfigError)?),
+        None => None,
+    };
+    // Merging unsorted order rows would hold every row of the file until the end of the stream
+    if APP_CONFIG.group_orders && APP_CONFIG.sorted_by.as_deref() != Some("token_id") {
+        return Err(anyhow::anyhow!("GROUP_ORDERS needs the CSV sorted by token id: sort the export and set SORTED_BY=token_id")
+            .context(ConfigError));
     }
     
-    let total_records = record_index; // Total in CSV
-    let remaining_records = records.len(); // Records to process
+    checkpoint.check_csv_len(input_metadata(csv_file)?.0);
+    let selection = select_rows(source, &mut checkpoint, SelectionRules {
+        headers: &headers,
+        id_selection: id_selection.as_mut(),
+        row_filter: row_filter.as_ref(),
+        priority_filter: priority_filter.as_ref(),
+        sorted_check,
+        sorted_violation: APP_CONFIG.sorted_violation,
+        group_orders: APP_CONFIG.group_orders,
+        duplicates: (APP_CONFIG.duplicate_resolution == DuplicateResolution::Ownership && !APP_CONFIG.group_orders)
+            .then(DuplicateIndex::default),
+        orders_history: APP_CONFIG.orders_history_index.is_some(),
+        build_document,
+    })?;
+    let total_records = selection.total_records; // Total in CSV
+    let remaining_records = selection.remaining_records(); // Records to process
+    let already_done = selection.already_done();
+    let duplicate_rows = selection.duplicate_rows();
+    let Selection { selected, history_only, priority_rows, duplicates, sorted_check, dynamic_fields, collections,
+                    filtered_rows, unselected_rows, done_rows, .. } = selection;
+    
+    if let Some(check) = &sorted_check {
+        info!("✓ CSV verified sorted by {}", check.column());
+    }
     
-    // Update checkpoint with total if it's new
-    if checkpoint.total_records == 0 {
-        checkpoint.total_records = total_records;
+    // The streaming pass seeks too, to the noted row before the first selected one
+    let stream_start = selected.min().and_then(|first| checkpoint.row_offset_before(first as usize));
+    if let Some(start) = stream_start.filter(|start| Some(*start) == checkpoint.boundary_offset) {
+        info!("⏩ Resumed batches start at record {} (byte {}), every record before it is confirmed", start.index, start.byte);
     }
     
-    println!("✓ CSV has {} total records", total_records);
-    if remaining_records < total_records {
-        println!("✓ Skipping {} safely processed records", total_records - remaining_records);
+    info!("✓ CSV has {} total records", total_records);
+    if already_done > 0 {
+        info!("✓ Skipping {} safely processed records", already_done);
     }
-    println!("✓ Will process {} remaining records", remaining_records);
+    if filtered_rows > 0 {
+        info!("✓ Filtered out {} records", filtered_rows);
+    }
+    if let Some(priority_rows) = &priority_rows {
+        info!("✓ {} records in the priority lane", priority_rows.len());
+    }
+    if !history_only.is_empty() {
+        info!("✓ {} records only need their orders history written", history_only.len());
+    }
+    if let Some(duplicates) = &duplicates {
+        report_duplicates(csv_file, duplicates).await?;
+    }
+    if let Some(selection) = &id_selection {
+        let missing = selection.missing();
+        if !missing.is_empty() {
+            warn!("⚠️  {} ids from --ids-file are not in the CSV: {}{}", missing.len(),
+                     missing.iter().take(10).copied().collect::<Vec<_>>().join(", "),
+                     if missing.len() > 10 { ", ..." } else { "" });
+        }
+    }
+    info!("✓ Will process {} remaining records", remaining_records);
+    emit("started", &RunStarted {
+        csv_file: csv_file.to_string(),
+        index: APP_CONFIG.target_index(),
+        to_process: remaining_records as u64,
+        total_records: checkpoint.total_records,
+    });
 
     if remaining_records == 0 {
-        println!("✅ Migration already completed!");
-        MigrationCheckpoint::cleanup(csv_file).await?;
-        return Ok(());
+        info!("✅ Migration already completed!");
+        if APP_CONFIG.progress_file.is_some() || is_json() {
+            let progress = ProgressFile::new(APP_CONFIG.progress_file.as_ref().map(PathBuf::from), APP_CONFIG.target_index(), 0);
+            progress.write(&progress.report(RunState::Completed, 0, &checkpoint)).await?;
+        }
+        MigrationCheckpoint::cleanup(&checkpoint_store, &checkpoint_keys).await?;
+        return Ok(Outcome::Success);
+    }
+
+    let impact = ImpactSummary {
+        cluster_url: APP_CONFIG.elasticsearch_url.clone(),
+        cluster_name: health["cluster_name"].as_str().map(str::to_string),
+        index: APP_CONFIG.target_index(),
+        profile: APP_CONFIG.profile.clone(),
+        mode: if id_selection.is_some() {
+            "reprocess selected ids (--ids-file)".to_string()
+        } else if already_done > 0 {
+            format!("resume ({:?} checkpoint, {} already done)", checkpoint.mode, already_done)
+        } else {
+            format!("new run ({:?} checkpoint)", checkpoint.mode)
+        },
+        scope: ImpactScope::Records { to_process: remaining_records, total: total_records },
+    };
+    if requires_confirmation(&APP_CONFIG) {
+        let assume_yes = APP_CONFIG.assume_yes || args.yes;
+        confirm(&impact, assume_yes)?;
     }
 
     // Process in batches
     let processed_count = Arc::new(AtomicU64::new(0));
+    let document_tally = Arc::new(DocumentTally::default());
+    let saved_records = checkpoint.processed_records;
+    // Positions mean nothing in key mode, so only index mode moves the boundary along
+    let row_starts = (checkpoint.mode == CheckpointMode::Index).then(|| Arc::new(RowStarts::default()));
     let checkpoint_mutex = Arc::new(Mutex::new(checkpoint));
+    let max_staleness = Duration::from_secs(APP_CONFIG.checkpoint_max_staleness_secs.unwrap_or(60));
+    let checkpoint_writer = CheckpointWriter::new(checkpoint_mutex.clone(), checkpoint_store.clone(), csv_file, max_staleness, saved_records);
+    let checkpoint_writer_task = checkpoint_writer.spawn();
     
-    // Create batches with their starting indices
-    let mut batches = Vec::new();
-    let mut current_batch = Vec::new();
-    let mut batch_start_index = 0;
-    
-    for (record_index, record) in records {
-        if current_batch.is_empty() {
-            batch_start_index = record_index;
+    let field_limit = check_field_limit(client, &APP_CONFIG.target_index(), &dynamic_fields, &collections).await?;
+    if APP_CONFIG.auto_create_index || args.create_index {
+        bootstrap_index(client, &APP_CONFIG.target_index(), &collections, &index_settings, field_limit).await?;
+    } else if let Some(limit) = field_limit {
+        warn!("🚨 {} doesn't exist and will be created by the first bulk request with the default total_fields.limit; \
+               create it with AUTO_CREATE_INDEX=true (or --create-index) to get a limit of {}", APP_CONFIG.target_index(), limit);
+    }
+
+    let history_index = APP_CONFIG.orders_history_index.clone();
+    if let Some(index) = &history_index {
+        if create_index_if_missing(client, elasticsearch_url(), index, &index_settings.apply(index, &orders_history_mapping())).await? {
+            info!("✓ Created index {}", index);
+            wait_for_index_health(client, elasticsearch_url(), index,
+                                  APP_CONFIG.index_wait_for_status, APP_CONFIG.index_wait_timeout()).await?;
         }
-        
-        current_batch.push(ElasticsearchDocument::from(record));
-        
-        if current_batch.len() >= APP_CONFIG.batch_size {
-            batches.push((batch_start_index, current_batch));
-            current_batch = Vec::new();
+        info!("✓ Orders history: events go to {}, rows are checkpointed once both indices have them", index);
+    }
+
+    // Second pass: stream documents to the workers through a bounded channel, so
+    // only the batches in flight are held in memory
+    let id_template = APP_CONFIG.id_strategy()?.template().to_string();
+    let grouping = APP_CONFIG.group_orders.then(|| {
+        let sorted_by_token_id = sorted_check.as_ref().is_some_and(|check| check.column() == "token_id");
+        if sorted_by_token_id && !token_rows_adjacent(&id_template, collections.len()) {
+            warn!("⚠️  {} collections share token ids, so a token's rows may not be adjacent; merging order rows holds them all until the end of the file",
+                  collections.len());
+            return false;
         }
+        sorted_by_token_id
+    });
+    let lane_progress = priority_rows.is_some().then(|| Arc::new(LaneProgress::default()));
+    let strict = APP_CONFIG.strict || args.strict;
+    if strict {
+        info!("✓ Strict mode: dropped or altered values fail the run");
     }
-    
-    // Add remaining records as final batch
-    if !current_batch.is_empty() {
-        batches.push((batch_start_index, current_batch));
+    let plan = StreamPlan { selected, history_only, priority_rows, grouping, start: stream_start, row_starts: row_starts.clone(), strict };
+    let (batch_sender, mut batch_receiver) = mpsc::channel(APP_CONFIG.workers * 2);
+    let batch_sizer = Arc::new(APP_CONFIG.batch_sizer());
+    let producer = {
+        let csv_file = csv_file.to_string();
+        let headers = headers.clone();
+        let lane_progress = lane_progress.clone();
+        let batch_sizer = batch_sizer.clone();
+        tokio::task::spawn_blocking(move || stream_documents(&csv_file, &headers, &plan, &batch_sizer, lane_progress, batch_sender))
+    };
+
+    info!("✓ Processing {} records in batches of {} with {} transform and {} indexing workers...",
+             remaining_records, APP_CONFIG.batch_size, APP_CONFIG.transform_workers(), APP_CONFIG.workers);
+    if let Some(max_bytes) = batch_sizer.max_bytes() {
+        info!("✓ Bulk requests capped at {:.1} MB", max_bytes as f64 / (1024.0 * 1024.0));
+    }
+    if batch_sizer.is_adaptive() {
+        info!("✓ Adaptive batch size: shrinks when bulk requests are slow or rejected");
     }
 
-    println!("✓ Processing {} batches with {} workers...", batches.len(), APP_CONFIG.workers);
+    let payment_tokens = match &APP_CONFIG.payment_tokens {
+        Some(spec) => {
+            let registry = PaymentTokenRegistry::parse(spec).context(ConfigError)?;
+            info!("✓ Payment token registry: {} known tokens", registry.len());
+            Some(Arc::new(registry))
+        }
+        None => None,
+    };
+
+    let asset_checker = match APP_CONFIG.asset_check {
+        AssetCheck::Off => None,
+        check => {
+            info!("✓ Asset URL check: {:?}", check);
+            // Asset URLs point at third-party hosts: use a client without the cluster credentials
+            let mut builder = Client::builder();
+            if let Some(proxy) = APP_CONFIG.proxy()? {
+                builder = builder.proxy(proxy);
+            }
+            let asset_client = builder
+                .timeout(Duration::from_secs(APP_CONFIG.timeout_secs))
+                .build()
+                .context("Failed to create HTTP client")?;
+            Some(Arc::new(AssetChecker::new(asset_client, check)))
+        }
+    };
+
+    let aggregators = Aggregators {
+        owners: APP_CONFIG.owners_summary_index.as_ref().map(|_| OwnerAggregator::default()),
+        collections: APP_CONFIG.collections_stats_index.as_ref().map(|_| CollectionAggregator::default()),
+    };
+    if aggregators.is_enabled() && remaining_records < total_records {
+        warn!("⚠️  Resumed run: side-indices will only summarize records processed in this session");
+    }
+    let aggregators = aggregators.is_enabled().then(|| Arc::new(Mutex::new(aggregators)));
+
+    let target_index = APP_CONFIG.target_index();
+    let retry_policy = APP_CONFIG.bulk_retry_policy();
+
+    let governor = APP_CONFIG.target_records_per_sec.map(|target| {
+        info!("✓ Throughput target: {:.0} records/sec", target);
+        Arc::new(ThroughputGovernor::new(target, APP_CONFIG.workers))
+    });
+
+    // Always installed, so limits can be added through the control endpoint mid-run
+    let rate_limiter = install_rate_limiter(APP_CONFIG.rate_limits().context(ConfigError)?);
+    if rate_limiter.limits() != RateLimits::default() {
+        info!("✓ Rate limits: {}", rate_limiter.limits());
+    }
+    // Made with the generic mapping by the first batch that has documents for it
+    let quarantine = APP_CONFIG.quarantine_failed_extraction.then(|| {
+        let index = quarantine_index_name(&target_index);
+        let body = index_settings.apply(&index, &generate_collection_mapping(None));
+        Arc::new((index, body))
+    });
+    let ensured_indices = Arc::new(EnsuredIndices::default());
+
+    let rate_limit_task = APP_CONFIG.rate_limit_file.as_ref().map(|path| {
+        let rate_limiter = rate_limiter.clone();
+        let path = PathBuf::from(path);
+        tokio::spawn(async move {
+            if let Err(e) = reload_on_sighup(rate_limiter, path).await {
+                warn!("⚠️  {:#}", e);
+            }
+        })
+    });
+
+    // Hold back new batches while the cluster is red or its pending task queue spikes
+    // Riding out rolling restarts: batches that find the cluster gone wait for it to come back
+    let restart_max_wait = APP_CONFIG.survive_restarts.then(|| Duration::from_secs(APP_CONFIG.restart_max_wait_secs.unwrap_or(600)));
+    let watchdog = match restart_max_wait {
+        Some(max_wait) => {
+            info!("✓ Surviving cluster restarts: batches wait up to {}s for Elasticsearch to return", max_wait.as_secs());
+            Some(Arc::new(HealthWatchdog::pausing_when_unreachable()))
+        }
+        None => APP_CONFIG.health_watchdog.then(|| Arc::new(HealthWatchdog::default())),
+    };
+    let watchdog_task = watchdog.as_ref().map(|watchdog| {
+        let interval = Duration::from_secs(APP_CONFIG.watchdog_interval_secs.unwrap_or(10).max(1));
+        let max_pending_tasks = APP_CONFIG.watchdog_max_pending_tasks.unwrap_or(100);
+        info!("✓ Health watchdog: polling every {}s, pausing on red or >{} pending tasks", interval.as_secs(), max_pending_tasks);
+        watchdog.spawn(client.clone(), elasticsearch_url().to_string(), interval, max_pending_tasks)
+    });
+
+    // Periodic per-worker status, so slow bulk requests can be told apart from hung ones
+    let heartbeats = APP_CONFIG.heartbeat_interval_secs.map(|_| Arc::new(Heartbeats::default()));
+    let heartbeat_task = heartbeats.as_ref().map(|heartbeats| {
+        let interval = Duration::from_secs(APP_CONFIG.heartbeat_interval_secs.unwrap_or(30).max(1));
+        let stall_threshold = Duration::from_secs(APP_CONFIG.stall_threshold_secs.unwrap_or(120));
+        info!("✓ Worker heartbeat every {}s, stall threshold {}s", interval.as_secs(), stall_threshold.as_secs());
+        heartbeats.spawn(interval, stall_threshold)
+    });
+
+    let error_log = Arc::new(BulkErrorLog::new(error_log_path(csv_file)));
+
+    let capture_samples = APP_CONFIG.capture_sample_on_error || args.capture_sample_on_error;
+    let quiet = APP_CONFIG.quiet || args.quiet;
+    let sample_capture = capture_samples.then(|| Arc::new(SampleCapture::new(csv_file)));
+
+    // Machine-readable progress for orchestrators, refreshed every PROGRESS_INTERVAL_SECS
+    if let Some(path) = &APP_CONFIG.progress_file {
+        info!("✓ Progress file: {}", path);
+    }
+    let progress = (APP_CONFIG.progress_file.is_some() || is_json()).then(|| {
+        Arc::new(ProgressFile::new(APP_CONFIG.progress_file.as_ref().map(PathBuf::from), target_index.clone(), remaining_records as u64))
+    });
+    let progress_task = progress.clone().map(|progress| {
+        let processed_count = processed_count.clone();
+        let checkpoint_mutex = checkpoint_mutex.clone();
+        let interval_secs = APP_CONFIG.progress_interval_secs.unwrap_or(5).max(1);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+            loop {
+                interval.tick().await;
+                let report = progress.report(RunState::Running, processed_count.load(Ordering::Relaxed), &*checkpoint_mutex.lock().await);
+                if let Err(e) = progress.write(&report).await {
+                    warn!("Failed to write progress file: {}", e);
+                }
+            }
+        })
+    });
 
     // Set up graceful shutdown handler
     let checkpoint_for_shutdown = checkpoint_mutex.clone();
+    let progress_for_shutdown = progress.clone();
+    let processed_for_shutdown = processed_count.clone();
+    let checkpoint_store_for_shutdown = checkpoint_store.clone();
+    let checkpoint_writer_for_shutdown = checkpoint_writer.clone();
     let csv_file_for_shutdown = csv_file.to_string();
-    tokio::spawn(async move {
-        signal::ctrl_c().await.expect("Failed to listen for ctrl+c");
-        println!("\n🛑 Received shutdown signal, saving checkpoint...");
+    let spool_for_shutdown = spool.clone();
+    let shutdown_handler = ShutdownHandler::register();
+    let shutdown_task = tokio::spawn(async move {
+        let spool_full = async {
+            match &spool_for_shutdown {
+                Some(spool) => spool.wait_until_full(SPOOL_CHECK_INTERVAL).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                result.expect("Failed to listen for ctrl+c");
+                info!("🛑 Received shutdown signal, saving checkpoint...");
+            }
+            problem = spool_full => error!("💽 {}; stopping, saving checkpoint...", problem),
+        }
+        // The final save is synchronous, after any background write in flight
+        checkpoint_writer_for_shutdown.finish().await;
         let checkpoint = checkpoint_for_shutdown.lock().await;
-        if let Err(e) = checkpoint.save(&csv_file_for_shutdown).await {
-            eprintln!("Failed to save checkpoint: {}", e);
+        if let Err(e) = checkpoint.save(&checkpoint_store_for_shutdown, &csv_file_for_shutdown).await {
+            warn!("Failed to save checkpoint: {}", e);
         }
-        std::process::exit(1);
+        if let Some(progress) = &progress_for_shutdown {
+            let report = progress.report(RunState::Interrupted, processed_for_shutdown.load(Ordering::Relaxed), &checkpoint);
+            if let Err(e) = progress.write(&report).await {
+                warn!("Failed to write progress file: {}", e);
+            }
+        }
+        drop(checkpoint);
+        shutdown_handler.saved_then_exit().await
     });
 
-    let results = stream::iter(batches.into_iter().enumerate())
-        .map(|(batch_num, (start_index, batch))| {
+    let progress_bar = {
+        let checkpoint = checkpoint_mutex.lock().await;
+        MigrationProgress::start(checkpoint.total_records as u64, checkpoint.processed_records as u64, quiet || is_json())
+    };
+    let worker_slots = Arc::new(WorkerSlots::default());
+    let results = stream::poll_fn(|cx| batch_receiver.poll_recv(cx))
+        .enumerate()
+        .map(|(batch_num, Batch { indices, keys, documents: mut batch, history_only, lane, .. })| {
+            let worker = worker_slots.acquire();
+            let span = info_span!("batch", batch = batch_num, worker = worker.id, records = batch.len(), index = %target_index);
             let client = client.clone();
             let processed_count = processed_count.clone();
+            let document_tally = document_tally.clone();
             let checkpoint_mutex = checkpoint_mutex.clone();
-            let csv_file = csv_file.to_string();
+            let checkpoint_writer = checkpoint_writer.clone();
+            let governor = governor.clone();
+            let asset_checker = asset_checker.clone();
+            let payment_tokens = payment_tokens.clone();
+            let aggregators = aggregators.clone();
+            let target_index = target_index.clone();
+            let retry_policy = retry_policy.clone();
+            let error_log = error_log.clone();
+            let sample_capture = sample_capture.clone();
+            let watchdog = watchdog.clone();
+            let heartbeats = heartbeats.clone();
+            let history_index = history_index.clone();
+            let lane_progress = lane_progress.clone();
+            let progress_bar = progress_bar.clone();
+            let batch_sizer = batch_sizer.clone();
+            let row_starts = row_starts.clone();
+            let rate_limiter = rate_limiter.clone();
+            let quarantine = quarantine.clone();
+            let ensured_indices = ensured_indices.clone();
             
             async move {
-                let batch_size = batch.len();
-                match bulk_index_documents(&client, &APP_CONFIG.elasticsearch_url, &APP_CONFIG.elasticsearch_index, batch).await {
-                    Ok(indexed_count) => {
+                if let Some(registry) = &payment_tokens {
+                    registry.annotate(&mut batch);
+                }
+                if let Some(checker) = &asset_checker {
+                    checker.annotate(&mut batch).await;
+                }
+                let snapshots: Vec<ListingSnapshot> = match &aggregators {
+                    Some(_) => batch.iter().filter(|doc| !doc.deleted).map(ListingSnapshot::from).collect(),
+                    None => Vec::new(),
+                };
+                let events: Vec<OrderEvent> = match &history_index {
+                    Some(_) => batch.iter().filter(|doc| !doc.deleted).flat_map(OrderEvents::order_events).collect(),
+                    None => Vec::new(),
+                };
+                // Both indices must have the batch before it's checkpointed. A token half
+                // that is already written (now or by an earlier run) isn't sent again.
+                let mut token_outcome = history_only.then(BulkOutcome::default);
+                let mut history_outcome = None;
+                // During a rolling restart the batch waits for the cluster instead of failing
+                let mut held_since: Option<Instant> = None;
+                let result = loop {
+                    if let Some((index, body)) = quarantine.as_deref().filter(|_| batch.iter().any(|doc| doc.needs_quarantine())) {
+                        let ensured = ensured_indices.ensure(index, || create_index_if_missing(&client, elasticsearch_url(), index, body)).await;
+                        if let Err(e) = ensured {
+                            break Err(e);
+                        }
+                    }
+                    if let Some(watchdog) = &watchdog {
+                        match restart_max_wait {
+                            Some(max_wait) if !watchdog.wait_until_healthy_for(max_wait).await => {
+                                break Err(anyhow::anyhow!("Elasticsearch still unavailable after {}s", max_wait.as_secs()));
+                            }
+                            Some(_) => {}
+                            None => watchdog.wait_until_healthy().await,
+                        }
+                    }
+                    let requests = token_outcome.is_none() as usize + history_index.is_some() as usize;
+                    let records = if token_outcome.is_none() { batch.len() } else { 0 } + events.len();
+                    rate_limiter.acquire(requests, records).await;
+                    let slot = match &governor {
+                        Some(governor) => Some(governor.acquire().await),
+                        None => None,
+                    };
+                    let heartbeat = heartbeats.as_ref().map(|heartbeats| heartbeats.start(batch_num));
+                    let mut result = Ok(());
+                    if token_outcome.is_none() {
+                        let started = Instant::now();
+                        result = bulk_index_documents(&client, elasticsearch_url(), &target_index, &batch, APP_CONFIG.quarantine_failed_extraction, APP_CONFIG.write_mode, &retry_policy).await
+                            .map(|outcome| token_outcome = Some(outcome));
+                        let rejected = match &result {
+                            Ok(()) => token_outcome.as_ref().is_some_and(|outcome| outcome.throttled > 0 || outcome.failures.iter().any(|failure| failure.status == 429)),
+                            Err(e) => e.downcast_ref::<PayloadTooLarge>().is_some(),
+                        };
+                        batch_sizer.record(started.elapsed(), rejected);
+                    }
+                    if let (Ok(()), Some(index)) = (&result, &history_index) {
+                        result = bulk_index_documents(&client, elasticsearch_url(), index, &events, false, WriteMode::Index, &retry_policy).await
+                            .map(|outcome| history_outcome = Some(outcome));
+                    }
+                    drop(heartbeat);
+                    drop(slot);
+                    if let (Err(e), Some(watchdog), Some(max_wait)) = (&result, &watchdog, restart_max_wait) {
+                        if let Some(unavailable) = e.downcast_ref::<ClusterUnavailable>() {
+                            // A cluster whose health answers while bulk requests keep failing
+                            // mustn't hold the batch forever
+                            if held_since.get_or_insert_with(Instant::now).elapsed() < max_wait {
+                                warn!("🔌 Batch {}: {}, holding it until the cluster is back", batch_num, unavailable);
+                                watchdog.observe(Some(unavailable.to_string()));
+                                continue;
+                            }
+                            warn!("🔌 Batch {}: {} for {}s (RESTART_MAX_WAIT_SECS), giving up", batch_num, unavailable, max_wait.as_secs());
+                        }
+                    }
+                    break result.map(|()| token_outcome.take().unwrap_or_default());
+                };
+                let result = match result {
+                    Ok(outcome) => {
+                        document_tally.add_outcome(batch.len(), history_only, &outcome);
+                        if outcome.skipped > 0 {
+                            warn!("⚠️  Batch {}: {} documents without a document id were not indexed", batch_num, outcome.skipped);
+                        }
+                        if let Err(e) = error_log.append(batch_num, &batch, APP_CONFIG.write_mode, &outcome.failures).await {
+                            warn!("Failed to write bulk error log: {}", e);
+                        }
+                        if let Some(history) = &history_outcome {
+                            if let Err(e) = error_log.append(batch_num, &events, WriteMode::Index, &history.failures).await {
+                                warn!("Failed to write bulk error log: {}", e);
+                            }
+                        }
+                        if let Some(samples) = &sample_capture {
+                            match samples.capture(batch_num, &batch, &outcome.failures).await {
+                                Ok(Some(bundle)) => info!("📦 Captured sample of rejected documents: {}", bundle.display()),
+                                Ok(None) => {}
+                                Err(e) => warn!("Failed to capture sample: {}", e),
+                            }
+                        }
+                        // Documents create mode found already present, and tombstones, count as done
+                        let indexed_count = outcome.indexed + outcome.existing + outcome.deleted + outcome.not_found;
+                        metrics::record_batch(true, indexed_count as u64);
+                        if let Some(governor) = &governor {
+                            governor.record(indexed_count);
+                        }
+                        if let Some(aggregators) = &aggregators {
+                            let mut aggregators = aggregators.lock().await;
+                            snapshots.iter().for_each(|snapshot| aggregators.add(snapshot));
+                        }
+                        if let Some(elapsed) = lane_progress.as_ref().and_then(|progress| progress.add_done(lane, indices.len())) {
+                            info!("⚡ All {} records indexed after {:.1}s", lane.name(), elapsed.as_secs_f64());
+                        }
                         let current = processed_count.fetch_add(indexed_count as u64, Ordering::Relaxed);
                         let new_total = current + indexed_count as u64;
                         
                         // Update checkpoint with completed batch range
                         {
                             let mut checkpoint = checkpoint_mutex.lock().await;
-                            checkpoint.add_completed_batch(start_index, batch_size);
+                            checkpoint.add_completed_batch(&indices, &keys);
+                            checkpoint.add_document_outcome(indexed_count, outcome.failed);
+                            if let Some(row_starts) = &row_starts {
+                                checkpoint.advance_boundary(row_starts);
+                            }
                             
+                            checkpoint_writer.changed();
                             // Save checkpoint every 10 batches or every 10k records
-                            if batch_num % 10 == 0 || new_total % 10000 == 0 {
-                                if let Err(e) = checkpoint.save(&csv_file).await {
-                                    eprintln!("Failed to save checkpoint: {}", e);
-                                }
+                            if batch_num.is_multiple_of(10) || new_total.is_multiple_of(10000) {
+                                checkpoint_writer.request();
                             }
                         }
+                        checkpoint_writer.save_if_stale().await;
                         
-                        if new_total % 10000 == 0 || new_total == remaining_records as u64 {
+                        if let Some(progress_bar) = &progress_bar {
+                            progress_bar.set_processed(checkpoint_mutex.lock().await.processed_records as u64);
+                        } else if new_total.is_multiple_of(10000) || new_total == remaining_records as u64 {
                             let checkpoint = checkpoint_mutex.lock().await;
-                            println!("  Migrated: {}/{} remaining ({:.1}% of total)", 
-                                   new_total, remaining_records,
-                                   ((checkpoint.processed_records as f64 / total_records as f64) * 100.0));
+                            let percent = (checkpoint.processed_records as f64 / total_records as f64) * 100.0;
+                            info!(migrated = new_total, remaining = remaining_records, percent,
+                                  "  Migrated: {}/{} remaining ({:.1}% of total)", new_total, remaining_records, percent);
                         }
                         Ok(indexed_count)
                     }
                     Err(e) => {
+                        metrics::record_batch(false, 0);
+                        document_tally.add_failed_batch(batch.len());
                         // Update checkpoint for failed batch
                         {
                             let mut checkpoint = checkpoint_mutex.lock().await;
                             checkpoint.add_failed_batch();
+                            if token_outcome.is_some() && !history_only {
+                                checkpoint.add_history_pending(&indices, &keys);
+                            }
+                        }
+                        if let Some(progress_bar) = &progress_bar {
+                            progress_bar.batch_failed();
                         }
-                        eprintln!("Batch failed: {}", e);
+                        error!("Batch failed: {}", e);
                         Err(e)
                     }
-                }
-            }
+                };
+                drop(worker);
+                result
+            }.instrument(span)
         })
         .buffer_unordered(APP_CONFIG.workers)
         .collect::<Vec<_>>()
         .await;
 
+    // A CSV error ends the stream early; save the checkpoint before reporting it
+    let stream_report = producer.await?;
     let successful = results.iter().filter(|r| r.is_ok()).count();
     let failed = results.iter().filter(|r| r.is_err()).count();
     let final_count = processed_count.load(Ordering::Relaxed);
     let duration = start_time.elapsed();
 
+    shutdown_task.abort();
+    if let Some(task) = progress_task {
+        task.abort();
+    }
+    if let Some(progress_bar) = &progress_bar {
+        progress_bar.finish();
+    }
+    if let Some(task) = rate_limit_task {
+        task.abort();
+    }
+    if let Some(task) = watchdog_task {
+        task.abort();
+    }
+    if let Some(task) = heartbeat_task {
+        task.abort();
+    }
+
     // Final checkpoint update
+    checkpoint_writer.finish().await;
+    checkpoint_writer_task.abort();
     {
         let checkpoint = checkpoint_mutex.lock().await;
+        if let Some(progress) = &progress {
+            let state = if checkpoint.is_completed() { RunState::Completed } else { RunState::Incomplete };
+            progress.write(&progress.report(state, final_count, &checkpoint)).await?;
+        }
         if checkpoint.is_completed() {
-            println!("✅ Migration completed successfully!");
+            info!("✅ Migration completed successfully!");
+            if id_selection.is_none() {
+                let run = CompletedRun::new(csv_file, total_records);
+                if let Err(e) = record_run(client, elasticsearch_url(), &target_index, &run).await {
+                    warn!("Failed to record completed run in {}: {:#}", target_index, e);
+                }
+            }
             drop(checkpoint);
-            MigrationCheckpoint::cleanup(csv_file).await?;
+            MigrationCheckpoint::cleanup(&checkpoint_store, &checkpoint_keys).await?;
+            // The downloaded CSV isn't needed anymore
+            if let Some(spool) = spool.as_ref().filter(|spool| is_url(&APP_CONFIG.csv_file) && spool.contains(Path::new(csv_file))) {
+                remove_download(Path::new(csv_file)).await?;
+                info!("🧹 Removed {} from spool dir {}", csv_file, spool.dir.display());
+            }
         } else {
-            println!("⚠️  Migration incomplete, checkpoint saved for resume");
-            checkpoint.save(csv_file).await?;
+            warn!("⚠️  Migration incomplete, checkpoint saved for resume");
+            checkpoint.save(&checkpoint_store, csv_file).await?;
         }
     }
+    // The checkpoint is saved, so a CSV error mid-stream leaves a resumable run
+    let mut stream_report = stream_report.context(Resumable)?;
 
-    println!("\n📊 Migration Summary:");
-    println!("   Duration: {:.2}s", duration.as_secs_f64());
-    println!("   Records processed this session: {}", final_count);
-    println!("   Successful batches: {}", successful);
-    println!("   Failed batches: {}", failed);
+    if let Some(aggregators) = aggregators {
+        let aggregators = std::mem::take(&mut *aggregators.lock().await);
+        if let (Some(index), Some(owners)) = (&APP_CONFIG.owners_summary_index, aggregators.owners) {
+            let count = owners.len();
+            write_side_index(client, index, &index_settings.apply(index, &owners_summary_mapping()), owners.into_summaries()).await?;
+            info!("✓ Indexed {} owner summaries into {}", count, index);
+        }
+        if let (Some(index), Some(collections)) = (&APP_CONFIG.collections_stats_index, aggregators.collections) {
+            let count = collections.len();
+            write_side_index(client, index, &index_settings.apply(index, &collections_stats_mapping()), collections.into_stats()).await?;
+            info!("✓ Indexed {} collection stats into {}", count, index);
+        }
+    }
+
+    info!(duration_secs = duration.as_secs_f64(), indexed = final_count, successful_batches = successful, failed_batches = failed,
+          "📊 Migration Summary:");
+    info!("   Duration: {:.2}s", duration.as_secs_f64());
+    info!("   Documents indexed this session: {}", final_count);
+    info!("   Successful batches: {}", successful);
+    info!("   Failed batches: {}", failed);
+    if row_filter.is_some() {
+        info!("   Records filtered out: {}", filtered_rows);
+    }
     if final_count > 0 {
-        println!("   Rate: {:.0} records/sec", final_count as f64 / duration.as_secs_f64());
+        info!("   Rate: {:.0} records/sec", final_count as f64 / duration.as_secs_f64());
+    }
+    let mut conservation = Conservation {
+        rows_read: total_records,
+        rows_done_earlier: done_rows,
+        rows_filtered: filtered_rows,
+        rows_unselected: unselected_rows,
+        rows_superseded: duplicate_rows,
+        rows_parsed: stream_report.rows,
+        rows_merged: stream_report.rows - stream_report.documents,
+        documents_built: stream_report.documents,
+        ..Conservation::default()
+    };
+    conservation.add_tally(&document_tally);
+    conservation.print();
+    if let Some(data_loss) = stream_report.data_loss.as_mut() {
+        data_loss.rejected_documents = conservation.documents_rejected as u64;
     }
+    if conservation.documents_without_id > 0 {
+        warn!("⚠️  {} rows had no document id (a token_id, or a token_address DOCUMENT_ID_TEMPLATE needs) and were not indexed",
+              conservation.documents_without_id);
+    }
+    let coverage = stream_report.coverage.report();
+    // Quiet runs only get the totals
+    if !quiet {
+        if let Some(registry) = &payment_tokens {
+            let unknown = registry.unknown_tokens();
+            if !unknown.is_empty() {
+                info!("   Unknown payment tokens:");
+                for (token, count) in unknown.iter().take(10) {
+                    info!("     {}: {} documents", token, count);
+                }
+                if unknown.len() > 10 {
+                    info!("     ... and {} more", unknown.len() - 10);
+                }
+            }
+        }
+        if error_log.len() > 0 {
+            info!("   Documents rejected by Elasticsearch: {}, saved to {}; fix the cause and rerun with --retry-dlq",
+                  error_log.len(), error_log.path().display());
+        }
+        if let Some(checker) = &asset_checker {
+            info!("   Documents with broken assets: {}", checker.broken_documents());
+        }
+        if let Some(governor) = &governor {
+            let unreachable = governor.unreachable_windows();
+            if unreachable > 0 {
+                info!("   Target {:.0} records/sec was unreachable for {} adjustment windows", governor.target(), unreachable);
+            }
+        }
+        if ensured_indices.created() > 0 {
+            info!("   Indices created during the run: {}", ensured_indices.created());
+        }
+        if !rate_limiter.waited().is_zero() {
+            info!("   Held back by rate limits for {:.1}s in total", rate_limiter.waited().as_secs_f64());
+        }
+        if checkpoint_writer.max_window() > 0 {
+            info!("   Checkpoint lag: at most {} records would have been reprocessed after a crash", checkpoint_writer.max_window());
+        }
+        if batch_sizer.is_adaptive() {
+            info!("   Final batch size: {} documents", batch_sizer.documents());
+        }
+        if let Some(lane_progress) = &lane_progress {
+            for lane in lane_progress.status() {
+                match lane.finished {
+                    Some(elapsed) => info!("   Lane {}: {} records, done after {:.1}s", lane.lane.name(), lane.done, elapsed.as_secs_f64()),
+                    None => info!("   Lane {}: {}/{} records (incomplete)", lane.lane.name(), lane.done, lane.sent),
+                }
+            }
+        }
+        if grouping.is_some() {
+            info!("   Order rows grouped: {} rows into {} token documents", stream_report.order_rows, stream_report.grouped_documents);
+        }
+        if APP_CONFIG.expired_listings != ExpiredListings::Keep {
+            let action = match APP_CONFIG.expired_listings {
+                ExpiredListings::Archive => "archived",
+                _ => "cleared",
+            };
+            info!("   Expired listings with order fields {}: {}", action, stream_report.expired_listings);
+        }
+        let sizes = stream_report.shapes.report().bulk_bytes;
+        if sizes.count > 0 {
+            info!("   Document size: p50 ≤ {} bytes, p99 ≤ {} bytes, largest {} bytes", sizes.p50, sizes.p99, sizes.max);
+        }
+        if !coverage.is_empty() {
+            info!("   Extracted field coverage:");
+            print_coverage(&coverage);
+        }
+        if let Some(watchdog) = &watchdog {
+            let windows = watchdog.pause_windows();
+            if !windows.is_empty() {
+                let paused: Duration = windows.iter().map(|w| w.duration).sum();
+                info!("   Paused by health watchdog: {} times, {:.1}s total", windows.len(), paused.as_secs_f64());
+                for window in &windows {
+                    info!("     {:.1}s: {}", window.duration.as_secs_f64(), window.reason);
+                }
+            }
+        }
+        let counters = BulkCounters::now().since(&counters_at_start);
+        if counters.deleted_documents > 0 {
+            info!("   Documents deleted by tombstone rows: {}", counters.deleted_documents);
+        }
+        if counters.deletes_not_found > 0 {
+            warn!("⚠️  Tombstone rows whose document wasn't found: {} (already deleted, or indexed with other ROUTING)",
+                  counters.deletes_not_found);
+        }
+        if counters.reissued_requests > 0 {
+            info!("   Stalled bulk requests reissued: {}", counters.reissued_requests);
+        }
+        if !counters.throttled_time.is_zero() {
+            info!("   Time throttled by HTTP 429 (all workers): {:.1}s", counters.throttled_time.as_secs_f64());
+        }
     
-    {
+        ResourceUsage::current().print();
+    }
+    if let Some(path) = APP_CONFIG.field_coverage_report.as_ref().filter(|_| !coverage.is_empty()) {
+        write_coverage(Path::new(path), &coverage).await?;
+        info!("   Field coverage report written to {}", path);
+    }
+    
+    let outcome = {
         let checkpoint = checkpoint_mutex.lock().await;
-        println!("   Total progress: {:.1}% ({}/{})", 
+        info!("   Total progress: {:.1}% ({}/{})", 
                  checkpoint.progress_percentage(), 
                  checkpoint.processed_records, 
                  checkpoint.total_records);
+        info!("   Documents indexed (all sessions): {}, rejected: {}", checkpoint.indexed_documents, checkpoint.rejected_documents);
+        if !checkpoint.history_pending.is_empty() {
+            info!("   Records with token documents but no orders history yet: {} (written on resume)", checkpoint.history_pending.len());
+        }
+        if let Some(progress) = &progress {
+            let state = if checkpoint.is_completed() { RunState::Completed } else { RunState::Incomplete };
+            emit("summary", &RunSummary {
+                progress: progress.report(state, final_count, &checkpoint),
+                duration_secs: duration.as_secs_f64(),
+                session_successful_batches: successful,
+                session_failed_batches: failed,
+                rejected_documents: error_log.len(),
+                document_shapes: stream_report.shapes.report(),
+                conservation,
+                deprecation_warnings: deprecation_warnings(),
+            });
+        }
+        Outcome::of_migration(checkpoint.is_completed(), error_log.len())
+    };
+
+    if let Some(data_loss) = &stream_report.data_loss {
+        data_loss.verify()?;
+    }
+
+    Ok(outcome)
+}
+
+/// Print the tokens whose duplicate rows disagreed on the owner and write the full list
+async fn report_duplicates(csv_file: &str, duplicates: &DuplicateIndex) -> Result<()> {
+    if duplicates.superseded() > 0 {
+        info!("✓ Collapsed {} duplicate rows by latest ownership", duplicates.superseded());
+    }
+    let conflicts = duplicates.conflicts();
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    warn!("⚠️  {} tokens had duplicate rows with different owners:", conflicts.len());
+    for conflict in conflicts.iter().take(10) {
+        info!("   token {}: {} -> kept {} (block {})", conflict.token_id, conflict.owners.join(", "),
+                 conflict.kept_owner.as_deref().unwrap_or("-"),
+                 conflict.kept_block_number.map_or("-".to_string(), |b| b.to_string()));
+    }
+    let path = state_file(csv_file, "ownership_conflicts.json");
+    ensure_parent_dir(&path).await?;
+    tokio::fs::write(&path, serde_json::to_string_pretty(&conflicts)?).await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("   Full list written to {}", path.display());
+    Ok(())
+}
+
+/// Documents on their way to a worker
+#[derive(Default)]
+struct Batch {
+    /// Record indices the batch covers
+    indices: RoaringTreemap,
+    /// Checkpoint keys of its documents
+    keys: Vec<u64>,
+    documents: Vec<FlexibleElasticsearchDocument>,
+    /// Estimated bulk body size, tracked only under MAX_BULK_BYTES
+    bytes: usize,
+    /// The token documents are already written, only orders history is missing
+    history_only: bool,
+    lane: Lane,
+}
+
+/// What the streaming pass sends, as decided by the first pass
+struct StreamPlan {
+    selected: RoaringTreemap,
+    /// Rows whose token document was written by an earlier run but whose orders history wasn't
+    history_only: RoaringTreemap,
+    /// Rows matching PRIORITY_FILTER; documents built from any of them go in the priority lane
+    priority_rows: Option<RoaringTreemap>,
+    /// Some(sorted_by_id) when order rows are merged per token
+    grouping: Option<bool>,
+    /// Noted row to seek to, at or before the first selected one
+    start: Option<RowOffset>,
+    /// Where transform chunks start, for the checkpoint's boundary offset (index mode only)
+    row_starts: Option<Arc<RowStarts>>,
+    /// Count every value or row lost on the way to a document (--strict)
+    strict: bool,
+}
+
+impl StreamPlan {
+    fn lane(&self, indices: &RoaringTreemap) -> Lane {
+        match &self.priority_rows {
+            Some(priority) if !indices.is_disjoint(priority) => Lane::Priority,
+            _ => Lane::Rest,
+        }
+    }
+}
+
+/// What the streaming pass saw, for the summary
+#[derive(Default)]
+struct StreamReport {
+    coverage: CoverageTracker,
+    expired_listings: usize,
+    shapes: DocumentShapes,
+    order_rows: usize,
+    grouped_documents: usize,
+    /// Rows behind the documents pushed, and the documents
+    rows: usize,
+    documents: usize,
+    /// Only under --strict
+    data_loss: Option<DataLoss>,
+}
+
+/// Collects documents into batches within the sizer's limits and hands them to the
+/// workers, blocking while the channel is full
+struct BatchSink<'a> {
+    sender: mpsc::Sender<Batch>,
+    plan: &'a StreamPlan,
+    sizer: &'a BatchSizer,
+    /// Lane being streamed; documents of the other lane are left for its pass
+    lane: Lane,
+    lane_progress: Option<Arc<LaneProgress>>,
+    batch: Batch,
+    /// Documents of rows in `history_only`, batched separately
+    history_batch: Batch,
+    report: StreamReport,
+    now: i64,
+}
+
+impl BatchSink<'_> {
+    fn push(&mut self, indices: RoaringTreemap, mut doc: FlexibleElasticsearchDocument) -> Result<()> {
+        if self.plan.lane(&indices) != self.lane {
+            return Ok(());
+        }
+        self.report.rows += indices.len() as usize;
+        self.report.documents += 1;
+        // Listings that expired before the migration shouldn't show up as purchasable
+        let expired = expire_listing(&mut doc, APP_CONFIG.expired_listings, self.now);
+        if expired {
+            self.report.expired_listings += 1;
+        }
+        self.report.coverage.add(&doc);
+        if let Some(data_loss) = &mut self.report.data_loss {
+            data_loss.add_document(&doc);
+            // Archived listings keep their order fields, cleared ones don't
+            if expired && APP_CONFIG.expired_listings == ExpiredListings::Clear {
+                data_loss.cleared_listings += 1;
+            }
+        }
+
+        let history_only = indices.is_subset(&self.plan.history_only) && !indices.is_empty();
+        let sizer = self.sizer;
+        let size = bulk_size(&doc);
+        self.report.shapes.add(&doc, size);
+        let size = sizer.max_bytes().map_or(0, |_| size);
+        if sizer.overflows(self.batch_mut(history_only).bytes, size) {
+            let batch = std::mem::take(self.batch_mut(history_only));
+            self.send(batch, history_only)?;
+        }
+        let batch = self.batch_mut(history_only);
+        batch.indices |= indices;
+        if let Some(doc_id) = doc.document_id() {
+            batch.keys.push(record_key(doc_id));
+        }
+        batch.documents.push(doc);
+        batch.bytes += size;
+        if sizer.is_full(batch.documents.len(), batch.bytes) {
+            let batch = std::mem::take(batch);
+            self.send(batch, history_only)?;
+        }
+        Ok(())
+    }
+
+    fn batch_mut(&mut self, history_only: bool) -> &mut Batch {
+        if history_only { &mut self.history_batch } else { &mut self.batch }
+    }
+
+    /// Merge order rows into one document per token and push those
+    fn push_order_rows(&mut self, rows: Vec<(usize, FlexibleElasticsearchDocument)>, sorted_by_id: bool) -> Result<()> {
+        for (indices, doc) in merge_order_rows(rows, sorted_by_id) {
+            if self.plan.lane(&indices) == self.lane {
+                self.report.order_rows += indices.len() as usize;
+                self.report.grouped_documents += 1;
+            }
+            self.push(indices, doc)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, mut batch: Batch, history_only: bool) -> Result<()> {
+        if batch.documents.is_empty() {
+            return Ok(());
+        }
+        batch.history_only = history_only;
+        batch.lane = self.lane;
+        if let Some(progress) = &self.lane_progress {
+            progress.add_sent(self.lane, batch.indices.len());
+        }
+        self.sender.blocking_send(batch)
+            .map_err(|_| anyhow::anyhow!("Workers stopped before all batches were sent"))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+        self.send(batch, false)?;
+        let history_batch = std::mem::take(&mut self.history_batch);
+        self.send(history_batch, true)
+    }
+}
+
+/// Second pass over the CSV: build documents for the rows the first pass selected
+/// and send them to the workers in batches. With a priority filter the file is
+/// read twice, sending the priority lane first.
+fn stream_documents(csv_file: &str, headers: &StringRecord, plan: &StreamPlan, sizer: &BatchSizer, lane_progress: Option<Arc<LaneProgress>>, sender: mpsc::Sender<Batch>) -> Result<StreamReport> {
+    let mut sink = BatchSink {
+        sender,
+        plan,
+        sizer,
+        lane: Lane::Rest,
+        lane_progress,
+        batch: Batch::default(),
+        history_batch: Batch::default(),
+        report: StreamReport { data_loss: plan.strict.then(DataLoss::default), ..Default::default() },
+        now: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64,
+    };
+    let lanes = match plan.priority_rows {
+        Some(_) => vec![Lane::Priority, Lane::Rest],
+        None => vec![Lane::Rest],
+    };
+    for lane in lanes {
+        sink.lane = lane;
+        stream_lane(csv_file, headers, &mut sink)?;
+        sink.flush()?;
+        if let Some(elapsed) = sink.lane_progress.as_ref().and_then(|progress| progress.close(lane)) {
+            info!("⚡ All {} records indexed after {:.1}s", lane.name(), elapsed.as_secs_f64());
+        }
+    }
+    Ok(sink.report)
+}
+
+/// Rows handed to a transform worker at a time
+const TRANSFORM_CHUNK_ROWS: usize = 256;
+
+/// Chunks queued per transform worker, both ways
+const TRANSFORM_QUEUE_CHUNKS: usize = 2;
+
+/// Documents built from a chunk of rows, in row order
+struct DocumentChunk {
+    documents: Vec<(usize, FlexibleElasticsearchDocument)>,
+    /// Only under --strict
+    data_loss: Option<DataLoss>,
+}
+
+/// One read of the CSV, pushing the documents of the sink's lane. A reader thread
+/// selects rows, TRANSFORM_WORKERS threads parse them into documents, and this
+/// thread takes the chunks back in the order they were read, so grouping and
+/// batching see the rows as the file has them. Sorted input only buffers one
+/// token's rows while grouping.
+fn stream_lane(csv_file: &str, headers: &StringRecord, sink: &mut BatchSink) -> Result<()> {
+    let (mut source, _) = open_source(csv_file)?;
+    let source_file = Path::new(csv_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    let plan = sink.plan;
+    let lane = sink.lane;
+    if let Some(start) = &plan.start {
+        source.seek_to_row(start)?;
+    }
+
+    // Set before the reader hangs up, so the consumer doesn't flush a token cut short
+    let read_failed = AtomicBool::new(false);
+    let streamed = std::thread::scope(|scope| {
+        let sink = &mut *sink;
+        let mut chunk_senders = Vec::new();
+        let mut document_receivers = Vec::new();
+        for _ in 0..APP_CONFIG.transform_workers() {
+            let (chunk_sender, chunk_receiver) = std::sync::mpsc::sync_channel::<Vec<(usize, StringRecord)>>(TRANSFORM_QUEUE_CHUNKS);
+            let (document_sender, document_receiver) = std::sync::mpsc::sync_channel(TRANSFORM_QUEUE_CHUNKS);
+            let source_file = &source_file;
+            scope.spawn(move || {
+                for rows in chunk_receiver {
+                    if document_sender.send(transform_rows(rows, headers, plan, lane, source_file)).is_err() {
+                        break;
+                    }
+                }
+            });
+            chunk_senders.push(chunk_sender);
+            document_receivers.push(document_receiver);
+        }
+
+        let read_failed = &read_failed;
+        let consumer = scope.spawn(move || -> Result<()> {
+            let mut order_rows: Vec<(usize, FlexibleElasticsearchDocument)> = Vec::new();
+            // Chunk n went to worker n % workers, and is taken back from there
+            for receiver in document_receivers.iter().cycle() {
+                // Closed once the reader is done and the worker has sent what it had
+                let Ok(chunk) = receiver.recv() else { break };
+                let chunk = chunk?;
+                if let (Some(total), Some(data_loss)) = (sink.report.data_loss.as_mut(), chunk.data_loss) {
+                    total.merge(data_loss);
+                }
+                for (record_index, doc) in chunk.documents {
+                    match plan.grouping {
+                        None => sink.push(RoaringTreemap::from_iter([record_index as u64]), doc)?,
+                        // A token's rows are adjacent, so merge them as soon as the id changes
+                        Some(true) => {
+                            if order_rows.last().is_some_and(|(_, last)| last.document_id() != doc.document_id()) {
+                                sink.push_order_rows(std::mem::take(&mut order_rows), true)?;
+                            }
+                            order_rows.push((record_index, doc));
+                        }
+                        Some(false) => order_rows.push((record_index, doc)),
+                    }
+                }
+            }
+            // The rows of the last token (all of them, unsorted) may be missing after a failed read
+            if let Some(sorted_by_id) = plan.grouping.filter(|_| !read_failed.load(Ordering::Acquire)) {
+                sink.push_order_rows(order_rows, sorted_by_id)?;
+            }
+            Ok(())
+        });
+
+        let read = (|| -> Result<()> {
+            let mut chunk = Vec::with_capacity(TRANSFORM_CHUNK_ROWS);
+            let mut workers = chunk_senders.iter().cycle();
+            for (record_index, result) in (plan.start.map_or(0, |start| start.index)..).zip(source.rows()) {
+                let row = result?;
+                if !plan.selected.contains(record_index as u64) {
+                    continue;
+                }
+                // Ungrouped rows are one document each, so rows of the other lane needn't be parsed
+                if plan.grouping.is_none() && plan.lane(&RoaringTreemap::from_iter([record_index as u64])) != lane {
+                    continue;
+                }
+                if let (true, Some(row_starts), Some(position)) = (chunk.is_empty(), &plan.row_starts, row.position()) {
+                    row_starts.note(RowOffset::new(record_index, position));
+                }
+                chunk.push((record_index, row));
+                if chunk.len() == TRANSFORM_CHUNK_ROWS {
+                    let full = std::mem::replace(&mut chunk, Vec::with_capacity(TRANSFORM_CHUNK_ROWS));
+                    // Workers only hang up once the consumer has stopped taking documents
+                    if workers.next().is_some_and(|worker| worker.send(full).is_err()) {
+                        return Ok(());
+                    }
+                }
+            }
+            if let Some(worker) = workers.next().filter(|_| !chunk.is_empty()) {
+                worker.send(chunk).ok();
+            }
+            Ok(())
+        })();
+        if read.is_err() {
+            read_failed.store(true, Ordering::Release);
+        }
+        // Hanging up lets the workers finish, and with them the consumer
+        drop(chunk_senders);
+        let pushed = consumer.join().map_err(|_| anyhow::anyhow!("Document consumer thread panicked"))?;
+        // A failed push explains a stopped read better than the read does
+        pushed.and(read)
+    });
+    // Both lanes read the whole file; its dropped values are counted once
+    if let Some(data_loss) = sink.report.data_loss.as_mut().filter(|_| lane == Lane::Rest) {
+        data_loss.merge(DataLoss { unsampled_keys: source.dropped_keys(), ..DataLoss::default() });
+    }
+    streamed
+}
+
+/// Parse a chunk of rows into documents
+fn transform_rows(rows: Vec<(usize, StringRecord)>, headers: &StringRecord, plan: &StreamPlan, lane: Lane, source_file: &Option<String>) -> Result<DocumentChunk> {
+    let mut data_loss = plan.strict.then(DataLoss::default);
+    let mut documents = Vec::with_capacity(rows.len());
+    for (record_index, row) in rows {
+        let record: CsvRecord = row.deserialize(Some(headers))?;
+        // Grouped rows of the other lane are read for their token, not counted twice
+        if let Some(data_loss) = data_loss.as_mut().filter(|_| plan.lane(&RoaringTreemap::from_iter([record_index as u64])) == lane) {
+            data_loss.add_record(&record);
+        }
+        let mut doc = build_document(record);
+        doc.source_file = source_file.clone();
+        doc.source_row = Some(row.position().map_or(0, |p| p.line()));
+        documents.push((record_index, doc));
+    }
+    Ok(DocumentChunk { documents, data_loss })
+}
+
+/// Document for a CSV row, with the typed fields of its collection extracted
+/// when the collection is configured and HASH_FIELDS pseudonymized
+fn build_document(mut record: CsvRecord) -> FlexibleElasticsearchDocument {
+    pseudonymize(&mut record);
+    let config = record.token_address.as_deref().and_then(get_collection_config);
+    FlexibleElasticsearchDocument::from_record(record, config.as_deref())
+}
+
+/// The bulk error log, where documents the cluster rejected are kept for `--retry-dlq`
+fn error_log_path(csv_file: &str) -> PathBuf {
+    match &APP_CONFIG.bulk_error_log {
+        Some(path) => PathBuf::from(path),
+        None => spool_file(csv_file, "errors.ndjson"),
+    }
+}
+
+/// What a migration needs: `create_index` on the indices it creates, `write` on every
+/// index it writes, and `manage` on the target for the run history (PUT _mapping)
+/// the existing-data guard reads and for RAISE_TOTAL_FIELDS_LIMIT (PUT _settings)
+async fn migrate_privileges(client: &Client, args: &MigrateArgs) -> Result<Vec<IndexPrivileges>> {
+    let written = APP_CONFIG.written_indices();
+    let missing = missing_indices(client, elasticsearch_url(), &written).await?;
+    let mut required = for_writing(written, &missing);
+    let run_history = !existing_data_allowed(args);
+    if run_history || APP_CONFIG.raise_total_fields_limit {
+        required.push(IndexPrivileges::manage(vec![APP_CONFIG.target_index()]));
+    }
+    Ok(required)
+}
+
+/// Whether a fresh run may write into an index that already has documents: with
+/// ALLOW_EXISTING, or WRITE_MODE=upsert, which exists to update populated indices
+fn existing_data_allowed(args: &MigrateArgs) -> bool {
+    APP_CONFIG.allow_existing || args.allow_existing || APP_CONFIG.write_mode == WriteMode::Upsert
+}
+
+/// A fresh run into an index that already has documents is most likely a double load
+async fn refuse_existing_data(client: &Client, csv_file: &str) -> Result<()> {
+    let index = APP_CONFIG.target_index();
+    let count = document_count(client, elasticsearch_url(), &index).await?;
+    if count.is_some_and(|count| count > 0) {
+        let last_run = last_run(client, elasticsearch_url(), &index).await?;
+        if let Some(error) = existing_data_error(&index, count, last_run.as_ref(), csv_file) {
+            return Err(anyhow::anyhow!(error));
+        }
+    }
+    Ok(())
+}
+
+/// `--retry-dlq`: re-send only the documents in the bulk error log
+async fn run_retry_dlq(client: &Client, csv_file: &str) -> Result<Outcome> {
+    let path = error_log_path(csv_file);
+    if !path.exists() {
+        info!("✅ No dead letters to retry ({} does not exist)", path.display());
+        return Ok(Outcome::Success);
+    }
+    info!("🔁 Retrying dead letters from {}", path.display());
+    let settings = ResendSettings { batch_size: APP_CONFIG.batch_size, mode: APP_CONFIG.write_mode, retry: &APP_CONFIG.bulk_retry_policy() };
+    let report = retry_dead_letters(client, elasticsearch_url(), &path, &APP_CONFIG.target_index(),
+                                    &settings, &APP_CONFIG.collection_index_pattern()?).await?;
+    info!("✓ Retried {} documents: {} indexed, {} still failing", report.retried, report.indexed, report.still_failing);
+    if report.still_failing > 0 {
+        warn!("⚠️  {} documents still rejected, kept in {}", report.still_failing, path.display());
+        return Ok(Outcome::CompletedWithDeadLetters);
+    }
+    info!("✅ Bulk error log cleared");
+    Ok(Outcome::Success)
+}
+
+/// Create the target index with the mapping generated for the CSV's collections, or
+/// fail if an existing index maps any of those fields with a different type
+async fn bootstrap_index(client: &Client, index: &str, collections: &BTreeSet<String>, index_settings: &IndexSettings,
+                         field_limit: Option<u64>) -> Result<()> {
+    let mapping = generate_index_mapping(collections)?;
+    match fetch_index_mapping(client, elasticsearch_url(), index).await? {
+        Some(existing) => {
+            let conflicts = mapping_conflicts(&mapping["mappings"], &existing);
+            if !conflicts.is_empty() {
+                anyhow::bail!("Mapping of index {} conflicts with the generated mapping:\n  {}", index, conflicts.join("\n  "));
+            }
+            info!("✓ Index {} exists with a compatible mapping", index);
+        }
+        None => {
+            let mut body = index_settings.apply(index, &mapping);
+            if let Some(limit) = field_limit {
+                body["settings"]["index.mapping.total_fields.limit"] = serde_json::json!(limit);
+            }
+            create_index_if_missing(client, elasticsearch_url(), index, &body).await?;
+            let configured = collections.iter().filter(|address| get_collection_config(address).is_some()).count();
+            info!("✓ Created index {} with the generated mapping ({} configured collections)", index, configured);
+            wait_for_index_health(client, elasticsearch_url(), index,
+                                  APP_CONFIG.index_wait_for_status, APP_CONFIG.index_wait_timeout()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Compare the fields the run will map against the index's total_fields.limit, and
+/// raise the limit (RAISE_TOTAL_FIELDS_LIMIT) or warn before anything is written.
+/// Returns the limit to create the index with when it doesn't exist yet.
+async fn check_field_limit(client: &Client, index: &str, dynamic: &BTreeSet<String>, collections: &BTreeSet<String>) -> Result<Option<u64>> {
+    let usage = fetch_field_usage(client, elasticsearch_url(), index).await?;
+    let exists = usage.is_some();
+    let (limit, mapped) = match usage {
+        Some(usage) => usage,
+        None => {
+            // Not created yet: estimate from the mappings that would be generated
+            let mut mapped = BTreeSet::new();
+            mapping_field_paths(&generate_collection_mapping(None)["mappings"]["properties"], "", &mut mapped);
+            for mapping in collections.iter().filter_map(|address| collection_mapping(address)) {
+                mapping_field_paths(&mapping["mappings"]["properties"], "", &mut mapped);
+            }
+            (DEFAULT_TOTAL_FIELDS_LIMIT, mapped)
+        }
+    };
+
+    let estimate = estimate_fields(&mapped, dynamic);
+    if (estimate.total as u64) <= limit {
+        return Ok(None);
+    }
+
+    let new_limit = suggested_limit(estimate.total);
+    if APP_CONFIG.raise_total_fields_limit && !exists {
+        info!("✓ {} will be created with index.mapping.total_fields.limit {} (estimated {} fields)", index, new_limit, estimate.total);
+        return Ok(Some(new_limit));
+    }
+    if APP_CONFIG.raise_total_fields_limit {
+        raise_field_limit(client, elasticsearch_url(), index, new_limit).await?;
+        info!("✓ Raised index.mapping.total_fields.limit of {} from {} to {} (estimated {} fields)",
+                 index, limit, new_limit, estimate.total);
+    } else {
+        warn!("🚨 Estimated {} fields ({} mapped + {} new dynamic) exceed index.mapping.total_fields.limit {} of {}",
+                 estimate.total, estimate.mapped, estimate.dynamic_new, limit, index);
+        warn!("🚨 Batches will fail once the limit is hit; set RAISE_TOTAL_FIELDS_LIMIT=true to raise it to {}", new_limit);
+    }
+    Ok(None)
+}
+
+/// Create a side-index if needed and bulk index the documents aggregated for it
+async fn write_side_index<D: BulkDocument>(client: &Client, index: &str, mapping: &serde_json::Value, mut documents: Vec<D>) -> Result<()> {
+    if create_index_if_missing(client, elasticsearch_url(), index, mapping).await? {
+        info!("✓ Created index {}", index);
+        wait_for_index_health(client, elasticsearch_url(), index,
+                              APP_CONFIG.index_wait_for_status, APP_CONFIG.index_wait_timeout()).await?;
+    }
+
+    while !documents.is_empty() {
+        let rest = documents.split_off(documents.len().min(APP_CONFIG.batch_size));
+        bulk_index_documents(client, elasticsearch_url(), index, &documents, false, WriteMode::Index, &APP_CONFIG.bulk_retry_policy()).await?;
+        documents = rest;
+    }
+    Ok(())
+}
+
+/// Install COLLECTIONS_FILE's collection configs, if one is set
+fn install_configured_collections() -> Result<()> {
+    if let Some(path) = &APP_CONFIG.collections_file {
+        let collections = load_collections(path).context(ConfigError)?;
+        info!("✓ Loaded {} collection configs from {}", collections.len(), path);
+        install_collections(collections);
+    }
+    Ok(())
+}
+
+/// Install FIELD_ANALYZERS and DESCRIPTION_LANGUAGES for the mappings, checked
+/// against the plugins of the cluster's nodes before any index is created
+async fn configure_text_analysis(client: &Client) -> Result<()> {
+    let mut analysis = APP_CONFIG.text_analysis().context(ConfigError)?;
+    if analysis.is_empty() {
+        return Ok(());
+    }
+    if analysis.needs_plugins() {
+        analysis.plugins = Some(installed_plugins(client, elasticsearch_url()).await?);
+        analysis.gate().context(ConfigError)?;
+    }
+    let languages: Vec<&str> = analysis.languages.iter().map(|language| language.code()).collect();
+    if !languages.is_empty() {
+        info!("✓ Description subfields for {}", languages.join(", "));
+    }
+    install_text_analysis(analysis);
+    Ok(())
+}
+
+fn configured_index_settings() -> Result<IndexSettings> {
+    match &APP_CONFIG.index_settings_file {
+        Some(path) => IndexSettings::load(path).context(ConfigError),
+        None => Ok(IndexSettings::default()),
+    }
+}
+
+/// `create-index`: create the target index for the collections in the CSV, or
+/// check that an existing index's mapping agrees with the generated one
+async fn run_create_index(client: &Client) -> Result<()> {
+    install_configured_collections()?;
+    configure_text_analysis(client).await?;
+    let index_settings = configured_index_settings()?;
+    let mut collections = BTreeSet::new();
+    for file in input_files().await? {
+        let (mut source, headers) = open_with_headers(&file)?;
+        if let Some(column) = headers.iter().position(|h| h == "token_address") {
+            for row in source.rows() {
+                if let Some(address) = row?.get(column).map(str::trim).filter(|a| !a.is_empty()) {
+                    collections.insert(address.to_lowercase());
+                }
+            }
+        }
+    }
+    bootstrap_index(client, &APP_CONFIG.target_index(), &collections, &index_settings, None).await
+}
+
+/// `status`: what the checkpoint says about the CSV's migration
+async fn run_status(client: &Client) -> Result<()> {
+    let store = CheckpointStore::from_config(&APP_CONFIG, client, elasticsearch_url())?;
+    if is_multi_file(&APP_CONFIG.csv_file) {
+        let manifest = FileManifest::load(&csv_files_manifest_path(&APP_CONFIG.csv_file)).await?;
+        for file in expand_csv_files(&APP_CONFIG.csv_file).context(ConfigError)? {
+            match manifest.is_completed(&file) {
+                true => println!("✅ {}: completed", file),
+                false => print_checkpoint_status(&store, &file).await?,
+            }
+        }
+        return Ok(());
+    }
+    fetch_csv(false).await?;
+    print_checkpoint_status(&store, csv_file()).await
+}
+
+/// What the checkpoint of one CSV says
+async fn print_checkpoint_status(store: &CheckpointStore, csv_file: &str) -> Result<()> {
+    let content_key = content_key_of(Path::new(csv_file)).ok();
+    let Some(checkpoint) = MigrationCheckpoint::load(store, csv_file, APP_CONFIG.checkpoint_for.as_deref(), content_key.as_deref(),
+                                                     APP_CONFIG.checkpoint_mode).await? else {
+        println!("No checkpoint for {} ({}): not started, or already completed", csv_file, store.describe(csv_file));
+        return Ok(());
+    };
+    println!("📁 {} ({:?} checkpoint in {})", csv_file, checkpoint.mode, store.describe(csv_file));
+    println!("   Progress: {:.1}% ({}/{} records)", checkpoint.progress_percentage(), checkpoint.processed_records, checkpoint.total_records);
+    if checkpoint.mode == CheckpointMode::Index {
+        println!("   Resumes from record {}", checkpoint.get_safe_resume_point());
+    }
+    println!("   Batches: {} successful, {} failed", checkpoint.successful_batches, checkpoint.failed_batches);
+    println!("   Documents indexed: {}, rejected: {}", checkpoint.indexed_documents, checkpoint.rejected_documents);
+    if !checkpoint.history_pending.is_empty() {
+        println!("   Records with token documents but no orders history yet: {}", checkpoint.history_pending.len());
+    }
+    let dead_letters = error_log_path(csv_file);
+    if dead_letters.exists() {
+        println!("   Dead letters waiting for --retry-dlq: {} ({})", read_dead_letters(&dead_letters).await?.len(), dead_letters.display());
+    }
+    Ok(())
+}
+
+/// `purge --owner <address> [--dry-run] [--yes]`: count, confirm, then delete the
+/// address's documents from every index the migrator writes
+async fn run_purge(client: &Client, owner: &str, dry_run: bool, yes: bool) -> Result<()> {
+    let owner = owner.trim();
+    if !Regex::new("^0x[0-9a-fA-F]{40}$").unwrap().is_match(owner) {
+        anyhow::bail!("'{}' is not an address (0x and 40 hex digits)", owner);
+    }
+    let mut addresses = vec![owner.to_string()];
+    // Pseudonymized indices hold the address's pseudonym instead
+    if let Some(hasher) = APP_CONFIG.field_hasher().context(ConfigError)? {
+        addresses.push(hasher.pseudonym(owner));
+    }
+    let query = purge_query(&addresses);
+    let mut indices = APP_CONFIG.data_indices();
+    // An earlier run may have quarantined documents even if this one wouldn't
+    let quarantine = quarantine_index_name(&APP_CONFIG.target_index());
+    if !indices.contains(&quarantine) {
+        indices.insert(1, quarantine);
+    }
+
+    let mut matched = Vec::new();
+    for index in &indices {
+        match count_matches(client, elasticsearch_url(), index, &query).await? {
+            Some(count) => {
+                println!("🔍 {}: {} documents name {}", index, count, owner);
+                if count > 0 {
+                    matched.push(index);
+                }
+            }
+            None => println!("   {}: does not exist", index),
+        }
+    }
+    if matched.is_empty() {
+        println!("✅ Nothing to purge");
+        return Ok(());
+    }
+    if dry_run {
+        println!("(dry run, nothing deleted)");
+        return Ok(());
+    }
+    confirm_by_typing("the address", owner, APP_CONFIG.assume_yes || yes)?;
+
+    let audit = PurgeAudit::new(&match &APP_CONFIG.purge_audit_log {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(APP_CONFIG.state_dir.as_deref().unwrap_or(".")).join("purge_audit.ndjson"),
+    });
+    for index in matched {
+        let deleted = purge_index(client, elasticsearch_url(), index, &query, owner, &audit).await?;
+        println!("🗑️  {}: deleted {} documents", index, deleted);
+        if count_matches(client, elasticsearch_url(), index, &query).await?.unwrap_or(0) > 0 {
+            return Err(anyhow::anyhow!("{} still has documents naming {}", index, owner).context(VerificationFailed));
+        }
+    }
+    println!("✅ Purged {}; deleted ids logged to {}", owner, audit.path().display());
+    Ok(())
+}
+
+/// `alias next --alias <alias>`: the versioned index to migrate into next, alone on
+/// stdout so it can be used as `ELASTICSEARCH_INDEX=$(... alias next --alias nfts)`
+async fn run_alias_next(client: &Client, alias: &str) -> Result<()> {
+    let existing = versioned_indices(client, elasticsearch_url(), alias).await?;
+    println!("{}", next_versioned_index(alias, &existing));
+    Ok(())
+}
+
+/// `alias swap --alias <alias> [--sample <n>] [--delete-old] [--yes]`: verify the
+/// index migrated into (ELASTICSEARCH_INDEX), then move the alias from the indices it
+/// points at to it in one request, deleting those with `--delete-old`
+async fn run_alias_swap(client: &Client, alias: &str, sample_size: usize, delete_old: bool, yes: bool) -> Result<()> {
+    let index = APP_CONFIG.target_index();
+    if index == alias {
+        return Err(anyhow::anyhow!("ELASTICSEARCH_INDEX is the alias {}; set it to the versioned index to swap in", alias).context(ConfigError));
+    }
+    if resolve_alias(client, elasticsearch_url(), &index).await?.is_some() {
+        return Err(anyhow::anyhow!("ELASTICSEARCH_INDEX {} is an alias; set it to the versioned index to swap in", index).context(ConfigError));
+    }
+    let current = match resolve_alias(client, elasticsearch_url(), alias).await? {
+        Some(target) => target.indices,
+        None if count_documents(client, elasticsearch_url(), alias, None).await?.is_some() => {
+            anyhow::bail!("{} is an index, not an alias; migrate it into a versioned index and delete it before swapping", alias);
+        }
+        None => Vec::new(),
+    };
+    if current == [index.clone()] {
+        println!("✅ {} already points at {}", alias, index);
+        return Ok(());
+    }
+    let managed: Vec<String> = [alias.to_string(), index.clone()].into_iter().chain(current.iter().filter(|current| **current != index).cloned()).collect();
+    check_privileges(client, elasticsearch_url(), &[IndexPrivileges::manage(managed)]).await.context(ConfigError)?;
+    run_verify(client, sample_size, None).await?;
+
+    let old: Vec<String> = current.into_iter().filter(|current| *current != index).collect();
+    if delete_old && !old.is_empty() {
+        println!("🗑️  {} will be deleted after the swap", old.join(", "));
+        confirm_by_typing("the alias", alias, APP_CONFIG.assume_yes || yes)?;
+    }
+    swap_alias(client, elasticsearch_url(), alias, &old, &index).await?;
+    match old.is_empty() {
+        true => println!("🔀 {} created, pointing at {}", alias, index),
+        false => println!("🔀 {} moved from {} to {}", alias, old.join(", "), index),
+    }
+    if delete_old {
+        for old_index in &old {
+            delete_index(client, elasticsearch_url(), old_index).await?;
+            println!("🗑️  Deleted {}", old_index);
+        }
+    } else if !old.is_empty() {
+        println!("   Kept {} to swap back to if needed", old.join(", "));
+    }
+    Ok(())
+}
+
+/// `verify [--sample <n>] [--token-address <address>]`: compare the distinct document
+/// ids of the CSV with the documents in the target index, then fetch a random sample
+/// of rows back by _id and check their fields
+async fn run_verify(client: &Client, sample_size: usize, token_address: Option<&str>) -> Result<()> {
+    let index = APP_CONFIG.target_index();
+    // Counts and samples read through the alias writes went through, once they're visible
+    if let Some(alias) = resolve_alias(client, elasticsearch_url(), &index).await? {
+        println!("🔗 {} is an alias of {}", index, alias.indices.join(", "));
+    }
+    refresh_index(client, elasticsearch_url(), &index).await?;
+    let Some(count) = count_documents(client, elasticsearch_url(), &index, token_address).await? else {
+        anyhow::bail!("Index {} does not exist", index);
+    };
+    install_configured_collections()?;
+    let files = input_files().await?;
+    let mut ids = RoaringTreemap::new();
+    let mut rows = 0;
+    let seed = match APP_CONFIG.random_seed {
+        Some(seed) => seed,
+        None => std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos() as u64,
+    };
+    // Sampled rows keep which file's headers they're read with
+    let mut sampler = Sampler::new(sample_size, seed);
+    let mut file_headers = Vec::with_capacity(files.len());
+    for file in &files {
+        let (mut source, headers) = open_with_headers(file)?;
+        let column = headers.iter().position(|h| h == "token_id").with_context(|| format!("{} has no token_id column", file))?;
+        let address_column = headers.iter().position(|h| h == "token_address");
+        if token_address.is_some() && address_column.is_none() {
+            anyhow::bail!("{} has no token_address column to filter on", file);
+        }
+        for row in source.rows() {
+            let row = row?;
+            let address = address_column.and_then(|column| row.get(column));
+            if let (Some(wanted), Some(address)) = (token_address, address) {
+                if !address.trim().eq_ignore_ascii_case(wanted.trim()) {
+                    continue;
+                }
+            }
+            rows += 1;
+            if let Some(doc_id) = token_document_id(address, row.get(column)) {
+                ids.insert(record_key(&doc_id));
+                sampler.offer((file_headers.len(), row));
+            }
+        }
+        file_headers.push(headers);
     }
 
+    let scope = token_address.map(|address| format!(" of {}", address)).unwrap_or_default();
+    let input = match files.as_slice() {
+        [file] => file.clone(),
+        files => format!("{} ({} files)", APP_CONFIG.csv_file, files.len()),
+    };
+    println!("📊 {}: {} rows{}, {} distinct document ids", input, rows, scope, ids.len());
+    println!("   {}: {} documents{}", index, count, scope);
+    let missing_count = match count.cmp(&ids.len()) {
+        std::cmp::Ordering::Equal => {
+            println!("✅ Index has a document for every document id");
+            0
+        }
+        std::cmp::Ordering::Greater => {
+            println!("⚠️  Index has {} more documents than the CSV has document ids", count - ids.len());
+            0
+        }
+        std::cmp::Ordering::Less => ids.len() - count,
+    };
+
+    let sample = sampler.into_sample();
+    let mut problems = 0;
+    if !sample.is_empty() {
+        let mut expected = Vec::new();
+        for (file, row) in sample {
+            let record: CsvRecord = row.deserialize(Some(&file_headers[file]))?;
+            let document = build_document(record);
+            let Some(id) = document.id.clone() else { continue };
+            expected.push((id, serde_json::to_value(&document)?));
+        }
+        let ids: Vec<String> = expected.iter().map(|(id, _)| id.clone()).collect();
+        let indexed = fetch_documents(client, elasticsearch_url(), &index, &ids).await?;
+        for (id, document) in &expected {
+            match indexed.get(id) {
+                None => println!("❌ {}: missing", id),
+                Some(source) => match differing_fields(document, source) {
+                    fields if fields.is_empty() => continue,
+                    fields => {
+                        println!("❌ {}: differs in {}", id, fields.join(", "));
+                        for field in fields {
+                            println!("   {}: expected {}, indexed {}", field, document[&field],
+                                     source.get(&field).map_or_else(|| "nothing".to_string(), |value| value.to_string()));
+                        }
+                    }
+                },
+            }
+            problems += 1;
+        }
+        println!("   Sampled {} rows (RANDOM_SEED={}): {} match, {} missing or different", expected.len(), seed, expected.len() - problems, problems);
+    }
+
+    if missing_count > 0 {
+        return Err(anyhow::anyhow!("Index is missing at least {} documents", missing_count).context(VerificationFailed));
+    }
+    if problems > 0 {
+        return Err(anyhow::anyhow!("{} sampled documents are missing or differ from the CSV", problems).context(VerificationFailed));
+    }
+    Ok(())
+}
+
+/// `analyze-traits [--format json|csv] [--output <file>] [--facets <file>]`: distinct
+/// values and counts of each trait per collection, as they would be indexed, over every
+/// file CSV_FILE names
+async fn run_analyze_traits(format: TraitFormat, output: Option<&str>, facets: Option<&str>) -> Result<()> {
+    // Progress goes to stderr so the report can be piped from stdout
+    install_configured_collections()?;
+    let files = input_files().await?;
+    // One report over every input, each read with its own headers
+    let mut analyzer = TraitAnalyzer::default();
+    let mut rows = 0;
+    for file in &files {
+        let (mut source, headers) = open_with_headers(file)?;
+        for row in source.rows() {
+            let record: CsvRecord = row?.deserialize(Some(&headers))?;
+            analyzer.add(&build_document(record));
+            rows += 1;
+        }
+    }
+
+    let report = match format {
+        TraitFormat::Json => analyzer.to_json()?,
+        TraitFormat::Csv => analyzer.to_csv()?,
+    };
+    let traits: usize = analyzer.report().values().map(|collection| collection.traits.len()).sum();
+    info!("📊 {} rows in {} files, {} collections, {} traits", rows, files.len(), analyzer.report().len(), traits);
+    match output {
+        Some(path) => {
+            std::fs::write(path, report).with_context(|| format!("Failed to write {}", path))?;
+            info!("💾 Trait report written to {}", path);
+        }
+        None => println!("{}", report.trim_end()),
+    }
+    if let Some(path) = facets {
+        let facets = FacetConfig::generate(&APP_CONFIG.target_index(), &analyzer);
+        std::fs::write(path, serde_json::to_string_pretty(&facets)?).with_context(|| format!("Failed to write {}", path))?;
+        info!("💾 Facets of {} collections written to {}", facets.collections.len(), path);
+    }
+    Ok(())
+}
+
+/// `checkpoint merge <checkpoint-file>... [--output <merged-file>]`
+///
+/// Combines checkpoints of sharded runs over the same CSV and fails if any
+/// record range was not covered by at least one of them.
+async fn run_checkpoint_merge(paths: &[String], output: Option<&str>) -> Result<()> {
+    let mut checkpoints = Vec::new();
+    for path in paths {
+        checkpoints.push(MigrationCheckpoint::read_from(Path::new(path)).await?);
+    }
+    let report = MigrationCheckpoint::merge(checkpoints)?;
+    let merged = &report.merged;
+
+    println!("📊 Coverage of {} ({} checkpoints, {:?} mode):", merged.csv_file_path, paths.len(), merged.mode);
+    println!("   Covered: {}/{} records ({:.1}%)",
+             report.covered_records, merged.total_records, merged.progress_percentage());
+    println!("   Batches: {} successful, {} failed", merged.successful_batches, merged.failed_batches);
+
+    if let Some(output) = output {
+        merged.write_to(Path::new(output)).await?;
+        println!("💾 Merged checkpoint written to {}", output);
+    }
+
+    if report.gaps.is_empty() {
+        if merged.is_completed() {
+            println!("✅ Full coverage, no gaps");
+            return Ok(());
+        }
+        return Err(anyhow::anyhow!("Only {} of {} records covered", report.covered_records, merged.total_records).context(VerificationFailed));
+    }
+
+    println!("❌ {} gaps in coverage:", report.gaps.len());
+    for (start, end) in report.gaps.iter().take(50) {
+        println!("   records {}..{} ({} missing)", start, end, end - start);
+    }
+    if report.gaps.len() > 50 {
+        println!("   ... and {} more", report.gaps.len() - 50);
+    }
+    Err(anyhow::anyhow!("{} gaps in coverage", report.gaps.len()).context(VerificationFailed))
+}
+
+/// `mapping preview [--collection <address>] [--index <name>]`
+///
+/// Prints the index body (settings + mappings) that would be used for a
+/// collection, as plain JSON so it can be diffed against `GET <index>/_mapping`.
+/// With `--index`, the allocation settings INDEX_SETTINGS_FILE has for that index are included.
+fn run_mapping_preview(collection: Option<&str>, index: Option<&str>) -> Result<()> {
+    dotenvy::dotenv().ok();
+    if let Ok(path) = std::env::var("COLLECTIONS_FILE") {
+        install_collections(load_collections(&path)?);
+    }
+    // Every language subfield is shown: without a cluster, plugins aren't known
+    install_text_analysis(TextAnalysis {
+        field_analyzers: parse_field_analyzers(&std::env::var("FIELD_ANALYZERS").unwrap_or_default())?,
+        languages: parse_languages(&std::env::var("DESCRIPTION_LANGUAGES").unwrap_or_default())?,
+        plugins: None,
+        on_missing_plugin: MissingPlugin::Degrade,
+    });
+    let config = collection.and_then(get_collection_config);
+    if let (Some(address), None) = (collection, &config) {
+        warn!("⚠️  No collection config for {}, showing the generic mapping", address);
+    }
+
+    let mut mapping = generate_collection_mapping(config.as_deref());
+    if let Some(index) = index {
+        if let Ok(path) = std::env::var("INDEX_SETTINGS_FILE") {
+            mapping = IndexSettings::load(&path)?.apply(index, &mapping);
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&mapping)?);
     Ok(())
-}
\ No newline at end of file
+}
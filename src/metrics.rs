@@ -0,0 +1,223 @@
+//! Prometheus metrics for long migrations, served as text on `GET /metrics` when
+//! METRICS_ADDR is set. Counters are always kept; they are a few atomics. The same
+//! server takes `GET`/`POST /rate-limit` to read and change the rate limits.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::rate_limit::rate_limiter;
+
+/// Upper bounds (seconds) of the bulk latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Records/sec is averaged over this much recent history
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+static METRICS: Metrics = Metrics::new();
+
+struct Metrics {
+    records: AtomicU64,
+    batches_succeeded: AtomicU64,
+    batches_failed: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_micros: AtomicU64,
+    // Bulk responses by HTTP status ("error" when no response came back)
+    responses: Mutex<BTreeMap<String, u64>>,
+    // Rejected documents by Elasticsearch error type
+    item_errors: Mutex<BTreeMap<String, u64>>,
+    indices_created: AtomicU64,
+    // (when, records so far) of recent batches, for the current rate
+    progress: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            records: AtomicU64::new(0),
+            batches_succeeded: AtomicU64::new(0),
+            batches_failed: AtomicU64::new(0),
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            latency_count: AtomicU64::new(0),
+            latency_micros: AtomicU64::new(0),
+            responses: Mutex::new(BTreeMap::new()),
+            item_errors: Mutex::new(BTreeMap::new()),
+            indices_created: AtomicU64::new(0),
+            progress: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+/// A batch finished; `records` of it were indexed
+pub fn record_batch(succeeded: bool, records: u64) {
+    let counter = if succeeded { &METRICS.batches_succeeded } else { &METRICS.batches_failed };
+    counter.fetch_add(1, Ordering::Relaxed);
+    let total = METRICS.records.fetch_add(records, Ordering::Relaxed) + records;
+    let now = Instant::now();
+    let mut progress = METRICS.progress.lock().unwrap();
+    progress.push_back((now, total));
+    while progress.front().is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW) {
+        progress.pop_front();
+    }
+}
+
+/// A bulk request got a response with `status` (None: no response) after `latency`
+pub fn record_bulk_response(status: Option<u16>, latency: Duration) {
+    let status = status.map_or_else(|| "error".to_string(), |status| status.to_string());
+    *METRICS.responses.lock().unwrap().entry(status).or_default() += 1;
+    let seconds = latency.as_secs_f64();
+    for (bucket, bound) in METRICS.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+        if seconds <= bound {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    METRICS.latency_count.fetch_add(1, Ordering::Relaxed);
+    METRICS.latency_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Documents the cluster rejected, by error type
+pub fn record_item_errors<'a>(error_types: impl IntoIterator<Item = &'a str>) {
+    let mut item_errors = METRICS.item_errors.lock().unwrap();
+    for error_type in error_types {
+        *item_errors.entry(error_type.to_string()).or_default() += 1;
+    }
+}
+
+/// An index created mid-run, the first time a batch needed it
+pub fn record_index_created() {
+    METRICS.indices_created.fetch_add(1, Ordering::Relaxed);
+}
+
+fn records_per_sec() -> f64 {
+    let progress = METRICS.progress.lock().unwrap();
+    match (progress.front(), progress.back()) {
+        (Some((first_at, first)), Some((last_at, last))) if last_at > first_at => {
+            (last - first) as f64 / last_at.duration_since(*first_at).as_secs_f64()
+        }
+        _ => 0.0,
+    }
+}
+
+/// Prometheus text exposition of all metrics
+pub fn render() -> String {
+    let mut out = String::new();
+    let counter = |out: &mut String, name: &str, help: &str| {
+        writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name).unwrap();
+    };
+
+    counter(&mut out, "migrator_records_processed_total", "Records indexed");
+    writeln!(out, "migrator_records_processed_total {}", METRICS.records.load(Ordering::Relaxed)).unwrap();
+
+    counter(&mut out, "migrator_batches_total", "Batches by result");
+    writeln!(out, "migrator_batches_total{{result=\"succeeded\"}} {}", METRICS.batches_succeeded.load(Ordering::Relaxed)).unwrap();
+    writeln!(out, "migrator_batches_total{{result=\"failed\"}} {}", METRICS.batches_failed.load(Ordering::Relaxed)).unwrap();
+
+    counter(&mut out, "migrator_bulk_responses_total", "Bulk requests by HTTP status");
+    for (status, count) in METRICS.responses.lock().unwrap().iter() {
+        writeln!(out, "migrator_bulk_responses_total{{status=\"{}\"}} {}", status, count).unwrap();
+    }
+
+    counter(&mut out, "migrator_bulk_item_errors_total", "Rejected documents by Elasticsearch error type");
+    for (error_type, count) in METRICS.item_errors.lock().unwrap().iter() {
+        writeln!(out, "migrator_bulk_item_errors_total{{type=\"{}\"}} {}", error_type.replace('"', ""), count).unwrap();
+    }
+
+    counter(&mut out, "migrator_indices_created_total", "Indices created during the run");
+    writeln!(out, "migrator_indices_created_total {}", METRICS.indices_created.load(Ordering::Relaxed)).unwrap();
+
+    writeln!(out, "# HELP migrator_bulk_request_duration_seconds Bulk request latency\n# TYPE migrator_bulk_request_duration_seconds histogram").unwrap();
+    for (bucket, bound) in METRICS.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+        writeln!(out, "migrator_bulk_request_duration_seconds_bucket{{le=\"{}\"}} {}", bound, bucket.load(Ordering::Relaxed)).unwrap();
+    }
+    let count = METRICS.latency_count.load(Ordering::Relaxed);
+    writeln!(out, "migrator_bulk_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", count).unwrap();
+    writeln!(out, "migrator_bulk_request_duration_seconds_sum {}", METRICS.latency_micros.load(Ordering::Relaxed) as f64 / 1e6).unwrap();
+    writeln!(out, "migrator_bulk_request_duration_seconds_count {}", count).unwrap();
+
+    writeln!(out, "# HELP migrator_records_per_second Indexing rate over the last {}s\n# TYPE migrator_records_per_second gauge", RATE_WINDOW.as_secs()).unwrap();
+    writeln!(out, "migrator_records_per_second {:.1}", records_per_sec()).unwrap();
+    out
+}
+
+/// `/rate-limit`: the current limits, after applying the query of a POST
+fn rate_limit_endpoint(method: &str, query: &str) -> (&'static str, String) {
+    let Some(limiter) = rate_limiter() else {
+        return ("404 Not Found", "No migration running\n".to_string());
+    };
+    if method == "POST" {
+        match limiter.limits().with_query(query) {
+            Ok(limits) => limiter.set_limits(limits),
+            Err(e) => return ("400 Bad Request", format!("{:#}\n", e)),
+        }
+    }
+    ("200 OK", format!("{}\n", limiter.limits()))
+}
+
+/// Serve `GET /metrics` and `/rate-limit` on `addr` until the task is aborted
+pub async fn serve(addr: SocketAddr) -> Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr).await.with_context(|| format!("Failed to bind metrics server to {}", addr))?;
+    let addr = listener.local_addr()?;
+    let task = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 1024];
+                let Ok(read) = stream.read(&mut buffer).await else { return };
+                let request = String::from_utf8_lossy(&buffer[..read]);
+                let mut request_line = request.split_whitespace();
+                let method = request_line.next().unwrap_or_default();
+                let (path, query) = request_line.next().map_or(("", ""), |target| target.split_once('?').unwrap_or((target, "")));
+                let (status, body) = match path {
+                    "/metrics" => ("200 OK", render()),
+                    "/rate-limit" => rate_limit_endpoint(method, query),
+                    _ => ("404 Not Found", String::new()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                );
+                stream.write_all(response.as_bytes()).await.ok();
+                stream.shutdown().await.ok();
+            });
+        }
+    });
+    Ok((addr, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        record_batch(true, 500);
+        record_batch(false, 0);
+        record_bulk_response(Some(200), Duration::from_millis(300));
+        record_bulk_response(None, Duration::from_secs(90));
+        record_item_errors(["mapper_parsing_exception"]);
+        record_index_created();
+
+        let (addr, task) = serve("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let body = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+        task.abort();
+
+        // Counters are process-wide, so other tests may have added to them
+        let value = |series: &str| body.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse::<f64>().ok())
+            .unwrap_or_else(|| panic!("{} missing in\n{}", series, body));
+        assert!(value("migrator_records_processed_total") >= 500.0);
+        assert!(value("migrator_batches_total{result=\"failed\"}") >= 1.0);
+        assert!(value("migrator_bulk_responses_total{status=\"error\"}") >= 1.0);
+        assert!(value("migrator_bulk_item_errors_total{type=\"mapper_parsing_exception\"}") >= 1.0);
+        assert!(value("migrator_indices_created_total") >= 1.0);
+        assert!(value("migrator_bulk_request_duration_seconds_bucket{le=\"0.5\"}") >= 1.0);
+        assert!(value("migrator_bulk_request_duration_seconds_bucket{le=\"+Inf\"}") >= value("migrator_bulk_request_duration_seconds_bucket{le=\"60\"}") + 1.0);
+        value("migrator_records_per_second");
+    }
+}
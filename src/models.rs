@@ -1,42 +1,19 @@
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::{Map, Value};
-use std::collections::HashMap;
 
-#[derive(Debug, Deserialize)]
-pub struct CsvRecord {
-    pub token_address: Option<String>,
-    pub token_id: Option<String>,
-    pub owner: Option<String>,
-    pub base_price: Option<String>,
-    pub ended_at: Option<String>,
-    pub ended_price: Option<String>,
-    pub expired_at: Option<String>,
-    pub kind: Option<String>,
-    pub maker: Option<String>,
-    pub matcher: Option<String>,
-    pub order_id: Option<String>,
-    pub payment_token: Option<String>,
-    pub price: Option<String>,
-    pub started_at: Option<String>,
-    pub state: Option<String>,
-    pub name: Option<String>,
-    pub attributes: Option<String>,
-    pub image: Option<String>,
-    pub video: Option<String>,
-    pub metadata_last_updated: Option<String>,
-    pub cdn_image: Option<String>,
-    pub animation_url: Option<String>,
-    pub description: Option<String>,
-    pub is_shown: Option<String>,
-    pub ownership_block_number: Option<String>,
-    pub ownership_log_index: Option<String>,
-    pub raw_metadata: Option<String>,
-    pub order_status: Option<String>,
-    pub ron_price: Option<String>,
-}
+use crate::elasticsearch::token_document_id;
+use crate::orders::OrderEntry;
+use crate::record::NftRecord;
+#[cfg(test)]
+use crate::record::parse_optional_json;
+
+pub use crate::record::CsvRecord;
 
 #[derive(Debug, Serialize)]
 pub struct ElasticsearchDocument {
+    /// `_id` under the document id strategy
+    #[serde(skip)]
+    pub id: Option<String>,
     pub token_address: Option<String>,
     pub token_id: Option<String>,
     pub owner: Option<String>,
@@ -66,116 +43,120 @@ pub struct ElasticsearchDocument {
     pub raw_metadata: Option<Value>,
     pub order_status: Option<String>,
     pub ron_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orders: Option<Vec<OrderEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived_order: Option<OrderEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assets_ok: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_token_symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_token_known: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_row: Option<u64>,
 }
 
+/// Action line of a bulk request, e.g. `{"index": {"_id": ...}}`
 #[derive(Debug, Serialize)]
-pub struct BulkIndexAction {
-    pub index: BulkIndexMetadata,
+#[serde(rename_all = "lowercase")]
+pub enum BulkAction {
+    Index(BulkIndexMetadata),
+    Create(BulkIndexMetadata),
+    Update(BulkIndexMetadata),
+    Delete(BulkIndexMetadata),
 }
 
 #[derive(Debug, Serialize)]
 pub struct BulkIndexMetadata {
     #[serde(rename = "_id")]
     pub id: String,
+    #[serde(rename = "_index", skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>,
+    /// Shard routing value (`_routing` on clusters before 7.0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routing: Option<String>,
 }
 
-fn parse_optional_string(s: &Option<String>) -> Option<String> {
-    s.as_ref()
-        .filter(|s| !s.trim().is_empty())
-        .map(|s| s.trim().to_string())
-}
-
-fn parse_optional_f64(s: &Option<String>) -> Option<f64> {
-    s.as_ref()
-        .and_then(|s| s.trim().parse().ok())
-}
-
-fn parse_optional_i64(s: &Option<String>) -> Option<i64> {
-    s.as_ref()
-        .and_then(|s| s.trim().parse().ok())
-}
-
-fn parse_optional_i32(s: &Option<String>) -> Option<i32> {
-    s.as_ref()
-        .and_then(|s| s.trim().parse().ok())
+/// String parsers for the fuzz tests; documents are built from NftRecord
+#[cfg(test)]
+pub fn parse_attributes(attributes_str: &Option<String>) -> Option<Map<String, Value>> {
+    flatten_attributes(parse_optional_json(attributes_str)?)
 }
 
-fn parse_optional_bool(s: &Option<String>) -> Option<bool> {
-    s.as_ref().and_then(|s| match s.trim().to_lowercase().as_str() {
-        "t" | "true" => Some(true),
-        "f" | "false" => Some(false),
-        _ => None,
-    })
-}
-
-fn parse_attributes(attributes_str: &Option<String>) -> Option<Map<String, Value>> {
-    let attr_str = attributes_str.as_ref()?.trim();
-    if attr_str.is_empty() {
+/// Flatten an attributes object for easier querying; None unless it is an object
+fn flatten_attributes(attributes: Value) -> Option<Map<String, Value>> {
+    let Value::Object(attrs) = attributes else {
         return None;
+    };
+    let mut flattened = Map::new();
+    for (key, value) in attrs {
+        // Convert array values to single values for easier querying
+        // e.g., {"tier": ["1"]} -> {"tier": "1"}
+        let flattened_value = match value {
+            Value::Array(arr) if !arr.is_empty() => arr[0].clone(),
+            other => other,
+        };
+        flattened.insert(key, flattened_value);
     }
-
-    match serde_json::from_str::<HashMap<String, Value>>(attr_str) {
-        Ok(attrs) => {
-            let mut flattened = Map::new();
-            for (key, value) in attrs {
-                // Convert array values to single values for easier querying
-                // e.g., {"tier": ["1"]} -> {"tier": "1"}
-                let flattened_value = match value {
-                    Value::Array(arr) if !arr.is_empty() => arr[0].clone(),
-                    other => other,
-                };
-                flattened.insert(key, flattened_value);
-            }
-            Some(flattened)
-        }
-        Err(_) => None,
-    }
+    Some(flattened)
 }
 
-fn parse_raw_metadata(raw_metadata_str: &Option<String>) -> Option<Value> {
-    let metadata_str = raw_metadata_str.as_ref()?.trim();
-    if metadata_str.is_empty() {
-        return None;
-    }
-
-    match serde_json::from_str(metadata_str) {
-        Ok(metadata) => Some(metadata),
-        Err(_) => None,
-    }
+#[cfg(test)]
+pub fn parse_raw_metadata(raw_metadata_str: &Option<String>) -> Option<Value> {
+    parse_optional_json(raw_metadata_str)
 }
 
 impl From<CsvRecord> for ElasticsearchDocument {
     fn from(record: CsvRecord) -> Self {
+        NftRecord::from(record).into()
+    }
+}
+
+impl From<NftRecord> for ElasticsearchDocument {
+    fn from(record: NftRecord) -> Self {
         Self {
-            token_address: parse_optional_string(&record.token_address),
-            token_id: parse_optional_string(&record.token_id),
-            owner: parse_optional_string(&record.owner),
-            base_price: parse_optional_f64(&record.base_price),
-            ended_at: parse_optional_i64(&record.ended_at),
-            ended_price: parse_optional_f64(&record.ended_price),
-            expired_at: parse_optional_i64(&record.expired_at),
-            kind: parse_optional_i64(&record.kind),
-            maker: parse_optional_string(&record.maker),
-            matcher: parse_optional_string(&record.matcher),
-            order_id: parse_optional_i64(&record.order_id),
-            payment_token: parse_optional_string(&record.payment_token),
-            price: parse_optional_f64(&record.price),
-            started_at: parse_optional_i64(&record.started_at),
-            state: parse_optional_string(&record.state),
-            name: parse_optional_string(&record.name),
-            attributes: parse_attributes(&record.attributes),
-            image: parse_optional_string(&record.image),
-            video: parse_optional_string(&record.video),
-            metadata_last_updated: parse_optional_i64(&record.metadata_last_updated),
-            cdn_image: parse_optional_string(&record.cdn_image),
-            animation_url: parse_optional_string(&record.animation_url),
-            description: parse_optional_string(&record.description),
-            is_shown: parse_optional_bool(&record.is_shown),
-            ownership_block_number: parse_optional_i64(&record.ownership_block_number),
-            ownership_log_index: parse_optional_i32(&record.ownership_log_index),
-            raw_metadata: parse_raw_metadata(&record.raw_metadata),
-            order_status: parse_optional_string(&record.order_status),
-            ron_price: parse_optional_f64(&record.ron_price),
+            id: token_document_id(record.token_address.as_deref(), record.token_id.as_deref()),
+            token_address: record.token_address,
+            token_id: record.token_id,
+            owner: record.owner,
+            base_price: record.base_price,
+            ended_at: record.ended_at,
+            ended_price: record.ended_price,
+            expired_at: record.expired_at,
+            kind: record.kind,
+            maker: record.maker,
+            matcher: record.matcher,
+            order_id: record.order_id,
+            payment_token: record.payment_token,
+            price: record.price,
+            started_at: record.started_at,
+            state: record.state,
+            name: record.name,
+            attributes: record.attributes.and_then(flatten_attributes),
+            image: record.image,
+            video: record.video,
+            metadata_last_updated: record.metadata_last_updated,
+            cdn_image: record.cdn_image,
+            animation_url: record.animation_url,
+            description: record.description,
+            is_shown: record.is_shown,
+            ownership_block_number: record.ownership_block_number,
+            ownership_log_index: record.ownership_log_index,
+            raw_metadata: record.raw_metadata,
+            order_status: record.order_status,
+            ron_price: record.ron_price,
+            orders: None,
+            archived_order: None,
+            assets_ok: None,
+            payment_token_symbol: None,
+            payment_token_known: None,
+            source_file: None,
+            source_row: None,
         }
     }
 }
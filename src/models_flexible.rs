@@ -1,39 +1,13 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use crate::collection_config::{CollectionConfig, extract_collection_fields, extract_typed_value};
+use crate::collection_config::{CollectionConfig, extract_collection_fields, extraction_errors};
+use crate::elasticsearch::token_document_id;
+use crate::orders::OrderEntry;
+use crate::record::NftRecord;
+#[cfg(test)]
+use crate::record::parse_optional_json;
 
-#[derive(Debug, Deserialize)]
-pub struct CsvRecord {
-    pub token_address: Option<String>,
-    pub token_id: Option<String>,
-    pub owner: Option<String>,
-    pub base_price: Option<String>,
-    pub ended_at: Option<String>,
-    pub ended_price: Option<String>,
-    pub expired_at: Option<String>,
-    pub kind: Option<String>,
-    pub maker: Option<String>,
-    pub matcher: Option<String>,
-    pub order_id: Option<String>,
-    pub payment_token: Option<String>,
-    pub price: Option<String>,
-    pub started_at: Option<String>,
-    pub state: Option<String>,
-    pub name: Option<String>,
-    pub attributes: Option<String>,
-    pub image: Option<String>,
-    pub video: Option<String>,
-    pub metadata_last_updated: Option<String>,
-    pub cdn_image: Option<String>,
-    pub animation_url: Option<String>,
-    pub description: Option<String>,
-    pub is_shown: Option<String>,
-    pub ownership_block_number: Option<String>,
-    pub ownership_log_index: Option<String>,
-    pub raw_metadata: Option<String>,
-    pub order_status: Option<String>,
-    pub ron_price: Option<String>,
-}
+pub use crate::record::CsvRecord;
 
 /// Raw metadata structure as received from the indexer service
 #[derive(Debug, Deserialize)]
@@ -41,7 +15,6 @@ pub struct RawMetadata {
     pub name: Option<String>,
     pub image: Option<String>,
     pub video: Option<String>,
-    pub attributes: Option<Value>,
     pub properties: Option<Map<String, Value>>,
     pub description: Option<String>,
     pub external_url: Option<String>,
@@ -52,6 +25,13 @@ pub struct RawMetadata {
 /// Uses serde_json::Value for dynamic fields
 #[derive(Debug, Serialize)]
 pub struct FlexibleElasticsearchDocument {
+    /// `_id` under the document id strategy
+    #[serde(skip)]
+    pub id: Option<String>,
+    /// Tombstone row: the bulk request deletes `id` instead of indexing the document
+    #[serde(skip)]
+    pub deleted: bool,
+
     // Universal infrastructure fields
     pub token_address: Option<String>,
     pub token_id: Option<String>,
@@ -67,11 +47,21 @@ pub struct FlexibleElasticsearchDocument {
     pub matcher: Option<String>,
     pub order_id: Option<i64>,
     pub payment_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_token_symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_token_known: Option<bool>,
     pub price: Option<f64>,
     pub ron_price: Option<f64>,
     pub started_at: Option<i64>,
     pub state: Option<String>,
     pub order_status: Option<String>,
+    /// All orders of the token, newest first (only when order rows are grouped)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orders: Option<Vec<OrderEntry>>,
+    /// The order fields of an expired listing, moved aside (EXPIRED_LISTINGS=archive)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived_order: Option<OrderEntry>,
     
     // NFT metadata
     pub name: Option<String>,
@@ -80,7 +70,10 @@ pub struct FlexibleElasticsearchDocument {
     pub cdn_image: Option<String>,
     pub animation_url: Option<String>,
     pub description: Option<String>,
+    pub external_url: Option<String>,
     pub metadata_last_updated: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assets_ok: Option<bool>,
     
     // Flexible fields (different per collection)
     pub properties: Option<Map<String, Value>>,
@@ -91,54 +84,25 @@ pub struct FlexibleElasticsearchDocument {
     pub ownership_block_number: Option<i64>,
     pub ownership_log_index: Option<i32>,
     
+    // Provenance: CSV file name and line the document was built from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_row: Option<u64>,
+    
     // Collection-specific extracted fields (dynamic)
     #[serde(flatten)]
     pub extracted_fields: Map<String, Value>,
+    
+    // Why required collection fields couldn't be extracted (quarantine candidates)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extraction_errors: Vec<String>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct BulkIndexAction {
-    pub index: BulkIndexMetadata,
-}
-
-#[derive(Debug, Serialize)]
-pub struct BulkIndexMetadata {
-    #[serde(rename = "_id")]
-    pub id: String,
-}
-
-// Helper functions
-fn parse_optional_string(s: &Option<String>) -> Option<String> {
-    s.as_ref()
-        .filter(|s| !s.trim().is_empty())
-        .map(|s| s.trim().to_string())
-}
-
-fn parse_optional_f64(s: &Option<String>) -> Option<f64> {
-    s.as_ref()
-        .and_then(|s| s.trim().parse().ok())
-}
-
-fn parse_optional_i64(s: &Option<String>) -> Option<i64> {
-    s.as_ref()
-        .and_then(|s| s.trim().parse().ok())
-}
-
-fn parse_optional_i32(s: &Option<String>) -> Option<i32> {
-    s.as_ref()
-        .and_then(|s| s.trim().parse().ok())
-}
-
-fn parse_optional_bool(s: &Option<String>) -> Option<bool> {
-    s.as_ref().and_then(|s| match s.trim().to_lowercase().as_str() {
-        "t" | "true" => Some(true),
-        "f" | "false" => Some(false),
-        _ => None,
-    })
-}
-
-/// Parse raw_metadata JSON string into RawMetadata struct
-fn parse_raw_metadata_struct(raw_metadata_str: &Option<String>) -> Option<RawMetadata> {
+/// Parse raw_metadata JSON string into RawMetadata struct. Documents parse it through
+/// NftRecord; the string parsers are kept for the fuzz tests.
+#[cfg(test)]
+pub fn parse_raw_metadata_struct(raw_metadata_str: &Option<String>) -> Option<RawMetadata> {
     let metadata_str = raw_metadata_str.as_ref()?.trim();
     if metadata_str.is_empty() {
         return None;
@@ -148,20 +112,22 @@ fn parse_raw_metadata_struct(raw_metadata_str: &Option<String>) -> Option<RawMet
 }
 
 /// Parse raw_metadata as generic JSON Value for storage
-fn parse_raw_metadata_value(raw_metadata_str: &Option<String>) -> Option<Value> {
-    let metadata_str = raw_metadata_str.as_ref()?.trim();
-    if metadata_str.is_empty() {
-        return None;
-    }
-
-    serde_json::from_str(metadata_str).ok()
+#[cfg(test)]
+pub fn parse_raw_metadata_value(raw_metadata_str: &Option<String>) -> Option<Value> {
+    parse_optional_json(raw_metadata_str)
 }
 
 impl FlexibleElasticsearchDocument {
     /// Build document from CSV record with optional collection-specific config
     pub fn from_record(record: CsvRecord, config: Option<&CollectionConfig>) -> Self {
+        Self::from_nft_record(NftRecord::from(record), config)
+    }
+
+    /// Build document from a record of any source with optional collection-specific config
+    pub fn from_nft_record(record: NftRecord, config: Option<&CollectionConfig>) -> Self {
         // Parse raw_metadata to extract structured properties
-        let raw_metadata_struct = parse_raw_metadata_struct(&record.raw_metadata);
+        let raw_metadata_struct = record.raw_metadata.as_ref()
+            .and_then(|raw_metadata| RawMetadata::deserialize(raw_metadata).ok());
         
         // Get properties from raw_metadata if available
         let properties = raw_metadata_struct
@@ -174,75 +140,91 @@ impl FlexibleElasticsearchDocument {
         } else {
             Map::new()
         };
+        let extraction_errors = config
+            .map(|cfg| extraction_errors(properties.as_ref(), cfg))
+            .unwrap_or_default();
         
         // Get metadata from raw_metadata or fall back to CSV
         let name = raw_metadata_struct
             .as_ref()
             .and_then(|rm| rm.name.clone())
-            .or_else(|| parse_optional_string(&record.name));
+            .or(record.name);
         
         let image = raw_metadata_struct
             .as_ref()
             .and_then(|rm| rm.image.clone())
-            .or_else(|| parse_optional_string(&record.image));
+            .or(record.image);
         
         let video = raw_metadata_struct
             .as_ref()
             .and_then(|rm| rm.video.clone())
-            .or_else(|| parse_optional_string(&record.video));
+            .or(record.video);
         
         let animation_url = raw_metadata_struct
             .as_ref()
             .and_then(|rm| rm.animation_url.clone())
-            .or_else(|| parse_optional_string(&record.animation_url));
+            .or(record.animation_url);
         
         let description = raw_metadata_struct
             .as_ref()
             .and_then(|rm| rm.description.clone())
-            .or_else(|| parse_optional_string(&record.description));
+            .or(record.description);
         
         Self {
             // Infrastructure
-            token_address: parse_optional_string(&record.token_address),
-            token_id: parse_optional_string(&record.token_id),
-            owner: parse_optional_string(&record.owner),
+            id: token_document_id(record.token_address.as_deref(), record.token_id.as_deref()),
+            deleted: record.deleted,
+            token_address: record.token_address,
+            token_id: record.token_id,
+            owner: record.owner,
             
             // Marketplace
-            base_price: parse_optional_f64(&record.base_price),
-            ended_at: parse_optional_i64(&record.ended_at),
-            ended_price: parse_optional_f64(&record.ended_price),
-            expired_at: parse_optional_i64(&record.expired_at),
-            kind: parse_optional_i64(&record.kind),
-            maker: parse_optional_string(&record.maker),
-            matcher: parse_optional_string(&record.matcher),
-            order_id: parse_optional_i64(&record.order_id),
-            payment_token: parse_optional_string(&record.payment_token),
-            price: parse_optional_f64(&record.price),
-            ron_price: parse_optional_f64(&record.ron_price),
-            started_at: parse_optional_i64(&record.started_at),
-            state: parse_optional_string(&record.state),
-            order_status: parse_optional_string(&record.order_status),
+            base_price: record.base_price,
+            ended_at: record.ended_at,
+            ended_price: record.ended_price,
+            expired_at: record.expired_at,
+            kind: record.kind,
+            maker: record.maker,
+            matcher: record.matcher,
+            order_id: record.order_id,
+            payment_token: record.payment_token,
+            payment_token_symbol: None,
+            payment_token_known: None,
+            price: record.price,
+            ron_price: record.ron_price,
+            started_at: record.started_at,
+            state: record.state,
+            order_status: record.order_status,
+            orders: None,
+            archived_order: None,
             
             // Metadata
             name,
             image,
             video,
-            cdn_image: parse_optional_string(&record.cdn_image),
+            cdn_image: record.cdn_image,
             animation_url,
             description,
-            metadata_last_updated: parse_optional_i64(&record.metadata_last_updated),
+            external_url: raw_metadata_struct.as_ref().and_then(|rm| rm.external_url.clone()),
+            metadata_last_updated: record.metadata_last_updated,
+            assets_ok: None,
             
             // Flexible fields
             properties,
-            raw_metadata: parse_raw_metadata_value(&record.raw_metadata),
+            raw_metadata: record.raw_metadata,
             
             // Other
-            is_shown: parse_optional_bool(&record.is_shown),
-            ownership_block_number: parse_optional_i64(&record.ownership_block_number),
-            ownership_log_index: parse_optional_i32(&record.ownership_log_index),
+            is_shown: record.is_shown,
+            ownership_block_number: record.ownership_block_number,
+            ownership_log_index: record.ownership_log_index,
+            
+            // Provenance is stamped by the reader, which knows the file and line
+            source_file: None,
+            source_row: None,
             
             // Collection-specific extracted fields (flattened into document root)
             extracted_fields,
+            extraction_errors,
         }
     }
 }
@@ -294,43 +276,23 @@ mod tests {
         assert_eq!(doc.extracted_fields.get("level"), Some(&serde_json::json!(5)));
         assert_eq!(doc.extracted_fields.get("rarity"), Some(&serde_json::json!("common")));
         assert_eq!(doc.extracted_fields.get("nft_type"), Some(&serde_json::json!("archer")));
+        assert!(doc.extraction_errors.is_empty());
     }
-}
 
-// Default implementation for CsvRecord for tests
-impl Default for CsvRecord {
-    fn default() -> Self {
-        Self {
-            token_address: None,
-            token_id: None,
-            owner: None,
-            base_price: None,
-            ended_at: None,
-            ended_price: None,
-            expired_at: None,
-            kind: None,
-            maker: None,
-            matcher: None,
-            order_id: None,
-            payment_token: None,
-            price: None,
-            started_at: None,
-            state: None,
-            name: None,
-            attributes: None,
-            image: None,
-            video: None,
-            metadata_last_updated: None,
-            cdn_image: None,
-            animation_url: None,
-            description: None,
-            is_shown: None,
-            ownership_block_number: None,
-            ownership_log_index: None,
-            raw_metadata: None,
-            order_status: None,
-            ron_price: None,
-        }
+    #[test]
+    fn test_build_document_with_missing_properties() {
+        let config = get_collection_config("0xa038c593115f6fcd673f6833e15462b475994879").unwrap();
+        
+        let record = CsvRecord {
+            token_address: Some("0xa038c593115f6fcd673f6833e15462b475994879".to_string()),
+            token_id: Some("123".to_string()),
+            raw_metadata: Some(r#"{"name":"Test"}"#.to_string()),
+            ..Default::default()
+        };
+        
+        let doc = FlexibleElasticsearchDocument::from_record(record, Some(&config));
+        
+        assert!(doc.extracted_fields.is_empty());
+        assert_eq!(doc.extraction_errors, vec!["raw_metadata.properties missing".to_string()]);
     }
 }
-
@@ -0,0 +1,283 @@
+use roaring::RoaringTreemap;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::elasticsearch::BulkDocument;
+use crate::expiry::ListingExpiry;
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// One order of a token, as embedded in the token document's `orders` array
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct OrderEntry {
+    pub order_id: Option<i64>,
+    pub kind: Option<i64>,
+    pub maker: Option<String>,
+    pub matcher: Option<String>,
+    pub state: Option<String>,
+    pub order_status: Option<String>,
+    pub payment_token: Option<String>,
+    pub price: Option<f64>,
+    pub base_price: Option<f64>,
+    pub ended_price: Option<f64>,
+    pub ron_price: Option<f64>,
+    pub started_at: Option<i64>,
+    pub ended_at: Option<i64>,
+    pub expired_at: Option<i64>,
+}
+
+/// Documents built from one order row that can carry all orders of their token
+pub trait OrderFields {
+    /// The order on this row, if it has one
+    fn order_entry(&self) -> Option<OrderEntry>;
+    fn set_orders(&mut self, orders: Vec<OrderEntry>);
+    /// A tombstone row of a grouped token only cancels its order: the token stays
+    fn keep_token(&mut self) {}
+}
+
+macro_rules! order_entry_from {
+    ($doc:expr) => {
+        $doc.order_id.map(|order_id| OrderEntry {
+            order_id: Some(order_id),
+            kind: $doc.kind,
+            maker: $doc.maker.clone(),
+            matcher: $doc.matcher.clone(),
+            state: $doc.state.clone(),
+            order_status: $doc.order_status.clone(),
+            payment_token: $doc.payment_token.clone(),
+            price: $doc.price,
+            base_price: $doc.base_price,
+            ended_price: $doc.ended_price,
+            ron_price: $doc.ron_price,
+            started_at: $doc.started_at,
+            ended_at: $doc.ended_at,
+            expired_at: $doc.expired_at,
+        })
+    };
+}
+
+impl OrderFields for ElasticsearchDocument {
+    fn order_entry(&self) -> Option<OrderEntry> {
+        order_entry_from!(self)
+    }
+
+    fn set_orders(&mut self, orders: Vec<OrderEntry>) {
+        self.orders = Some(orders);
+    }
+}
+
+impl OrderFields for FlexibleElasticsearchDocument {
+    fn order_entry(&self) -> Option<OrderEntry> {
+        order_entry_from!(self)
+    }
+
+    fn set_orders(&mut self, orders: Vec<OrderEntry>) {
+        self.orders = Some(orders);
+    }
+
+    fn keep_token(&mut self) {
+        self.deleted = false;
+    }
+}
+
+/// Newest order first: by started_at, then order_id
+fn order_recency<D: OrderFields>(doc: &D) -> (Option<i64>, Option<i64>) {
+    doc.order_entry().map_or((None, None), |order| (order.started_at, order.order_id))
+}
+
+/// Whether a CSV sorted by token_id keeps each document's rows adjacent: not when
+/// the document id includes the token address and several collections' rows can
+/// interleave under one token id
+pub fn token_rows_adjacent(id_template: &str, collections: usize) -> bool {
+    collections <= 1 || !id_template.contains("{token_address}")
+}
+
+/// Merge order rows of the same token into one document per token.
+///
+/// Rows are `(record_index, document)`. The returned documents keep the order in
+/// which each token first appeared, carry the fields of the token's latest order
+/// plus an `orders` array (newest first), and list every record index merged in.
+/// Documents without an id pass through unmerged.
+///
+/// A tombstone row with an order cancels that order: it is left out of `orders`
+/// and the top-level fields come from the latest order still open. A tombstone
+/// row without an order deletes the whole token document.
+///
+/// With `sorted_by_id` the rows of a token are known to be adjacent, so only
+/// consecutive rows are merged and no id map is kept.
+pub fn merge_order_rows<D: ListingExpiry + BulkDocument>(rows: Vec<(usize, D)>, sorted_by_id: bool) -> Vec<(RoaringTreemap, D)> {
+    let mut groups: Vec<(RoaringTreemap, Vec<D>)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (record_index, doc) in rows {
+        let existing = match doc.document_id() {
+            Some(id) if sorted_by_id => groups.last()
+                .filter(|(_, docs)| docs[0].document_id() == Some(id))
+                .map(|_| groups.len() - 1),
+            Some(id) => positions.get(id).copied(),
+            None => None,
+        };
+        let position = match existing {
+            Some(position) => position,
+            None => {
+                if let (Some(id), false) = (doc.document_id(), sorted_by_id) {
+                    positions.insert(id.to_string(), groups.len());
+                }
+                groups.push((RoaringTreemap::new(), Vec::new()));
+                groups.len() - 1
+            }
+        };
+        groups[position].0.insert(record_index as u64);
+        groups[position].1.push(doc);
+    }
+
+    groups
+        .into_iter()
+        .map(|(indices, mut docs)| {
+            if let Some(token_tombstone) = docs.iter().position(|doc| doc.is_deletion() && doc.order_entry().is_none()) {
+                return (indices, docs.swap_remove(token_tombstone));
+            }
+            let cancelled: Vec<Option<i64>> = docs.iter()
+                .filter(|doc| doc.is_deletion())
+                .filter_map(|doc| doc.order_entry().map(|order| order.order_id))
+                .collect();
+            let is_cancelled = |doc: &D| doc.is_deletion() || doc.order_entry().is_some_and(|order| cancelled.contains(&order.order_id));
+            // Open orders first, newest first
+            docs.sort_by_key(|doc| (is_cancelled(doc), std::cmp::Reverse(order_recency(doc))));
+            let mut orders: Vec<OrderEntry> = Vec::new();
+            for order in docs.iter().filter_map(OrderFields::order_entry).filter(|order| !cancelled.contains(&order.order_id)) {
+                if !orders.iter().any(|o| o.order_id == order.order_id) {
+                    orders.push(order);
+                }
+            }
+            let mut latest = docs.swap_remove(0);
+            if is_cancelled(&latest) {
+                // No open order left: the token has no current listing
+                latest.keep_token();
+                latest.clear_order_fields();
+            }
+            latest.set_orders(orders);
+            (indices, latest)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elasticsearch::{token_document_id, IdStrategy};
+    use crate::models_flexible::CsvRecord;
+
+    fn order_row(token_id: &str, order_id: Option<&str>, started_at: &str, price: &str) -> FlexibleElasticsearchDocument {
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_address: Some("0xabc".to_string()),
+            token_id: Some(token_id.to_string()),
+            order_id: order_id.map(str::to_string),
+            started_at: Some(started_at.to_string()),
+            price: Some(price.to_string()),
+            ..Default::default()
+        }, None)
+    }
+
+    #[test]
+    fn test_rows_merged_per_token_with_latest_order() {
+        let rows = vec![
+            (0, order_row("1", Some("10"), "100", "1.0")),
+            (1, order_row("2", None, "0", "")),
+            (2, order_row("1", Some("11"), "300", "3.0")),
+            (3, order_row("1", Some("12"), "200", "2.0")),
+            (4, order_row("1", Some("11"), "300", "3.0")),
+        ];
+
+        let merged = merge_order_rows(rows, false);
+
+        assert_eq!(merged.len(), 2);
+        let (indices, token) = &merged[0];
+        assert_eq!(indices.iter().collect::<Vec<_>>(), vec![0, 2, 3, 4]);
+        assert_eq!(token.order_id, Some(11));
+        assert_eq!(token.price, Some(3.0));
+        let order_ids: Vec<_> = token.orders.as_ref().unwrap().iter().map(|o| o.order_id).collect();
+        assert_eq!(order_ids, vec![Some(11), Some(12), Some(10)]);
+
+        let (indices, token) = &merged[1];
+        assert_eq!(indices.iter().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(token.orders, Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_interleaved_collections_merged_per_document() {
+        let row = |token_address: &str, order_id: &str, started_at: &str| {
+            let mut row = order_row("1", Some(order_id), started_at, "1.0");
+            row.token_address = Some(token_address.to_string());
+            row.id = token_document_id(Some(token_address), Some("1"));
+            row
+        };
+        // Sorted by token_id, but token 1 of two collections alternates
+        let rows = vec![
+            (0, row("0xabc", "10", "100")),
+            (1, row("0xdef", "20", "100")),
+            (2, row("0xabc", "11", "200")),
+            (3, row("0xdef", "21", "200")),
+        ];
+        let sorted_by_id = token_rows_adjacent(IdStrategy::DEFAULT, 2);
+        assert!(!sorted_by_id);
+
+        let merged = merge_order_rows(rows, sorted_by_id);
+        assert_eq!(merged.len(), 2);
+        for (indices, token) in &merged {
+            assert_eq!(indices.len(), 2);
+            assert_eq!(token.orders.as_ref().unwrap().len(), 2);
+        }
+        assert!(token_rows_adjacent(IdStrategy::DEFAULT, 1));
+        assert!(token_rows_adjacent("{token_id}", 2));
+    }
+
+    #[test]
+    fn test_grouped_tombstones_cancel_orders() {
+        let tombstone = |token_id: &str, order_id: Option<&str>| {
+            let mut row = order_row(token_id, order_id, "400", "");
+            row.deleted = true;
+            row
+        };
+        let rows = vec![
+            (0, order_row("1", Some("10"), "100", "1.0")),
+            (1, order_row("1", Some("11"), "300", "3.0")),
+            (2, tombstone("1", Some("11"))),
+            (3, order_row("2", Some("12"), "100", "5.0")),
+            (4, tombstone("2", Some("12"))),
+            (5, order_row("3", Some("13"), "100", "5.0")),
+            (6, tombstone("3", None)),
+        ];
+
+        let merged = merge_order_rows(rows, false);
+
+        let (indices, token) = &merged[0];
+        assert_eq!(indices.len(), 3);
+        assert!(!token.deleted);
+        assert_eq!((token.order_id, token.price), (Some(10), Some(1.0)));
+        assert_eq!(token.orders.as_ref().unwrap().iter().map(|o| o.order_id).collect::<Vec<_>>(), vec![Some(10)]);
+
+        let token = &merged[1].1;
+        assert!(!token.deleted);
+        assert_eq!((token.order_id, token.price), (None, None));
+        assert_eq!(token.orders, Some(Vec::new()));
+
+        assert!(merged[2].1.deleted);
+    }
+
+    #[test]
+    fn test_sorted_input_merges_adjacent_rows() {
+        let rows = vec![
+            (0, order_row("1", Some("10"), "100", "1.0")),
+            (1, order_row("1", Some("11"), "200", "2.0")),
+            (2, order_row("2", Some("12"), "100", "5.0")),
+        ];
+
+        let merged = merge_order_rows(rows, true);
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].1.order_id, Some(11));
+        assert_eq!(merged[0].1.orders.as_ref().unwrap().len(), 2);
+        assert_eq!(merged[1].0.iter().collect::<Vec<_>>(), vec![2]);
+    }
+}
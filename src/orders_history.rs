@@ -0,0 +1,128 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::elasticsearch::BulkDocument;
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+use crate::orders::{OrderEntry, OrderFields};
+
+/// One order of a token as a document of the orders history index
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct OrderEvent {
+    #[serde(skip)]
+    id: String,
+    pub token_address: Option<String>,
+    pub token_id: String,
+    #[serde(flatten)]
+    pub order: OrderEntry,
+}
+
+impl BulkDocument for OrderEvent {
+    fn document_id(&self) -> Option<&str> {
+        Some(&self.id)
+    }
+}
+
+impl OrderEvent {
+    /// None for orders without an id: they can't be written idempotently
+    fn new(doc_id: &str, token_address: &Option<String>, token_id: &str, order: OrderEntry) -> Option<Self> {
+        let id = format!("{}:{}", doc_id, order.order_id?);
+        Some(Self { id, token_address: token_address.clone(), token_id: token_id.to_string(), order })
+    }
+}
+
+/// Documents whose orders are also written to the orders history index
+pub trait OrderEvents {
+    /// An event per order of the token: every merged order, or the row's own order
+    fn order_events(&self) -> Vec<OrderEvent>;
+}
+
+macro_rules! order_events_from {
+    ($doc:expr) => {{
+        let (Some(doc_id), Some(token_id)) = ($doc.document_id(), &$doc.token_id) else { return Vec::new() };
+        let orders = match &$doc.orders {
+            Some(orders) => orders.clone(),
+            None => $doc.order_entry().into_iter().collect(),
+        };
+        orders.into_iter()
+            .filter_map(|order| OrderEvent::new(doc_id, &$doc.token_address, token_id, order))
+            .collect()
+    }};
+}
+
+impl OrderEvents for ElasticsearchDocument {
+    fn order_events(&self) -> Vec<OrderEvent> {
+        order_events_from!(self)
+    }
+}
+
+impl OrderEvents for FlexibleElasticsearchDocument {
+    fn order_events(&self) -> Vec<OrderEvent> {
+        order_events_from!(self)
+    }
+}
+
+pub fn orders_history_mapping() -> Value {
+    json!({
+        "settings": {
+            "number_of_shards": 1,
+            "number_of_replicas": 1
+        },
+        "mappings": {
+            "dynamic": false,
+            "properties": {
+                "token_address": {"type": "keyword"},
+                "token_id": {"type": "keyword"},
+                "order_id": {"type": "long"},
+                "kind": {"type": "long"},
+                "maker": {"type": "keyword"},
+                "matcher": {"type": "keyword"},
+                "state": {"type": "keyword"},
+                "order_status": {"type": "keyword"},
+                "payment_token": {"type": "keyword"},
+                "price": {"type": "double"},
+                "base_price": {"type": "double"},
+                "ended_price": {"type": "double"},
+                "ron_price": {"type": "double"},
+                "started_at": {"type": "long"},
+                "ended_at": {"type": "long"},
+                "expired_at": {"type": "long"}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CsvRecord;
+    use crate::orders::merge_order_rows;
+
+    fn order_row(token_id: &str, order_id: Option<&str>, started_at: &str) -> ElasticsearchDocument {
+        ElasticsearchDocument::from(CsvRecord {
+            token_address: Some("0xabc".to_string()),
+            token_id: Some(token_id.to_string()),
+            order_id: order_id.map(str::to_string),
+            started_at: Some(started_at.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_one_event_per_order_with_stable_ids() {
+        let events = order_row("7", Some("100"), "10").order_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].document_id(), Some("0xabc:7:100"));
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json["order_id"], 100);
+        assert_eq!(json["token_id"], "7");
+        assert!(json.get("id").is_none());
+
+        assert!(order_row("7", None, "10").order_events().is_empty());
+
+        let rows = vec![(0, order_row("7", Some("100"), "10")), (1, order_row("7", Some("101"), "20"))];
+        let (_, merged) = merge_order_rows(rows, true).remove(0);
+        let ids: Vec<_> = merged.order_events().iter().map(|e| e.document_id().unwrap().to_string()).collect();
+        assert_eq!(ids, vec!["0xabc:7:101", "0xabc:7:100"]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Exit codes, so wrappers can branch on how a run ended without parsing logs:
+//!
+//! | code | meaning |
+//! |------|---------|
+//! | 0 | success |
+//! | 1 | any other failure |
+//! | 2 | completed, but some documents were rejected and dead-lettered |
+//! | 3 | stopped early with the checkpoint saved; `resume` continues |
+//! | 4 | configuration error |
+//! | 5 | verification failure (`verify`, `checkpoint merge`, `--strict`) |
+//!
+//! Errors carry their class as context (`.context(ConfigError)`), found again
+//! however many layers of context are added on top.
+
+use std::fmt;
+use std::process::ExitCode;
+
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_DEAD_LETTERS: u8 = 2;
+pub const EXIT_RESUMABLE: u8 = 3;
+pub const EXIT_CONFIG: u8 = 4;
+pub const EXIT_VERIFICATION: u8 = 5;
+
+/// How a command that didn't fail ended, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Success,
+    CompletedWithDeadLetters,
+    AbortedResumable,
+}
+
+impl Outcome {
+    /// How a migration ended: resumable until the checkpoint is complete, and
+    /// with dead letters if the cluster rejected any document
+    pub fn of_migration(completed: bool, rejected_documents: usize) -> Self {
+        match (completed, rejected_documents) {
+            (false, _) => Outcome::AbortedResumable,
+            (true, 0) => Outcome::Success,
+            (true, _) => Outcome::CompletedWithDeadLetters,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::CompletedWithDeadLetters => EXIT_DEAD_LETTERS,
+            Outcome::AbortedResumable => EXIT_RESUMABLE,
+        }
+    }
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        ExitCode::from(outcome.code())
+    }
+}
+
+/// Settings (or flags) that can't work; fixing them is up to the operator
+#[derive(Debug)]
+pub struct ConfigError;
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Configuration error")
+    }
+}
+
+/// The data checked doesn't match what it should
+#[derive(Debug)]
+pub struct VerificationFailed;
+
+impl fmt::Display for VerificationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Verification failed")
+    }
+}
+
+/// The run stopped after saving its checkpoint
+#[derive(Debug)]
+pub struct Resumable;
+
+impl fmt::Display for Resumable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Stopped with the checkpoint saved for resume")
+    }
+}
+
+/// Exit code for a run that ended with `error`
+pub fn error_code(error: &anyhow::Error) -> u8 {
+    if error.downcast_ref::<ConfigError>().is_some() {
+        EXIT_CONFIG
+    } else if error.downcast_ref::<VerificationFailed>().is_some() {
+        EXIT_VERIFICATION
+    } else if error.downcast_ref::<Resumable>().is_some() {
+        EXIT_RESUMABLE
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_class_survives_more_context() {
+        let error = anyhow::anyhow!("Invalid FILTER").context(ConfigError).context("Failed to start");
+        assert_eq!(error_code(&error), EXIT_CONFIG);
+        let error = Err::<(), _>(anyhow::anyhow!("3 gaps")).context(VerificationFailed).unwrap_err();
+        assert_eq!(error_code(&error), EXIT_VERIFICATION);
+        assert_eq!(format!("{:#}", error), "Verification failed: 3 gaps");
+        assert_eq!(error_code(&anyhow::anyhow!("CSV error").context(Resumable)), EXIT_RESUMABLE);
+        assert_eq!(error_code(&anyhow::anyhow!("boom")), EXIT_FAILURE);
+        assert_eq!(Outcome::CompletedWithDeadLetters.code(), 2);
+    }
+
+    #[test]
+    fn test_migration_outcome() {
+        assert_eq!(Outcome::of_migration(true, 0), Outcome::Success);
+        assert_eq!(Outcome::of_migration(true, 3), Outcome::CompletedWithDeadLetters);
+        assert_eq!(Outcome::of_migration(false, 0), Outcome::AbortedResumable);
+        assert_eq!(Outcome::of_migration(false, 3), Outcome::AbortedResumable);
+    }
+}
@@ -0,0 +1,87 @@
+//! What a run writes to stdout. Logs always go to stderr; by default stdout only
+//! carries the few prints meant for the operator (the impact summary before a
+//! confirmation). With OUTPUT=json (`--output-format json`) stdout carries nothing but
+//! JSON lines events for orchestrators — `started`, `progress`, `summary` and
+//! `error` — and the prints for people move to stderr.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::OnceLock;
+use tracing::warn;
+
+use crate::outcome::error_code;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+static OUTPUT_FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// Set the format for the rest of the process; only the first call takes effect
+pub fn install_output_format(format: OutputFormat) {
+    OUTPUT_FORMAT.set(format).ok();
+}
+
+pub fn is_json() -> bool {
+    OUTPUT_FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+#[derive(Serialize)]
+struct Event<'a, T> {
+    event: &'a str,
+    #[serde(flatten)]
+    fields: &'a T,
+}
+
+fn event_line<T: Serialize>(event: &str, fields: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&Event { event, fields })
+}
+
+/// Print an event as one JSON line, under `--output-format json` only
+pub fn emit<T: Serialize>(event: &str, fields: &T) {
+    if !is_json() {
+        return;
+    }
+    match event_line(event, fields) {
+        Ok(line) => println!("{}", line),
+        Err(e) => warn!("Failed to serialize {} event: {}", event, e),
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorEvent {
+    message: String,
+    exit_code: u8,
+}
+
+/// The error a run ends with, as an `error` event
+pub fn emit_error(error: &anyhow::Error) {
+    emit("error", &ErrorEvent { message: format!("{:#}", error), exit_code: error_code(error) });
+}
+
+/// A line meant for people: stdout normally, stderr when stdout is for events
+pub fn human(line: impl Display) {
+    match is_json() {
+        true => eprintln!("{}", line),
+        false => println!("{}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_fields_flattened() {
+        #[derive(Serialize)]
+        struct Progress {
+            processed: u64,
+        }
+        assert_eq!(event_line("progress", &Progress { processed: 5 }).unwrap(), r#"{"event":"progress","processed":5}"#);
+        assert!(!is_json());
+    }
+}
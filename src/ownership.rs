@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::elasticsearch::BulkDocument;
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// What to do with CSV rows that produce the same document id
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateResolution {
+    /// Keep the row with the latest ownership transfer
+    /// (ownership_block_number, then ownership_log_index); later rows win ties
+    #[default]
+    Ownership,
+    /// Send every row; whichever lands in Elasticsearch last wins
+    Off,
+}
+
+/// Documents that record which transfer their owner comes from
+pub trait OwnershipFields {
+    fn owner(&self) -> Option<&str>;
+    fn ownership_position(&self) -> (Option<i64>, Option<i32>);
+}
+
+impl OwnershipFields for ElasticsearchDocument {
+    fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    fn ownership_position(&self) -> (Option<i64>, Option<i32>) {
+        (self.ownership_block_number, self.ownership_log_index)
+    }
+}
+
+impl OwnershipFields for FlexibleElasticsearchDocument {
+    fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    fn ownership_position(&self) -> (Option<i64>, Option<i32>) {
+        (self.ownership_block_number, self.ownership_log_index)
+    }
+}
+
+/// Duplicate rows of one token that disagreed on its owner
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OwnershipConflict {
+    pub token_id: String,
+    /// Distinct owners in row order
+    pub owners: Vec<String>,
+    pub kept_owner: Option<String>,
+    pub kept_block_number: Option<i64>,
+}
+
+/// The row currently kept for one document id
+#[derive(Debug)]
+struct KeptRow {
+    record_index: usize,
+    position: (Option<i64>, Option<i32>),
+    owner: Option<String>,
+    /// Distinct owners of all rows with this id, lowercase
+    owners: Vec<String>,
+    /// Indexed by an earlier run: a newer row overwrites it, nothing is left out
+    written: bool,
+    /// A tombstone, which outranks any ownership
+    deleted: bool,
+}
+
+/// Picks, per document id, the row with the latest ownership while the CSV is
+/// scanned, so superseded duplicates can be left out of the streamed documents.
+/// Holds one small entry per id instead of the documents themselves.
+#[derive(Debug, Default)]
+pub struct DuplicateIndex {
+    kept: HashMap<String, KeptRow>,
+    // ids whose rows disagree on the owner, in the order they were found
+    conflicts: Vec<String>,
+    superseded: usize,
+}
+
+impl DuplicateIndex {
+    /// Add a row; returns the record index that is no longer needed (this row or
+    /// the one kept so far) when the id was seen before
+    pub fn add<D: OwnershipFields + BulkDocument>(&mut self, record_index: usize, doc: &D) -> Option<usize> {
+        self.add_row(record_index, doc, false)
+    }
+
+    /// Add a row an earlier run already indexed, so a resumed run doesn't let an
+    /// older unfinished duplicate overwrite its owner. Returns the unfinished row
+    /// it supersedes, if any.
+    pub fn add_written<D: OwnershipFields + BulkDocument>(&mut self, record_index: usize, doc: &D) -> Option<usize> {
+        self.add_row(record_index, doc, true)
+    }
+
+    fn add_row<D: OwnershipFields + BulkDocument>(&mut self, record_index: usize, doc: &D, written: bool) -> Option<usize> {
+        let id = doc.document_id()?;
+        let owner = doc.owner().map(str::to_lowercase);
+        let position = doc.ownership_position();
+        let deleted = doc.is_deletion();
+
+        let kept = match self.kept.entry(id.to_string()) {
+            Entry::Vacant(entry) => {
+                entry.insert(KeptRow { record_index, position, owner: doc.owner().map(str::to_string), owners: owner.into_iter().collect(), written, deleted });
+                return None;
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+        };
+
+        if let Some(owner) = owner {
+            if !kept.owners.contains(&owner) {
+                kept.owners.push(owner);
+                if kept.owners.len() == 2 {
+                    self.conflicts.push(id.to_string());
+                }
+            }
+        }
+        // Tombstones win, then the latest transfer; later rows win ties
+        if (deleted, position) >= (kept.deleted, kept.position) {
+            let superseded = std::mem::replace(&mut kept.record_index, record_index);
+            let was_written = std::mem::replace(&mut kept.written, written);
+            kept.position = position;
+            kept.deleted = deleted;
+            kept.owner = doc.owner().map(str::to_string);
+            // A written row isn't sent again anyway; the newer row overwrites it
+            (!was_written).then(|| {
+                self.superseded += 1;
+                superseded
+            })
+        } else {
+            (!written).then(|| {
+                self.superseded += 1;
+                record_index
+            })
+        }
+    }
+
+    /// Number of rows dropped in favour of a later ownership
+    pub fn superseded(&self) -> usize {
+        self.superseded
+    }
+
+    pub fn conflicts(&self) -> Vec<OwnershipConflict> {
+        self.conflicts.iter()
+            .map(|token_id| {
+                let kept = &self.kept[token_id];
+                OwnershipConflict {
+                    token_id: token_id.clone(),
+                    owners: kept.owners.clone(),
+                    kept_owner: kept.owner.clone(),
+                    kept_block_number: kept.position.0,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models_flexible::CsvRecord;
+
+    fn row(token_id: &str, owner: &str, block: &str, log_index: &str) -> FlexibleElasticsearchDocument {
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_address: Some("0xabc".to_string()),
+            token_id: Some(token_id.to_string()),
+            owner: Some(owner.to_string()),
+            ownership_block_number: Some(block.to_string()),
+            ownership_log_index: Some(log_index.to_string()),
+            ..Default::default()
+        }, None)
+    }
+
+    #[test]
+    fn test_latest_ownership_kept() {
+        let mut duplicates = DuplicateIndex::default();
+        assert_eq!(duplicates.add(0, &row("1", "0xNEW", "200", "3")), None);
+        assert_eq!(duplicates.add(1, &row("2", "0xaa", "50", "0")), None);
+        // Older transfer: the new row is dropped
+        assert_eq!(duplicates.add(2, &row("1", "0xold", "150", "9")), Some(2));
+        // Later log index in the same block replaces the kept row
+        assert_eq!(duplicates.add(3, &row("1", "0xnewer", "200", "4")), Some(0));
+        assert_eq!(duplicates.add(4, &row("2", "0xAA", "40", "0")), Some(4));
+        assert_eq!(duplicates.superseded(), 3);
+
+        // Resumed: a written row with a newer owner keeps an older unfinished one out
+        let mut resumed = DuplicateIndex::default();
+        assert_eq!(resumed.add_written(0, &row("1", "0xnew", "200", "3")), None);
+        assert_eq!(resumed.add(1, &row("1", "0xold", "150", "9")), Some(1));
+        assert_eq!(resumed.add(2, &row("1", "0xnewer", "201", "0")), None);
+        assert_eq!(resumed.add(3, &row("2", "0xaa", "10", "0")), None);
+        assert_eq!(resumed.add_written(4, &row("2", "0xaa", "11", "0")), Some(3));
+        assert_eq!(resumed.superseded(), 2);
+
+        // A tombstone carries no ownership but still wins over the live row
+        let tombstone = FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_address: Some("0xabc".to_string()),
+            token_id: Some("1".to_string()),
+            deleted: Some("true".to_string()),
+            ..Default::default()
+        }, None);
+        let mut deletions = DuplicateIndex::default();
+        assert_eq!(deletions.add(0, &tombstone), None);
+        assert_eq!(deletions.add(1, &row("1", "0xnew", "200", "3")), Some(1));
+
+        // Token 2's rows only differ in address case, which isn't a conflict
+        assert_eq!(duplicates.conflicts(), vec![OwnershipConflict {
+            token_id: "0xabc:1".to_string(),
+            owners: vec!["0xnew".to_string(), "0xold".to_string(), "0xnewer".to_string()],
+            kept_owner: Some("0xnewer".to_string()),
+            kept_block_number: Some(200),
+        }]);
+    }
+}
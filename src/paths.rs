@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::record_key;
+use crate::config::APP_CONFIG;
+use crate::remote::is_remote;
+
+/// Path of a per-CSV state file (checkpoint, dead letters, reports).
+///
+/// Next to the CSV by default; with STATE_DIR set, inside that directory so
+/// read-only CSV mounts work. There the name also carries a hash of the full
+/// CSV path, so same-named files from different directories don't collide.
+/// An object store CSV has no directory to keep them in, so without STATE_DIR
+/// they go in the working directory.
+pub fn state_file(csv_file: &str, suffix: &str) -> PathBuf {
+    state_file_in(APP_CONFIG.state_dir.as_deref(), csv_file, suffix)
+}
+
+fn state_file_in(state_dir: Option<&str>, csv_file: &str, suffix: &str) -> PathBuf {
+    let state_dir = state_dir.or(is_remote(csv_file).then_some("."));
+    let csv_path = Path::new(csv_file);
+    let mut name = csv_path.file_name().unwrap_or(csv_path.as_os_str()).to_os_string();
+
+    match state_dir {
+        Some(dir) => {
+            name.push(format!(".{:08x}.{}", record_key(csv_file) as u32, suffix));
+            Path::new(dir).join(name)
+        }
+        None => {
+            name.push(format!(".{}", suffix));
+            csv_path.with_file_name(name)
+        }
+    }
+}
+
+/// Path of a large per-CSV output (dead letters, bulk error log): like `state_file`,
+/// but inside SPOOL_DIR when one is set
+pub fn spool_file(csv_file: &str, suffix: &str) -> PathBuf {
+    state_file_in(APP_CONFIG.spool_dir.as_deref().or(APP_CONFIG.state_dir.as_deref()), csv_file, suffix)
+}
+
+/// Make sure the directory a state file goes into exists
+pub async fn ensure_parent_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => tokio::fs::create_dir_all(parent).await,
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_file_next_to_csv() {
+        assert_eq!(state_file_in(None, "data/export.csv", "checkpoint"), PathBuf::from("data/export.csv.checkpoint"));
+        assert_eq!(state_file_in(None, "export.csv", "checkpoint"), PathBuf::from("export.csv.checkpoint"));
+        let remote = state_file_in(None, "s3://bucket/exports/export.csv", "checkpoint");
+        assert!(remote.starts_with(".") && remote.file_name().unwrap().to_string_lossy().starts_with("export.csv."));
+    }
+
+    #[test]
+    fn test_state_file_in_state_dir() {
+        let first = state_file_in(Some("/var/lib/migrator"), "/mnt/a/export.csv", "checkpoint");
+        let second = state_file_in(Some("/var/lib/migrator"), "/mnt/b/export.csv", "checkpoint");
+
+        assert!(first.starts_with("/var/lib/migrator"));
+        assert!(first.to_string_lossy().ends_with(".checkpoint"));
+        assert!(first.file_name().unwrap().to_string_lossy().starts_with("export.csv."));
+        assert_ne!(first, second);
+    }
+}
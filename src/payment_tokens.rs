@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::models::ElasticsearchDocument;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// Documents that carry an order payment token
+pub trait PaymentTokenFields {
+    fn payment_token(&self) -> Option<&str>;
+    fn set_payment_token_info(&mut self, symbol: Option<String>, known: bool);
+}
+
+impl PaymentTokenFields for ElasticsearchDocument {
+    fn payment_token(&self) -> Option<&str> {
+        self.payment_token.as_deref()
+    }
+
+    fn set_payment_token_info(&mut self, symbol: Option<String>, known: bool) {
+        self.payment_token_symbol = symbol;
+        self.payment_token_known = Some(known);
+    }
+}
+
+impl PaymentTokenFields for FlexibleElasticsearchDocument {
+    fn payment_token(&self) -> Option<&str> {
+        self.payment_token.as_deref()
+    }
+
+    fn set_payment_token_info(&mut self, symbol: Option<String>, known: bool) {
+        self.payment_token_symbol = symbol;
+        self.payment_token_known = Some(known);
+    }
+}
+
+/// Known payment token addresses and the symbols they're shown as
+pub struct PaymentTokenRegistry {
+    symbols: HashMap<String, String>,
+    unknown: Mutex<HashMap<String, u64>>,
+}
+
+impl PaymentTokenRegistry {
+    /// Parse `PAYMENT_TOKENS` entries of the form `address=SYMBOL,...`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let symbols = spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (address, symbol) = entry.split_once('=')
+                    .with_context(|| format!("Invalid payment token entry '{}', expected address=SYMBOL", entry))?;
+                Ok((address.trim().to_lowercase(), symbol.trim().to_string()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { symbols, unknown: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Set payment_token_symbol / payment_token_known and count unknown tokens
+    pub fn annotate<D: PaymentTokenFields>(&self, documents: &mut [D]) {
+        for doc in documents.iter_mut() {
+            let Some(token) = doc.payment_token().map(str::to_lowercase) else {
+                continue;
+            };
+            match self.symbols.get(&token) {
+                Some(symbol) => doc.set_payment_token_info(Some(symbol.clone()), true),
+                None => {
+                    *self.unknown.lock().unwrap().entry(token).or_default() += 1;
+                    doc.set_payment_token_info(None, false);
+                }
+            }
+        }
+    }
+
+    /// Unknown payment tokens seen so far with their counts, most frequent first
+    pub fn unknown_tokens(&self) -> Vec<(String, u64)> {
+        let mut unknown: Vec<_> = self.unknown.lock().unwrap()
+            .iter()
+            .map(|(token, count)| (token.clone(), *count))
+            .collect();
+        unknown.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models_flexible::CsvRecord;
+
+    fn doc_with_token(token: Option<&str>) -> FlexibleElasticsearchDocument {
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_id: Some("1".to_string()),
+            payment_token: token.map(str::to_string),
+            ..Default::default()
+        }, None)
+    }
+
+    #[test]
+    fn test_known_and_unknown_tokens() {
+        let registry = PaymentTokenRegistry::parse("0xC99A=WETH, 0xe514=WRON").unwrap();
+        let mut docs = vec![
+            doc_with_token(Some("0xc99a")),
+            doc_with_token(Some("0xdead")),
+            doc_with_token(Some("0xDEAD")),
+            doc_with_token(None),
+        ];
+
+        registry.annotate(&mut docs);
+
+        assert_eq!(docs[0].payment_token_symbol.as_deref(), Some("WETH"));
+        assert_eq!(docs[0].payment_token_known, Some(true));
+        assert_eq!(docs[1].payment_token_symbol, None);
+        assert_eq!(docs[1].payment_token_known, Some(false));
+        assert_eq!(docs[3].payment_token_known, None);
+        assert_eq!(registry.unknown_tokens(), vec![("0xdead".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_invalid_registry_entry() {
+        assert!(PaymentTokenRegistry::parse("0xc99a").is_err());
+    }
+}
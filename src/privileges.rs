@@ -0,0 +1,141 @@
+//! Pre-flight check of the configured credentials through the security
+//! `_has_privileges` API: a user missing `write` on the target should hear so at
+//! startup, by privilege name, not as a 403 on the first bulk request. Clusters
+//! without security (or without the API) skip the check.
+
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::deprecations::SendNotingWarnings;
+
+/// Index privileges a command needs on some index names
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexPrivileges {
+    pub names: Vec<String>,
+    pub privileges: Vec<&'static str>,
+}
+
+impl IndexPrivileges {
+    /// Creating `names` and bulk writing to them (migrate, for missing indices)
+    pub fn create_and_write(names: Vec<String>) -> Self {
+        Self { names, privileges: vec!["create_index", "write"] }
+    }
+
+    /// Bulk writing to `names` (migrate, for existing indices)
+    pub fn write(names: Vec<String>) -> Self {
+        Self { names, privileges: vec!["write"] }
+    }
+
+    /// Pointing aliases at `names` and deleting them (`alias swap`)
+    pub fn manage(names: Vec<String>) -> Self {
+        Self { names, privileges: vec!["manage"] }
+    }
+}
+
+/// What writing to `names` takes: `create_index` only on the ones in `missing`
+pub fn for_writing(names: Vec<String>, missing: &[String]) -> Vec<IndexPrivileges> {
+    let (to_create, existing): (Vec<String>, Vec<String>) = names.into_iter().partition(|name| missing.contains(name));
+    [(!to_create.is_empty()).then(|| IndexPrivileges::create_and_write(to_create)),
+     (!existing.is_empty()).then(|| IndexPrivileges::write(existing))]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// The indices of `names` that don't exist yet
+pub async fn missing_indices(client: &Client, elasticsearch_url: &str, names: &[String]) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+    for name in names {
+        let response = client.head(format!("{}/{}", elasticsearch_url, name)).send_noting_warnings().await
+            .with_context(|| format!("Failed to check index {}", name))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            missing.push(name.clone());
+        }
+    }
+    Ok(missing)
+}
+
+/// Body of a `_has_privileges` request asking for `required`
+fn request_body(required: &[IndexPrivileges]) -> Value {
+    json!({
+        "index": required.iter()
+            .map(|required| json!({"names": required.names, "privileges": required.privileges}))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Privileges the response denies, as `<privilege> on <index>`
+fn missing_privileges(response: &Value) -> Vec<String> {
+    response["index"].as_object().into_iter().flatten()
+        .flat_map(|(index, privileges)| privileges.as_object().into_iter().flatten()
+            .filter(|(_, granted)| granted.as_bool() == Some(false))
+            .map(move |(privilege, _)| format!("{} on {}", privilege, index)))
+        .collect()
+}
+
+/// Fail unless the credentials hold every privilege in `required`
+pub async fn check_privileges(client: &Client, elasticsearch_url: &str, required: &[IndexPrivileges]) -> Result<()> {
+    let url = format!("{}/_security/user/_has_privileges", elasticsearch_url);
+    let response = client.post(&url).json(&request_body(required)).send_noting_warnings().await
+        .context("Failed to check privileges")?;
+    match response.status() {
+        status if status.is_success() => {}
+        StatusCode::UNAUTHORIZED => anyhow::bail!("The cluster rejected the configured credentials (HTTP 401)"),
+        status => {
+            // Security disabled, or a distribution without the API
+            warn!("⚠️  Can't check privileges (HTTP {}), assuming the credentials may write", status);
+            return Ok(());
+        }
+    }
+
+    let body: Value = response.json().await.context("Failed to parse privileges response")?;
+    let missing = missing_privileges(&body);
+    if !missing.is_empty() {
+        let user = body["username"].as_str().unwrap_or("the configured user");
+        anyhow::bail!("{} is missing privileges: {}", user, missing.join(", "));
+    }
+    let privileges: Vec<String> = required.iter()
+        .map(|required| format!("{} on {}", required.privileges.join("/"), required.names.join(", ")))
+        .collect();
+    info!("✓ Credentials have {}", privileges.join("; "));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_privileges() {
+        let required = [
+            IndexPrivileges::create_and_write(vec!["nfts".to_string(), "nfts_quarantine".to_string()]),
+            IndexPrivileges::manage(vec!["nfts_v2".to_string()]),
+        ];
+        assert_eq!(request_body(&required), json!({"index": [
+            {"names": ["nfts", "nfts_quarantine"], "privileges": ["create_index", "write"]},
+            {"names": ["nfts_v2"], "privileges": ["manage"]},
+        ]}));
+
+        let response = json!({
+            "username": "migrator",
+            "has_all_requested": false,
+            "index": {
+                "nfts": {"create_index": true, "write": true},
+                "nfts_quarantine": {"create_index": false, "write": true},
+                "nfts_v2": {"manage": false},
+            },
+        });
+        assert_eq!(missing_privileges(&response), vec!["create_index on nfts_quarantine", "manage on nfts_v2"]);
+        assert!(missing_privileges(&json!({"has_all_requested": true, "index": {"nfts": {"write": true}}})).is_empty());
+
+        // Existing indices only need write
+        let names = vec!["nfts".to_string(), "nfts_quarantine".to_string()];
+        assert_eq!(for_writing(names.clone(), &["nfts_quarantine".to_string()]), vec![
+            IndexPrivileges::create_and_write(vec!["nfts_quarantine".to_string()]),
+            IndexPrivileges::write(vec!["nfts".to_string()]),
+        ]);
+        assert_eq!(for_writing(names.clone(), &[]), vec![IndexPrivileges::write(names)]);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+use crate::checkpoint::MigrationCheckpoint;
+use crate::conservation::Conservation;
+use crate::deprecations::DeprecationWarning;
+use crate::histogram::DocumentShapesReport;
+use crate::output;
+use crate::paths::ensure_parent_dir;
+
+/// Lifecycle of a run as seen by orchestrators polling the progress file
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState {
+    Running,
+    Completed,
+    Incomplete,
+    Interrupted,
+}
+
+/// Contents of PROGRESS_FILE
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ProgressReport {
+    pub state: RunState,
+    pub csv_file: String,
+    pub index: String,
+    /// Records indexed by this run
+    pub processed: u64,
+    /// Records this run set out to index
+    pub to_process: u64,
+    /// Records covered by the checkpoint, including earlier runs
+    pub completed_records: usize,
+    pub total_records: usize,
+    pub failed_batches: usize,
+    pub rate_per_sec: f64,
+    pub eta_secs: Option<u64>,
+    pub updated_at: u64,
+}
+
+/// `started` event of `--output-format json`
+#[derive(Debug, Serialize)]
+pub struct RunStarted {
+    pub csv_file: String,
+    pub index: String,
+    pub to_process: u64,
+    pub total_records: usize,
+}
+
+/// `summary` event of `--output-format json`: the final progress and this session's outcome
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    #[serde(flatten)]
+    pub progress: ProgressReport,
+    pub duration_secs: f64,
+    pub session_successful_batches: usize,
+    pub session_failed_batches: usize,
+    pub rejected_documents: usize,
+    /// Rows and documents stage by stage, from rows read to documents acknowledged
+    pub conservation: Conservation,
+    /// Of the documents built this session
+    pub document_shapes: DocumentShapesReport,
+    /// Distinct `Warning` headers of Elasticsearch responses
+    pub deprecation_warnings: Vec<DeprecationWarning>,
+}
+
+/// Writes a machine-readable progress file (Airflow/Argo sensors poll it instead of
+/// stdout), and under `--output-format json` the same report as a `progress` event
+pub struct ProgressFile {
+    path: Option<PathBuf>,
+    index: String,
+    to_process: u64,
+    started: Instant,
+}
+
+impl ProgressFile {
+    pub fn new(path: Option<PathBuf>, index: String, to_process: u64) -> Self {
+        Self { path, index, to_process, started: Instant::now() }
+    }
+
+    pub fn report(&self, state: RunState, processed: u64, checkpoint: &MigrationCheckpoint) -> ProgressReport {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate_per_sec = if elapsed > 0.0 { processed as f64 / elapsed } else { 0.0 };
+        let eta_secs = (state == RunState::Running && rate_per_sec > 0.0)
+            .then(|| (self.to_process.saturating_sub(processed) as f64 / rate_per_sec).ceil() as u64);
+
+        ProgressReport {
+            state,
+            csv_file: checkpoint.csv_file_path.clone(),
+            index: self.index.clone(),
+            processed,
+            to_process: self.to_process,
+            completed_records: checkpoint.processed_records,
+            total_records: checkpoint.total_records,
+            failed_batches: checkpoint.failed_batches,
+            rate_per_sec,
+            eta_secs,
+            updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        }
+    }
+
+    /// Replace the progress file atomically (write a temp file, then rename over it)
+    pub async fn write(&self, report: &ProgressReport) -> Result<()> {
+        output::emit("progress", report);
+        let Some(path) = &self.path else { return Ok(()) };
+        let json = serde_json::to_string_pretty(report)?;
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        ensure_parent_dir(path).await?;
+        fs::write(&temp, json).await
+            .with_context(|| format!("Failed to write progress file {}", path.display()))?;
+        fs::rename(&temp, path).await
+            .with_context(|| format!("Failed to replace progress file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::CheckpointMode;
+
+    #[tokio::test]
+    async fn test_progress_file_written_atomically() {
+        let dir = std::env::temp_dir().join(format!("progress-test-{}", std::process::id()));
+        let path = dir.join("progress.json");
+        let progress = ProgressFile::new(Some(path.clone()), "nfts".to_string(), 100);
+        let mut checkpoint = MigrationCheckpoint::new("tokens.csv".to_string(), 150, CheckpointMode::Index);
+        checkpoint.add_completed_batch(0..75, &[]);
+
+        let report = progress.report(RunState::Running, 25, &checkpoint);
+        progress.write(&report).await.unwrap();
+
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).await.unwrap()).unwrap();
+        assert_eq!(written["state"], "running");
+        assert_eq!(written["processed"], 25);
+        assert_eq!(written["completed_records"], 75);
+        assert!(written["eta_secs"].is_u64());
+        assert!(!dir.join("progress.json.tmp").exists());
+
+        let done = progress.report(RunState::Completed, 100, &checkpoint);
+        assert_eq!(done.eta_secs, None);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
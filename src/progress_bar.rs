@@ -0,0 +1,84 @@
+//! Live progress bar for interactive runs: percentage, records/sec, ETA and failed
+//! batches on one line of stderr. Under --quiet, or when stderr isn't a terminal
+//! (CI), there is no bar and progress is logged every 10000 records instead.
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The bar on screen, so log lines can be printed above it instead of through it
+static ACTIVE_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Run `f` with the active bar (if any) cleared from the screen
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let bar = ACTIVE_BAR.lock().unwrap().clone();
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+pub struct MigrationProgress {
+    bar: ProgressBar,
+    failed_batches: AtomicU64,
+}
+
+impl MigrationProgress {
+    /// Show a bar over all `total` records, `done` of them already processed; None
+    /// when quiet or not on a terminal
+    pub fn start(total: u64, done: u64, quiet: bool) -> Option<Arc<Self>> {
+        if quiet || !std::io::stderr().is_terminal() {
+            return None;
+        }
+        let progress = Arc::new(Self::new(ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stderr()), done));
+        *ACTIVE_BAR.lock().unwrap() = Some(progress.bar.clone());
+        Some(progress)
+    }
+
+    fn new(bar: ProgressBar, done: u64) -> Self {
+        bar.set_style(ProgressStyle::with_template(
+            "[{elapsed_precise}] {wide_bar} {percent}% {human_pos}/{human_len} records, {per_sec}, ETA {eta} {msg}",
+        ).unwrap());
+        bar.set_position(done);
+        // Rate and ETA are about this session, not the records resumed past
+        bar.reset_eta();
+        Self { bar, failed_batches: AtomicU64::new(0) }
+    }
+
+    /// `processed` records of the CSV are done, counting earlier sessions
+    pub fn set_processed(&self, processed: u64) {
+        self.bar.set_position(processed);
+    }
+
+    pub fn batch_failed(&self) {
+        let failed = self.failed_batches.fetch_add(1, Ordering::Relaxed) + 1;
+        self.bar.set_message(format!("({} failed batches)", failed));
+    }
+
+    /// Leave the final state on screen and stop routing logs around the bar
+    pub fn finish(&self) {
+        self.bar.abandon();
+        ACTIVE_BAR.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_counts_resumed_records_and_failures() {
+        assert!(MigrationProgress::start(100, 0, true).is_none());
+
+        let progress = MigrationProgress::new(ProgressBar::hidden(), 40);
+        progress.bar.set_length(100);
+        assert_eq!(progress.bar.position(), 40);
+        progress.set_processed(75);
+        progress.batch_failed();
+        progress.batch_failed();
+        assert_eq!(progress.bar.position(), 75);
+        assert_eq!(progress.bar.message(), "(2 failed batches)");
+        assert_eq!(suspend(|| 1), 1);
+    }
+}
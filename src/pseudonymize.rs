@@ -0,0 +1,119 @@
+//! Pseudonymized addresses for analytics clusters. With HASH_FIELDS set, each of
+//! the named columns (owner, maker, matcher) is replaced by an HMAC-SHA256 of the
+//! address under a secret key (HASH_KEY, or the contents of HASH_KEY_FILE). The
+//! same key gives the same pseudonym in every run and file, so joins on owner still
+//! work; without the key an address can't be recovered or checked against a guess.
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+use crate::record::CsvRecord;
+
+/// Columns that can be pseudonymized
+pub const HASHABLE_FIELDS: &[&str] = &["owner", "maker", "matcher"];
+
+const BLOCK_SIZE: usize = 64;
+
+/// HMAC-SHA256 (RFC 2104)
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new().chain_update(pad(0x36)).chain_update(message).finalize();
+    Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
+
+pub struct FieldHasher {
+    key: Vec<u8>,
+    fields: Vec<&'static str>,
+}
+
+impl FieldHasher {
+    /// `fields` is a comma-separated list of HASHABLE_FIELDS
+    pub fn new(key: &[u8], fields: &str) -> Result<Self> {
+        if key.len() < 16 {
+            anyhow::bail!("The HASH_KEY must be at least 16 bytes");
+        }
+        let fields = fields.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(|field| HASHABLE_FIELDS.iter().find(|known| **known == field).copied()
+                .ok_or_else(|| anyhow::anyhow!("HASH_FIELDS: '{}' can't be hashed (one of {})", field, HASHABLE_FIELDS.join(", "))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { key: key.to_vec(), fields })
+    }
+
+    pub fn fields(&self) -> &[&'static str] {
+        &self.fields
+    }
+
+    /// The address's pseudonym: the first 20 bytes of its HMAC, shaped like an
+    /// address so mappings and normalizers for addresses still apply. Addresses
+    /// differing only in case or surrounding spaces get the same pseudonym.
+    pub fn pseudonym(&self, address: &str) -> String {
+        let mac = hmac_sha256(&self.key, address.trim().to_lowercase().as_bytes());
+        let hex: String = mac[..20].iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("0x{}", hex)
+    }
+
+    pub fn apply(&self, record: &mut CsvRecord) {
+        for field in &self.fields {
+            let value = match *field {
+                "owner" => &mut record.owner,
+                "maker" => &mut record.maker,
+                _ => &mut record.matcher,
+            };
+            if let Some(address) = value.as_mut().filter(|address| !address.trim().is_empty()) {
+                *address = self.pseudonym(address);
+            }
+        }
+    }
+}
+
+static FIELD_HASHER: OnceLock<FieldHasher> = OnceLock::new();
+
+/// Pseudonymize HASH_FIELDS for the rest of the process; only the first call takes effect
+pub fn install_field_hasher(hasher: FieldHasher) {
+    FIELD_HASHER.set(hasher).ok();
+}
+
+/// Replace the configured columns of `record` by their pseudonyms
+pub fn pseudonymize(record: &mut CsvRecord) {
+    if let Some(hasher) = FIELD_HASHER.get() {
+        hasher.apply(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_and_consistent_pseudonyms() {
+        // RFC 4231 test case 2
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        let hex: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        let hasher = FieldHasher::new(b"0123456789abcdef", "owner, maker").unwrap();
+        let mut record = CsvRecord {
+            owner: Some("0xABC".to_string()),
+            maker: Some(" 0xabc ".to_string()),
+            matcher: Some("0xabc".to_string()),
+            ..Default::default()
+        };
+        hasher.apply(&mut record);
+        assert_eq!(record.owner, record.maker);
+        assert_eq!(record.owner.as_ref().unwrap().len(), 42);
+        assert_eq!(record.matcher.as_deref(), Some("0xabc"));
+        assert_ne!(FieldHasher::new(b"another key 1234", "owner").unwrap().pseudonym("0xabc"), hasher.pseudonym("0xabc"));
+
+        assert!(FieldHasher::new(b"0123456789abcdef", "owner,token_id").is_err());
+        assert!(FieldHasher::new(b"short", "owner").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! `purge --owner <address>`: erasure requests. Every document naming the address as
+//! owner, maker or matcher, or as either in its orders history, is deleted from the
+//! indices the migrator writes, by id, and the ids removed from each index are
+//! appended to an audit log.
+
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::deprecations::SendNotingWarnings;
+use crate::elasticsearch::BulkResponse;
+use crate::paths::ensure_parent_dir;
+
+/// Fields an address is looked up in
+pub const PURGE_FIELDS: &[&str] = &["owner", "maker", "matcher"];
+
+/// Fields of the nested `orders` history an address is looked up in
+const NESTED_ORDER_FIELDS: &[&str] = &["orders.maker", "orders.matcher"];
+
+/// Ids fetched, then deleted, per round
+const PAGE_SIZE: usize = 1000;
+
+/// Documents with any of `addresses` in any of PURGE_FIELDS or in an order of
+/// their `orders` history, whatever their case
+pub fn purge_query(addresses: &[String]) -> Value {
+    let terms = |fields: &'static [&'static str]| -> Vec<Value> {
+        fields.iter()
+            .flat_map(|field| addresses.iter().map(move |address| json!({"term": {*field: {"value": address, "case_insensitive": true}}})))
+            .collect()
+    };
+    let mut should = terms(PURGE_FIELDS);
+    // Indices without the nested mapping (orders history, summaries) just don't match
+    should.push(json!({"nested": {
+        "path": "orders",
+        "ignore_unmapped": true,
+        "query": {"bool": {"should": terms(NESTED_ORDER_FIELDS), "minimum_should_match": 1}},
+    }}));
+    json!({"bool": {"should": should, "minimum_should_match": 1}})
+}
+
+/// Documents in `index` matching `query`; None if the index doesn't exist
+pub async fn count_matches(client: &Client, elasticsearch_url: &str, index: &str, query: &Value) -> Result<Option<u64>> {
+    let url = format!("{}/{}/_count", elasticsearch_url, index);
+    let response = client.post(&url).json(&json!({"query": query})).send_noting_warnings().await.context("Failed to count documents")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to count documents in {}: HTTP {}", index, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse count response")?;
+    Ok(body["count"].as_u64())
+}
+
+/// A matching document: its `_id` and, if it was indexed with one, its `_routing`
+type Hit = (String, Option<String>);
+
+async fn matching_ids(client: &Client, elasticsearch_url: &str, index: &str, query: &Value) -> Result<Vec<Hit>> {
+    let url = format!("{}/{}/_search", elasticsearch_url, index);
+    let body = json!({"query": query, "size": PAGE_SIZE, "_source": false});
+    let response = client.post(&url).json(&body).send_noting_warnings().await.context("Failed to search documents")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to search {}: HTTP {}", index, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse search response")?;
+    Ok(body["hits"]["hits"].as_array().into_iter().flatten()
+        .filter_map(|hit| Some((hit["_id"].as_str()?.to_string(), hit["_routing"].as_str().map(str::to_string))))
+        .collect())
+}
+
+/// Bulk deletes of `hits`, with the routing they were indexed with (ROUTING)
+fn delete_body(index: &str, hits: &[Hit]) -> String {
+    let mut body = String::new();
+    for (id, routing) in hits {
+        let mut action = json!({"_index": index, "_id": id});
+        if let Some(routing) = routing {
+            action["routing"] = json!(routing);
+        }
+        body.push_str(&json!({"delete": action}).to_string());
+        body.push('\n');
+    }
+    body
+}
+
+/// Delete `hits`, refreshing so the next search no longer finds them; returns the ids deleted
+async fn delete_ids(client: &Client, elasticsearch_url: &str, index: &str, hits: &[Hit]) -> Result<Vec<String>> {
+    let body = delete_body(index, hits);
+    let url = format!("{}/_bulk?refresh=true", elasticsearch_url);
+    let response = client.post(&url)
+        .header("Content-Type", "application/x-ndjson")
+        .body(body)
+        .send_noting_warnings().await.context("Failed to delete documents")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to delete documents from {}: HTTP {}", index, response.status());
+    }
+    let response: BulkResponse = response.json().await.context("Failed to parse bulk response")?;
+    let mut deleted = Vec::new();
+    for item in &response.items {
+        let result = item.result();
+        match &result.error {
+            Some(error) => anyhow::bail!("Failed to delete {} from {}: {} {}", result.id.as_deref().unwrap_or("?"),
+                                         index, error.error_type, error.reason.as_deref().unwrap_or("")),
+            // A 404 means the delete missed the document (e.g. another shard), which is still there
+            None if result.status == StatusCode::NOT_FOUND.as_u16() => {}
+            None => deleted.extend(result.id.clone()),
+        }
+    }
+    Ok(deleted)
+}
+
+/// One round of deletions, as kept in the audit log
+#[derive(Debug, Serialize)]
+pub struct PurgeRecord<'a> {
+    /// Unix seconds
+    pub purged_at: u64,
+    /// The address as requested
+    pub address: &'a str,
+    pub index: &'a str,
+    pub ids: &'a [String],
+}
+
+/// Append-only NDJSON log of purged documents
+pub struct PurgeAudit {
+    path: PathBuf,
+}
+
+impl PurgeAudit {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn append(&self, record: &PurgeRecord<'_>) -> Result<()> {
+        ensure_parent_dir(&self.path).await?;
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await
+            .with_context(|| format!("Failed to open purge audit log {}", self.path.display()))?;
+        file.write_all(format!("{}\n", serde_json::to_string(record)?).as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Delete every document of `index` matching `query`, logging the ids to `audit`
+/// before going on; returns how many were deleted
+pub async fn purge_index(client: &Client, elasticsearch_url: &str, index: &str, query: &Value, address: &str, audit: &PurgeAudit) -> Result<usize> {
+    let mut deleted = 0;
+    loop {
+        let hits = matching_ids(client, elasticsearch_url, index, query).await?;
+        if hits.is_empty() {
+            return Ok(deleted);
+        }
+        let removed = delete_ids(client, elasticsearch_url, index, &hits).await?;
+        let purged_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        audit.append(&PurgeRecord { purged_at, address, index, ids: &removed }).await?;
+        // Nothing deleted means the search keeps finding the same documents
+        if removed.is_empty() {
+            anyhow::bail!("Documents matching {} in {} could not be deleted", address, index);
+        }
+        deleted += removed.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_matches_deleted_and_audited() {
+        let query = purge_query(&["0xAbc".to_string()]);
+        assert_eq!(query["bool"]["should"].as_array().unwrap().len(), PURGE_FIELDS.len() + 1);
+        assert_eq!(query["bool"]["should"][1]["term"]["maker"]["value"], "0xAbc");
+        let nested = &query["bool"]["should"][PURGE_FIELDS.len()]["nested"];
+        assert_eq!(nested["path"], "orders");
+        assert_eq!(nested["query"]["bool"]["should"][1]["term"]["orders.matcher"]["value"], "0xAbc");
+
+        let searches = Arc::new(AtomicUsize::new(0));
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), {
+            let searches = searches.clone();
+            move |_, request_line| match request_line {
+                line if line.starts_with("POST /nfts/_search") => match searches.fetch_add(1, Ordering::Relaxed) {
+                    0 => Reply::Respond(200, json!({"hits": {"hits": [{"_id": "0xabc:1", "_routing": "0xabc"}, {"_id": "0xabc:2"}]}}).to_string()),
+                    _ => Reply::Respond(200, json!({"hits": {"hits": []}}).to_string()),
+                },
+                line if line.starts_with("POST /_bulk") => Reply::Respond(200, json!({"errors": false, "items": [
+                    {"delete": {"_id": "0xabc:1", "_index": "nfts", "status": 200}},
+                    {"delete": {"_id": "0xabc:2", "_index": "nfts", "status": 200}},
+                ]}).to_string()),
+                line if line.starts_with("POST /missing/_count") => Reply::Respond(404, "{}".to_string()),
+                _ => Reply::Respond(200, r#"{"count": 2}"#.to_string()),
+            }
+        }).await;
+        let hits = [("0xabc:1".to_string(), Some("0xabc".to_string())), ("0xabc:2".to_string(), None)];
+        assert_eq!(delete_body("nfts", &hits), concat!(
+            r#"{"delete":{"_id":"0xabc:1","_index":"nfts","routing":"0xabc"}}"#, "\n",
+            r#"{"delete":{"_id":"0xabc:2","_index":"nfts"}}"#, "\n",
+        ));
+
+        let client = Client::new();
+        assert_eq!(count_matches(&client, &server.url(), "nfts", &query).await.unwrap(), Some(2));
+        assert_eq!(count_matches(&client, &server.url(), "missing", &query).await.unwrap(), None);
+
+        let path = std::env::temp_dir().join(format!("purge-audit-{}.ndjson", std::process::id()));
+        let audit = PurgeAudit::new(&path);
+        assert_eq!(purge_index(&client, &server.url(), "nfts", &query, "0xAbc", &audit).await.unwrap(), 2);
+        let log = std::fs::read_to_string(&path).unwrap();
+        let record: Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+        assert_eq!(record["ids"], json!(["0xabc:1", "0xabc:2"]));
+        assert_eq!(record["index"], "nfts");
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,198 @@
+//! Ceilings on how hard a migration hits a shared cluster: bulk requests/sec
+//! (MAX_REQUESTS_PER_SEC) and records/sec (MAX_RECORDS_PER_SEC), as token buckets
+//! shared by all workers. Unlike TARGET_RECORDS_PER_SEC, which steers toward a rate,
+//! these only ever hold requests back. They can be changed while a migration runs:
+//! edit RATE_LIMIT_FILE and send SIGHUP, or `POST /rate-limit?records_per_sec=…`
+//! on METRICS_ADDR.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+static RATE_LIMITER: OnceLock<Arc<RateLimiter>> = OnceLock::new();
+
+/// The limiter shared by every file of the process, reachable from the control
+/// endpoint; made with `limits` by the first call
+pub fn install_rate_limiter(limits: RateLimits) -> Arc<RateLimiter> {
+    RATE_LIMITER.get_or_init(|| Arc::new(RateLimiter::new(limits))).clone()
+}
+
+pub fn rate_limiter() -> Option<&'static RateLimiter> {
+    RATE_LIMITER.get().map(Arc::as_ref)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    #[serde(default)]
+    pub requests_per_sec: Option<f64>,
+    #[serde(default)]
+    pub records_per_sec: Option<f64>,
+}
+
+impl RateLimits {
+    /// Read a RATE_LIMIT_FILE: `requests_per_sec = 5` and/or `records_per_sec = 2000`
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let limits: Self = toml::from_str(&content).with_context(|| format!("Invalid rate limit file {}", path.display()))?;
+        limits.validated()
+    }
+
+    /// These limits with the ones in a query string (`records_per_sec=500&requests_per_sec=off`)
+    /// applied; parameters left out keep their value
+    pub fn with_query(mut self, query: &str) -> Result<Self> {
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').with_context(|| format!("Expected name=value, got '{}'", pair))?;
+            let value = match value {
+                "off" => None,
+                value => Some(value.parse::<f64>().with_context(|| format!("Invalid {} '{}'", name, value))?),
+            };
+            match name {
+                "requests_per_sec" => self.requests_per_sec = value,
+                "records_per_sec" => self.records_per_sec = value,
+                _ => anyhow::bail!("Unknown rate limit '{}'", name),
+            }
+        }
+        self.validated()
+    }
+
+    fn validated(self) -> Result<Self> {
+        for (name, value) in [("requests_per_sec", self.requests_per_sec), ("records_per_sec", self.records_per_sec)] {
+            if value.is_some_and(|value| !value.is_finite() || value <= 0.0) {
+                anyhow::bail!("{} must be above 0 (or off)", name);
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl std::fmt::Display for RateLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let limit = |value: Option<f64>| value.map_or("unlimited".to_string(), |value| format!("{}/s", value));
+        write!(f, "requests {}, records {}", limit(self.requests_per_sec), limit(self.records_per_sec))
+    }
+}
+
+/// Refills at `rate` tokens/sec and holds up to one second's worth. Takes may
+/// overdraw it; the taker then waits until the debt is paid off.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { rate, tokens: rate.max(1.0), updated: now }
+    }
+
+    /// Take `amount` tokens; how long to wait before they are covered
+    fn take(&mut self, amount: f64, now: Instant) -> Duration {
+        let refill = now.saturating_duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate.max(1.0)) - amount;
+        self.updated = now;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+    waited_millis: AtomicU64,
+}
+
+struct LimiterState {
+    limits: RateLimits,
+    requests: Option<Bucket>,
+    records: Option<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(LimiterState {
+                limits,
+                requests: limits.requests_per_sec.map(|rate| Bucket::new(rate, now)),
+                records: limits.records_per_sec.map(|rate| Bucket::new(rate, now)),
+            }),
+            waited_millis: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.state.lock().unwrap() = Self::new(limits).state.into_inner().unwrap();
+        info!("🎚️  Rate limits now: {}", limits);
+    }
+
+    /// Wait until `requests` bulk requests carrying `records` records may go out
+    pub async fn acquire(&self, requests: usize, records: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let requests = state.requests.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(requests as f64, now));
+            let records = state.records.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(records as f64, now));
+            requests.max(records)
+        };
+        if !wait.is_zero() {
+            self.waited_millis.fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Total time requests were held back
+    pub fn waited(&self) -> Duration {
+        Duration::from_millis(self.waited_millis.load(Ordering::Relaxed))
+    }
+}
+
+/// Re-read `path` into the limiter on every SIGHUP, until the task is aborted
+pub async fn reload_on_sighup(limiter: Arc<RateLimiter>, path: PathBuf) -> Result<()> {
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .context("Failed to listen for SIGHUP")?;
+    while hangups.recv().await.is_some() {
+        match RateLimits::load(&path) {
+            Ok(limits) => limiter.set_limits(limits),
+            Err(e) => warn!("⚠️  Keeping rate limits {}: {:#}", limiter.limits(), e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_pace_requests_and_limits_update() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(100.0, start);
+        // A second's worth goes out at once, then takes wait for the refill
+        assert_eq!(bucket.take(100.0, start), Duration::ZERO);
+        assert_eq!(bucket.take(50.0, start), Duration::from_millis(500));
+        assert_eq!(bucket.take(50.0, start + Duration::from_millis(500)), Duration::from_millis(500));
+        assert_eq!(bucket.take(10.0, start + Duration::from_secs(10)), Duration::ZERO);
+
+        let limits = RateLimits { requests_per_sec: Some(5.0), records_per_sec: None };
+        let updated = limits.with_query("records_per_sec=2000&requests_per_sec=off").unwrap();
+        assert_eq!(updated, RateLimits { requests_per_sec: None, records_per_sec: Some(2000.0) });
+        assert!(limits.with_query("records_per_sec=0").is_err());
+        assert!(limits.with_query("workers=3").is_err());
+        assert_eq!(updated.to_string(), "requests unlimited, records 2000/s");
+
+        let limiter = RateLimiter::new(RateLimits::default());
+        limiter.set_limits(updated);
+        assert_eq!(limiter.limits(), updated);
+        assert!(limiter.state.lock().unwrap().records.is_some());
+    }
+}
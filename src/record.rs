@@ -0,0 +1,307 @@
+//! The canonical NFT record. Each input source converts its rows into an
+//! `NftRecord` with typed values, and documents are built from that, so the
+//! column list and value parsing live in one place.
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// One row of the CSV export, every column as exported
+#[derive(Debug, Deserialize, Default)]
+pub struct CsvRecord {
+    pub token_address: Option<String>,
+    pub token_id: Option<String>,
+    pub owner: Option<String>,
+    pub base_price: Option<String>,
+    pub ended_at: Option<String>,
+    pub ended_price: Option<String>,
+    pub expired_at: Option<String>,
+    pub kind: Option<String>,
+    pub maker: Option<String>,
+    pub matcher: Option<String>,
+    pub order_id: Option<String>,
+    pub payment_token: Option<String>,
+    pub price: Option<String>,
+    pub started_at: Option<String>,
+    pub state: Option<String>,
+    pub name: Option<String>,
+    pub attributes: Option<String>,
+    pub image: Option<String>,
+    pub video: Option<String>,
+    pub metadata_last_updated: Option<String>,
+    pub cdn_image: Option<String>,
+    pub animation_url: Option<String>,
+    pub description: Option<String>,
+    pub is_shown: Option<String>,
+    pub ownership_block_number: Option<String>,
+    pub ownership_log_index: Option<String>,
+    pub raw_metadata: Option<String>,
+    pub order_status: Option<String>,
+    pub ron_price: Option<String>,
+    /// Tombstone flag (optional column): true/t/1/yes deletes the token's document
+    pub deleted: Option<String>,
+    /// Bulk operation (optional column): `delete` deletes the token's document
+    pub operation: Option<String>,
+}
+
+/// A token with its listing and metadata, independent of the source it came from.
+/// Blank or unparseable values are None.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NftRecord {
+    pub token_address: Option<String>,
+    pub token_id: Option<String>,
+    pub owner: Option<String>,
+    pub base_price: Option<f64>,
+    pub ended_at: Option<i64>,
+    pub ended_price: Option<f64>,
+    pub expired_at: Option<i64>,
+    pub kind: Option<i64>,
+    pub maker: Option<String>,
+    pub matcher: Option<String>,
+    pub order_id: Option<i64>,
+    pub payment_token: Option<String>,
+    pub price: Option<f64>,
+    pub started_at: Option<i64>,
+    pub state: Option<String>,
+    pub name: Option<String>,
+    /// Attributes JSON as exported
+    pub attributes: Option<Value>,
+    pub image: Option<String>,
+    pub video: Option<String>,
+    pub metadata_last_updated: Option<i64>,
+    pub cdn_image: Option<String>,
+    pub animation_url: Option<String>,
+    pub description: Option<String>,
+    pub is_shown: Option<bool>,
+    pub ownership_block_number: Option<i64>,
+    pub ownership_log_index: Option<i32>,
+    pub raw_metadata: Option<Value>,
+    pub order_status: Option<String>,
+    pub ron_price: Option<f64>,
+    /// The row is a tombstone: delete the document instead of indexing it
+    pub deleted: bool,
+}
+
+fn parse_optional_string(s: &Option<String>) -> Option<String> {
+    s.as_ref()
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| s.trim().to_string())
+}
+
+fn parse_optional<T: std::str::FromStr>(s: &Option<String>) -> Option<T> {
+    s.as_ref()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+fn parse_optional_bool(s: &Option<String>) -> Option<bool> {
+    s.as_ref().and_then(|s| match s.trim().to_lowercase().as_str() {
+        "t" | "true" => Some(true),
+        "f" | "false" => Some(false),
+        _ => None,
+    })
+}
+
+fn parse_deleted_flag(s: &str) -> Option<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "1" | "yes" | "y" => Some(true),
+        "0" | "no" | "n" => Some(false),
+        other => parse_optional_bool(&Some(other.to_string())),
+    }
+}
+
+fn parse_operation(s: &str) -> Option<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "delete" => Some(true),
+        "index" | "upsert" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a JSON column; blank or invalid JSON is None
+pub fn parse_optional_json(s: &Option<String>) -> Option<Value> {
+    let json = s.as_ref()?.trim();
+    if json.is_empty() {
+        return None;
+    }
+    serde_json::from_str(json).ok()
+}
+
+/// Whether a non-blank value of a typed column survives conversion
+type Validator = fn(&str) -> bool;
+
+fn parses<T: std::str::FromStr>(s: &str) -> bool {
+    s.parse::<T>().is_ok()
+}
+
+impl CsvRecord {
+    /// Columns whose value is lost in the NftRecord: not blank, but not a valid
+    /// number, boolean or JSON either
+    pub fn unparseable_columns(&self) -> Vec<&'static str> {
+        let typed: [(&'static str, &Option<String>, Validator); 17] = [
+            ("base_price", &self.base_price, parses::<f64>),
+            ("ended_at", &self.ended_at, parses::<i64>),
+            ("ended_price", &self.ended_price, parses::<f64>),
+            ("expired_at", &self.expired_at, parses::<i64>),
+            ("kind", &self.kind, parses::<i64>),
+            ("order_id", &self.order_id, parses::<i64>),
+            ("price", &self.price, parses::<f64>),
+            ("started_at", &self.started_at, parses::<i64>),
+            ("metadata_last_updated", &self.metadata_last_updated, parses::<i64>),
+            ("ownership_block_number", &self.ownership_block_number, parses::<i64>),
+            ("ownership_log_index", &self.ownership_log_index, parses::<i32>),
+            ("ron_price", &self.ron_price, parses::<f64>),
+            ("is_shown", &self.is_shown, |s| parse_optional_bool(&Some(s.to_string())).is_some()),
+            ("attributes", &self.attributes, |s| serde_json::from_str::<Value>(s).is_ok()),
+            ("raw_metadata", &self.raw_metadata, |s| serde_json::from_str::<Value>(s).is_ok()),
+            ("deleted", &self.deleted, |s| parse_deleted_flag(s).is_some()),
+            ("operation", &self.operation, |s| parse_operation(s).is_some()),
+        ];
+        typed.into_iter()
+            .filter(|(_, value, valid)| value.as_deref().map(str::trim).is_some_and(|value| !value.is_empty() && !valid(value)))
+            .map(|(column, _, _)| column)
+            .collect()
+    }
+
+    /// Text values changed by trimming surrounding whitespace
+    pub fn trimmed_values(&self) -> usize {
+        [&self.token_address, &self.token_id, &self.owner, &self.maker, &self.matcher, &self.payment_token,
+         &self.state, &self.name, &self.image, &self.video, &self.cdn_image, &self.animation_url,
+         &self.description, &self.order_status]
+            .into_iter()
+            .filter(|value| value.as_deref().is_some_and(|value| !value.trim().is_empty() && value.trim() != value))
+            .count()
+    }
+}
+
+impl From<CsvRecord> for NftRecord {
+    fn from(record: CsvRecord) -> Self {
+        Self {
+            token_address: parse_optional_string(&record.token_address),
+            token_id: parse_optional_string(&record.token_id),
+            owner: parse_optional_string(&record.owner),
+            base_price: parse_optional(&record.base_price),
+            ended_at: parse_optional(&record.ended_at),
+            ended_price: parse_optional(&record.ended_price),
+            expired_at: parse_optional(&record.expired_at),
+            kind: parse_optional(&record.kind),
+            maker: parse_optional_string(&record.maker),
+            matcher: parse_optional_string(&record.matcher),
+            order_id: parse_optional(&record.order_id),
+            payment_token: parse_optional_string(&record.payment_token),
+            price: parse_optional(&record.price),
+            started_at: parse_optional(&record.started_at),
+            state: parse_optional_string(&record.state),
+            name: parse_optional_string(&record.name),
+            attributes: parse_optional_json(&record.attributes),
+            image: parse_optional_string(&record.image),
+            video: parse_optional_string(&record.video),
+            metadata_last_updated: parse_optional(&record.metadata_last_updated),
+            cdn_image: parse_optional_string(&record.cdn_image),
+            animation_url: parse_optional_string(&record.animation_url),
+            description: parse_optional_string(&record.description),
+            is_shown: parse_optional_bool(&record.is_shown),
+            ownership_block_number: parse_optional(&record.ownership_block_number),
+            ownership_log_index: parse_optional(&record.ownership_log_index),
+            raw_metadata: parse_optional_json(&record.raw_metadata),
+            order_status: parse_optional_string(&record.order_status),
+            ron_price: parse_optional(&record.ron_price),
+            deleted: record.deleted.as_deref().and_then(parse_deleted_flag).unwrap_or(false)
+                || record.operation.as_deref().and_then(parse_operation).unwrap_or(false),
+        }
+    }
+}
+
+/// A JSON value as the CSV export writes it: strings as they are, other values as
+/// JSON text, null as an empty cell (None)
+pub fn csv_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// A JSON object with the CSV column names (NDJSON exports). Values may be typed
+/// JSON or strings as in the CSV; nested attributes/raw_metadata may be objects.
+impl TryFrom<&Value> for NftRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &Value) -> anyhow::Result<Self> {
+        let object = value.as_object().context("record is not a JSON object")?;
+        // Render every value the way the CSV export would, then parse it the same way
+        let columns: Map<String, Value> = object.iter()
+            .map(|(key, value)| (key.clone(), csv_text(value).map_or(Value::Null, Value::String)))
+            .collect();
+        let record: CsvRecord = serde_json::from_value(Value::Object(columns))?;
+        Ok(record.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_csv_and_json_sources_agree() {
+        let csv = NftRecord::from(CsvRecord {
+            token_address: Some(" 0xabc ".to_string()),
+            token_id: Some("7".to_string()),
+            price: Some("12.5".to_string()),
+            ownership_log_index: Some("3".to_string()),
+            is_shown: Some("t".to_string()),
+            raw_metadata: Some(r#"{"name":"Seven"}"#.to_string()),
+            expired_at: Some("".to_string()),
+            ..Default::default()
+        });
+        let json = NftRecord::try_from(&json!({
+            "token_address": "0xabc",
+            "token_id": 7,
+            "price": 12.5,
+            "ownership_log_index": "3",
+            "is_shown": true,
+            "raw_metadata": {"name": "Seven"},
+            "expired_at": null,
+            "unknown_column": "ignored"
+        })).unwrap();
+
+        assert_eq!(csv, json);
+        assert_eq!(csv.token_address.as_deref(), Some("0xabc"));
+        assert_eq!(csv.price, Some(12.5));
+        assert_eq!(csv.is_shown, Some(true));
+        assert_eq!(csv.raw_metadata, Some(json!({"name": "Seven"})));
+        assert_eq!(csv.expired_at, None);
+        assert!(NftRecord::try_from(&json!([1, 2])).is_err());
+
+        let lossy = CsvRecord {
+            token_address: Some(" 0xabc ".to_string()),
+            price: Some("12,5".to_string()),
+            is_shown: Some("yes".to_string()),
+            raw_metadata: Some("{broken".to_string()),
+            expired_at: Some(" ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(lossy.unparseable_columns(), vec!["price", "is_shown", "raw_metadata"]);
+        assert_eq!(lossy.trimmed_values(), 1);
+        assert!(!csv.deleted);
+    }
+
+    #[test]
+    fn test_tombstone_rows() {
+        let deleted = |flag: &str, operation: &str| NftRecord::from(CsvRecord {
+            deleted: Some(flag.to_string()),
+            operation: Some(operation.to_string()),
+            ..Default::default()
+        }).deleted;
+        assert!(deleted("1", ""));
+        assert!(deleted("TRUE", ""));
+        assert!(deleted("", "delete"));
+        assert!(deleted("false", "Delete"));
+        assert!(!deleted("0", "index"));
+        assert!(!deleted("", ""));
+        assert!(NftRecord::try_from(&json!({"token_id": "7", "deleted": true})).unwrap().deleted);
+
+        let typo = CsvRecord { deleted: Some("maybe".to_string()), operation: Some("remove".to_string()), ..Default::default() };
+        assert_eq!(typo.unparseable_columns(), vec!["deleted", "operation"]);
+    }
+}
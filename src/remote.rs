@@ -0,0 +1,280 @@
+//! CSV_FILE in an object store: `s3://bucket/key`, `gs://bucket/key` or
+//! `az://container/key`, and patterns like `s3://bucket/exports/*.csv.gz`. Objects
+//! are read in ranged requests as the rows are parsed, the next block fetched while
+//! the current one is read, instead of being copied locally first; a resumed run
+//! starts fetching at the checkpoint's offset. Credentials come from each
+//! provider's environment (AWS_*, GOOGLE_*, AZURE_*, instance and workload
+//! identities), with OBJECT_STORE_OPTIONS on top.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use object_store::aws::{AmazonS3Builder, AmazonS3ConfigKey};
+use object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+use object_store::gcp::{GoogleCloudStorageBuilder, GoogleConfigKey};
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, ObjectStore};
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Runtime;
+use url::Url;
+
+use crate::config::APP_CONFIG;
+
+/// Bytes fetched per ranged request
+const BLOCK_BYTES: u64 = 8 << 20;
+
+const SCHEMES: &[&str] = &["s3", "gs", "az", "abfs", "abfss"];
+
+pub fn is_remote(csv_file: &str) -> bool {
+    csv_file.split_once("://").is_some_and(|(scheme, _)| SCHEMES.contains(&scheme))
+}
+
+/// Requests run on their own runtime, so the blocking readers of the CSV passes can
+/// wait for them from any thread, async or not
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("object-store")
+        .enable_all()
+        .build()
+        .expect("Failed to start the object store runtime"))
+}
+
+fn request<T: Send + 'static>(future: impl Future<Output = Result<T>> + Send + 'static) -> Receiver<Result<T>> {
+    let (sender, receiver) = channel();
+    runtime().spawn(async move {
+        sender.send(future.await).ok();
+    });
+    receiver
+}
+
+fn wait<T>(receiver: Receiver<Result<T>>) -> Result<T> {
+    receiver.recv().unwrap_or_else(|_| Err(anyhow::anyhow!("Object store request was dropped")))
+}
+
+/// OBJECT_STORE_OPTIONS: comma-separated `key=value` builder options, e.g.
+/// `aws_region=eu-west-1,aws_endpoint=http://minio:9000`
+fn parse_options(options: &str) -> Result<Vec<(&str, &str)>> {
+    options.split(',')
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+        .map(|option| option.split_once('=').map(|(key, value)| (key.trim(), value.trim()))
+            .with_context(|| format!("OBJECT_STORE_OPTIONS: '{}' is not key=value", option)))
+        .collect()
+}
+
+/// PROXY_URL (with PROXY_USERNAME/PASSWORD as its credentials) and NO_PROXY as
+/// client options, so object store requests take the same way out as the others
+fn proxy_options() -> Result<Vec<(&'static str, String)>> {
+    // Also checks the proxy settings
+    if APP_CONFIG.proxy()?.is_none() {
+        return Ok(Vec::new());
+    }
+    let mut proxy_url = Url::parse(APP_CONFIG.proxy_url.as_deref().unwrap_or_default())
+        .context("Invalid PROXY_URL")?;
+    if let (Some(username), Some(password)) = (&APP_CONFIG.proxy_username, &APP_CONFIG.proxy_password) {
+        proxy_url.set_username(username).ok();
+        proxy_url.set_password(Some(password)).ok();
+    }
+    let mut options = vec![("proxy_url", proxy_url.to_string())];
+    if let Some(no_proxy) = &APP_CONFIG.no_proxy {
+        options.push(("proxy_excludes", no_proxy.clone()));
+    }
+    Ok(options)
+}
+
+fn store_for(url: &Url) -> Result<Arc<dyn ObjectStore>> {
+    let proxy = proxy_options()?;
+    let configured = parse_options(APP_CONFIG.object_store_options.as_deref().unwrap_or(""))?;
+    // OBJECT_STORE_OPTIONS come last, so they can still override the proxy
+    let options: Vec<(&str, &str)> = proxy.iter().map(|(key, value)| (*key, value.as_str())).chain(configured).collect();
+    let unknown = |key: &str| format!("OBJECT_STORE_OPTIONS: unknown option '{}' for {}://", key, url.scheme());
+    let store: Arc<dyn ObjectStore> = match url.scheme() {
+        "s3" => {
+            let mut builder = AmazonS3Builder::from_env().with_url(url.as_str());
+            for (key, value) in options {
+                builder = builder.with_config(key.parse::<AmazonS3ConfigKey>().with_context(|| unknown(key))?, value);
+            }
+            Arc::new(builder.build()?)
+        }
+        "gs" => {
+            let mut builder = GoogleCloudStorageBuilder::from_env().with_url(url.as_str());
+            for (key, value) in options {
+                builder = builder.with_config(key.parse::<GoogleConfigKey>().with_context(|| unknown(key))?, value);
+            }
+            Arc::new(builder.build()?)
+        }
+        _ => {
+            let mut builder = MicrosoftAzureBuilder::from_env().with_url(url.as_str());
+            for (key, value) in options {
+                builder = builder.with_config(key.parse::<AzureConfigKey>().with_context(|| unknown(key))?, value);
+            }
+            Arc::new(builder.build()?)
+        }
+    };
+    Ok(store)
+}
+
+/// An object named by its URL
+#[derive(Clone)]
+pub struct RemoteObject {
+    store: Arc<dyn ObjectStore>,
+    path: ObjectPath,
+}
+
+impl RemoteObject {
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = Url::parse(url).with_context(|| format!("Invalid CSV_FILE URL '{}'", url))?;
+        let store = store_for(&parsed).with_context(|| format!("Failed to configure the object store for {}", url))?;
+        let path = ObjectPath::from_url_path(parsed.path()).with_context(|| format!("Invalid object path in '{}'", url))?;
+        Ok(Self { store, path })
+    }
+
+    /// Size and modification time (Unix seconds)
+    pub fn metadata(&self) -> Result<(u64, Option<u64>)> {
+        let (store, path) = (self.store.clone(), self.path.clone());
+        let meta = wait(request(async move { Ok(store.head(&path).await?) }))
+            .with_context(|| format!("Failed to look up {}", self.path))?;
+        Ok((meta.size, u64::try_from(meta.last_modified.timestamp()).ok()))
+    }
+
+    pub fn open(&self) -> Result<RemoteFile> {
+        let (store, path) = (self.store.clone(), self.path.clone());
+        let meta = wait(request(async move { Ok(store.head(&path).await?) }))
+            .with_context(|| format!("Failed to look up {}", self.path))?;
+        Ok(RemoteFile { object: self.clone(), len: meta.size, e_tag: meta.e_tag, position: 0, block: Bytes::new(), block_start: 0, next: None })
+    }
+
+    /// A block of the object, only if it's still the version with `e_tag`: blocks of
+    /// an object replaced mid-read would splice two exports together
+    fn fetch(&self, start: u64, len: u64, e_tag: Option<String>) -> Receiver<Result<Bytes>> {
+        let (store, path) = (self.store.clone(), self.path.clone());
+        request(async move {
+            let options = GetOptions { if_match: e_tag, range: Some((start..(start + BLOCK_BYTES).min(len)).into()), ..GetOptions::default() };
+            match store.get_opts(&path, options).await {
+                Ok(result) => Ok(result.bytes().await?),
+                Err(object_store::Error::Precondition { .. }) => Err(anyhow::anyhow!(
+                    "{} was replaced while it was being read; run again to read the new version", path)),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+}
+
+/// The URLs of the objects directly under `dir_url` (ending in `/`)
+pub fn list_objects(dir_url: &str) -> Result<Vec<String>> {
+    let object = RemoteObject::new(dir_url)?;
+    let prefix = object.path.clone();
+    let store = object.store.clone();
+    let objects = wait(request(async move { Ok(store.list(Some(&prefix)).try_collect::<Vec<_>>().await?) }))
+        .with_context(|| format!("Failed to list {}", dir_url))?;
+    let dir = object.path.as_ref();
+    Ok(objects.into_iter()
+        .filter_map(|meta| {
+            let name = meta.location.as_ref().strip_prefix(dir)?.trim_start_matches('/').to_string();
+            (!name.is_empty() && !name.contains('/')).then(|| format!("{}{}", dir_url, name))
+        })
+        .collect())
+}
+
+/// An object read as a file: blocks of BLOCK_BYTES fetched as reading reaches them
+pub struct RemoteFile {
+    object: RemoteObject,
+    len: u64,
+    /// Version the blocks are read from
+    e_tag: Option<String>,
+    position: u64,
+    block: Bytes,
+    block_start: u64,
+    /// The block after `block`, on its way
+    next: Option<(u64, Receiver<Result<Bytes>>)>,
+}
+
+impl RemoteFile {
+    fn load_block(&mut self) -> Result<()> {
+        let block = match self.next.take() {
+            Some((start, receiver)) if start == self.position => wait(receiver)?,
+            _ => wait(self.object.fetch(self.position, self.len, self.e_tag.clone()))?,
+        };
+        self.block_start = self.position;
+        self.block = block;
+        let next = self.block_start + self.block.len() as u64;
+        if next < self.len && !self.block.is_empty() {
+            self.next = Some((next, self.object.fetch(next, self.len, self.e_tag.clone())));
+        }
+        Ok(())
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.len {
+            return Ok(0);
+        }
+        let block_end = self.block_start + self.block.len() as u64;
+        if self.position < self.block_start || self.position >= block_end {
+            self.load_block().map_err(std::io::Error::other)?;
+        }
+        let offset = (self.position - self.block_start) as usize;
+        let available = &self.block[offset..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the object"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_remote_file_reads_and_seeks_across_blocks() {
+        let content: Vec<u8> = (0..BLOCK_BYTES * 2 + 100).map(|n| (n % 251) as u8).collect();
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let path = ObjectPath::from("exports/export.csv");
+        let (put_store, put_path, payload) = (store.clone(), path.clone(), content.clone());
+        wait(request(async move { Ok(put_store.put(&put_path, payload.into()).await?) })).unwrap();
+
+        let object = RemoteObject { store: store.clone(), path: path.clone() };
+        let mut file = object.open().unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert!(read == content);
+
+        file.seek(SeekFrom::Start(BLOCK_BYTES - 10)).unwrap();
+        let mut buffer = [0u8; 20];
+        file.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &content[BLOCK_BYTES as usize - 10..BLOCK_BYTES as usize + 10]);
+
+        // Replaced while read: blocks fetched from then on aren't taken from the new object
+        let (put_store, put_path) = (store.clone(), path.clone());
+        wait(request(async move { Ok(put_store.put(&put_path, Bytes::from_static(b"token_id\n").into()).await?) })).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let error = file.read(&mut buffer).unwrap_err().to_string();
+        assert!(error.contains("replaced"), "{}", error);
+
+        assert!(is_remote("s3://bucket/exports/*.csv.gz"));
+        assert!(!is_remote("https://example.com/export.csv"));
+        assert_eq!(parse_options("aws_region=eu-west-1, aws_endpoint=http://minio:9000").unwrap(),
+                   vec![("aws_region", "eu-west-1"), ("aws_endpoint", "http://minio:9000")]);
+        assert!(parse_options("aws_region").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
+
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+
+/// Count request body bytes sent to Elasticsearch
+pub fn record_bytes_sent(bytes: usize) {
+    BYTES_SENT.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Process resource usage, for right-sizing migration pods
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    pub peak_rss_bytes: Option<u64>,
+    pub cpu_time: Option<Duration>,
+    pub bytes_sent: u64,
+}
+
+impl ResourceUsage {
+    pub fn current() -> Self {
+        let (peak_rss_bytes, cpu_time) = rusage();
+        Self {
+            peak_rss_bytes,
+            cpu_time,
+            bytes_sent: BYTES_SENT.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn print(&self) {
+        if let Some(rss) = self.peak_rss_bytes {
+            info!("   Peak RSS: {:.1} MB", rss as f64 / 1_048_576.0);
+        }
+        if let Some(cpu) = self.cpu_time {
+            info!("   CPU time: {:.2}s", cpu.as_secs_f64());
+        }
+        info!("   Bytes sent: {:.1} MB", self.bytes_sent as f64 / 1_048_576.0);
+    }
+}
+
+#[cfg(unix)]
+fn rusage() -> (Option<u64>, Option<Duration>) {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes into the provided struct
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return (None, None);
+    }
+    // SAFETY: getrusage returned success, so the struct is initialized
+    let usage = unsafe { usage.assume_init() };
+
+    // ru_maxrss is KiB on Linux but bytes on macOS
+    let max_rss = usage.ru_maxrss as u64;
+    let peak_rss = if cfg!(target_os = "macos") { max_rss } else { max_rss * 1024 };
+
+    let timeval = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    let cpu_time = timeval(usage.ru_utime) + timeval(usage.ru_stime);
+
+    (Some(peak_rss), Some(cpu_time))
+}
+
+#[cfg(not(unix))]
+fn rusage() -> (Option<u64>, Option<Duration>) {
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_is_reported() {
+        record_bytes_sent(128);
+        let usage = ResourceUsage::current();
+
+        assert!(usage.bytes_sent >= 128);
+        if cfg!(unix) {
+            assert!(usage.peak_rss_bytes.unwrap() > 0);
+        }
+    }
+}
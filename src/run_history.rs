@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::alias::write_index_entry;
+use crate::deprecations::SendNotingWarnings;
+
+/// Key under the target index's mapping `_meta` that records the last completed run
+const META_KEY: &str = "migrator_last_run";
+
+/// A run that indexed a whole CSV, kept in the index it wrote to
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CompletedRun {
+    pub csv_file: String,
+    pub total_records: usize,
+    /// Unix seconds
+    pub completed_at: u64,
+}
+
+impl CompletedRun {
+    pub fn new(csv_file: &str, total_records: usize) -> Self {
+        let completed_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self { csv_file: csv_file.to_string(), total_records, completed_at }
+    }
+}
+
+/// Documents in the index, None when it doesn't exist
+pub async fn document_count(client: &Client, elasticsearch_url: &str, index: &str) -> Result<Option<u64>> {
+    let url = format!("{}/{}/_count", elasticsearch_url, index);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to count documents")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to count documents in {}: HTTP {}", index, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse count response")?;
+    Ok(body["count"].as_u64())
+}
+
+async fn fetch_meta(client: &Client, elasticsearch_url: &str, index: &str) -> Result<Value> {
+    let url = format!("{}/{}/_mapping", elasticsearch_url, index);
+    let mapping: Value = client.get(&url).send_noting_warnings().await.context("Failed to fetch index mapping")?
+        .json().await.context("Failed to parse index mapping")?;
+    // Keyed by concrete index; an alias may have several
+    Ok(write_index_entry(&mapping)
+        .map(|index| index["mappings"]["_meta"].clone())
+        .unwrap_or(Value::Null))
+}
+
+fn parse_last_run(meta: &Value) -> Option<CompletedRun> {
+    serde_json::from_value(meta.get(META_KEY)?.clone()).ok()
+}
+
+/// The last completed run recorded in the index, if any
+pub async fn last_run(client: &Client, elasticsearch_url: &str, index: &str) -> Result<Option<CompletedRun>> {
+    Ok(parse_last_run(&fetch_meta(client, elasticsearch_url, index).await?))
+}
+
+/// Record a completed run in the index's `_meta`, keeping any other keys there
+pub async fn record_run(client: &Client, elasticsearch_url: &str, index: &str, run: &CompletedRun) -> Result<()> {
+    let mut meta = match fetch_meta(client, elasticsearch_url, index).await? {
+        Value::Object(meta) => meta,
+        _ => Default::default(),
+    };
+    meta.insert(META_KEY.to_string(), serde_json::to_value(run)?);
+
+    let url = format!("{}/{}/_mapping", elasticsearch_url, index);
+    let response = client.put(&url).json(&json!({"_meta": meta})).send_noting_warnings().await
+        .context("Failed to record run in index mapping")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to record run in {}: HTTP {}", index, response.status());
+    }
+    Ok(())
+}
+
+/// Why a fresh run shouldn't write into the index, None when it is empty or missing
+pub fn existing_data_error(index: &str, count: Option<u64>, last_run: Option<&CompletedRun>, csv_file: &str) -> Option<String> {
+    let count = count.filter(|&count| count > 0)?;
+    let mut message = format!("Index {} already holds {} documents", index, count);
+    match last_run {
+        Some(run) if run.csv_file == csv_file => message.push_str(&format!(
+            ", including a completed run of this CSV ({} records, finished at unix time {})", run.total_records, run.completed_at)),
+        Some(run) => message.push_str(&format!(", last completed run was {}", run.csv_file)),
+        None => {}
+    }
+    message.push_str(". Pass --allow-existing (or ALLOW_EXISTING=true) to index into it anyway, or --ids-file to update specific tokens");
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_run_read_from_meta() {
+        let run = CompletedRun { csv_file: "nfts.csv".to_string(), total_records: 10, completed_at: 1_700_000_000 };
+        let meta = json!({"owner": "team-a", META_KEY: run});
+        assert_eq!(parse_last_run(&meta), Some(run));
+        assert_eq!(parse_last_run(&json!({"owner": "team-a"})), None);
+        assert_eq!(parse_last_run(&Value::Null), None);
+    }
+
+    #[test]
+    fn test_only_non_empty_index_blocks_fresh_run() {
+        assert_eq!(existing_data_error("nfts", None, None, "nfts.csv"), None);
+        assert_eq!(existing_data_error("nfts", Some(0), None, "nfts.csv"), None);
+
+        let run = CompletedRun { csv_file: "nfts.csv".to_string(), total_records: 10, completed_at: 1 };
+        let error = existing_data_error("nfts", Some(10), Some(&run), "nfts.csv").unwrap();
+        assert!(error.contains("completed run of this CSV"), "{}", error);
+        let error = existing_data_error("nfts", Some(10), Some(&run), "other.csv").unwrap();
+        assert!(error.contains("last completed run was nfts.csv"), "{}", error);
+        assert!(existing_data_error("nfts", Some(3), None, "nfts.csv").unwrap().contains("--allow-existing"));
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use csv::WriterBuilder;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::elasticsearch::{BulkDocument, BulkItemFailure};
+use crate::source::open_source;
+use crate::paths::state_file;
+use crate::pseudonymize::{FieldHasher, HASHABLE_FIELDS};
+
+/// Wallet addresses are personal data; they're replaced by a keyed hash in bundles
+const REDACTED_FIELDS: &[&str] = HASHABLE_FIELDS;
+
+/// Address fields of the grouped orders history
+const REDACTED_ORDER_FIELDS: &[&str] = &["maker", "matcher"];
+
+/// Bundles written per run, so a systematically broken export doesn't flood the disk
+const MAX_BUNDLES: usize = 5;
+
+/// Item errors that point at the data rather than the cluster
+fn is_data_error(error_type: &str) -> bool {
+    error_type.ends_with("parsing_exception")
+        || error_type == "strict_dynamic_mapping_exception"
+        || error_type == "illegal_argument_exception"
+}
+
+/// A key drawn for the run: addresses keep one pseudonym across a run's bundles,
+/// but a bundle can't be checked against a list of known addresses
+fn run_redactor() -> FieldHasher {
+    let key: Vec<u8> = (0..4)
+        .flat_map(|_| std::collections::hash_map::RandomState::new().build_hasher().finish().to_le_bytes())
+        .collect();
+    FieldHasher::new(&key, &REDACTED_FIELDS.join(",")).expect("a 32-byte key and hashable fields")
+}
+
+fn redact(redactor: &FieldHasher, value: &str) -> String {
+    format!("redacted:{}", &redactor.pseudonym(value)[2..])
+}
+
+fn redact_fields(redactor: &FieldHasher, doc: &mut Value, fields: &[&str]) {
+    if let Some(object) = doc.as_object_mut() {
+        for field in fields {
+            if let Some(Value::String(value)) = object.get_mut(*field) {
+                *value = redact(redactor, value);
+            }
+        }
+    }
+}
+
+fn redact_document(redactor: &FieldHasher, doc: &mut Value) {
+    redact_fields(redactor, doc, REDACTED_FIELDS);
+    if let Some(orders) = doc.get_mut("orders").and_then(Value::as_array_mut) {
+        for order in orders {
+            redact_fields(redactor, order, REDACTED_ORDER_FIELDS);
+        }
+    }
+}
+
+/// Saves redacted samples of documents rejected with mapping/parsing errors
+/// (`--capture-sample-on-error`), for support tickets and bug reports
+pub struct SampleCapture {
+    csv_file: String,
+    dir: PathBuf,
+    bundles: AtomicUsize,
+    redactor: Arc<FieldHasher>,
+}
+
+impl SampleCapture {
+    pub fn new(csv_file: &str) -> Self {
+        Self { csv_file: csv_file.to_string(), dir: state_file(csv_file, "samples"), bundles: AtomicUsize::new(0), redactor: Arc::new(run_redactor()) }
+    }
+
+    /// Write a bundle for the batch's data errors; returns its directory if one was written
+    pub async fn capture<D: BulkDocument>(&self, batch_num: usize, documents: &[D], failures: &[BulkItemFailure]) -> Result<Option<PathBuf>> {
+        let failures: Vec<&BulkItemFailure> = failures.iter().filter(|f| is_data_error(&f.error.error_type)).collect();
+        if failures.is_empty() || self.bundles.fetch_add(1, Ordering::Relaxed) >= MAX_BUNDLES {
+            return Ok(None);
+        }
+
+        let failed_ids: HashSet<&str> = failures.iter().filter_map(|f| f.id.as_deref()).collect();
+        let mut bulk_lines = String::new();
+        let mut source_rows = BTreeSet::new();
+        for doc in documents {
+            let Some(id) = doc.document_id().filter(|id| failed_ids.contains(id)) else {
+                continue;
+            };
+            let mut value = serde_json::to_value(doc)?;
+            if let Some(row) = value["source_row"].as_u64() {
+                source_rows.insert(row);
+            }
+            redact_document(&self.redactor, &mut value);
+            bulk_lines.push_str(&json!({"index": {"_id": id}}).to_string());
+            bulk_lines.push('\n');
+            bulk_lines.push_str(&value.to_string());
+            bulk_lines.push('\n');
+        }
+
+        let errors: Vec<Value> = failures.iter()
+            .map(|f| json!({"_id": f.id, "status": f.status, "type": f.error.error_type, "reason": f.error.reason}))
+            .collect();
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let bundle = self.dir.join(format!("{}-batch{}", timestamp, batch_num));
+        tokio::fs::create_dir_all(&bundle).await
+            .with_context(|| format!("Failed to create sample bundle {}", bundle.display()))?;
+        tokio::fs::write(bundle.join("bulk.ndjson"), bulk_lines).await?;
+        tokio::fs::write(bundle.join("errors.json"), serde_json::to_string_pretty(&errors)?).await?;
+
+        let csv_file = self.csv_file.clone();
+        let redactor = self.redactor.clone();
+        let rows = tokio::task::spawn_blocking(move || extract_rows(&redactor, &csv_file, &source_rows)).await??;
+        tokio::fs::write(bundle.join("rows.csv"), rows).await?;
+
+        Ok(Some(bundle))
+    }
+}
+
+/// The header plus the CSV records starting on the given lines, with wallet columns redacted
+fn extract_rows(redactor: &FieldHasher, csv_file: &str, lines: &BTreeSet<u64>) -> Result<Vec<u8>> {
+    let (mut source, _) = open_source(csv_file)?;
+    let headers = source.headers().clone();
+    let redacted_columns: Vec<usize> = headers.iter()
+        .enumerate()
+        .filter(|(_, name)| REDACTED_FIELDS.contains(&name.trim()))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut writer = WriterBuilder::new().from_writer(Vec::new());
+    writer.write_record(&headers)?;
+    for result in source.rows() {
+        let row = result?;
+        let line = row.position().map_or(0, |p| p.line());
+        if !lines.contains(&line) {
+            continue;
+        }
+        let redacted: Vec<String> = row.iter()
+            .enumerate()
+            .map(|(i, value)| if redacted_columns.contains(&i) && !value.is_empty() { redact(redactor, value) } else { value.to_string() })
+            .collect();
+        writer.write_record(&redacted)?;
+    }
+    writer.into_inner().context("Failed to write sample rows")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wallets_redacted_consistently() {
+        let redactor = run_redactor();
+        let mut doc = json!({
+            "token_id": "1", "owner": "0xABcd", "maker": "0xabcd", "price": 1.5,
+            "orders": [{"order_id": "7", "maker": "0xabcd", "matcher": "0xef"}],
+        });
+        redact_document(&redactor, &mut doc);
+
+        assert_eq!(doc["owner"], doc["maker"]);
+        assert_eq!(doc["orders"][0]["maker"], doc["maker"]);
+        assert!(doc["owner"].as_str().unwrap().starts_with("redacted:"));
+        assert!(doc["orders"][0]["matcher"].as_str().unwrap().starts_with("redacted:"));
+        assert_eq!(doc["orders"][0]["order_id"], "7");
+        assert_eq!(doc["price"], 1.5);
+        // Another run's key gives other pseudonyms
+        assert_ne!(redact(&run_redactor(), "0xabcd"), doc["owner"]);
+    }
+
+    #[test]
+    fn test_only_data_errors_captured() {
+        assert!(is_data_error("mapper_parsing_exception"));
+        assert!(is_data_error("document_parsing_exception"));
+        assert!(!is_data_error("es_rejected_execution_exception"));
+    }
+
+    #[test]
+    fn test_extract_rows_by_line() {
+        let path = std::env::temp_dir().join(format!("samples-{}.csv", std::process::id()));
+        std::fs::write(&path, "token_id,owner,price\n1,0xab,1\n2,\"0xcd\",bad\n3,,3\n").unwrap();
+
+        let rows = extract_rows(&run_redactor(), path.to_str().unwrap(), &BTreeSet::from([3, 4])).unwrap();
+        let rows = String::from_utf8(rows).unwrap();
+        let lines: Vec<&str> = rows.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "token_id,owner,price");
+        assert!(lines[1].starts_with("2,redacted:"));
+        assert_eq!(lines[2], "3,,3");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use csv::StringRecord;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// Column names CsvRecord deserializes from, in export order
+pub const EXPECTED_COLUMNS: &[&str] = &[
+    "token_address", "token_id", "owner", "base_price", "ended_at", "ended_price",
+    "expired_at", "kind", "maker", "matcher", "order_id", "payment_token", "price",
+    "started_at", "state", "name", "attributes", "image", "video", "metadata_last_updated",
+    "cdn_image", "animation_url", "description", "is_shown", "ownership_block_number",
+    "ownership_log_index", "raw_metadata", "order_status", "ron_price",
+];
+
+/// Columns CsvRecord reads when present; their absence is not reported
+pub const OPTIONAL_COLUMNS: &[&str] = &["deleted", "operation"];
+
+/// Difference between the CSV header row and the columns CsvRecord expects
+#[derive(Debug, Default, PartialEq)]
+pub struct HeaderReport {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub renamed: Vec<(String, String)>, // (column in CSV, expected column it probably is)
+}
+
+impl HeaderReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.renamed.is_empty()
+    }
+
+    pub fn print(&self) {
+        for (found, expected) in &self.renamed {
+            warn!("⚠️  Column '{}' looks like renamed '{}'", found, expected);
+        }
+        for column in &self.missing {
+            warn!("⚠️  Missing column '{}' (will be empty for every row)", column);
+        }
+        for column in &self.extra {
+            info!("ℹ️  Ignoring unknown column '{}'", column);
+        }
+        if !self.renamed.is_empty() {
+            let entries: Vec<String> = self.renamed.iter()
+                .map(|(found, expected)| format!("{}={}", found, expected))
+                .collect();
+            info!("💡 Suggested config: COLUMN_MAPPING={}", entries.join(","));
+        }
+    }
+}
+
+/// Parse `COLUMN_MAPPING` entries of the form `csv_column=expected_column,...`
+pub fn parse_column_mapping(spec: &str) -> Result<HashMap<String, String>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (from, to) = entry.split_once('=')
+                .with_context(|| format!("Invalid column mapping entry '{}', expected from=to", entry))?;
+            Ok((from.trim().to_string(), to.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Rename CSV header columns according to the column mapping
+pub fn apply_column_mapping(headers: &StringRecord, mapping: &HashMap<String, String>) -> StringRecord {
+    headers.iter()
+        .map(|column| mapping.get(column).map(String::as_str).unwrap_or(column))
+        .collect()
+}
+
+/// Compare the header row against EXPECTED_COLUMNS
+pub fn validate_headers(headers: &StringRecord) -> HeaderReport {
+    let present: Vec<&str> = headers.iter().collect();
+    let mut missing: Vec<String> = EXPECTED_COLUMNS.iter()
+        .filter(|expected| !present.contains(expected))
+        .map(|expected| expected.to_string())
+        .collect();
+    let mut extra: Vec<String> = present.iter()
+        .filter(|column| !EXPECTED_COLUMNS.contains(column) && !OPTIONAL_COLUMNS.contains(column))
+        .map(|column| column.to_string())
+        .collect();
+
+    // Pair extra columns with missing ones that only differ in case/separators
+    let mut renamed = Vec::new();
+    extra.retain(|column| {
+        match missing.iter().position(|expected| normalize(expected) == normalize(column)) {
+            Some(pos) => {
+                renamed.push((column.clone(), missing.remove(pos)));
+                false
+            }
+            None => true,
+        }
+    });
+
+    HeaderReport { missing, extra, renamed }
+}
+
+/// Strip byte order marks, zero-width characters, stray carriage returns and
+/// surrounding whitespace from header names. Returns the repaired header and the
+/// names that were changed as (original, repaired).
+pub fn repair_headers(headers: &StringRecord) -> (StringRecord, Vec<(String, String)>) {
+    let mut repairs = Vec::new();
+    let repaired = headers.iter()
+        .map(|column| {
+            let clean: String = column
+                .chars()
+                .filter(|c| !matches!(c, '\u{feff}' | '\u{200b}' | '\r'))
+                .collect::<String>()
+                .trim()
+                .to_string();
+            if clean != column {
+                repairs.push((column.to_string(), clean.clone()));
+            }
+            clean
+        })
+        .collect();
+    (repaired, repairs)
+}
+
+/// `tokenAddress`, `Token-Address` and `token_address` all normalize to `tokenaddress`
+fn normalize(column: &str) -> String {
+    column.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expected_headers_are_clean() {
+        let headers: StringRecord = EXPECTED_COLUMNS.iter().copied().collect();
+        assert_eq!(validate_headers(&headers), HeaderReport::default());
+
+        let tombstones: StringRecord = EXPECTED_COLUMNS.iter().chain(OPTIONAL_COLUMNS).copied().collect();
+        assert_eq!(validate_headers(&tombstones), HeaderReport::default());
+    }
+
+    #[test]
+    fn test_detects_missing_extra_and_renamed() {
+        let mut columns: Vec<&str> = EXPECTED_COLUMNS.iter().copied()
+            .filter(|c| *c != "token_id" && *c != "ron_price")
+            .collect();
+        columns.push("tokenId");
+        columns.push("collection_slug");
+        let headers: StringRecord = columns.into_iter().collect();
+
+        let report = validate_headers(&headers);
+        assert_eq!(report.missing, vec!["ron_price".to_string()]);
+        assert_eq!(report.extra, vec!["collection_slug".to_string()]);
+        assert_eq!(report.renamed, vec![("tokenId".to_string(), "token_id".to_string())]);
+    }
+
+    #[test]
+    fn test_column_mapping_renames_headers() {
+        let mapping = parse_column_mapping("tokenId=token_id, Owner = owner").unwrap();
+        let headers = StringRecord::from(vec!["tokenId", "Owner", "price"]);
+
+        let mapped = apply_column_mapping(&headers, &mapping);
+        assert_eq!(mapped, StringRecord::from(vec!["token_id", "owner", "price"]));
+        assert!(parse_column_mapping("token_id").is_err());
+    }
+
+    #[test]
+    fn test_repair_headers_strips_invisible_characters() {
+        let headers = StringRecord::from(vec!["\u{feff}token_address", "token_id", " owner\r"]);
+
+        let (repaired, repairs) = repair_headers(&headers);
+        assert_eq!(repaired, StringRecord::from(vec!["token_address", "token_id", "owner"]));
+        assert_eq!(repairs.len(), 2);
+        assert_eq!(repairs[0].1, "token_address");
+    }
+}
@@ -0,0 +1,302 @@
+//! First pass of a migration: decide which rows this run indexes. Only their
+//! indices and what the whole-file checks need are kept; the documents are built
+//! again while streaming.
+
+use anyhow::Result;
+use csv::StringRecord;
+use roaring::RoaringTreemap;
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+
+use crate::checkpoint::{record_key, CheckpointMode, MigrationCheckpoint};
+use crate::elasticsearch::{token_document_id, BulkDocument};
+use crate::field_limit::DynamicFields;
+use crate::filter::RowFilter;
+use crate::ids::IdSelection;
+use crate::input::RowOffset;
+use crate::key_set::KeySet;
+use crate::models_flexible::{CsvRecord, FlexibleElasticsearchDocument};
+use crate::ownership::DuplicateIndex;
+use crate::source::RecordSource;
+use crate::sorted::{SortViolation, SortedInputCheck};
+
+/// What decides the selection besides the checkpoint
+pub struct SelectionRules<'a> {
+    pub headers: &'a StringRecord,
+    /// --ids-file: only the listed tokens are part of the run
+    pub id_selection: Option<&'a mut IdSelection>,
+    pub row_filter: Option<&'a RowFilter>,
+    pub priority_filter: Option<&'a RowFilter>,
+    pub sorted_check: Option<SortedInputCheck>,
+    pub sorted_violation: SortViolation,
+    pub group_orders: bool,
+    /// Keeps the latest ownership of duplicate token rows (DUPLICATE_RESOLUTION=ownership)
+    pub duplicates: Option<DuplicateIndex>,
+    /// ORDERS_HISTORY_INDEX is set
+    pub orders_history: bool,
+    pub build_document: fn(CsvRecord) -> FlexibleElasticsearchDocument,
+}
+
+/// The rows the first pass selected, and how the others were accounted for
+#[derive(Default)]
+pub struct Selection {
+    pub selected: RoaringTreemap,
+    /// Rows whose token document was written by an earlier run but whose orders history wasn't
+    pub history_only: RoaringTreemap,
+    /// Rows matching PRIORITY_FILTER, when set
+    pub priority_rows: Option<RoaringTreemap>,
+    pub duplicates: Option<DuplicateIndex>,
+    /// Still set if the file turned out sorted
+    pub sorted_check: Option<SortedInputCheck>,
+    pub dynamic_fields: BTreeSet<String>,
+    /// Lowercase token addresses of the selected rows
+    pub collections: BTreeSet<String>,
+    /// Rows in the file
+    pub total_records: usize,
+    pub filtered_rows: usize,
+    pub unselected_rows: usize,
+    pub done_rows: usize,
+}
+
+impl Selection {
+    pub fn remaining_records(&self) -> usize {
+        self.selected.len() as usize
+    }
+
+    pub fn duplicate_rows(&self) -> usize {
+        self.duplicates.as_ref().map_or(0, DuplicateIndex::superseded)
+    }
+
+    /// Rows an earlier run already indexed
+    pub fn already_done(&self) -> usize {
+        self.total_records - self.remaining_records() - self.filtered_rows - self.unselected_rows - self.duplicate_rows()
+    }
+}
+
+/// Read `source` once and select the rows to index: not done by an earlier run
+/// (by position in index mode, by document id in key mode), listed in --ids-file
+/// and passing FILTER. Sets the checkpoint's totals for the file.
+pub fn select_rows(mut source: Box<dyn RecordSource>, checkpoint: &mut MigrationCheckpoint, rules: SelectionRules) -> Result<Selection> {
+    let SelectionRules { headers, mut id_selection, row_filter, priority_filter, sorted_check, sorted_violation,
+                         group_orders, duplicates, orders_history, build_document } = rules;
+    let mut selection = Selection {
+        priority_rows: priority_filter.map(|_| RoaringTreemap::new()),
+        duplicates,
+        sorted_check,
+        ..Selection::default()
+    };
+    let token_id_column = headers.iter().position(|h| h == "token_id");
+    let mut record_index = 0;
+    // Rows a later duplicate with a newer ownership replaced
+    let mut superseded_rows = Vec::new();
+    // Key mode: the document ids of the rows to write, done or not
+    let mut key_mode_ids = KeySet::new();
+    let resume_point = checkpoint.get_safe_resume_point();
+    // Sortedness and the latest ownership of a token are properties of the whole file,
+    // so checked or deduplicated runs read every row
+    let seekable = selection.sorted_check.is_none() && selection.duplicates.is_none();
+    if let Some(offset) = checkpoint.row_offset_before(resume_point).filter(|_| seekable) {
+        source.seek_to_row(&offset)?;
+        record_index = offset.index;
+        selection.done_rows = offset.index;
+        info!("⏩ Starting at row {} (byte {}) without reading the rows before it", offset.index, offset.byte);
+    }
+
+    for result in source.rows() {
+        let row = result?;
+        checkpoint.note_row_offset(record_index, row.position());
+        if let Some(position) = row.position().filter(|_| record_index == resume_point) {
+            checkpoint.note_boundary_offset(RowOffset::new(record_index, position));
+        }
+
+        // Every row is checked, including skipped ones: sortedness is a property of the file
+        if let Some(check) = &mut selection.sorted_check {
+            if !check.check(&row) {
+                let violation = check.violation().unwrap_or_default().to_string();
+                match sorted_violation {
+                    SortViolation::Abort => return Err(anyhow::anyhow!(
+                        "{} (fix the export, or set SORTED_VIOLATION=fallback)", violation)),
+                    SortViolation::Fallback if group_orders => return Err(anyhow::anyhow!(
+                        "{} (GROUP_ORDERS can't fall back to unsorted processing; fix the export)", violation)),
+                    SortViolation::Fallback => {
+                        warn!("⚠️  {}, falling back to unsorted processing", violation);
+                        selection.sorted_check = None;
+                    }
+                }
+            }
+        }
+
+        // Skip records that were already safely processed
+        if record_index < resume_point || checkpoint.is_index_completed(record_index) {
+            // Written rows still rank against their unfinished duplicates
+            if let Some(duplicates) = &mut selection.duplicates {
+                let doc = build_document(row.deserialize(Some(headers))?);
+                superseded_rows.extend(duplicates.add_written(record_index, &doc));
+            }
+            selection.done_rows += 1;
+            record_index += 1;
+            continue;
+        }
+
+        // With --ids-file only the listed tokens are read; other rows are not part of this run
+        if let Some(ids) = &mut id_selection {
+            let token_id = token_id_column.and_then(|column| row.get(column)).unwrap_or("");
+            if !ids.select(token_id) {
+                selection.unselected_rows += 1;
+                record_index += 1;
+                continue;
+            }
+        }
+
+        // Rows the filter rejects are never transformed, but count as handled
+        if row_filter.is_some_and(|filter| !filter.matches(&row)) {
+            checkpoint.add_filtered(record_index);
+            selection.filtered_rows += 1;
+            record_index += 1;
+            continue;
+        }
+
+        let record: CsvRecord = row.deserialize(Some(headers))?;
+
+        // In key mode, skip rows whose id was indexed regardless of position
+        if let Some(doc_id) = token_document_id(record.token_address.as_deref(), record.token_id.as_deref()) {
+            if checkpoint.mode == CheckpointMode::Key {
+                key_mode_ids.insert(record_key(&doc_id));
+            }
+            if checkpoint.is_key_completed(&doc_id) {
+                selection.done_rows += 1;
+                record_index += 1;
+                continue;
+            }
+        }
+
+        let doc = build_document(record);
+        doc.dynamic_field_paths(&mut selection.dynamic_fields);
+        if let Some(address) = &doc.token_address {
+            selection.collections.insert(address.to_lowercase());
+        }
+        selection.selected.insert(record_index as u64);
+        if orders_history && checkpoint.is_history_pending(record_index, doc.document_id()) {
+            selection.history_only.insert(record_index as u64);
+        }
+        if let (Some(filter), Some(priority_rows)) = (priority_filter, &mut selection.priority_rows) {
+            if filter.matches(&row) {
+                priority_rows.insert(record_index as u64);
+            }
+        }
+        // Duplicate token rows: keep the latest ownership instead of whichever batch lands last
+        superseded_rows.extend(selection.duplicates.as_mut().and_then(|duplicates| duplicates.add(record_index, &doc)));
+        record_index += 1;
+    }
+    for superseded in superseded_rows {
+        selection.selected.remove(superseded as u64);
+        selection.history_only.remove(superseded as u64);
+        if let Some(priority_rows) = &mut selection.priority_rows {
+            priority_rows.remove(superseded as u64);
+        }
+        checkpoint.add_filtered(superseded);
+    }
+    selection.total_records = record_index;
+
+    // Update checkpoint with total if it's new; key mode counts the document ids of
+    // this export, which a re-export may add to or drop from
+    if checkpoint.mode == CheckpointMode::Key {
+        checkpoint.set_current_keys(key_mode_ids);
+    } else if checkpoint.total_records == 0 {
+        checkpoint.total_records = match id_selection {
+            Some(_) => selection.remaining_records() + selection.filtered_rows + selection.duplicate_rows(),
+            None => selection.total_records,
+        };
+    }
+    Ok(selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{open_source_as, RecordFormat};
+
+    const ROWS: &str = "token_address,token_id,owner,ownership_block_number,state\n\
+        0xab,1,0x01,10,\n\
+        0xab,2,0x02,10,active\n\
+        0xab,3,0x03,10,\n\
+        0xab,2,0x04,20,\n\
+        0xcd,4,0x05,10,active\n";
+
+    fn headers() -> StringRecord {
+        StringRecord::from(vec!["token_address", "token_id", "owner", "ownership_block_number", "state"])
+    }
+
+    fn source(name: &str) -> Box<dyn RecordSource> {
+        let path = std::env::temp_dir().join(format!("selection-{}-{}.csv", name, std::process::id()));
+        std::fs::write(&path, ROWS).unwrap();
+        let (source, _) = open_source_as(&path.to_string_lossy(), RecordFormat::Csv).unwrap();
+        std::fs::remove_file(&path).ok();
+        source
+    }
+
+    fn rules(headers: &StringRecord) -> SelectionRules<'_> {
+        SelectionRules {
+            headers,
+            id_selection: None,
+            row_filter: None,
+            priority_filter: None,
+            sorted_check: None,
+            sorted_violation: SortViolation::Abort,
+            group_orders: false,
+            duplicates: None,
+            orders_history: false,
+            build_document: |record| FlexibleElasticsearchDocument::from_record(record, None),
+        }
+    }
+
+    #[test]
+    fn test_index_mode_skips_confirmed_rows() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 0, CheckpointMode::Index);
+        checkpoint.add_completed_batch([0, 1, 3], &[]);
+        let headers = headers();
+        let selection = select_rows(source("index"), &mut checkpoint, rules(&headers)).unwrap();
+        assert_eq!(selection.selected.iter().collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!((selection.total_records, selection.done_rows, selection.already_done()), (5, 3, 3));
+        assert_eq!(selection.collections, BTreeSet::from(["0xab".to_string(), "0xcd".to_string()]));
+        // A new checkpoint gets the file's total
+        assert_eq!(checkpoint.total_records, 5);
+
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 0, CheckpointMode::Index);
+        let filter = RowFilter::compile("state == 'active'", &headers).unwrap();
+        let selection = select_rows(source("filter"), &mut checkpoint, SelectionRules { row_filter: Some(&filter), ..rules(&headers) }).unwrap();
+        assert_eq!(selection.selected.iter().collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(selection.filtered_rows, 3);
+        assert_eq!(checkpoint.total_records, 5);
+    }
+
+    #[test]
+    fn test_key_mode_skips_indexed_ids() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 0, CheckpointMode::Key);
+        checkpoint.add_completed_batch(std::iter::empty(), &[record_key("0xab:2"), record_key("0xab:9")]);
+        let headers = headers();
+        let selection = select_rows(source("key"), &mut checkpoint, rules(&headers)).unwrap();
+        // Both rows of token 2 are done, wherever they are
+        assert_eq!(selection.selected.iter().collect::<Vec<_>>(), vec![0, 2, 4]);
+        assert_eq!(selection.already_done(), 2);
+        // Progress counts the ids of this export, not the stale one
+        assert_eq!((checkpoint.total_records, checkpoint.processed_records), (4, 1));
+    }
+
+    #[test]
+    fn test_ids_file_and_duplicates() {
+        let mut checkpoint = MigrationCheckpoint::new("test.csv".to_string(), 0, CheckpointMode::Index);
+        let headers = headers();
+        let mut ids = IdSelection::parse("2\n4\n7\n");
+        let selection = select_rows(source("ids"), &mut checkpoint, SelectionRules {
+            id_selection: Some(&mut ids),
+            duplicates: Some(DuplicateIndex::default()),
+            ..rules(&headers)
+        }).unwrap();
+        // The later row of token 2 has the newer ownership
+        assert_eq!(selection.selected.iter().collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!((selection.unselected_rows, selection.duplicate_rows(), selection.already_done()), (2, 1, 0));
+        assert_eq!(checkpoint.total_records, 3);
+        assert_eq!(ids.missing(), vec!["7"]);
+    }
+}
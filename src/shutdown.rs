@@ -0,0 +1,70 @@
+//! Ending the process on ctrl+c (or a full spool) once every run in progress has
+//! saved its checkpoint. Several files can be migrating at once, each with its own
+//! shutdown handler; the last handler to finish saving exits.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::outcome::EXIT_RESUMABLE;
+
+#[derive(Default)]
+struct Handlers {
+    registered: usize,
+    saved: usize,
+}
+
+static HANDLERS: Mutex<Handlers> = Mutex::new(Handlers { registered: 0, saved: 0 });
+
+/// A run's shutdown handler; dropped (or aborted with its task) when the run ends
+pub struct ShutdownHandler {
+    saved: bool,
+}
+
+impl ShutdownHandler {
+    pub fn register() -> Self {
+        HANDLERS.lock().unwrap().registered += 1;
+        Self { saved: false }
+    }
+
+    /// Note that this run's checkpoint is saved, and exit once every other
+    /// registered run has saved too
+    pub async fn saved_then_exit(mut self) -> ! {
+        self.saved = true;
+        HANDLERS.lock().unwrap().saved += 1;
+        loop {
+            if all_saved(&HANDLERS.lock().unwrap()) {
+                std::process::exit(EXIT_RESUMABLE.into());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Drop for ShutdownHandler {
+    fn drop(&mut self) {
+        let mut handlers = HANDLERS.lock().unwrap();
+        handlers.registered -= 1;
+        if self.saved {
+            handlers.saved -= 1;
+        }
+    }
+}
+
+fn all_saved(handlers: &Handlers) -> bool {
+    handlers.saved >= handlers.registered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waits_for_every_registered_run() {
+        let mut handlers = Handlers { registered: 2, ..Default::default() };
+        handlers.saved += 1;
+        assert!(!all_saved(&handlers));
+        // The other run ended on its own
+        handlers.registered -= 1;
+        assert!(all_saved(&handlers));
+    }
+}
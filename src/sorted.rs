@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use csv::StringRecord;
+use serde::Deserialize;
+use std::cmp::Ordering;
+
+/// What to do when a CSV declared sorted turns out not to be
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortViolation {
+    /// Stop before anything is written
+    #[default]
+    Abort,
+    /// Warn and continue with the code paths that don't rely on ordering
+    Fallback,
+}
+
+/// Streaming check of the SORTED_BY assumption: values of the column must never decrease
+#[derive(Debug)]
+pub struct SortedInputCheck {
+    column: String,
+    position: usize,
+    previous: Option<String>,
+    violation: Option<String>,
+}
+
+impl SortedInputCheck {
+    pub fn new(column: &str, headers: &StringRecord) -> Result<Self> {
+        let position = headers.iter().position(|h| h == column)
+            .with_context(|| format!("SORTED_BY column '{}' is not in the CSV header", column))?;
+        Ok(Self { column: column.to_string(), position, previous: None, violation: None })
+    }
+
+    pub fn column(&self) -> &str {
+        &self.column
+    }
+
+    /// Check the next row; returns false once the order was violated
+    pub fn check(&mut self, row: &StringRecord) -> bool {
+        if self.violation.is_some() {
+            return false;
+        }
+        let value = row.get(self.position).unwrap_or("").trim();
+        if let Some(previous) = &self.previous {
+            if compare_values(previous, value) == Ordering::Greater {
+                let line = row.position().map_or(0, |p| p.line());
+                self.violation = Some(format!(
+                    "CSV is not sorted by {}: '{}' follows '{}' on line {}", self.column, value, previous, line));
+                return false;
+            }
+        }
+        self.previous = Some(value.to_string());
+        true
+    }
+
+    /// Description of the first out-of-order row, if any
+    pub fn violation(&self) -> Option<&str> {
+        self.violation.as_deref()
+    }
+}
+
+/// Numbers compare numerically (token ids are unpadded), everything else as strings
+fn compare_values(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_column(values: &[&str]) -> SortedInputCheck {
+        let mut check = SortedInputCheck::new("token_id", &StringRecord::from(vec!["owner", "token_id"])).unwrap();
+        for value in values {
+            check.check(&StringRecord::from(vec!["0xab", value]));
+        }
+        check
+    }
+
+    #[test]
+    fn test_numeric_order_and_duplicates_accepted() {
+        assert!(check_column(&["2", "9", "9", "10", "100"]).violation().is_none());
+    }
+
+    #[test]
+    fn test_violation_reported_once() {
+        let check = check_column(&["1", "3", "2", "1"]);
+        assert!(check.violation().unwrap().contains("'2' follows '3'"));
+    }
+
+    #[test]
+    fn test_unknown_column_rejected() {
+        assert!(SortedInputCheck::new("order_id", &StringRecord::from(vec!["token_id"])).is_err());
+    }
+}
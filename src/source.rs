@@ -0,0 +1,479 @@
+//! Where the rows of CSV_FILE come from. It's a CSV by default; with
+//! INPUT_FORMAT=jsonl it's JSON Lines, one object per line keyed by the CSV column
+//! names, and with INPUT_FORMAT=parquet a Parquet file with those columns. Every
+//! source yields `StringRecord` rows under a header row, with typed values written
+//! the way the CSV export writes them, so filters, checks, resume offsets and
+//! `CsvRecord` parsing work the same for all of them.
+
+use anyhow::{Context, Result};
+use csv::{Position, ReaderBuilder, StringRecord};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::serialized_reader::ReadOptionsBuilder;
+use parquet::record::reader::RowIter;
+use parquet::record::Field;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::config::APP_CONFIG;
+use crate::input::{prepare_input, seek_to_row, GzipInput, InputFormat, RowOffset, UTF8_BOM};
+use crate::record::csv_text;
+use crate::remote::{is_remote, RemoteObject};
+use crate::schema::EXPECTED_COLUMNS;
+
+/// Objects read for the column list of a JSON Lines file
+const HEADER_SAMPLE_LINES: usize = 1000;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl RecordFormat {
+    /// Extension of the files a directory CSV_FILE is expanded to
+    pub fn extension(self) -> &'static str {
+        match self {
+            RecordFormat::Csv => "csv",
+            RecordFormat::Jsonl => "jsonl",
+            RecordFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// Rows of an input file, in order
+pub trait RecordSource {
+    /// Column names, for the CSV its header row
+    fn headers(&self) -> &StringRecord;
+    /// Name the columns differently (repaired headers, COLUMN_MAPPING)
+    fn set_headers(&mut self, headers: StringRecord);
+    /// Continue at the row `offset` points to, skipping the rows before it without reading them
+    fn seek_to_row(&mut self, offset: &RowOffset) -> Result<()>;
+    /// The next row, with its position set; None at the end of the input
+    fn next_row(&mut self) -> Option<Result<StringRecord>>;
+    /// Values read so far that no column holds, by key (JSON Lines keys the header sample missed)
+    fn dropped_keys(&self) -> BTreeMap<String, u64> {
+        BTreeMap::new()
+    }
+}
+
+impl dyn RecordSource {
+    pub fn rows(&mut self) -> impl Iterator<Item = Result<StringRecord>> + '_ {
+        std::iter::from_fn(move || self.next_row())
+    }
+}
+
+/// Open CSV_FILE (or one of the files it names) in the configured INPUT_FORMAT
+pub fn open_source(path: &str) -> Result<(Box<dyn RecordSource>, InputFormat)> {
+    open_source_as(path, APP_CONFIG.input_format)
+}
+
+pub fn open_source_as(path: &str, format: RecordFormat) -> Result<(Box<dyn RecordSource>, InputFormat)> {
+    if format == RecordFormat::Parquet {
+        if is_remote(path) {
+            anyhow::bail!("INPUT_FORMAT=parquet reads local files only; copy {} locally first", path);
+        }
+        return Ok((Box::new(ParquetSource::open(Path::new(path))?), InputFormat::default()));
+    }
+    let mut input = open_input(path)?;
+    if path.ends_with(".gz") {
+        input = Box::new(GzipInput::new(input));
+    }
+    let (input, input_format) = prepare_input(input)?;
+    let source: Box<dyn RecordSource> = match format {
+        RecordFormat::Jsonl => Box::new(JsonlSource::new(input, &input_format)?),
+        _ => Box::new(CsvSource::new(input, &input_format)?),
+    };
+    Ok((source, input_format))
+}
+
+/// Bytes of an input file, local or in an object store
+pub trait Input: Read + Seek {}
+
+impl<T: Read + Seek> Input for T {}
+
+/// The file at `path` as stored, compressed or not
+pub fn open_input(path: &str) -> Result<Box<dyn Input>> {
+    if is_remote(path) {
+        return Ok(Box::new(RemoteObject::new(path)?.open()?));
+    }
+    Ok(Box::new(File::open(path).with_context(|| format!("Failed to open {}", path))?))
+}
+
+/// Size and modification time (Unix seconds) of the file at `path`, as stored
+pub fn input_metadata(path: &str) -> Result<(u64, Option<u64>)> {
+    if is_remote(path) {
+        return RemoteObject::new(path)?.metadata();
+    }
+    let metadata = std::fs::metadata(path).with_context(|| format!("Failed to read metadata of {}", path))?;
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+struct CsvSource {
+    reader: csv::Reader<BufReader<Box<dyn Input>>>,
+    format: InputFormat,
+    headers: StringRecord,
+}
+
+impl CsvSource {
+    fn new(input: BufReader<Box<dyn Input>>, format: &InputFormat) -> Result<Self> {
+        let mut reader = ReaderBuilder::new().has_headers(true).from_reader(input);
+        let headers = reader.headers()?.clone();
+        Ok(Self { reader, format: format.clone(), headers })
+    }
+}
+
+impl RecordSource for CsvSource {
+    fn headers(&self) -> &StringRecord {
+        &self.headers
+    }
+
+    fn set_headers(&mut self, headers: StringRecord) {
+        self.reader.set_headers(headers.clone());
+        self.headers = headers;
+    }
+
+    fn seek_to_row(&mut self, offset: &RowOffset) -> Result<()> {
+        seek_to_row(&mut self.reader, &self.format, offset)
+    }
+
+    fn next_row(&mut self) -> Option<Result<StringRecord>> {
+        let mut row = StringRecord::new();
+        match self.reader.read_record(&mut row) {
+            Ok(true) => Some(Ok(row)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// JSON Lines: the columns are the keys found in the first HEADER_SAMPLE_LINES
+/// objects, expected ones first; keys an object lacks are empty cells. A key
+/// first seen later has no column: its values are counted and warned about
+struct JsonlSource {
+    reader: BufReader<Box<dyn Input>>,
+    boms: u64,
+    /// Keys looked up in each object, in column order; renaming the columns keeps them
+    keys: Vec<String>,
+    headers: StringRecord,
+    /// Position of the next line: byte after the byte order marks, line and record number
+    byte: u64,
+    line: u64,
+    record: u64,
+    dropped_keys: BTreeMap<String, u64>,
+}
+
+impl JsonlSource {
+    fn new(mut reader: BufReader<Box<dyn Input>>, format: &InputFormat) -> Result<Self> {
+        let boms = (format.boms_stripped * UTF8_BOM.len()) as u64;
+        let mut found = BTreeSet::new();
+        let mut line = String::new();
+        let mut objects = 0;
+        while objects < HEADER_SAMPLE_LINES && reader.read_line(&mut line)? > 0 {
+            if let Ok(Value::Object(object)) = serde_json::from_str::<Value>(&line) {
+                found.extend(object.into_iter().map(|(key, _)| key));
+                objects += 1;
+            }
+            line.clear();
+        }
+        reader.seek(SeekFrom::Start(boms))?;
+
+        let mut keys: Vec<String> = EXPECTED_COLUMNS.iter().filter(|column| found.remove(**column)).map(|column| column.to_string()).collect();
+        keys.extend(found);
+        let headers = StringRecord::from(keys.clone());
+        Ok(Self { reader, boms, keys, headers, byte: 0, line: 1, record: 0, dropped_keys: BTreeMap::new() })
+    }
+
+    fn row(&mut self, object: &Map<String, Value>) -> StringRecord {
+        for key in object.keys().filter(|key| !self.keys.contains(key)) {
+            let dropped = self.dropped_keys.entry(key.clone()).or_default();
+            if *dropped == 0 {
+                warn!("⚠️  Line {} has key {:?}, which the first {} objects didn't; its values are dropped", self.line, key, HEADER_SAMPLE_LINES);
+            }
+            *dropped += 1;
+        }
+        let mut row: StringRecord = self.keys.iter()
+            .map(|key| object.get(key).and_then(csv_text).unwrap_or_default())
+            .collect();
+        let mut position = Position::new();
+        position.set_byte(self.byte).set_line(self.line).set_record(self.record);
+        row.set_position(Some(position));
+        row
+    }
+}
+
+impl RecordSource for JsonlSource {
+    fn headers(&self) -> &StringRecord {
+        &self.headers
+    }
+
+    fn set_headers(&mut self, headers: StringRecord) {
+        self.headers = headers;
+    }
+
+    fn seek_to_row(&mut self, offset: &RowOffset) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(self.boms + offset.byte)).context("Failed to seek in the JSON Lines file")?;
+        self.byte = offset.byte;
+        self.line = offset.line;
+        self.record = offset.record;
+        Ok(())
+    }
+
+    fn next_row(&mut self) -> Option<Result<StringRecord>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if !line.trim().is_empty() {
+                let row = serde_json::from_str::<Value>(&line)
+                    .with_context(|| format!("Invalid JSON on line {}", self.line))
+                    .and_then(|value| match value {
+                        Value::Object(object) => Ok(self.row(&object)),
+                        _ => anyhow::bail!("Line {} is not a JSON object", self.line),
+                    });
+                self.byte += read as u64;
+                self.line += 1;
+                self.record += 1;
+                return Some(row);
+            }
+            self.byte += read as u64;
+            self.line += 1;
+        }
+    }
+
+    fn dropped_keys(&self) -> BTreeMap<String, u64> {
+        self.dropped_keys.clone()
+    }
+}
+
+/// Parquet: the columns of the file's schema. Timestamps and dates become Unix
+/// seconds like the CSV's time columns, nested values JSON. Positions count rows
+/// (`byte` and `record` are the row index), and seeking skips whole row groups.
+struct ParquetSource {
+    path: PathBuf,
+    headers: StringRecord,
+    rows: RowIter<'static>,
+    /// Index of the next row
+    index: u64,
+}
+
+impl ParquetSource {
+    fn open(path: &Path) -> Result<Self> {
+        let reader = Self::reader(path, 0)?;
+        let headers = reader.metadata().file_metadata().schema_descr().root_schema().get_fields().iter()
+            .map(|field| field.name().to_string())
+            .collect();
+        Ok(Self { path: path.to_path_buf(), headers, rows: RowIter::from_file_into(Box::new(reader)), index: 0 })
+    }
+
+    /// A reader of the row groups from `first_group` on
+    fn reader(path: &Path, first_group: usize) -> Result<SerializedFileReader<File>> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let options = ReadOptionsBuilder::new().with_predicate(Box::new(move |_, group| group >= first_group)).build();
+        SerializedFileReader::new_with_options(file, options).with_context(|| format!("Failed to read Parquet file {}", path.display()))
+    }
+
+    /// A value as the CSV export writes it, from its Parquet type; going through JSON
+    /// would base64 byte arrays and widen 32-bit floats (0.1 -> 0.10000000149011612)
+    fn column_text(field: &Field) -> String {
+        match field {
+            Field::Null => String::new(),
+            Field::Bool(value) => value.to_string(),
+            Field::Byte(n) => n.to_string(),
+            Field::Short(n) => n.to_string(),
+            Field::Int(n) => n.to_string(),
+            Field::Long(n) => n.to_string(),
+            Field::UByte(n) => n.to_string(),
+            Field::UShort(n) => n.to_string(),
+            Field::UInt(n) => n.to_string(),
+            Field::ULong(n) => n.to_string(),
+            Field::Float16(n) => n.to_string(),
+            Field::Float(n) => n.to_string(),
+            Field::Double(n) => n.to_string(),
+            // Every digit of the unscaled value, e.g. wei prices as DECIMAL(38, 18)
+            Field::Decimal(_) => field.to_string(),
+            Field::Str(text) => text.clone(),
+            // Binary columns without a UTF8 annotation are usually text all the same
+            Field::Bytes(bytes) => match std::str::from_utf8(bytes.data()) {
+                Ok(text) => text.to_string(),
+                Err(_) => format!("0x{}", bytes.data().iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+            },
+            Field::Date(days) => (*days as i64 * 86_400).to_string(),
+            Field::TimestampMillis(millis) => millis.div_euclid(1000).to_string(),
+            Field::TimestampMicros(micros) => micros.div_euclid(1_000_000).to_string(),
+            Field::Group(_) | Field::ListInternal(_) | Field::MapInternal(_) => field.to_json_value().to_string(),
+        }
+    }
+}
+
+impl RecordSource for ParquetSource {
+    fn headers(&self) -> &StringRecord {
+        &self.headers
+    }
+
+    fn set_headers(&mut self, headers: StringRecord) {
+        self.headers = headers;
+    }
+
+    fn seek_to_row(&mut self, offset: &RowOffset) -> Result<()> {
+        let target = offset.index as u64;
+        let reader = Self::reader(&self.path, 0)?;
+        let mut first_row = 0;
+        let mut first_group = 0;
+        for group in reader.metadata().row_groups() {
+            if first_row + group.num_rows() as u64 > target {
+                break;
+            }
+            first_row += group.num_rows() as u64;
+            first_group += 1;
+        }
+        self.rows = RowIter::from_file_into(Box::new(Self::reader(&self.path, first_group)?));
+        self.index = first_row;
+        while self.index < target {
+            match self.rows.next() {
+                Some(row) => row.with_context(|| format!("Failed to read {}", self.path.display()))?,
+                None => break,
+            };
+            self.index += 1;
+        }
+        Ok(())
+    }
+
+    fn next_row(&mut self) -> Option<Result<StringRecord>> {
+        let row = match self.rows.next()? {
+            Ok(row) => row,
+            Err(e) => return Some(Err(anyhow::Error::new(e).context(format!("Failed to read {}", self.path.display())))),
+        };
+        let mut record: StringRecord = row.get_column_iter().map(|(_, field)| Self::column_text(field)).collect();
+        let mut position = Position::new();
+        position.set_byte(self.index).set_line(self.index + 1).set_record(self.index);
+        record.set_position(Some(position));
+        self.index += 1;
+        Some(Ok(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonl_rows_resume_at_noted_offset() {
+        let path = std::env::temp_dir().join(format!("source-test-{}.jsonl", std::process::id()));
+        std::fs::write(&path, concat!(
+            "\u{feff}{\"token_address\":\"0xab\",\"token_id\":1,\"price\":1.5,\"rarity\":\"rare\"}\n",
+            "\n",
+            "{\"token_id\":\"2\",\"token_address\":\"0xab\",\"is_shown\":true,\"raw_metadata\":{\"name\":\"Two\"}}\n",
+            "{\"token_address\":\"0xab\",\"token_id\":3,\"price\":null}\n",
+        )).unwrap();
+        let (mut source, format) = open_source_as(&path.to_string_lossy(), RecordFormat::Jsonl).unwrap();
+        assert_eq!(format.boms_stripped, 1);
+        let headers = source.headers().clone();
+        assert_eq!(headers.iter().collect::<Vec<_>>(), vec!["token_address", "token_id", "price", "is_shown", "raw_metadata", "rarity"]);
+
+        let rows: Vec<StringRecord> = source.rows().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].iter().collect::<Vec<_>>(), vec!["0xab", "1", "1.5", "", "", "rare"]);
+        assert_eq!(&rows[1][4], r#"{"name":"Two"}"#);
+        assert_eq!(rows[2].position().unwrap().line(), 4);
+        let record: crate::record::CsvRecord = rows[1].deserialize(Some(&headers)).unwrap();
+        assert_eq!(record.is_shown.as_deref(), Some("true"));
+
+        let offset = RowOffset::new(2, rows[2].position().unwrap());
+        let (mut source, _) = open_source_as(&path.to_string_lossy(), RecordFormat::Jsonl).unwrap();
+        source.seek_to_row(&offset).unwrap();
+        let rest: Vec<StringRecord> = source.rows().map(Result::unwrap).collect();
+        assert_eq!(rest, vec![rows[2].clone()]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_jsonl_keys_after_header_sample_are_counted() {
+        let path = std::env::temp_dir().join(format!("source-test-late-{}.jsonl", std::process::id()));
+        let mut lines = "{\"token_address\":\"0xab\",\"token_id\":1}\n".repeat(HEADER_SAMPLE_LINES);
+        lines.push_str("{\"token_address\":\"0xab\",\"token_id\":2,\"rarity\":\"rare\"}\n");
+        std::fs::write(&path, lines).unwrap();
+        let (mut source, _) = open_source_as(&path.to_string_lossy(), RecordFormat::Jsonl).unwrap();
+        assert_eq!(source.headers().iter().collect::<Vec<_>>(), vec!["token_address", "token_id"]);
+        assert_eq!(source.rows().count(), HEADER_SAMPLE_LINES + 1);
+        assert_eq!(source.dropped_keys(), BTreeMap::from([("rarity".to_string(), 1)]));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_csv_final_row_with_or_without_trailing_newline() {
+        let path = std::env::temp_dir().join(format!("source-test-{}.csv", std::process::id()));
+        for body in ["token_address,token_id\n0xab,1\n0xab,2", "token_address,token_id\n0xab,1\n0xab,2\n",
+                     "token_address,token_id\r\n0xab,1\r\n0xab,2\r\n\r\n"] {
+            std::fs::write(&path, body).unwrap();
+            let (mut source, _) = open_source_as(&path.to_string_lossy(), RecordFormat::Csv).unwrap();
+            let rows: Vec<StringRecord> = source.rows().map(Result::unwrap).collect();
+            assert_eq!(rows.iter().map(|row| &row[1]).collect::<Vec<_>>(), vec!["1", "2"], "{:?}", body);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_parquet_typed_columns_and_row_group_seek() {
+        use parquet::data_type::{ByteArray, ByteArrayType, FloatType, Int64Type};
+        use parquet::file::properties::WriterProperties;
+        use parquet::file::writer::SerializedFileWriter;
+        use parquet::schema::parser::parse_message_type;
+        use std::sync::Arc;
+
+        let path = std::env::temp_dir().join(format!("source-test-{}.parquet", std::process::id()));
+        let schema = parse_message_type("message nft {
+            required binary token_address;
+            required int64 token_id;
+            optional float price;
+            required int64 ended_at (TIMESTAMP_MILLIS);
+        }").unwrap();
+        let mut writer = SerializedFileWriter::new(File::create(&path).unwrap(), Arc::new(schema), Arc::new(WriterProperties::builder().build())).unwrap();
+        // Two row groups: tokens 1 and 2, then token 3
+        for (ids, prices, levels) in [(vec![1, 2], vec![1.5], vec![1, 0]), (vec![3], vec![0.1], vec![1])] {
+            let mut group = writer.next_row_group().unwrap();
+            let mut column = 0;
+            while let Some(mut writer) = group.next_column().unwrap() {
+                match column {
+                    0 => writer.typed::<ByteArrayType>().write_batch(&vec![ByteArray::from("0xab"); ids.len()], None, None),
+                    1 => writer.typed::<Int64Type>().write_batch(&ids, None, None),
+                    2 => writer.typed::<FloatType>().write_batch(&prices, Some(&levels), None),
+                    _ => writer.typed::<Int64Type>().write_batch(&vec![1_700_000_000_123; ids.len()], None, None),
+                }.unwrap();
+                writer.close().unwrap();
+                column += 1;
+            }
+            group.close().unwrap();
+        }
+        writer.close().unwrap();
+
+        let (mut source, _) = open_source_as(&path.to_string_lossy(), RecordFormat::Parquet).unwrap();
+        assert_eq!(source.headers().iter().collect::<Vec<_>>(), vec!["token_address", "token_id", "price", "ended_at"]);
+        let rows: Vec<StringRecord> = source.rows().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].iter().collect::<Vec<_>>(), vec!["0xab", "1", "1.5", "1700000000"]);
+        assert_eq!(&rows[1][2], "");
+        assert_eq!(&rows[2][2], "0.1");
+
+        let (mut source, _) = open_source_as(&path.to_string_lossy(), RecordFormat::Parquet).unwrap();
+        source.seek_to_row(&RowOffset::new(2, rows[2].position().unwrap())).unwrap();
+        let rest: Vec<StringRecord> = source.rows().map(Result::unwrap).collect();
+        assert_eq!(rest, vec![rows[2].clone()]);
+        assert_eq!(rest[0].position().unwrap().line(), 3);
+        std::fs::remove_file(&path).ok();
+    }
+}
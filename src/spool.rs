@@ -0,0 +1,111 @@
+//! Managed spool directory (SPOOL_DIR) for the large files a run writes locally:
+//! downloaded CSVs and the bulk error log (rejected documents). Runs check for room
+//! before they start and keep checking while they go, stopping with a saved
+//! checkpoint instead of failing on a full disk hours in.
+
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct Spool {
+    pub dir: PathBuf,
+    /// Most the spool's files may take up together (SPOOL_MAX_BYTES)
+    pub max_bytes: Option<u64>,
+    /// Free space the disk must keep (SPOOL_MIN_FREE_BYTES)
+    pub min_free_bytes: u64,
+}
+
+impl Spool {
+    /// Fail unless `incoming` more bytes fit within both limits
+    pub fn preflight(&self, incoming: u64) -> Result<()> {
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Failed to create spool dir {}", self.dir.display()))?;
+        match self.shortage(incoming)? {
+            Some(problem) => anyhow::bail!("Not enough room in spool dir {}: {}", self.dir.display(), problem),
+            None => Ok(()),
+        }
+    }
+
+    /// Why `incoming` more bytes don't fit, if they don't
+    fn shortage(&self, incoming: u64) -> Result<Option<String>> {
+        let free = free_bytes(&self.dir)?;
+        if free < self.min_free_bytes.saturating_add(incoming) {
+            return Ok(Some(format!("{} MB free, {} MB must stay free{}", free >> 20, self.min_free_bytes >> 20,
+                                   if incoming > 0 { format!(" after {} MB more", incoming >> 20) } else { String::new() })));
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            let used = dir_size(&self.dir)?;
+            if used.saturating_add(incoming) > max_bytes {
+                return Ok(Some(format!("{} MB used{}, SPOOL_MAX_BYTES is {} MB", used >> 20,
+                                       if incoming > 0 { format!(" and {} MB more coming", incoming >> 20) } else { String::new() },
+                                       max_bytes >> 20)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check every `interval` and return once the spool is out of room
+    pub async fn wait_until_full(&self, interval: Duration) -> String {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.shortage(0) {
+                Ok(Some(problem)) => return format!("Spool dir {} is out of room: {}", self.dir.display(), problem),
+                Ok(None) => {}
+                Err(e) => warn!("⚠️  Checking spool dir {}: {:#}", self.dir.display(), e),
+            }
+        }
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.dir)
+    }
+}
+
+/// Bytes available to unprivileged users on the file system holding `dir`
+fn free_bytes(dir: &Path) -> Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to stat file system of {}", dir.display()));
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types are narrower on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        total += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preflight_limits() {
+        let dir = std::env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        let spool = Spool { dir: dir.clone(), max_bytes: Some(1000), min_free_bytes: 0 };
+        spool.preflight(0).unwrap();
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("nested/dead_letter.ndjson"), vec![b'x'; 600]).unwrap();
+        assert_eq!(dir_size(&dir).unwrap(), 600);
+
+        spool.preflight(400).unwrap();
+        let error = spool.preflight(401).unwrap_err().to_string();
+        assert!(error.contains("SPOOL_MAX_BYTES"), "{}", error);
+        assert!(Spool { min_free_bytes: u64::MAX, ..spool.clone() }.preflight(0).is_err());
+        assert!(spool.contains(&dir.join("nfts.csv")));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
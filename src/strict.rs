@@ -0,0 +1,132 @@
+//! `--strict` (STRICT): every place a run quietly drops or alters data is counted
+//! — values that didn't parse, trimmed values, rows without a document id,
+//! required collection fields that couldn't be extracted, JSON Lines keys without
+//! a column, expired listings cleared, documents the cluster rejected — and a run
+//! with any of them fails once it has finished and reported them.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+
+use crate::models_flexible::{CsvRecord, FlexibleElasticsearchDocument};
+use crate::outcome::VerificationFailed;
+
+#[derive(Debug, Default, Serialize)]
+pub struct DataLoss {
+    /// Non-blank values that became null, by column
+    pub unparseable_values: BTreeMap<&'static str, u64>,
+    /// Text values trimmed of surrounding whitespace
+    pub trimmed_values: u64,
+    /// Rows that were never sent for lack of a token_address or token_id
+    pub rows_without_id: u64,
+    /// Documents missing required collection fields (quarantined when enabled)
+    pub failed_extractions: u64,
+    /// JSON Lines values under keys first seen after the header sample, by key
+    pub unsampled_keys: BTreeMap<String, u64>,
+    /// Expired listings whose order fields were nulled (EXPIRED_LISTINGS=clear)
+    pub cleared_listings: u64,
+    /// Documents the cluster rejected, left in the bulk error log
+    pub rejected_documents: u64,
+}
+
+impl DataLoss {
+    pub fn add_record(&mut self, record: &CsvRecord) {
+        for column in record.unparseable_columns() {
+            *self.unparseable_values.entry(column).or_default() += 1;
+        }
+        self.trimmed_values += record.trimmed_values() as u64;
+    }
+
+    pub fn add_document(&mut self, document: &FlexibleElasticsearchDocument) {
+        if document.id.is_none() {
+            self.rows_without_id += 1;
+        }
+        if !document.extraction_errors.is_empty() {
+            self.failed_extractions += 1;
+        }
+    }
+
+    /// Add the counts of `other`, kept by another transform worker
+    pub fn merge(&mut self, other: DataLoss) {
+        for (column, count) in other.unparseable_values {
+            *self.unparseable_values.entry(column).or_default() += count;
+        }
+        self.trimmed_values += other.trimmed_values;
+        self.rows_without_id += other.rows_without_id;
+        self.failed_extractions += other.failed_extractions;
+        self.cleared_listings += other.cleared_listings;
+        self.rejected_documents += other.rejected_documents;
+        for (key, count) in other.unsampled_keys {
+            *self.unsampled_keys.entry(key).or_default() += count;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.unparseable_values.values().sum::<u64>() + self.trimmed_values + self.rows_without_id + self.failed_extractions
+            + self.unsampled_keys.values().sum::<u64>() + self.cleared_listings + self.rejected_documents
+    }
+
+    /// Fail the run if anything was lost, after logging what
+    pub fn verify(&self) -> Result<()> {
+        if self.total() > 0 {
+            warn!("⚠️  Strict mode: {} values or rows were dropped or altered:", self.total());
+            self.report();
+            return Err(anyhow::anyhow!("{} data loss events under --strict", self.total()).context(VerificationFailed));
+        }
+        info!("✓ Strict mode: no values or rows were dropped or altered");
+        Ok(())
+    }
+
+    /// Log every kind of loss that occurred
+    pub fn report(&self) {
+        for (column, count) in &self.unparseable_values {
+            warn!("   Unparseable {} values dropped: {}", column, count);
+        }
+        for (key, count) in &self.unsampled_keys {
+            warn!("   Values of JSON Lines key {:?} dropped (no column): {}", key, count);
+        }
+        let counts = [
+            ("Values trimmed of whitespace", self.trimmed_values),
+            ("Rows skipped without a document id", self.rows_without_id),
+            ("Documents with failed extraction", self.failed_extractions),
+            ("Expired listings cleared", self.cleared_listings),
+            ("Documents rejected by Elasticsearch", self.rejected_documents),
+        ];
+        for (what, count) in counts.into_iter().filter(|(_, count)| *count > 0) {
+            warn!("   {}: {}", what, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_losses_counted_by_kind() {
+        let mut loss = DataLoss::default();
+        let record = CsvRecord {
+            token_address: Some("0xabc ".to_string()),
+            price: Some("n/a".to_string()),
+            ..Default::default()
+        };
+        loss.add_record(&record);
+        loss.add_document(&FlexibleElasticsearchDocument::from_record(record, None));
+        assert_eq!(loss.unparseable_values.get("price"), Some(&1));
+        assert_eq!(loss.trimmed_values, 1);
+        assert_eq!(loss.rows_without_id, 1);
+        assert_eq!(loss.total(), 3);
+
+        // Counted after indexing, and by the stream for expired listings
+        loss.merge(DataLoss { cleared_listings: 2, rejected_documents: 1, ..DataLoss::default() });
+        assert_eq!(loss.total(), 6);
+    }
+
+    #[test]
+    fn test_any_loss_fails_verification() {
+        assert!(DataLoss::default().verify().is_ok());
+        let error = DataLoss { rejected_documents: 1, ..DataLoss::default() }.verify().unwrap_err();
+        assert_eq!(crate::outcome::error_code(&error), crate::outcome::EXIT_VERIFICATION);
+    }
+}
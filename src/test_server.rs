@@ -0,0 +1,100 @@
+//! Minimal HTTP server standing in for Elasticsearch in tests that need to see
+//! the cluster go away and come back (rolling restarts, stalled nodes).
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// What the server does with the n-th request (0-based) it receives
+pub enum Reply {
+    /// Close the connection without answering, like a node shutting down
+    Drop,
+    Respond(u16, String),
+}
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub requests: Arc<AtomicUsize>,
+    /// Bodies of the requests answered, in the order they were read
+    pub bodies: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Serve on `addr` (port 0 picks a free one), answering each request with `reply`
+    pub async fn start<F>(addr: SocketAddr, reply: F) -> Self
+    where
+        F: Fn(usize, &str) -> Reply + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let reply = Arc::new(reply);
+        let task = tokio::spawn({
+            let requests = requests.clone();
+            let bodies = bodies.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let number = requests.fetch_add(1, Ordering::Relaxed);
+                    let reply = reply.clone();
+                    let bodies = bodies.clone();
+                    tokio::spawn(async move { handle(stream, number, &*reply, &bodies).await });
+                }
+            }
+        });
+        Self { addr, requests, bodies, task }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Stop listening; new connections are refused until a server starts on the address again
+    pub fn stop(self) -> SocketAddr {
+        self.task.abort();
+        self.addr
+    }
+}
+
+async fn handle<F: Fn(usize, &str) -> Reply>(mut stream: TcpStream, number: usize, reply: &F, bodies: &Mutex<Vec<String>>) {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 8192];
+    // Read the head, then as much body as Content-Length announces
+    loop {
+        let Ok(read) = stream.read(&mut buffer).await else { return };
+        if read == 0 {
+            return;
+        }
+        request.extend_from_slice(&buffer[..read]);
+        let text = String::from_utf8_lossy(&request);
+        if let Some(head_end) = text.find("\r\n\r\n") {
+            let content_length = text[..head_end].lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() >= head_end + 4 + content_length {
+                break;
+            }
+        }
+    }
+    let request = String::from_utf8_lossy(&request).into_owned();
+    let request_line = request.lines().next().unwrap_or_default().to_string();
+    if let Some((_, body)) = request.split_once("\r\n\r\n") {
+        bodies.lock().unwrap().push(body.to_string());
+    }
+    match reply(number, &request_line) {
+        Reply::Drop => {}
+        Reply::Respond(status, body) => {
+            let response = format!(
+                "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            stream.write_all(response.as_bytes()).await.ok();
+            stream.shutdown().await.ok();
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Analyzers of the searchable text fields. FIELD_ANALYZERS picks the analyzer of
+//! `name` or `description` (which isn't searchable without one), and
+//! DESCRIPTION_LANGUAGES adds a subfield per language (`description.ja`) with that
+//! language's analyzer.
+//!
+//! Analyzers from plugins (icu, kuromoji) are checked against the plugins every
+//! node has before any index is created: an index whose shards land on a node
+//! without the plugin can't be written to. MISSING_PLUGIN=degrade (the default)
+//! leaves such subfields out and falls back to the standard analyzer; fail stops
+//! with the missing plugins named.
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::OnceLock;
+use tracing::warn;
+
+use crate::deprecations::SendNotingWarnings;
+
+/// Text fields of the base mapping whose analyzer can be configured
+const TEXT_FIELDS: &[&str] = &["name", "description"];
+
+/// Analyzer name prefixes and the plugin providing them
+const PLUGIN_ANALYZERS: &[(&str, &str)] = &[
+    ("icu_", "analysis-icu"),
+    ("kuromoji", "analysis-kuromoji"),
+    ("nori", "analysis-nori"),
+    ("smartcn", "analysis-smartcn"),
+    ("polish", "analysis-stempel"),
+    ("ukrainian", "analysis-ukrainian"),
+];
+
+/// Plugin an analyzer comes from; None for built-in and custom analyzers
+pub fn analyzer_plugin(analyzer: &str) -> Option<&'static str> {
+    PLUGIN_ANALYZERS.iter()
+        .find(|(prefix, _)| analyzer.starts_with(prefix))
+        .map(|(_, plugin)| *plugin)
+}
+
+/// What to do when an analyzer's plugin is missing from the cluster (MISSING_PLUGIN)
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingPlugin {
+    /// Leave language subfields out, use the standard analyzer instead
+    #[default]
+    Degrade,
+    /// Stop before creating any index
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    Japanese,
+    Vietnamese,
+}
+
+impl Language {
+    fn parse(code: &str) -> Option<Self> {
+        match code {
+            "en" => Some(Self::English),
+            "ja" => Some(Self::Japanese),
+            "vi" => Some(Self::Vietnamese),
+            _ => None,
+        }
+    }
+
+    /// Name of the subfield
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::English => "en",
+            Self::Japanese => "ja",
+            Self::Vietnamese => "vi",
+        }
+    }
+
+    fn analyzer(self) -> &'static str {
+        match self {
+            Self::English => "english",
+            Self::Japanese => "kuromoji",
+            Self::Vietnamese => "icu_analyzer",
+        }
+    }
+
+    /// Plugin providing the analyzer; None for those built in
+    pub fn plugin(self) -> Option<&'static str> {
+        analyzer_plugin(self.analyzer())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TextAnalysis {
+    /// Analyzer per text field (FIELD_ANALYZERS)
+    pub field_analyzers: BTreeMap<String, String>,
+    /// Languages `description` gets a subfield for (DESCRIPTION_LANGUAGES)
+    pub languages: Vec<Language>,
+    /// Plugins every node of the cluster has; None when not looked up (mapping preview)
+    pub plugins: Option<BTreeSet<String>>,
+    pub on_missing_plugin: MissingPlugin,
+}
+
+/// FIELD_ANALYZERS: comma-separated `field=analyzer`, e.g. `description=standard`
+pub fn parse_field_analyzers(spec: &str) -> Result<BTreeMap<String, String>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (field, analyzer) = entry.split_once('=')
+                .with_context(|| format!("FIELD_ANALYZERS: '{}' is not field=analyzer", entry))?;
+            let field = field.trim();
+            if !TEXT_FIELDS.contains(&field) {
+                anyhow::bail!("FIELD_ANALYZERS: '{}' is not a text field ({})", field, TEXT_FIELDS.join(", "));
+            }
+            Ok((field.to_string(), analyzer.trim().to_string()))
+        })
+        .collect()
+}
+
+/// DESCRIPTION_LANGUAGES: comma-separated language codes (en, ja, vi)
+pub fn parse_languages(spec: &str) -> Result<Vec<Language>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|code| !code.is_empty())
+        .map(|code| Language::parse(code)
+            .with_context(|| format!("DESCRIPTION_LANGUAGES: unknown language '{}' (en, ja, vi)", code)))
+        .collect()
+}
+
+/// Plugins of each node in a `_nodes/plugins` response, by node name
+fn node_plugins(body: &Value) -> BTreeMap<String, BTreeSet<String>> {
+    body["nodes"].as_object().into_iter().flatten()
+        .map(|(id, node)| {
+            let name = node["name"].as_str().unwrap_or(id).to_string();
+            let plugins = node["plugins"].as_array().into_iter().flatten()
+                .filter_map(|plugin| plugin["name"].as_str())
+                .map(str::to_string)
+                .collect();
+            (name, plugins)
+        })
+        .collect()
+}
+
+/// Plugins every node has, warning about those only some nodes have. Without the
+/// privilege to read `_nodes`, `_cat/plugins` tells which plugins any node has.
+pub async fn installed_plugins(client: &Client, elasticsearch_url: &str) -> Result<BTreeSet<String>> {
+    let url = format!("{}/_nodes/plugins", elasticsearch_url);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to list node plugins")?;
+    if response.status().is_success() {
+        let body: Value = response.json().await.context("Failed to parse node plugins")?;
+        let nodes = node_plugins(&body);
+        let mut everywhere = nodes.values().next().cloned().unwrap_or_default();
+        for plugins in nodes.values() {
+            everywhere.retain(|plugin| plugins.contains(plugin));
+        }
+        for (plugin, missing) in partial_plugins(&nodes, &everywhere) {
+            warn!("⚠️  Plugin {} is missing on {}; treating it as unavailable", plugin, missing.join(", "));
+        }
+        return Ok(everywhere);
+    }
+
+    warn!("⚠️  Can't read _nodes/plugins (HTTP {}), checking _cat/plugins instead", response.status());
+    let url = format!("{}/_cat/plugins?format=json&h=component", elasticsearch_url);
+    let response = client.get(&url).send_noting_warnings().await.context("Failed to list plugins")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to list plugins: HTTP {}", response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse plugin list")?;
+    Ok(body.as_array().into_iter().flatten()
+        .filter_map(|plugin| plugin["component"].as_str())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Whether an analyzer needing `plugin` can be used; anything can when the
+/// plugins weren't looked up
+fn has_plugin(plugins: &Option<BTreeSet<String>>, plugin: Option<&str>) -> bool {
+    match (plugin, plugins) {
+        (Some(plugin), Some(plugins)) => plugins.contains(plugin),
+        _ => true,
+    }
+}
+
+/// Plugins some nodes have but not all, with the nodes lacking them
+fn partial_plugins(nodes: &BTreeMap<String, BTreeSet<String>>, everywhere: &BTreeSet<String>) -> BTreeMap<String, Vec<String>> {
+    let mut partial: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let anywhere: BTreeSet<&String> = nodes.values().flatten().collect();
+    for plugin in anywhere.into_iter().filter(|plugin| !everywhere.contains(*plugin)) {
+        let missing = nodes.iter().filter(|(_, plugins)| !plugins.contains(plugin)).map(|(node, _)| node.clone()).collect();
+        partial.insert(plugin.clone(), missing);
+    }
+    partial
+}
+
+impl TextAnalysis {
+    pub fn is_empty(&self) -> bool {
+        self.field_analyzers.is_empty() && self.languages.is_empty()
+    }
+
+    /// Whether any configured analyzer comes from a plugin
+    pub fn needs_plugins(&self) -> bool {
+        self.languages.iter().any(|language| language.plugin().is_some())
+            || self.field_analyzers.values().any(|analyzer| analyzer_plugin(analyzer).is_some())
+    }
+
+    /// Check the analyzers against the cluster's plugins: fail naming the missing
+    /// ones, or leave out and replace what needs them (MISSING_PLUGIN)
+    pub fn gate(&mut self) -> Result<()> {
+        let mut missing = Vec::new();
+        for language in &self.languages {
+            if !has_plugin(&self.plugins, language.plugin()) {
+                missing.push(format!("description.{} needs {}", language.code(), language.plugin().unwrap_or_default()));
+            }
+        }
+        for (field, analyzer) in &self.field_analyzers {
+            if !has_plugin(&self.plugins, analyzer_plugin(analyzer)) {
+                missing.push(format!("{} analyzer {} needs {}", field, analyzer, analyzer_plugin(analyzer).unwrap_or_default()));
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if self.on_missing_plugin == MissingPlugin::Fail {
+            anyhow::bail!("The cluster lacks plugins the mapping needs ({}); install them on every node, \
+                           or set MISSING_PLUGIN=degrade to index without them", missing.join(", "));
+        }
+        for feature in &missing {
+            warn!("⚠️  {}, which the cluster lacks; indexing without it", feature);
+        }
+        let plugins = &self.plugins;
+        self.languages.retain(|language| has_plugin(plugins, language.plugin()));
+        for analyzer in self.field_analyzers.values_mut() {
+            if !has_plugin(plugins, analyzer_plugin(analyzer)) {
+                *analyzer = "standard".to_string();
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the analyzers and language subfields in the `properties` of a mapping
+    pub fn apply(&self, properties: &mut Value) {
+        for (field, analyzer) in &self.field_analyzers {
+            if let Some(mapping) = properties[field.as_str()].as_object_mut() {
+                // Stored-only fields become searchable once they have an analyzer
+                mapping.remove("index");
+                mapping.insert("analyzer".to_string(), json!(analyzer));
+            }
+        }
+        if let (false, Some(mapping)) = (self.languages.is_empty(), properties["description"].as_object_mut()) {
+            let subfields = mapping.entry("fields").or_insert_with(|| json!({}));
+            for language in &self.languages {
+                subfields[language.code()] = json!({"type": "text", "analyzer": language.analyzer()});
+            }
+        }
+    }
+}
+
+static TEXT_ANALYSIS: OnceLock<TextAnalysis> = OnceLock::new();
+
+/// Use `analysis` in every mapping generated from now on; only the first call takes effect
+pub fn install_text_analysis(analysis: TextAnalysis) {
+    TEXT_ANALYSIS.set(analysis).ok();
+}
+
+pub fn installed_text_analysis() -> Option<&'static TextAnalysis> {
+    TEXT_ANALYSIS.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyzers_and_language_subfields() {
+        let mut analysis = TextAnalysis {
+            field_analyzers: parse_field_analyzers("description=standard, name=nft_name_analyzer").unwrap(),
+            languages: parse_languages("en,ja,vi").unwrap(),
+            plugins: Some(BTreeSet::from(["analysis-icu".to_string()])),
+            on_missing_plugin: MissingPlugin::Degrade,
+        };
+        assert!(parse_field_analyzers("owner=standard").is_err());
+        assert!(parse_languages("en,fr").is_err());
+
+        analysis.gate().unwrap();
+        let mut properties = json!({
+            "name": {"type": "text", "analyzer": "nft_name_analyzer"},
+            "description": {"type": "text", "index": false},
+        });
+        analysis.apply(&mut properties);
+        assert_eq!(properties["description"], json!({
+            "type": "text",
+            "analyzer": "standard",
+            "fields": {
+                "en": {"type": "text", "analyzer": "english"},
+                "vi": {"type": "text", "analyzer": "icu_analyzer"},
+            },
+        }));
+    }
+
+    #[test]
+    fn test_plugins_gated_on_every_node() {
+        let body = json!({"nodes": {
+            "a1": {"name": "es-1", "plugins": [{"name": "analysis-icu"}, {"name": "analysis-kuromoji"}]},
+            "b2": {"name": "es-2", "plugins": [{"name": "analysis-icu"}]},
+        }});
+        let nodes = node_plugins(&body);
+        let everywhere = BTreeSet::from(["analysis-icu".to_string()]);
+        assert_eq!(partial_plugins(&nodes, &everywhere),
+                   BTreeMap::from([("analysis-kuromoji".to_string(), vec!["es-2".to_string()])]));
+
+        let mut analysis = TextAnalysis {
+            field_analyzers: parse_field_analyzers("name=kuromoji").unwrap(),
+            languages: parse_languages("ja").unwrap(),
+            plugins: Some(everywhere),
+            on_missing_plugin: MissingPlugin::Fail,
+        };
+        assert!(analysis.needs_plugins());
+        let error = analysis.clone().gate().unwrap_err().to_string();
+        assert!(error.contains("description.ja needs analysis-kuromoji") && error.contains("name analyzer kuromoji"), "{}", error);
+
+        analysis.on_missing_plugin = MissingPlugin::Degrade;
+        analysis.gate().unwrap();
+        assert!(analysis.languages.is_empty());
+        assert_eq!(analysis.field_analyzers["name"], "standard");
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{info, warn};
+
+/// How often the observed rate is compared against the target
+const ADJUST_INTERVAL: Duration = Duration::from_secs(5);
+/// Rates within this fraction of the target are left alone
+const TOLERANCE: f64 = 0.1;
+const MIN_DELAY: Duration = Duration::from_millis(50);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Holds the migration near a target records/sec by changing how many workers
+/// may send bulk requests at once and how long each waits before sending.
+///
+/// Slowing down first drops workers, then adds delay; speeding up removes the
+/// delay first, then adds workers back up to the configured maximum.
+pub struct ThroughputGovernor {
+    target: f64,
+    max_workers: usize,
+    semaphore: Semaphore,
+    // Permits to swallow instead of returning, when concurrency was lowered while busy
+    pending_reductions: AtomicUsize,
+    state: Mutex<GovernorState>,
+}
+
+struct GovernorState {
+    window_start: Instant,
+    window_records: u64,
+    active_workers: usize,
+    delay: Duration,
+    unreachable_windows: u64,
+    reported_unreachable: bool,
+}
+
+/// Permission to send one bulk request; returns the worker slot on drop
+pub struct BatchSlot<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    governor: &'a ThroughputGovernor,
+}
+
+impl ThroughputGovernor {
+    pub fn new(target: f64, max_workers: usize) -> Self {
+        Self {
+            target,
+            max_workers,
+            semaphore: Semaphore::new(max_workers),
+            pending_reductions: AtomicUsize::new(0),
+            state: Mutex::new(GovernorState {
+                window_start: Instant::now(),
+                window_records: 0,
+                active_workers: max_workers,
+                delay: Duration::ZERO,
+                unreachable_windows: 0,
+                reported_unreachable: false,
+            }),
+        }
+    }
+
+    pub fn target(&self) -> f64 {
+        self.target
+    }
+
+    /// Wait for a free worker slot and the current inter-batch delay
+    pub async fn acquire(&self) -> BatchSlot<'_> {
+        let permit = self.semaphore.acquire().await.expect("governor semaphore is never closed");
+        let delay = self.state.lock().unwrap().delay;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        BatchSlot { permit: Some(permit), governor: self }
+    }
+
+    /// Count indexed records and retune once per adjustment window
+    pub fn record(&self, records: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.window_records += records as u64;
+
+        let elapsed = state.window_start.elapsed();
+        if elapsed < ADJUST_INTERVAL {
+            return;
+        }
+
+        let rate = state.window_records as f64 / elapsed.as_secs_f64();
+        state.window_start = Instant::now();
+        state.window_records = 0;
+
+        if rate > self.target * (1.0 + TOLERANCE) {
+            state.unreachable_windows = 0;
+            state.reported_unreachable = false;
+            if state.active_workers > 1 {
+                state.active_workers -= 1;
+                self.remove_worker();
+            } else {
+                state.delay = (state.delay * 2).clamp(MIN_DELAY, MAX_DELAY);
+            }
+        } else if rate < self.target * (1.0 - TOLERANCE) {
+            if !state.delay.is_zero() {
+                state.delay /= 2;
+                if state.delay < MIN_DELAY {
+                    state.delay = Duration::ZERO;
+                }
+            } else if state.active_workers < self.max_workers {
+                state.active_workers += 1;
+                self.add_worker();
+            } else {
+                // Everything is already wide open, the cluster is the bottleneck
+                state.unreachable_windows += 1;
+                if !state.reported_unreachable {
+                    state.reported_unreachable = true;
+                    warn!("⚠️  Target {:.0} records/sec unreachable: {:.0} records/sec with all {} workers and no delay (cluster limited)",
+                             self.target, rate, self.max_workers);
+                }
+                return;
+            }
+        } else {
+            return;
+        }
+
+        info!("🎚️  Throughput {:.0}/s (target {:.0}/s): {} workers, {}ms delay",
+                 rate, self.target, state.active_workers, state.delay.as_millis());
+    }
+
+    /// Number of adjustment windows spent below target at full concurrency
+    pub fn unreachable_windows(&self) -> u64 {
+        self.state.lock().unwrap().unreachable_windows
+    }
+
+    fn remove_worker(&self) {
+        // Take an idle slot right away if there is one, otherwise the next returned slot
+        if self.semaphore.forget_permits(1) == 0 {
+            self.pending_reductions.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn add_worker(&self) {
+        // Cancel a reduction that hasn't happened yet before growing the pool
+        let cancelled = self
+            .pending_reductions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if !cancelled {
+            self.semaphore.add_permits(1);
+        }
+    }
+}
+
+impl Drop for BatchSlot<'_> {
+    fn drop(&mut self) {
+        let swallow = self
+            .governor
+            .pending_reductions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if let Some(permit) = self.permit.take() {
+            if swallow {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reduced_worker_is_taken_from_busy_slot() {
+        let governor = ThroughputGovernor::new(100.0, 2);
+        let first = governor.acquire().await;
+        let second = governor.acquire().await;
+
+        governor.remove_worker();
+        drop(first);
+        assert_eq!(governor.semaphore.available_permits(), 0);
+
+        drop(second);
+        assert_eq!(governor.semaphore.available_permits(), 1);
+
+        governor.add_worker();
+        assert_eq!(governor.semaphore.available_permits(), 2);
+    }
+}
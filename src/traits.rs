@@ -0,0 +1,165 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::collection_config::get_collection_config;
+use crate::models_flexible::FlexibleElasticsearchDocument;
+
+/// Distinct values kept per trait; rarer values beyond it are only counted, so an
+/// id-like property can't take over memory
+pub const MAX_DISTINCT_VALUES: usize = 1000;
+
+/// Values of one trait across a collection's documents
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct TraitValues {
+    pub values: BTreeMap<String, u64>,
+    /// Occurrences of values beyond the first MAX_DISTINCT_VALUES distinct ones
+    #[serde(skip_serializing_if = "is_zero")]
+    pub other: u64,
+    /// Smallest and largest integer value, including the ones only counted in `other`
+    #[serde(skip)]
+    pub range: Option<(i64, i64)>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CollectionTraits {
+    pub name: Option<String>,
+    /// Whether the traits are the collection's extracted fields
+    #[serde(skip)]
+    configured: bool,
+    pub documents: u64,
+    pub traits: BTreeMap<String, TraitValues>,
+}
+
+/// Distinct trait values and their counts per collection, for building filter
+/// facets and checking normalizers. Configured collections report their extracted
+/// fields (normalized, as indexed); others every scalar `properties` key as found.
+#[derive(Debug, Default)]
+pub struct TraitAnalyzer {
+    // lowercase token_address -> traits
+    collections: BTreeMap<String, CollectionTraits>,
+}
+
+impl TraitAnalyzer {
+    pub fn add(&mut self, doc: &FlexibleElasticsearchDocument) {
+        let address = doc.token_address.as_deref().unwrap_or("unknown").to_lowercase();
+        let collection = self.collections.entry(address.clone()).or_insert_with(|| {
+            let config = get_collection_config(&address);
+            CollectionTraits {
+                configured: config.as_ref().is_some_and(|config| !config.extracted_fields.is_empty()),
+                name: config.map(|config| config.name.clone()),
+                documents: 0,
+                traits: BTreeMap::new(),
+            }
+        });
+        collection.documents += 1;
+
+        let empty = Map::new();
+        let traits = match collection.configured {
+            true => &doc.extracted_fields,
+            false => doc.properties.as_ref().unwrap_or(&empty),
+        };
+        for (name, value) in traits {
+            let values = collection.traits.entry(name.clone()).or_default();
+            // Multi-valued traits count each of their values
+            let items = match value {
+                Value::Array(items) => items.as_slice(),
+                other => std::slice::from_ref(other),
+            };
+            for item in items {
+                if let Some(n) = item.as_i64() {
+                    values.range = Some(values.range.map_or((n, n), |(min, max)| (min.min(n), max.max(n))));
+                }
+                let Some(item) = scalar(item) else { continue };
+                if let Some(count) = values.values.get_mut(&item) {
+                    *count += 1;
+                } else if values.values.len() < MAX_DISTINCT_VALUES {
+                    values.values.insert(item, 1);
+                } else {
+                    values.other += 1;
+                }
+            }
+        }
+    }
+
+    pub fn report(&self) -> &BTreeMap<String, CollectionTraits> {
+        &self.collections
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.collections)?)
+    }
+
+    /// One `token_address,collection,trait,value,count` row per distinct value
+    pub fn to_csv(&self) -> Result<String> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(["token_address", "collection", "trait", "value", "count"])?;
+        for (address, collection) in &self.collections {
+            let name = collection.name.as_deref().unwrap_or("");
+            for (trait_name, values) in &collection.traits {
+                for (value, count) in &values.values {
+                    writer.write_record([address.as_str(), name, trait_name, value, &count.to_string()])?;
+                }
+            }
+        }
+        Ok(String::from_utf8(writer.into_inner()?)?)
+    }
+}
+
+/// Facet value of a scalar; objects and nulls aren't facets
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models_flexible::CsvRecord;
+
+    fn doc(address: &str, properties: &str) -> FlexibleElasticsearchDocument {
+        let config = get_collection_config(address);
+        FlexibleElasticsearchDocument::from_record(CsvRecord {
+            token_address: Some(address.to_string()),
+            token_id: Some("1".to_string()),
+            raw_metadata: Some(format!(r#"{{"properties":{}}}"#, properties)),
+            ..Default::default()
+        }, config.as_deref())
+    }
+
+    #[test]
+    fn test_configured_and_discovered_traits() {
+        let wildforest = "0xa038c593115f6fcd673f6833e15462b475994879";
+        let mut analyzer = TraitAnalyzer::default();
+        analyzer.add(&doc(wildforest, r#"{"rarity":"Common","type":"Archer","tier":1,"level":5}"#));
+        analyzer.add(&doc(wildforest, r#"{"rarity":"COMMON","type":"Knight","tier":2,"level":5}"#));
+        analyzer.add(&doc("0x00000000000000000000000000000000000000aa", r#"{"color":["red","blue"],"meta":{"x":1}}"#));
+
+        let report = analyzer.report();
+        let configured = &report[wildforest];
+        assert_eq!(configured.documents, 2);
+        // Normalized values, under the extracted field names
+        assert_eq!(configured.traits["rarity"].values, BTreeMap::from([("common".to_string(), 2)]));
+        assert_eq!(configured.traits["nft_type"].values.len(), 2);
+
+        let discovered = &report["0x00000000000000000000000000000000000000aa"];
+        assert_eq!(discovered.name, None);
+        assert_eq!(discovered.traits["color"].values, BTreeMap::from([("blue".to_string(), 1), ("red".to_string(), 1)]));
+        assert!(discovered.traits["meta"].values.is_empty());
+
+        let csv = analyzer.to_csv().unwrap();
+        assert!(csv.starts_with("token_address,collection,trait,value,count\n"));
+        assert!(csv.contains(&format!("{},Wildforest Units,rarity,common,2\n", wildforest)));
+        let json: Value = serde_json::from_str(&analyzer.to_json().unwrap()).unwrap();
+        assert_eq!(json[wildforest]["traits"]["level"]["values"]["5"], 2);
+    }
+}
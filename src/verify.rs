@@ -0,0 +1,138 @@
+//! `verify` beyond counts: random CSV rows are fetched back from the index by `_id`
+//! and compared field by field with the document the migrator would write for them.
+
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::deprecations::SendNotingWarnings;
+
+/// Keeps a uniform random sample of `size` items from a stream of unknown length
+/// (reservoir sampling)
+pub struct Sampler<T> {
+    size: usize,
+    seen: u64,
+    state: u64,
+    sample: Vec<T>,
+}
+
+impl<T> Sampler<T> {
+    pub fn new(size: usize, seed: u64) -> Self {
+        // xorshift never leaves a zero state
+        Self { size, seen: 0, state: seed.max(1), sample: Vec::with_capacity(size) }
+    }
+
+    pub fn offer(&mut self, item: T) {
+        self.seen += 1;
+        if self.sample.len() < self.size {
+            self.sample.push(item);
+            return;
+        }
+        // Keep the n-th item with probability size/n, in place of a random one
+        let slot = self.next() % self.seen;
+        if (slot as usize) < self.size {
+            self.sample[slot as usize] = item;
+        }
+    }
+
+    pub fn into_sample(self) -> Vec<T> {
+        self.sample
+    }
+
+    // xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+}
+
+/// Documents in `index`, only those of one collection with `token_address`; None
+/// if the index doesn't exist
+pub async fn count_documents(client: &Client, elasticsearch_url: &str, index: &str, token_address: Option<&str>) -> Result<Option<u64>> {
+    let query = match token_address {
+        Some(address) => json!({"query": {"term": {"token_address": {"value": address, "case_insensitive": true}}}}),
+        None => json!({"query": {"match_all": {}}}),
+    };
+    let url = format!("{}/{}/_count", elasticsearch_url, index);
+    let response = client.post(&url).json(&query).send_noting_warnings().await.context("Failed to count documents")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to count documents in {}: HTTP {}", index, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse count response")?;
+    Ok(body["count"].as_u64())
+}
+
+/// Make everything written to `index` (an index or alias) visible to counts and searches
+pub async fn refresh_index(client: &Client, elasticsearch_url: &str, index: &str) -> Result<()> {
+    let url = format!("{}/{}/_refresh", elasticsearch_url, index);
+    let response = client.post(&url).send_noting_warnings().await.context("Failed to refresh index")?;
+    if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+        anyhow::bail!("Failed to refresh {}: HTTP {}", index, response.status());
+    }
+    Ok(())
+}
+
+/// `_source` of the documents with these ids; ids the index doesn't have are left out.
+/// A search rather than `_mget`, which an alias over several indices rejects.
+pub async fn fetch_documents(client: &Client, elasticsearch_url: &str, index: &str, ids: &[String]) -> Result<HashMap<String, Value>> {
+    let url = format!("{}/{}/_search", elasticsearch_url, index);
+    let query = json!({"query": {"ids": {"values": ids}}, "size": ids.len()});
+    let response = client.post(&url).json(&query).send_noting_warnings().await.context("Failed to fetch sampled documents")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to fetch sampled documents from {}: HTTP {}", index, response.status());
+    }
+    let body: Value = response.json().await.context("Failed to parse search response")?;
+    Ok(body["hits"]["hits"].as_array().into_iter().flatten()
+        .filter_map(|hit| Some((hit["_id"].as_str()?.to_string(), hit["_source"].clone())))
+        .collect())
+}
+
+/// Fields of the expected document that the indexed one lacks or holds differently.
+/// Fields only the index has (added by enrichment or other pipelines) are fine.
+pub fn differing_fields(expected: &Value, indexed: &Value) -> Vec<String> {
+    let Some(expected) = expected.as_object() else { return Vec::new() };
+    expected.iter()
+        .filter(|(_, value)| !value.is_null())
+        .filter(|(name, value)| indexed.get(name.as_str()) != Some(value))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+
+    #[tokio::test]
+    async fn test_sample_fetched_and_compared() {
+        let mut sampler = Sampler::new(10, 7);
+        (0..1000).for_each(|n| sampler.offer(n));
+        let sample = sampler.into_sample();
+        assert_eq!(sample.len(), 10);
+        // Not just the first rows
+        assert!(sample.iter().any(|&n| n >= 100));
+
+        let server = TestServer::start("127.0.0.1:0".parse().unwrap(), |_, request_line| match request_line {
+            line if line.starts_with("POST /nfts/_search") => Reply::Respond(200, json!({"hits": {"hits": [
+                {"_id": "0xabc:1", "_index": "nfts_v1", "_source": {"token_id": "1", "owner": "0x1", "asset_ok": true}},
+            ]}}).to_string()),
+            _ => Reply::Respond(200, r#"{"count": 2}"#.to_string()),
+        }).await;
+        let client = Client::new();
+        assert_eq!(count_documents(&client, &server.url(), "nfts", Some("0xABC")).await.unwrap(), Some(2));
+
+        let indexed = fetch_documents(&client, &server.url(), "nfts", &["0xabc:1".to_string(), "0xabc:2".to_string()]).await.unwrap();
+        assert_eq!(indexed.len(), 1);
+        let source = &indexed["0xabc:1"];
+        assert!(differing_fields(&json!({"token_id": "1", "owner": "0x1", "name": null}), source).is_empty());
+        let mut differing = differing_fields(&json!({"token_id": "1", "owner": "0x2", "name": "Axie"}), source);
+        differing.sort();
+        assert_eq!(differing, vec!["name", "owner"]);
+    }
+}
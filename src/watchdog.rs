@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::deprecations::SendNotingWarnings;
+
+/// The parts of `_cluster/health` the watchdog looks at
+#[derive(Debug, Deserialize)]
+pub struct ClusterHealth {
+    pub status: String,
+    #[serde(default)]
+    pub number_of_pending_tasks: u64,
+}
+
+impl ClusterHealth {
+    /// Why ingestion should pause, or None if the cluster looks stable
+    pub fn instability(&self, max_pending_tasks: u64) -> Option<String> {
+        if self.status == "red" {
+            Some("cluster health is red".to_string())
+        } else if self.number_of_pending_tasks > max_pending_tasks {
+            Some(format!("{} pending cluster tasks", self.number_of_pending_tasks))
+        } else {
+            None
+        }
+    }
+}
+
+/// A period during which workers held back new batches
+#[derive(Debug, Clone)]
+pub struct PauseWindow {
+    pub reason: String,
+    pub duration: Duration,
+}
+
+/// Polls cluster health in the background and pauses workers while the cluster
+/// is red or its pending task queue spikes. Batches already sent are left to
+/// finish; workers wait before sending the next one.
+pub struct HealthWatchdog {
+    /// Treat a failed health poll as instability (cluster restarting) instead of ignoring it
+    pause_when_unreachable: bool,
+    paused: watch::Sender<bool>,
+    current: Mutex<Option<(Instant, String)>>,
+    windows: Mutex<Vec<PauseWindow>>,
+}
+
+impl Default for HealthWatchdog {
+    fn default() -> Self {
+        Self { pause_when_unreachable: false, paused: watch::Sender::new(false), current: Mutex::new(None), windows: Mutex::new(Vec::new()) }
+    }
+}
+
+impl HealthWatchdog {
+    /// A watchdog that also pauses while the cluster can't be reached, for riding out restarts
+    pub fn pausing_when_unreachable() -> Self {
+        Self { pause_when_unreachable: true, ..Self::default() }
+    }
+
+    /// Start polling `_cluster/health` every `interval`
+    pub fn spawn(self: &Arc<Self>, client: Client, elasticsearch_url: String, interval: Duration, max_pending_tasks: u64) -> JoinHandle<()> {
+        let watchdog = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match fetch_health(&client, &elasticsearch_url).await {
+                    Ok(health) => watchdog.observe(health.instability(max_pending_tasks)),
+                    Err(e) if watchdog.pause_when_unreachable => watchdog.observe(Some(format!("cluster unreachable ({:#})", e))),
+                    // Otherwise connection problems are the bulk retries' business; keep the current state
+                    Err(e) => warn!("⚠️  Health watchdog: {:#}", e),
+                }
+            }
+        })
+    }
+
+    /// Record the latest poll: pause on the first unstable reading, resume on the first stable one
+    pub fn observe(&self, instability: Option<String>) {
+        let mut current = self.current.lock().unwrap();
+        match (current.take(), instability) {
+            (None, Some(reason)) => {
+                info!("⏸️  Pausing ingestion: {}", reason);
+                *current = Some((Instant::now(), reason));
+                self.paused.send_replace(true);
+            }
+            (Some((since, reason)), None) => {
+                let duration = since.elapsed();
+                info!("▶️  Resuming ingestion after {:.1}s ({})", duration.as_secs_f64(), reason);
+                self.windows.lock().unwrap().push(PauseWindow { reason, duration });
+                self.paused.send_replace(false);
+            }
+            (ongoing, _) => *current = ongoing,
+        }
+    }
+
+    /// Wait until the cluster is considered stable again
+    pub async fn wait_until_healthy(&self) {
+        let mut paused = self.paused.subscribe();
+        // The sender lives in self, so the channel can't close while we wait
+        let _ = paused.wait_for(|paused| !paused).await;
+    }
+
+    /// Like `wait_until_healthy`, giving up after `max_wait`; returns whether the cluster recovered
+    pub async fn wait_until_healthy_for(&self, max_wait: Duration) -> bool {
+        tokio::time::timeout(max_wait, self.wait_until_healthy()).await.is_ok()
+    }
+
+    /// Pause windows of the run so far, including one still in progress
+    pub fn pause_windows(&self) -> Vec<PauseWindow> {
+        let mut windows = self.windows.lock().unwrap().clone();
+        if let Some((since, reason)) = &*self.current.lock().unwrap() {
+            windows.push(PauseWindow { reason: format!("{} (still paused)", reason), duration: since.elapsed() });
+        }
+        windows
+    }
+}
+
+async fn fetch_health(client: &Client, elasticsearch_url: &str) -> Result<ClusterHealth> {
+    let url = format!("{}/_cluster/health", elasticsearch_url);
+    client.get(&url).send_noting_warnings().await
+        .context("Failed to poll cluster health")?
+        .json().await
+        .context("Failed to parse cluster health")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+
+    fn health(status: &str, pending: u64) -> ClusterHealth {
+        ClusterHealth { status: status.to_string(), number_of_pending_tasks: pending }
+    }
+
+    #[test]
+    fn test_instability_reasons() {
+        assert_eq!(health("green", 3).instability(100), None);
+        assert_eq!(health("yellow", 100).instability(100), None);
+        assert_eq!(health("red", 0).instability(100).unwrap(), "cluster health is red");
+        assert_eq!(health("green", 250).instability(100).unwrap(), "250 pending cluster tasks");
+    }
+
+    #[tokio::test]
+    async fn test_restart_pauses_until_cluster_is_back() {
+        let green = || TestServer::start("127.0.0.1:0".parse().unwrap(), |_, _| {
+            Reply::Respond(200, r#"{"status":"green","number_of_pending_tasks":0}"#.to_string())
+        });
+        let server = green().await;
+        let url = server.url();
+        let watchdog = Arc::new(HealthWatchdog::pausing_when_unreachable());
+        let task = watchdog.spawn(Client::new(), url.clone(), Duration::from_millis(20), 100);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(watchdog.wait_until_healthy_for(Duration::from_millis(10)).await);
+
+        // Node goes down: workers hold their batches
+        let addr = server.stop();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!watchdog.wait_until_healthy_for(Duration::from_millis(10)).await);
+
+        // Node comes back on the same address: workers resume
+        let _server = TestServer::start(addr, |_, _| {
+            Reply::Respond(200, r#"{"status":"yellow","number_of_pending_tasks":0}"#.to_string())
+        }).await;
+        assert!(watchdog.wait_until_healthy_for(Duration::from_secs(2)).await);
+        task.abort();
+
+        let windows = watchdog.pause_windows();
+        assert_eq!(windows.len(), 1);
+        assert!(windows[0].reason.starts_with("cluster unreachable"), "{}", windows[0].reason);
+    }
+
+    #[tokio::test]
+    async fn test_workers_wait_while_paused() {
+        let watchdog = Arc::new(HealthWatchdog::default());
+        watchdog.observe(Some("cluster health is red".to_string()));
+        watchdog.observe(Some("cluster health is red".to_string()));
+
+        let waiter = tokio::spawn({
+            let watchdog = watchdog.clone();
+            async move { watchdog.wait_until_healthy().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        assert_eq!(watchdog.pause_windows().len(), 1);
+
+        watchdog.observe(None);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+
+        let windows = watchdog.pause_windows();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].reason, "cluster health is red");
+    }
+}
@@ -0,0 +1,123 @@
+//! A migration that rides out an Elasticsearch restart. The cluster is a throwaway
+//! single-node container, restarted once the run has indexed its first batches;
+//! the run has to pause, resume and still index every row. It needs docker, so it
+//! only runs when asked for: `cargo test --test cluster_restart -- --ignored`.
+
+use reqwest::Client;
+use serde_json::Value;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const IMAGE: &str = "docker.elastic.co/elasticsearch/elasticsearch:8.15.0";
+const INDEX: &str = "restart_test";
+const ROWS: usize = 5_000;
+
+/// A single-node cluster on a fixed host port, so a restart keeps its address
+struct Container {
+    id: String,
+    url: String,
+}
+
+impl Container {
+    fn start() -> Self {
+        // Fixed rather than ephemeral: docker may map an ephemeral port anew on restart
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let output = Command::new("docker")
+            .args(["run", "-d", "--rm", "-p", &format!("127.0.0.1:{}:9200", port)])
+            .args(["-e", "discovery.type=single-node", "-e", "xpack.security.enabled=false", "-e", "ES_JAVA_OPTS=-Xms512m -Xmx512m"])
+            .arg(IMAGE)
+            .output()
+            .expect("docker must be installed to run this test");
+        assert!(output.status.success(), "docker run failed: {}", String::from_utf8_lossy(&output.stderr));
+        Self { id: String::from_utf8(output.stdout).unwrap().trim().to_string(), url: format!("http://127.0.0.1:{}", port) }
+    }
+
+    fn restart(&self) {
+        let status = Command::new("docker").args(["restart", "-t", "5", &self.id]).status().unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        Command::new("docker").args(["rm", "-f", &self.id]).stdout(Stdio::null()).status().ok();
+    }
+}
+
+async fn wait_for_cluster(client: &Client, url: &str) {
+    let started = Instant::now();
+    loop {
+        let health = client.get(format!("{}/_cluster/health?wait_for_status=yellow&timeout=5s", url)).send().await;
+        if health.is_ok_and(|response| response.status().is_success()) {
+            return;
+        }
+        assert!(started.elapsed() < Duration::from_secs(180), "Elasticsearch didn't start");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn document_count(client: &Client, url: &str) -> u64 {
+    let response = client.get(format!("{}/{}/_count", url, INDEX)).send().await;
+    match response {
+        Ok(response) if response.status().is_success() => response.json::<Value>().await.unwrap()["count"].as_u64().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn write_csv(path: &Path) {
+    let mut csv = String::from("token_address,token_id,owner,is_shown\n");
+    for token_id in 0..ROWS {
+        csv.push_str(&format!("0x0000000000000000000000000000000000abcdef,{},0x2222222222222222222222222222222222222222,t\n", token_id));
+    }
+    std::fs::write(path, csv).unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_migration_survives_cluster_restart() {
+    let container = Container::start();
+    let client = Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+    wait_for_cluster(&client, &container.url).await;
+
+    let dir = std::env::temp_dir().join(format!("cluster-restart-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let csv = dir.join("tokens.csv");
+    write_csv(&csv);
+
+    // Slow enough that the restart lands mid-run
+    let mut migrator = tokio::process::Command::new(env!("CARGO_BIN_EXE_erc721-elasticsearch-migrator"))
+        .current_dir(&dir)
+        .env("CSV_FILE", &csv)
+        .env("ELASTICSEARCH_URL", &container.url)
+        .env("ELASTICSEARCH_INDEX", INDEX)
+        .env("BATCH_SIZE", "50")
+        .env("WORKERS", "2")
+        .env("TIMEOUT_SECS", "5")
+        .env("MAX_REQUESTS_PER_SEC", "5")
+        .env("AUTO_CREATE_INDEX", "true")
+        .env("SURVIVE_RESTARTS", "true")
+        .env("RESTART_MAX_WAIT_SECS", "300")
+        .env("STATE_DIR", &dir)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let started = Instant::now();
+    while document_count(&client, &container.url).await == 0 {
+        assert!(started.elapsed() < Duration::from_secs(60), "the migration didn't start indexing");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    container.restart();
+
+    let status = tokio::time::timeout(Duration::from_secs(600), migrator.wait()).await
+        .expect("the migration didn't finish")
+        .unwrap();
+    assert!(status.success(), "the migration failed: {}", status);
+
+    wait_for_cluster(&client, &container.url).await;
+    client.post(format!("{}/{}/_refresh", container.url, INDEX)).send().await.unwrap();
+    assert_eq!(document_count(&client, &container.url).await, ROWS as u64);
+    std::fs::remove_dir_all(&dir).ok();
+}
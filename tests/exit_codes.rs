@@ -0,0 +1,48 @@
+//! Exit codes of runs that fail before reaching a cluster, with the binary as
+//! wrappers run it. Each runs in an empty directory, so no .env is picked up.
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn run(name: &str, args: &[&str], vars: &[(&str, &str)]) -> Output {
+    let dir = std::env::temp_dir().join(format!("exit-codes-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_erc721-elasticsearch-migrator"))
+        .args(args)
+        .envs(vars.iter().copied())
+        .env_remove("PROFILE")
+        .env_remove("RUST_BACKTRACE")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    output
+}
+
+fn profiles_file() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("profiles.example.toml")
+}
+
+#[test]
+fn test_unknown_profile_is_a_config_error() {
+    let profiles = profiles_file();
+    let output = run("profile", &["status", "--profile", "nope"], &[("PROFILES_FILE", profiles.to_str().unwrap())]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("Failed to load profile 'nope'"), "{}", stderr);
+}
+
+#[test]
+fn test_invalid_log_setting_is_a_config_error() {
+    let output = run("log-format", &["status"], &[("LOG_FORMAT", "xml")]);
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_merge_output_path_is_not_a_setting() {
+    let output = run("merge", &["checkpoint", "merge", "missing.json", "--output", "c.json"], &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // The checkpoint file is missing, which is a plain failure and not a panic over OUTPUT
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(!stderr.contains("unknown variant"), "{}", stderr);
+}